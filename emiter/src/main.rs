@@ -1,19 +1,25 @@
+use spy_protocol::device::{
+    IOCTL_EVENTS_OVERFLOW, IOCTL_EVENTS_READ, IOCTL_LOG_READ, IOCTL_LOG_SET_LEVEL,
+    IOCTL_PROCESS_APPROVE, IOCTL_PROCESS_REJECT, SPY_DEVICE_PATH,
+};
+use spy_protocol::{ProcessRecord, RecordRef};
 use std::collections::VecDeque;
-use std::ffi::CStr;
 use std::mem::MaybeUninit;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
-use std::{mem, ptr};
+use std::{io, mem, ptr};
 use winapi::shared::minwindef::{DWORD, FALSE, LPVOID};
 use winapi::um::errhandlingapi::GetLastError;
-use winapi::um::handleapi::CloseHandle;
+use winapi::um::fileapi::{CreateFileA, OPEN_EXISTING};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+use winapi::um::ioapiset::DeviceIoControl;
 use winapi::um::processthreadsapi::{
     CreateProcessA, CreateThread, TerminateProcess, TerminateThread, PROCESS_INFORMATION,
     STARTUPINFOA,
 };
-use winapi::um::synchapi::{OpenEventA, WaitForSingleObject};
+use winapi::um::synchapi::{OpenEventA, Sleep, WaitForSingleObject};
 use winapi::um::winbase::{CREATE_UNICODE_ENVIRONMENT, WAIT_OBJECT_0};
-use winapi::um::winnt::{HANDLE, SYNCHRONIZE};
+use winapi::um::winnt::{GENERIC_READ, GENERIC_WRITE, HANDLE, SYNCHRONIZE};
 
 const CREATE_EVENT_NAME: &'static [u8] = b"Global\\RustProcessSpyCreateEvent\0";
 const EXIT_EVENT_NAME: &'static [u8] = b"Global\\RustProcessSpyExitEvent\0";
@@ -30,6 +36,125 @@ unsafe impl Sync for Process {}
 
 static RUNNING: Mutex<VecDeque<Process>> = Mutex::new(VecDeque::new());
 
+//how many records one read of the events asks for
+const RECORDS_PER_READ: usize = 16;
+
+fn open_device() -> Result<HANDLE, DWORD> {
+    let device = unsafe {
+        CreateFileA(
+            SPY_DEVICE_PATH.as_ptr() as _,
+            GENERIC_READ | GENERIC_WRITE,
+            0,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if device == INVALID_HANDLE_VALUE {
+        Err(unsafe { GetLastError() })
+    } else {
+        Ok(device)
+    }
+}
+
+//sends the control code and returns how many bytes the driver wrote to `output`
+fn control(device: HANDLE, code: u32, input: &[u8], output: &mut [u8]) -> Result<usize, DWORD> {
+    let mut returned: DWORD = 0;
+    let result = unsafe {
+        DeviceIoControl(
+            device,
+            code,
+            input.as_ptr() as _,
+            input.len() as DWORD,
+            output.as_mut_ptr() as _,
+            output.len() as DWORD,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    if result == FALSE {
+        Err(unsafe { GetLastError() })
+    } else {
+        Ok(returned as usize)
+    }
+}
+
+fn print_record(record: RecordRef) {
+    match record {
+        RecordRef::Process(record) => {
+            let kind = if record.is_created() { "created" } else { "exited" };
+            println!(
+                "{kind} pid={} parent={} image={} path={} action={:?}",
+                record.pid.get(),
+                record.parent_pid.get(),
+                record.image_name,
+                record.image_path,
+                record.action(),
+            );
+        }
+        RecordRef::Registry(record) => {
+            println!("registry pid={} value={}", record.pid.get(), record.value_name);
+        }
+        RecordRef::Unknown(header) => println!("unknown record of kind {}", header.kind.get()),
+    }
+}
+
+pub extern "system" fn events_task(context: LPVOID) -> DWORD {
+    let device = context as HANDLE;
+    let mut buffer = vec![0u8; RECORDS_PER_READ * mem::size_of::<ProcessRecord>()];
+    while IS_ALIVE.load(Ordering::SeqCst) {
+        match control(device, IOCTL_EVENTS_READ, &[], &mut buffer) {
+            Ok(0) => unsafe { Sleep(500) },
+            Ok(length) => {
+                for record in spy_protocol::records(&buffer[..length]) {
+                    match record {
+                        Ok(record) => print_record(record),
+                        Err(error) => println!("Failed to decode the events: {error}"),
+                    }
+                }
+            }
+            Err(code) => {
+                println!("Failed to read the events. error_code={code}");
+                return 1;
+            }
+        }
+    }
+    0
+}
+
+fn run_command(device: HANDLE, line: &str) {
+    let mut words = line.split_whitespace();
+    let result = match (words.next(), words.next().map(str::parse::<u64>)) {
+        (Some("approve"), Some(Ok(pid))) => {
+            control(device, IOCTL_PROCESS_APPROVE, &pid.to_le_bytes(), &mut []).map(drop)
+        }
+        (Some("reject"), Some(Ok(pid))) => {
+            control(device, IOCTL_PROCESS_REJECT, &pid.to_le_bytes(), &mut []).map(drop)
+        }
+        (Some("level"), Some(Ok(level))) => {
+            control(device, IOCTL_LOG_SET_LEVEL, &(level as u32).to_le_bytes(), &mut []).map(drop)
+        }
+        (Some("log"), None) => {
+            let mut buffer = vec![0u8; 64 * 1024];
+            control(device, IOCTL_LOG_READ, &[], &mut buffer)
+                .map(|length| print!("{}", String::from_utf8_lossy(&buffer[..length])))
+        }
+        (Some("overflow"), None) => {
+            let mut dropped = [0u8; 8];
+            control(device, IOCTL_EVENTS_OVERFLOW, &[], &mut dropped)
+                .map(|_| println!("{} events were dropped", u64::from_le_bytes(dropped)))
+        }
+        _ => {
+            println!("Commands: approve <pid>, reject <pid>, level <0-5>, log, overflow, quit");
+            return;
+        }
+    };
+    if let Err(code) = result {
+        println!("The driver refused the command. error_code={code}");
+    }
+}
+
 pub extern "system" fn create_process_task(_context: LPVOID) -> DWORD {
     let event = unsafe { OpenEventA(SYNCHRONIZE, FALSE, CREATE_EVENT_NAME.as_ptr() as _) };
     if event.is_null() {
@@ -102,6 +227,13 @@ pub extern "system" fn exit_process_task(_context: LPVOID) -> DWORD {
 
 fn main() {
     println!("Not New");
+    let device = match open_device() {
+        Ok(device) => device,
+        Err(code) => {
+            println!("Failed to open the spy device. error_code={code}");
+            return;
+        }
+    };
     let create_task_thread = unsafe {
         CreateThread(
             ptr::null_mut(),
@@ -122,7 +254,25 @@ fn main() {
             ptr::null_mut(),
         )
     };
-    let _: String = text_io::read!();
+    let events_thread = unsafe {
+        CreateThread(
+            ptr::null_mut(),
+            0,
+            Some(events_task),
+            device as LPVOID,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    let mut line = String::new();
+    while IS_ALIVE.load(Ordering::SeqCst) {
+        line.clear();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) if matches!(line.trim(), "" | "quit") => break,
+            Ok(_) => run_command(device, &line),
+        }
+    }
     IS_ALIVE.store(false, Ordering::SeqCst);
     if unsafe { WaitForSingleObject(create_task_thread, 2000) } != WAIT_OBJECT_0 {
         println!("The create thread will be suppressed");
@@ -134,5 +284,10 @@ fn main() {
         println!("The exit thread will be suppressed");
         unsafe { TerminateThread(exit_task_thread, 1) };
     }
+    if unsafe { WaitForSingleObject(events_thread, 2000) } != WAIT_OBJECT_0 {
+        println!("The events thread will be suppressed");
+        unsafe { TerminateThread(events_thread, 1) };
+    }
+    unsafe { CloseHandle(device) };
     println!("Thanks for working with us!)");
 }
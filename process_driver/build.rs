@@ -1,4 +1,11 @@
+#[cfg(target_os = "windows")]
 fn main() -> Result<(), wdk_build::ConfigError> {
     wdk_build::Config::from_env_auto()?.configure_binary_build();
+    //PsSetCreateProcessNotifyRoutineEx refuses images without the integrity check flag
+    println!("cargo:rustc-cdylib-link-arg=/INTEGRITYCHECK");
     Ok(())
 }
+
+//the driver logic is also built on the host, where there is nothing to configure
+#[cfg(not(target_os = "windows"))]
+fn main() {}
@@ -0,0 +1,134 @@
+//! The settings of the spy, from the `Parameters` key of the service.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use utils::config::{ConfigError, FromParameters, Parameters};
+use utils::path::NtPath;
+use crate::watch::{RuleKind, WatchList, WatchRule};
+
+pub const WATCH_LIST_VALUE: &str = "WatchList";
+///the single name watched before there was a watch list, used when `WatchList` is missing
+pub const WATCHED_PROCESS_VALUE: &str = "WatchedProcess";
+pub const PROTECTED_PROCESSES_VALUE: &str = "ProtectedProcesses";
+pub const AUDIT_ONLY_VALUE: &str = "AuditOnly";
+pub const SUPERVISOR_PATH_VALUE: &str = "SupervisorPath";
+
+pub const DEFAULT_WATCHED_PROCESS: &str = "firefox.exe";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpyConfig {
+    ///the processes whose creation and exit are signaled, `REG_MULTI_SZ` of rules, see [`crate::watch`]
+    pub watch_list: WatchList,
+    ///image file names protected along with the supervisor and the names on the watch list, `REG_MULTI_SZ`
+    pub protected_processes: Vec<String>,
+    ///rules that deny, terminate or suspend only record what they would have done, `REG_DWORD`
+    pub audit_only: bool,
+    ///the NT path of the image of the user-mode supervisor, `REG_SZ`; without it no process is trusted as the supervisor
+    pub supervisor_path: Option<NtPath>,
+}
+
+impl Default for SpyConfig {
+    fn default() -> Self {
+        Self { watch_list: WatchList::parse(&[DEFAULT_WATCHED_PROCESS]).unwrap_or_default(), protected_processes: Vec::new(), audit_only: false, supervisor_path: None }
+    }
+}
+
+//processes are matched by the file name of their image, never by a path
+fn check_image_name(name: &'static str, image_name: &str) -> Result<(), ConfigError> {
+    if image_name.is_empty() {
+        return Err(ConfigError::invalid(name, "an image name is empty"));
+    }
+    if image_name.contains(['\\', '/']) {
+        return Err(ConfigError::invalid(name, "an image name is a path"));
+    }
+    Ok(())
+}
+
+impl FromParameters for SpyConfig {
+    fn from_parameters(parameters: &Parameters) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        if let Some(rules) = parameters.multi_string(WATCH_LIST_VALUE)? {
+            config.watch_list = WatchList::parse(rules).map_err(|error| ConfigError::invalid(WATCH_LIST_VALUE, error.reason()))?;
+        } else if let Some(watched_process) = parameters.string(WATCHED_PROCESS_VALUE)? {
+            check_image_name(WATCHED_PROCESS_VALUE, watched_process)?;
+            let rule = WatchRule::new(RuleKind::CaseInsensitive, watched_process)
+                .map_err(|error| ConfigError::invalid(WATCHED_PROCESS_VALUE, error.reason()))?;
+            config.watch_list = WatchList::new();
+            config.watch_list.add(rule).map_err(|error| ConfigError::invalid(WATCHED_PROCESS_VALUE, error.reason()))?;
+        }
+        if let Some(protected_processes) = parameters.multi_string(PROTECTED_PROCESSES_VALUE)? {
+            for image_name in protected_processes {
+                check_image_name(PROTECTED_PROCESSES_VALUE, image_name)?;
+            }
+            config.protected_processes = protected_processes.to_vec();
+        }
+        if let Some(audit_only) = parameters.dword(AUDIT_ONLY_VALUE)? {
+            config.audit_only = audit_only != 0;
+        }
+        if let Some(supervisor_path) = parameters.string(SUPERVISOR_PATH_VALUE)? {
+            //image paths are reported below the volume device
+            let path = NtPath::parse(supervisor_path).map_err(|_| ConfigError::invalid(SUPERVISOR_PATH_VALUE, "not an NT path"))?;
+            if !matches!(path, NtPath::Device { .. }) {
+                return Err(ConfigError::invalid(SUPERVISOR_PATH_VALUE, "not below a device"));
+            }
+            config.supervisor_path = Some(path);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use utils::config::RegistryValue;
+
+    fn config(values: &[(&str, RegistryValue)]) -> Result<SpyConfig, ConfigError> {
+        let mut parameters = Parameters::new();
+        for (name, value) in values {
+            parameters.insert(name, value.clone());
+        }
+        SpyConfig::from_parameters(&parameters)
+    }
+
+    #[test]
+    fn missing_values_keep_the_defaults() {
+        let config = config(&[]).unwrap();
+        assert_eq!(config, SpyConfig::default());
+        assert_eq!(config.watch_list.to_strings(), ["name:firefox.exe"]);
+    }
+
+    #[test]
+    fn the_watch_list_wins_over_the_single_name() {
+        let watched = (WATCHED_PROCESS_VALUE, RegistryValue::String("notepad.exe".into()));
+        assert_eq!(config(core::slice::from_ref(&watched)).unwrap().watch_list.to_strings(), ["name:notepad.exe"]);
+        let list = (WATCH_LIST_VALUE, RegistryValue::MultiString(vec!["deny:a.exe".into(), "glob:*.com".into()]));
+        assert_eq!(config(&[watched, list]).unwrap().watch_list.to_strings(), ["deny:name:a.exe", "glob:*.com"]);
+        let path = (WATCHED_PROCESS_VALUE, RegistryValue::String("\\Device\\a.exe".into()));
+        assert!(config(&[path]).is_err());
+        let unknown = (WATCH_LIST_VALUE, RegistryValue::MultiString(vec!["nokind:a.exe".into()]));
+        assert!(config(&[unknown]).is_err());
+    }
+
+    #[test]
+    fn protected_processes_are_names() {
+        let names = (PROTECTED_PROCESSES_VALUE, RegistryValue::MultiString(vec!["lsass.exe".into()]));
+        assert_eq!(config(&[names]).unwrap().protected_processes, ["lsass.exe"]);
+        let path = (PROTECTED_PROCESSES_VALUE, RegistryValue::MultiString(vec!["C:\\lsass.exe".into()]));
+        assert!(config(&[path]).is_err());
+        let empty = (PROTECTED_PROCESSES_VALUE, RegistryValue::MultiString(vec!["a.exe".into(), String::new()]));
+        assert!(config(&[empty]).is_err());
+    }
+
+    #[test]
+    fn the_supervisor_path_is_below_a_device() {
+        let path = (SUPERVISOR_PATH_VALUE, RegistryValue::String("\\Device\\HarddiskVolume2\\Tools\\emiter.exe".into()));
+        let config_path = config(&[path]).unwrap().supervisor_path.unwrap();
+        assert!(config_path.eq_ignore_case(&NtPath::parse("\\device\\harddiskvolume2\\tools\\EMITER.exe").unwrap()));
+        for invalid in ["emiter.exe", "\\??\\C:\\Tools\\emiter.exe"] {
+            assert!(config(&[(SUPERVISOR_PATH_VALUE, RegistryValue::String(invalid.into()))]).is_err(), "{invalid}");
+        }
+        let audit = (AUDIT_ONLY_VALUE, RegistryValue::Dword(1));
+        assert!(config(&[audit]).unwrap().audit_only);
+    }
+}
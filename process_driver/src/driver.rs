@@ -1,21 +1,21 @@
-use wdk::{nt_success, paged_code, println};
-use wdk_sys::{DRIVER_OBJECT, HANDLE, macros, ntddk::KeGetCurrentIrql, NTSTATUS, *};
+use wdk::{nt_success, paged_code};
+use wdk_sys::{DRIVER_OBJECT, macros, NTSTATUS, *};
 
 extern crate alloc;
 
 use alloc::{slice, string::String};
 use alloc::boxed::Box;
 
-use alloc::string::ToString;
-
 use core::{mem, ptr};
-use core::ffi::CStr;
-
-use core::ptr::NonNull;
 
-use wdk_sys::_WORK_QUEUE_TYPE::DelayedWorkQueue;
-use wdk_sys::ntddk::{IoAllocateWorkItem, IoCreateDevice, IoDeleteDevice, IofCompleteRequest, IoFreeWorkItem, IoQueueWorkItem, MmGetSystemRoutineAddress, PsLookupProcessByProcessId};
-use utils::{WindowsUnicode, add_notify_callback, KernelEvent, remove_notify_callback};
+use utils::{debug, dispatch, error, info, log, trace, Apc, KernelApi, NtError, Passive, Status};
+use utils::kernel::{HandleRequest, ImageLoadInfo, ProcessNotification, ThreadNotification};
+use utils::kernel::wdk::{ImageNotifyRegistration, ProcessNotifyRegistration, ThreadNotifyRegistration, WdkKernel, WdkObjectCallbacks};
+use utils::config::{self, FromParameters, Parameters};
+use utils::sync::PushLock;
+use crate::config::SpyConfig;
+use crate::protection;
+use crate::spy::ProcessSpy;
 
 pub struct StringObject {
     handle: WDFSTRING,
@@ -33,7 +33,7 @@ impl StringObject {
             )
         };
         if !nt_success(nt_status) {
-            panic!("WdfStringCreate failed {nt_status:#010X}");
+            panic!("WdfStringCreate failed {}", Status::new(nt_status));
         }
         Self { handle }
     }
@@ -47,7 +47,7 @@ impl StringObject {
                 &mut handle
             )
         };
-        assert!(nt_success(nt_status), "WdfStringCreate with wrapper failed {nt_status:#010X}");
+        assert!(nt_success(nt_status), "WdfStringCreate with wrapper failed {}", Status::new(nt_status));
         Self { handle }
     }
     pub fn as_unicode(&self) -> UNICODE_STRING {
@@ -77,190 +77,38 @@ impl Drop for StringObject {
     }
 }
 
-fn find_pid_resolver() -> Result<ProcessNameResolver, NTSTATUS> {
-    let mut resolver_name = "PsGetProcessImageFileName".to_string().to_unicode();
-    let resolver = unsafe {
-        let address: PVOID = MmGetSystemRoutineAddress(&mut resolver_name);
-        if address.is_null() {
-            println!("Failed to find GetProcessImageFileName");
-            Err(STATUS_NO_SUCH_MEMBER)
-        } else {
-            Ok(mem::transmute::<PVOID, ProcessNameResolver>(address))
-        }
-    };
-    resolver
-}
+static CURRENT_SPY: PushLock<Option<&'static ProcessSpy<WdkKernel>>> = PushLock::new(None);
 
-pub struct SpyWorker {
-    handle: PIO_WORKITEM,
-    proc: HANDLE,
-    //the process with which interact
-    is_created: BOOLEAN,
-    //the process state
-    spy: NonNull<ProcessSpy>,
+fn replace_current_spy(irql: &Passive, spy: &'static ProcessSpy<WdkKernel>) -> Option<&'static ProcessSpy<WdkKernel>> {
+    CURRENT_SPY.write(irql).replace(spy)
 }
 
-impl SpyWorker {
-    pub fn new(mut spy: NonNull<ProcessSpy>, proc: HANDLE, is_created: BOOLEAN) -> Box<SpyWorker> {
-        let device = unsafe { spy.as_mut().device() };
-        let handle = unsafe { IoAllocateWorkItem(device) };
-        Box::new(Self { handle, proc, is_created, spy })
-    }
-    pub const fn handle(&self) -> PIO_WORKITEM {
-        self.handle
-    }
-    pub unsafe extern "C" fn dispatch_wrapper(_device: *mut DEVICE_OBJECT, context: PVOID) {
-        println!("Starting worker dispatching");
-        let mut worker = Box::from_raw(context.cast::<Self>());
-        let spy = worker.spy.as_mut();
-        spy.dispatch(worker.proc, worker.is_created);
-    }
+//dropping a registration removes its notify routine
+struct NotifyRegistrations {
+    _process: ProcessNotifyRegistration,
+    _thread: ThreadNotifyRegistration,
+    _image: ImageNotifyRegistration,
+    _objects: WdkObjectCallbacks,
 }
 
-impl Drop for SpyWorker {
-    fn drop(&mut self) {
-        unsafe { IoFreeWorkItem(self.handle) }
-    }
-}
-
-///the main struct that control situation
-pub struct ProcessSpy {
-    device: NonNull<DEVICE_OBJECT>,
-    //events to communicate with user-mode manager that should start/close corresponding process
-    create_event: KernelEvent,
-    exit_event: KernelEvent,
-    //the function pointer
-    pid_resolver: ProcessNameResolver,
-}
-
-unsafe impl Send for ProcessSpy {}
-
-unsafe impl Sync for ProcessSpy {}
-
-type ProcessNameResolver = fn(PEPROCESS) -> PCHAR;
-
-
-impl ProcessSpy {
-    const TRACKABLE_PROCESS_NAME: &'static [u8] = b"firefox.exe";
-    pub fn new(driver: &mut DRIVER_OBJECT) -> Result<&'static mut Self, NTSTATUS> {
-        let mut device: *mut DEVICE_OBJECT = ptr::null_mut();
-        let nt_status = unsafe {
-            IoCreateDevice(
-                driver,
-                mem::size_of::<ProcessSpy>() as ULONG,
-                ptr::null_mut(),
-                FILE_DEVICE_UNKNOWN,
-                0,
-                FALSE as BOOLEAN,
-                &mut device,
-            )
-        };
-        if !nt_success(nt_status) {
-            return Err(nt_status);
-        }
-        if device.is_null() {
-            println!("Device object still null");
-            return Err(STATUS_UNEXPECTED_IO_ERROR);
-        }
-        let create_event_name = concat!("\\BaseNamedObjects\\", "RustProcessSpyCreateEvent")
-            .to_string();
-        let exit_event_name = concat!("\\BaseNamedObjects\\", "RustProcessSpyExitEvent")
-            .to_string();
-        let create_event = KernelEvent::new(&create_event_name)?;
-        let exit_event = KernelEvent::new(&exit_event_name)?;
-        let pid_resolver = find_pid_resolver()?;
-        let spy = unsafe {
-            let spy_layout = (*device).DeviceExtension.cast::<Self>();
-            spy_layout.write(Self {
-                device: NonNull::new_unchecked(device),
-                create_event,
-                exit_event,
-                pid_resolver,
-            });
-            &mut *spy_layout
-        };
-        println!("New spy is created");
-        Ok(spy)
-    }
-
-    pub fn dispatch(&mut self, pid: HANDLE, is_created: BOOLEAN) {
-        let mut process_info: PEPROCESS = ptr::null_mut();
-        let nt_status = unsafe { PsLookupProcessByProcessId(pid, &mut process_info) };
-        if !nt_success(nt_status) {
-            println!("Failed to lookup process by id {nt_status:#010X}");
-            return;
-        }
-        let pid_resolver = self.pid_resolver;
-        let process_name = unsafe {
-            let pid_name = pid_resolver(process_info);
-            CStr::from_ptr(pid_name)
-        };
-
-        match process_name.to_str() {
-            Ok(rust_string) => {
-                println!("Process {rust_string} is catched");
-            }
-            Err(_) => {
-                println!("Failed to parse UTF-8 from process name");
-            }
-        }
-
-        if !Self::same_with_trackable(process_name) {
-            return;
-        }
-        if is_created == TRUE as BOOLEAN {
-            println!("Firefox created!");
-            self.create_event.raise();
-        } else {
-            println!("Firefox left!");
-            self.exit_event.raise();
-        }
-    }
-    fn same_with_trackable(process_name: &CStr) -> bool {
-        let bytes = process_name.to_bytes();
-        let mut index = 0;
-        let mut same = true;
-        while same && index < usize::min(bytes.len(), Self::TRACKABLE_PROCESS_NAME.len()) {
-            same = bytes[index] == Self::TRACKABLE_PROCESS_NAME[index];
-            index += 1;
-        }
-        same
-    }
-    pub fn device(&mut self) -> &mut DEVICE_OBJECT {
-        unsafe { self.device.as_mut() }
-    }
-    pub unsafe fn free(&mut self) {
-        IoDeleteDevice(self.device.as_mut());
-        self.create_event.free();
-        self.exit_event.free();
-        println!("The spy is deleted");
-    }
-}
-
-
-static CURRENT_SPY: spin::Mutex<Option<&'static mut ProcessSpy>> = spin::Mutex::new(None);
-
-fn current_spy() -> NonNull<ProcessSpy> {
-    let mut guard = CURRENT_SPY.lock();
-    let spy = guard.as_deref_mut().expect("The Process Spy should be initialized");
-    NonNull::from(spy)
-}
-
-fn replace_current_spy(spy: &'static mut ProcessSpy) -> Option<&'static mut ProcessSpy> {
-    CURRENT_SPY.lock().replace(spy)
-}
-
-///the process callback that will be invoked each time when new process is created
-pub unsafe extern "C" fn notify_callback(_parent: HANDLE, child: HANDLE, is_created: BOOLEAN) {
-    println!("Notify callback is started");
-    let spy = current_spy();
-    let worker = SpyWorker::new(spy, child, is_created);
-    IoQueueWorkItem(
-        worker.handle(),
-        Some(SpyWorker::dispatch_wrapper),
-        DelayedWorkQueue,
-        (Box::leak(worker) as *mut SpyWorker).cast(),
-    );
+static NOTIFY_REGISTRATIONS: PushLock<Option<NotifyRegistrations>> = PushLock::new(None);
+
+fn register_notify_routines(irql: &Passive, spy: &'static ProcessSpy<WdkKernel>) -> Result<(), NtError> {
+    let kernel = spy.kernel();
+    let process = kernel.register_process_notify(irql, Box::new(move |irql: &mut Passive, notification: &mut ProcessNotification<'_>| {
+        spy.on_process_notification(irql, notification);
+    }))?;
+    let thread = kernel.register_thread_notify(irql, Box::new(move |irql: &mut Apc, notification: &ThreadNotification| {
+        spy.on_thread_notification(irql, notification);
+    }))?;
+    let image = kernel.register_image_notify(irql, Box::new(move |irql: &mut Passive, info: &ImageLoadInfo<'_>| {
+        spy.on_image_load(irql, info);
+    }))?;
+    let objects = kernel.register_object_callbacks(irql, protection::ALTITUDE, Box::new(move |irql: &mut Apc, request: &mut HandleRequest| {
+        spy.on_handle_request(irql, request);
+    }), None)?;
+    *NOTIFY_REGISTRATIONS.write(irql) = Some(NotifyRegistrations { _process: process, _thread: thread, _image: image, _objects: objects });
+    Ok(())
 }
 
 /// DriverEntry initializes the driver and is the first routine called by the
@@ -288,6 +136,22 @@ extern "system" fn driver_entry(
     driver: &mut DRIVER_OBJECT,
     registry_path: PCUNICODE_STRING,
 ) -> NTSTATUS {
+    //DriverEntry is called at PASSIVE_LEVEL
+    let irql = unsafe { Passive::new_unchecked() };
+    let parameters = config::read_parameters(&irql, unsafe { &*registry_path }).unwrap_or_else(|error| {
+        error!("Using the default settings, {error}");
+        Parameters::new()
+    });
+    if let Err(error) = log::load_max_level(&parameters) {
+        error!("Keeping the default log level, {error}");
+    }
+    let config = match SpyConfig::from_parameters(&parameters) {
+        Ok(config) => config,
+        Err(error) => {
+            error!("Invalid driver parameters, {error}");
+            return NtError::from(error).code();
+        }
+    };
     let mut driver_config = WDF_DRIVER_CONFIG {
         Size: mem::size_of::<WDF_DRIVER_CONFIG>() as ULONG,
         EvtDriverDeviceAdd: Some(echo_evt_device_add),
@@ -305,67 +169,56 @@ extern "system" fn driver_entry(
         )
     };
     if !nt_success(nt_status) {
-        println!("Error: WdfDriverCreate failed {nt_status:#010X}");
+        error!("WdfDriverCreate failed {}", Status::new(nt_status));
         return nt_status;
     }
-    init_driver_functions(driver);
-    let spy_result = ProcessSpy::new(driver);
-    match spy_result {
+    let spy_result = WdkKernel::new(driver, unsafe { &*registry_path }).and_then(|kernel| ProcessSpy::new(&irql, kernel, &config));
+    let spy = match spy_result {
         Ok(spy) => {
-            let old = replace_current_spy(spy);
+            let spy = Box::leak(Box::new(spy));
+            init_driver_functions(driver, spy);
+            let old = replace_current_spy(&irql, spy);
             debug_assert!(old.is_none());
+            spy
         }
-        Err(code) => {
-            return code;
+        Err(error) => {
+            error!("Failed to create the spy {error}");
+            return error.code();
         }
-    }
+    };
     echo_print_driver_version();
-    add_notify_callback(Some(notify_callback));
+    if let Err(error) = register_notify_routines(&irql, spy) {
+        error!("Failed to register the notify routines {error}");
+        //DriverUnload is not called when DriverEntry fails
+        unload_driver(driver);
+        return error.code();
+    }
     nt_status
 }
 
 #[link_section = "INIT"]
-fn init_driver_functions(driver: &mut DRIVER_OBJECT) {
-    for (index, function) in driver.MajorFunction.iter_mut().enumerate() {
-        let proc_index = index as u32;
-        if proc_index == IRP_MJ_CREATE || proc_index == IRP_MJ_CLOSE {
-            *function = Some(create_close_function);
-        } else {
-            *function = Some(unsupported_function);
-        }
-    }
+fn init_driver_functions(driver: &mut DRIVER_OBJECT, spy: &'static ProcessSpy<WdkKernel>) {
+    dispatch::install(driver, spy);
     driver.DriverUnload = Some(unload_driver);
-    println!("Driver functions are initialized");
-}
-
-extern "C" fn unsupported_function(_device: *mut DEVICE_OBJECT, irp: *mut IRP) -> NTSTATUS {
-    println!("Unsupported handler was invoked");
-    let request = unsafe { &mut *irp };
-    let status_block = &mut request.IoStatus;
-    status_block.__bindgen_anon_1.Status = STATUS_NOT_SUPPORTED;
-    status_block.Information = 0;
-    unsafe { IofCompleteRequest(irp, IO_NO_INCREMENT as _) };
-    STATUS_SUCCESS
-}
-
-extern "C" fn create_close_function(_device: *mut DEVICE_OBJECT, irp: *mut IRP) -> NTSTATUS {
-    println!("Create-Close handler was invoked");
-    let request = unsafe { &mut *irp };
-    let status_block = &mut request.IoStatus;
-    status_block.__bindgen_anon_1.Status = STATUS_SUCCESS;
-    status_block.Information = 0;
-    unsafe { IofCompleteRequest(irp, IO_NO_INCREMENT as _) };
-    STATUS_SUCCESS
+    debug!("Driver functions are initialized");
 }
 
 extern "C" fn unload_driver(_driver: *mut DRIVER_OBJECT) {
-    println!("Driver unloading is started");
-    unsafe {
-        let spy = current_spy().as_mut();
-        spy.free();
-        remove_notify_callback(Some(notify_callback));
+    info!("Driver unloading is started");
+    //DriverUnload is called at PASSIVE_LEVEL
+    let irql = unsafe { Passive::new_unchecked() };
+    //no notification may reach the spy once it is freed
+    drop(NOTIFY_REGISTRATIONS.write(&irql).take());
+    dispatch::uninstall();
+    //taken out first, so the spy is not freed in the critical region of the lock
+    let spy = CURRENT_SPY.write(&irql).take();
+    if let Some(spy) = spy {
+        let spy = unsafe { Box::from_raw(ptr::from_ref(spy).cast_mut()) };
+        spy.free(&irql);
     }
-    println!("Driver is unloaded");
+    #[cfg(not(test))]
+    crate::GLOBAL_ALLOCATOR.report_leaks();
+    info!("Driver is unloaded");
 }
 
 /// EvtDeviceAdd is called by the framework in response to AddDevice
@@ -384,7 +237,7 @@ extern "C" fn unload_driver(_driver: *mut DRIVER_OBJECT) {
 extern "C" fn echo_evt_device_add(_driver: WDFDRIVER, _device_init: PWDFDEVICE_INIT) -> NTSTATUS {
     paged_code!();
 
-    println!("Enter  EchoEvtDeviceAdd");
+    trace!("Enter  EchoEvtDeviceAdd");
     STATUS_SUCCESS
 }
 
@@ -413,7 +266,7 @@ fn echo_print_driver_version() -> NTSTATUS {
         )
     };
     if !nt_success(nt_status) {
-        println!("Error: WdfStringCreate failed {nt_status:#010X}");
+        error!("WdfStringCreate failed {}", Status::new(nt_status));
         return nt_status;
     }
 
@@ -428,7 +281,7 @@ fn echo_print_driver_version() -> NTSTATUS {
         // deleted when the driverobject is deleted when the DriverEntry
         // returns a failure status.
         //
-        println!("Error: WdfDriverRetrieveVersionString failed {nt_status:#010X}");
+        error!("WdfDriverRetrieveVersionString failed {}", Status::new(nt_status));
         return nt_status;
     }
 
@@ -441,7 +294,7 @@ fn echo_print_driver_version() -> NTSTATUS {
             us.Length as usize / core::mem::size_of_val(&(*us.Buffer)),
         )
     });
-    println!("Echo Sample {driver_version}");
+    info!("Echo Sample {driver_version}");
 
     let [_] = [unsafe {
         macros::call_unsafe_wdf_function_binding!(WdfObjectDelete, string as WDFOBJECT)
@@ -460,9 +313,9 @@ fn echo_print_driver_version() -> NTSTATUS {
         macros::call_unsafe_wdf_function_binding!(WdfDriverIsVersionAvailable, driver, &mut ver)
     } > 0
     {
-        println!("Yes, framework version is 1.0");
+        debug!("Yes, framework version is 1.0");
     } else {
-        println!("No, framework version is not 1.0");
+        debug!("No, framework version is not 1.0");
     }
 
     STATUS_SUCCESS
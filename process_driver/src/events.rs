@@ -0,0 +1,200 @@
+//! The queue of events of watched processes that user mode drains.
+//!
+//! Every start and exit of a process a rule matches is queued, whatever the
+//! action of the rule. User mode reads the events as
+//! [`spy_protocol::ProcessRecord`]s, with `ReadFile` on the spy device or
+//! with [`IOCTL_EVENTS_READ`], and only whole records are handed out. When
+//! the queue is full new events are dropped and counted; the sequence
+//! numbers of the records show where.
+
+use core::mem;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spy_protocol::{Le16, Le32, Le64, NameField, ProcessAction, ProcessRecord, Record};
+use utils::ioctl::{ControlCode, Ioctl, RequiredAccess, TransferMethod};
+use utils::kernel::{ProcessId, ThreadId};
+use utils::ring::Ring;
+use utils::NtError;
+use crate::spy::ProcessImage;
+use crate::watch::RuleAction;
+
+///how many events may wait for user mode before new ones are dropped
+pub const EVENT_CAPACITY: usize = 256;
+
+pub const RECORD_SIZE: usize = mem::size_of::<ProcessRecord>();
+
+///moves the queued records into the output buffer, as a read does
+pub const IOCTL_EVENTS_READ: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x205,
+    TransferMethod::Buffered,
+    RequiredAccess::Read,
+);
+///copies how many events were dropped, as a `u64`
+pub const IOCTL_EVENTS_OVERFLOW: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x206,
+    TransferMethod::Buffered,
+    RequiredAccess::Read,
+);
+
+///[`IOCTL_EVENTS_OVERFLOW`]
+pub struct EventsOverflow;
+
+impl Ioctl for EventsOverflow {
+    const CODE: ControlCode = IOCTL_EVENTS_OVERFLOW;
+    type Input = ();
+    type Output = u64;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcessEvent {
+    pub pid: ProcessId,
+    ///zero when it is not known, as on exit
+    pub parent_pid: ProcessId,
+    ///the thread that creates the process; zero on exit
+    pub creating_pid: ProcessId,
+    pub creating_tid: ThreadId,
+    pub session_id: u32,
+    ///when the notify routine ran, in 100ns intervals since 1601-01-01 UTC
+    pub timestamp: u64,
+    pub is_created: bool,
+    pub image: ProcessImage,
+    ///the action of the rule that matched
+    pub action: RuleAction,
+    ///the action was not taken because the spy only audits
+    pub audited: bool,
+}
+
+impl ProcessEvent {
+    #[must_use]
+    pub fn to_record(&self, sequence: u32) -> ProcessRecord {
+        let mut record = ProcessRecord::new(self.is_created, self.pid as u64, self.parent_pid as u64, &self.image.short_name);
+        let header = record.header_mut();
+        header.sequence = Le32::new(sequence);
+        header.timestamp = Le64::new(self.timestamp);
+        record.creating_pid = Le64::new(self.creating_pid as u64);
+        record.creating_tid = Le64::new(self.creating_tid as u64);
+        record.session_id = Le32::new(self.session_id);
+        record.action = Le16::new(ProcessAction::from(self.action) as u16);
+        record.flags = Le16::new(if self.audited { ProcessRecord::AUDITED } else { 0 });
+        record.image_path = self.image.full_path.as_deref().map_or_else(NameField::default, NameField::new);
+        record
+    }
+}
+
+impl From<RuleAction> for ProcessAction {
+    fn from(action: RuleAction) -> Self {
+        match action {
+            RuleAction::Signal => Self::Signal,
+            RuleAction::Record => Self::Record,
+            RuleAction::Deny => Self::Deny,
+            RuleAction::Terminate => Self::Terminate,
+            RuleAction::Suspend => Self::Suspend,
+        }
+    }
+}
+
+pub struct EventQueue {
+    events: Ring<(u32, ProcessEvent)>,
+    //taken by dropped events too, so user mode sees the gap
+    sequence: AtomicU32,
+}
+
+impl EventQueue {
+    ///`capacity` must be a power of two
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self { events: Ring::new(capacity), sequence: AtomicU32::new(0) }
+    }
+    ///returns `false` when the queue is full and the event is dropped
+    pub fn push(&self, event: ProcessEvent) -> bool {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        self.events.push((sequence, event)).is_ok()
+    }
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+    ///how many events were dropped because user mode fell behind
+    #[must_use]
+    pub fn overflowed(&self) -> usize {
+        self.events.dropped()
+    }
+    /// Moves as many whole records as fit into `buffer`, oldest first, and
+    /// returns how many bytes they take.
+    ///
+    /// # Errors
+    /// `STATUS_BUFFER_TOO_SMALL` when not even one record fits.
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, NtError> {
+        let (chunks, _) = buffer.as_chunks_mut::<RECORD_SIZE>();
+        if chunks.is_empty() {
+            return Err(NtError::BUFFER_TOO_SMALL);
+        }
+        let mut chunks = chunks.iter_mut();
+        let count = self.events.drain(chunks.len(), |(sequence, event)| {
+            if let Some(chunk) = chunks.next() {
+                chunk.copy_from_slice(event.to_record(sequence).as_bytes());
+            }
+        });
+        Ok(count * RECORD_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use spy_protocol::RecordRef;
+
+    fn event(pid: ProcessId) -> ProcessEvent {
+        ProcessEvent { pid, is_created: true, ..ProcessEvent::default() }
+    }
+
+    //the pid and sequence number of every record in `bytes`
+    fn decode(bytes: &[u8]) -> Vec<(u64, u32)> {
+        spy_protocol::records(bytes)
+            .map(|record| match record {
+                Ok(RecordRef::Process(record)) => (record.pid.get(), record.header.sequence.get()),
+                other => panic!("not a process record: {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_full_queue_drops_and_counts_new_events() {
+        let queue = EventQueue::new(4);
+        for pid in 1..=6 {
+            assert_eq!(queue.push(event(pid)), pid <= 4);
+        }
+        assert_eq!((queue.len(), queue.overflowed()), (4, 2));
+        let mut buffer = vec![0u8; 8 * RECORD_SIZE];
+        let length = queue.read(&mut buffer).unwrap();
+        assert_eq!(decode(&buffer[..length]), [(1, 0), (2, 1), (3, 2), (4, 3)]);
+        //the dropped events took sequence numbers 4 and 5
+        assert!(queue.push(event(7)));
+        let length = queue.read(&mut buffer).unwrap();
+        assert_eq!(decode(&buffer[..length]), [(7, 6)]);
+        assert_eq!(queue.overflowed(), 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn reads_hand_out_whole_records_only() {
+        let queue = EventQueue::new(4);
+        queue.push(event(1));
+        queue.push(event(2));
+        let mut buffer = vec![0u8; RECORD_SIZE * 3 / 2];
+        assert_eq!(queue.read(&mut buffer[..RECORD_SIZE - 1]), Err(NtError::BUFFER_TOO_SMALL));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.read(&mut buffer), Ok(RECORD_SIZE));
+        assert_eq!(decode(&buffer[..RECORD_SIZE]), [(1, 0)]);
+        assert_eq!(queue.read(&mut buffer), Ok(RECORD_SIZE));
+        assert_eq!(decode(&buffer[..RECORD_SIZE]), [(2, 1)]);
+        assert_eq!(queue.read(&mut buffer), Ok(0));
+    }
+}
@@ -29,7 +29,7 @@
 //!    would not need any additional explicit synchronization, just a
 //!    strategy for managing multiple requests outstanding.
 
-#![no_std]
+#![cfg_attr(target_os = "windows", no_std)]
 #![cfg_attr(feature = "nightly", feature(hint_must_use))]
 // #![deny(warnings)]
 // #![deny(clippy::all)]
@@ -38,9 +38,16 @@
 #![warn(clippy::cargo)]
 #![allow(clippy::missing_safety_doc)]
 
+#[cfg(target_os = "windows")]
 mod driver;
+pub mod config;
+pub mod events;
+pub mod protection;
+pub mod matcher;
+pub mod spy;
+pub mod watch;
 
-#[cfg(not(test))]
+#[cfg(all(not(test), target_os = "windows"))]
 #[panic_handler]
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
     wdk::println!("Driver panics={info}");
@@ -50,10 +57,10 @@ pub fn panic(info: &core::panic::PanicInfo) -> ! {
 extern crate alloc;
 extern crate utils;
 
-#[cfg(not(test))]
-use wdk_alloc::WDKAllocator;
+#[cfg(all(not(test), target_os = "windows"))]
+use utils::pool::{NonPagedPool, PoolTag, TaggedAllocator};
 
 
-#[cfg(not(test))]
+#[cfg(all(not(test), target_os = "windows"))]
 #[global_allocator]
-static GLOBAL_ALLOCATOR: WDKAllocator = WDKAllocator;
\ No newline at end of file
+static GLOBAL_ALLOCATOR: TaggedAllocator<NonPagedPool> = TaggedAllocator::new(NonPagedPool, PoolTag::new(b"PSpy"));
\ No newline at end of file
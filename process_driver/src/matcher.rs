@@ -0,0 +1,334 @@
+//! Matching of image names and paths against the patterns of watch rules.
+//!
+//! A [`Matcher`] compiles its pattern once: the case-insensitive modes fold
+//! the pattern with [`upcase`](utils::unicode::upcase) up front and a glob is split at its stars into
+//! segments, so a match walks the name once per segment and never
+//! allocates. Every mode but [`MatchMode::Exact`] ignores case the way the
+//! path comparisons of `utils` do, so a rule matches every name they see as
+//! the same.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use core::fmt::{Display, Formatter};
+use core::fmt;
+use utils::unicode::{str_eq_ignore_case, upcase_char};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MatchMode {
+    ///the whole name, compared as it is
+    Exact,
+    ///the whole name
+    CaseInsensitive,
+    ///the start of the name
+    Prefix,
+    ///the end of the name
+    Suffix,
+    ///the whole name, with `*` for any run of characters and `?` for any one
+    Glob,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PatternError {
+    ///an empty pattern would match every name in prefix and suffix mode and none in the others
+    Empty,
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("the pattern is empty"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Token {
+    //folded
+    Char(char),
+    Any,
+}
+
+impl Token {
+    fn matches(self, character: char) -> bool {
+        match self {
+            Self::Char(expected) => expected == upcase_char(character),
+            Self::Any => true,
+        }
+    }
+}
+
+//the patterns of all but the exact mode folded, a character at a time since folding may change
+//how many bytes one takes
+#[derive(Debug, Clone)]
+enum Compiled {
+    Exact(String),
+    CaseInsensitive(Box<[char]>),
+    Prefix(Box<[char]>),
+    Suffix(Box<[char]>),
+    //the runs between the stars, so there is always one more than there are stars
+    Glob(Box<[Box<[Token]>]>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    mode: MatchMode,
+    pattern: String,
+    compiled: Compiled,
+}
+
+impl Matcher {
+    /// # Errors
+    /// [`PatternError::Empty`] for an empty pattern.
+    pub fn new(mode: MatchMode, pattern: &str) -> Result<Self, PatternError> {
+        if pattern.is_empty() {
+            return Err(PatternError::Empty);
+        }
+        let compiled = match mode {
+            MatchMode::Exact => Compiled::Exact(pattern.to_string()),
+            MatchMode::CaseInsensitive => Compiled::CaseInsensitive(fold(pattern)),
+            MatchMode::Prefix => Compiled::Prefix(fold(pattern)),
+            MatchMode::Suffix => Compiled::Suffix(fold(pattern)),
+            MatchMode::Glob => Compiled::Glob(compile_glob(pattern)),
+        };
+        Ok(Self { mode, pattern: pattern.to_string(), compiled })
+    }
+    #[must_use]
+    pub const fn mode(&self) -> MatchMode {
+        self.mode
+    }
+    ///the pattern as it was given
+    #[must_use]
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+    #[must_use]
+    pub fn is_match(&self, name: &str) -> bool {
+        match &self.compiled {
+            Compiled::Exact(pattern) => name == pattern,
+            Compiled::CaseInsensitive(pattern) => name.chars().map(upcase_char).eq(pattern.iter().copied()),
+            Compiled::Prefix(pattern) => starts_with(name.chars(), pattern.iter()),
+            Compiled::Suffix(pattern) => starts_with(name.chars().rev(), pattern.iter().rev()),
+            Compiled::Glob(segments) => glob_matches(segments, name),
+        }
+    }
+}
+
+///patterns are the same when they match the same names, so only exact ones compare the case
+impl PartialEq for Matcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.mode == other.mode
+            && match self.mode {
+                MatchMode::Exact => self.pattern == other.pattern,
+                _ => str_eq_ignore_case(&self.pattern, &other.pattern),
+            }
+    }
+}
+
+impl Eq for Matcher {}
+
+fn fold(pattern: &str) -> Box<[char]> {
+    pattern.chars().map(upcase_char).collect()
+}
+
+//whether the folded `name` starts with the folded `pattern`
+fn starts_with<'a>(mut name: impl Iterator<Item = char>, mut pattern: impl Iterator<Item = &'a char>) -> bool {
+    pattern.all(|expected| name.next().map(upcase_char) == Some(*expected))
+}
+
+fn compile_glob(pattern: &str) -> Box<[Box<[Token]>]> {
+    pattern.split('*')
+        .map(|segment| {
+            segment.chars()
+                .map(|character| if character == '?' { Token::Any } else { Token::Char(upcase_char(character)) })
+                .collect()
+        })
+        .collect()
+}
+
+//where `segment` ends when it is matched at byte `start` of the name
+fn match_at(segment: &[Token], name: &str, start: usize) -> Option<usize> {
+    let mut characters = name[start..].char_indices();
+    let mut end = start;
+    for token in segment {
+        let (index, character) = characters.next()?;
+        if !token.matches(character) {
+            return None;
+        }
+        end = start + index + character.len_utf8();
+    }
+    Some(end)
+}
+
+//where the first match of `segment` at or after byte `from` ends
+fn find(segment: &[Token], name: &str, from: usize) -> Option<usize> {
+    name[from..].char_indices()
+        .map(|(index, _)| from + index)
+        .chain([name.len()])
+        .find_map(|start| match_at(segment, name, start))
+}
+
+//the first segment is anchored at the start and the last one at the end; taking the first
+//match of every segment in between leaves the most room for the ones after it
+fn glob_matches(segments: &[Box<[Token]>], name: &str) -> bool {
+    let Some((first, rest)) = segments.split_first() else {
+        return false;
+    };
+    let Some(mut position) = match_at(first, name, 0) else {
+        return false;
+    };
+    let Some((last, middle)) = rest.split_last() else {
+        return position == name.len();
+    };
+    for segment in middle {
+        match find(segment, name, position) {
+            Some(end) => position = end,
+            None => return false,
+        }
+    }
+    //the last segment matches exactly as many characters as it has tokens
+    let Some(start) = name.char_indices().map(|(index, _)| index).chain([name.len()]).rev().nth(last.len()) else {
+        return false;
+    };
+    start >= position && match_at(last, name, start) == Some(name.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn matches(mode: MatchMode, pattern: &str, name: &str) -> bool {
+        Matcher::new(mode, pattern).unwrap().is_match(name)
+    }
+
+    //the plain recursive definition the compiled glob must agree with
+    fn reference_glob(pattern: &[char], name: &[char]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some(('*', rest)) => (0..=name.len()).any(|skip| reference_glob(rest, &name[skip..])),
+            Some((expected, rest)) => name.split_first().is_some_and(|(character, name_rest)| {
+                (*expected == '?' || upcase_char(*expected) == upcase_char(*character)) && reference_glob(rest, name_rest)
+            }),
+        }
+    }
+
+    //every string of at most `max_len` characters of `alphabet`
+    fn strings(alphabet: &[char], max_len: usize) -> Vec<String> {
+        let mut all = alloc::vec![String::new()];
+        let mut last = all.clone();
+        for _ in 0..max_len {
+            last = last.iter().flat_map(|prefix| alphabet.iter().map(move |character| alloc::format!("{prefix}{character}"))).collect();
+            all.extend(last.iter().cloned());
+        }
+        all
+    }
+
+    #[test]
+    fn empty_patterns_are_rejected() {
+        for mode in [MatchMode::Exact, MatchMode::CaseInsensitive, MatchMode::Prefix, MatchMode::Suffix, MatchMode::Glob] {
+            assert_eq!(Matcher::new(mode, ""), Err(PatternError::Empty));
+        }
+    }
+
+    #[test]
+    fn truncated_and_extended_names_do_not_match() {
+        //each of these was a match for `firefox.exe` when names were compared up to the shorter length
+        for name in ["", "f", "fire", "firefox", "firefox.ex", "firefox.exe2", "firefox.exe.bak", "firefox.exefirefox.exe"] {
+            assert!(!matches(MatchMode::Exact, "firefox.exe", name), "{name:?}");
+            assert!(!matches(MatchMode::CaseInsensitive, "firefox.exe", name), "{name:?}");
+            assert!(!matches(MatchMode::Glob, "firefox.exe", name), "{name:?}");
+        }
+        assert!(matches(MatchMode::Exact, "firefox.exe", "firefox.exe"));
+    }
+
+    #[test]
+    fn exact_compares_the_case() {
+        assert!(!matches(MatchMode::Exact, "firefox.exe", "Firefox.exe"));
+        assert!(matches(MatchMode::CaseInsensitive, "firefox.exe", "FIREFOX.EXE"));
+        assert!(matches(MatchMode::CaseInsensitive, "FireFox.exe", "firefox.EXE"));
+    }
+
+    #[test]
+    fn prefix_and_suffix() {
+        assert!(matches(MatchMode::Prefix, "fire", "firefox.exe"));
+        assert!(matches(MatchMode::Prefix, "FIRE", "firefox.exe"));
+        assert!(matches(MatchMode::Prefix, "firefox.exe", "firefox.exe"));
+        assert!(!matches(MatchMode::Prefix, "firefox.exe", "fire"));
+        assert!(!matches(MatchMode::Prefix, "fire", ""));
+        assert!(!matches(MatchMode::Prefix, "fox", "firefox.exe"));
+        assert!(matches(MatchMode::Suffix, ".exe", "firefox.exe"));
+        assert!(matches(MatchMode::Suffix, ".EXE", "firefox.exe"));
+        assert!(!matches(MatchMode::Suffix, ".exe", "firefox.exe.bak"));
+        assert!(!matches(MatchMode::Suffix, "firefox.exe", ".exe"));
+        assert!(!matches(MatchMode::Suffix, ".exe", ""));
+    }
+
+    #[test]
+    fn every_mode_but_exact_folds_as_the_paths_do() {
+        for mode in [MatchMode::CaseInsensitive, MatchMode::Prefix, MatchMode::Suffix, MatchMode::Glob] {
+            assert!(matches(mode, "\u{e9}diteur.exe", "\u{c9}DITEUR.EXE"), "{mode:?}");
+            assert!(matches(mode, "\u{436}.exe", "\u{416}.exe"), "{mode:?}");
+            //the uppercase form of `\u{df}` is two characters, so it is left as is
+            assert!(!matches(mode, "\u{df}.exe", "SS.exe"), "{mode:?}");
+        }
+        assert!(!matches(MatchMode::Exact, "\u{e9}diteur.exe", "\u{c9}DITEUR.EXE"));
+        //`\u{2c65}` folds to `\u{23a}`, which takes one byte less
+        assert!(matches(MatchMode::CaseInsensitive, "\u{2c65}.exe", "\u{23a}.exe"));
+        assert!(matches(MatchMode::Prefix, "\u{2c65}", "\u{23a}.exe"));
+        assert!(matches(MatchMode::Suffix, "x\u{2c65}.exe", "X\u{23a}.exe"));
+        assert!(matches(MatchMode::Glob, "*\u{2c65}?exe", "a\u{23a}.exe"));
+        //the kernel table leaves the dotless i alone
+        assert!(!matches(MatchMode::CaseInsensitive, "\u{131}.exe", "i.exe"));
+        let (pattern, name) = ("\u{c9}t\u{e9}.exe", "\u{e9}T\u{c9}.EXE");
+        let path = |file| utils::path::NtPath::parse(&alloc::format!("\\Device\\HarddiskVolume2\\{file}")).unwrap();
+        assert_eq!(matches(MatchMode::CaseInsensitive, pattern, name), path(pattern).eq_ignore_case(&path(name)));
+    }
+
+    #[test]
+    fn prefix_and_suffix_never_split_a_character() {
+        assert!(!matches(MatchMode::Prefix, "a", "\u{e9}"));
+        assert!(!matches(MatchMode::Suffix, "a", "\u{e9}"));
+        assert!(matches(MatchMode::Prefix, "\u{e9}", "\u{e9}t\u{e9}.exe"));
+        assert!(matches(MatchMode::Suffix, "\u{e9}.exe", "\u{e9}t\u{e9}.exe"));
+    }
+
+    #[test]
+    fn glob() {
+        assert!(matches(MatchMode::Glob, "chrom*.exe", "chrome.exe"));
+        assert!(matches(MatchMode::Glob, "chrom*.exe", "Chromium.EXE"));
+        assert!(matches(MatchMode::Glob, "chrom*.exe", "chrom.exe"));
+        assert!(!matches(MatchMode::Glob, "chrom*.exe", "chrome.exe.bak"));
+        assert!(!matches(MatchMode::Glob, "chrom*.exe", "xchrome.exe"));
+        assert!(matches(MatchMode::Glob, "*", ""));
+        assert!(matches(MatchMode::Glob, "**", "anything"));
+        assert!(matches(MatchMode::Glob, "a*ab", "aab"));
+        assert!(!matches(MatchMode::Glob, "a*ab", "ab"));
+        assert!(matches(MatchMode::Glob, "*a*a", "aa"));
+        assert!(!matches(MatchMode::Glob, "?", ""));
+        assert!(matches(MatchMode::Glob, "?", "\u{e9}"));
+        assert!(matches(MatchMode::Glob, "??.exe", "\u{e9}t.exe"));
+        assert!(!matches(MatchMode::Glob, "???.exe", "\u{e9}t.exe"));
+    }
+
+    #[test]
+    fn glob_agrees_with_the_reference_on_every_small_input() {
+        let names = strings(&['a', 'b', 'A'], 5);
+        for pattern in strings(&['a', 'b', '*', '?'], 4).iter().filter(|pattern| !pattern.is_empty()) {
+            let matcher = Matcher::new(MatchMode::Glob, pattern).unwrap();
+            let pattern_chars: Vec<char> = pattern.chars().collect();
+            for name in &names {
+                let name_chars: Vec<char> = name.chars().collect();
+                assert_eq!(matcher.is_match(name), reference_glob(&pattern_chars, &name_chars), "{pattern:?} {name:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn equality_ignores_the_case_outside_exact_mode() {
+        let matcher = |mode, pattern| Matcher::new(mode, pattern).unwrap();
+        assert_eq!(matcher(MatchMode::Glob, "A*"), matcher(MatchMode::Glob, "a*"));
+        assert_eq!(matcher(MatchMode::Suffix, "\u{c9}.exe"), matcher(MatchMode::Suffix, "\u{e9}.EXE"));
+        assert_ne!(matcher(MatchMode::Exact, "A"), matcher(MatchMode::Exact, "a"));
+        assert_ne!(matcher(MatchMode::Prefix, "a"), matcher(MatchMode::Suffix, "a"));
+    }
+}
@@ -0,0 +1,241 @@
+//! Keeps other processes from terminating, suspending or writing into the
+//! protected ones, by stripping those rights from the handles they open.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use utils::kernel::{access, HandleObject, HandleRequest, ProcessId};
+use utils::path::NtPath;
+use utils::unicode::str_eq_ignore_case;
+use utils::sync::PushLock;
+use utils::{info, trace, Apc, AtMost, KernelApi, Passive};
+
+///the altitude of the object callbacks, in the range of the activity monitors
+pub const ALTITUDE: &str = "360123";
+
+pub const DEFAULT_PROCESS_ACCESS: u32 =
+    access::PROCESS_TERMINATE | access::PROCESS_VM_OPERATION | access::PROCESS_VM_WRITE | access::PROCESS_SUSPEND_RESUME;
+
+pub const DEFAULT_THREAD_ACCESS: u32 = access::THREAD_TERMINATE | access::THREAD_SUSPEND_RESUME | access::THREAD_SET_CONTEXT;
+
+/// Which processes are protected and which rights are stripped from
+/// handles to them.
+///
+/// Processes are matched when they are created, by the file name of their
+/// full image path, and protected by id from then on: the short name the
+/// kernel keeps is cut at 14 characters, so it would not match a longer
+/// name. Processes that were running before the driver was loaded are not
+/// protected. The supervisor is matched by the whole NT path of its image,
+/// since any image may be given its file name. It is protected as well, and
+/// only it may open the protected processes without restriction, so it can
+/// still stop what it started.
+#[derive(Debug)]
+pub struct ProtectionPolicy {
+    protected: Vec<String>,
+    //the running processes with a protected name
+    running: PushLock<Vec<ProcessId>>,
+    supervisor_path: Option<NtPath>,
+    //zero while the supervisor is not running
+    supervisor: AtomicUsize,
+    process_access: u32,
+    thread_access: u32,
+}
+
+impl ProtectionPolicy {
+    ///protects nothing until told what, stripping the default rights
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            protected: Vec::new(),
+            running: PushLock::new(Vec::new()),
+            supervisor_path: None,
+            supervisor: AtomicUsize::new(0),
+            process_access: DEFAULT_PROCESS_ACCESS,
+            thread_access: DEFAULT_THREAD_ACCESS,
+        }
+    }
+    ///the image of the supervisor, such as `\Device\HarddiskVolume2\Tools\emiter.exe`
+    pub fn set_supervisor_path(&mut self, path: NtPath) {
+        self.supervisor_path = Some(path);
+    }
+    ///the process the supervisor runs in
+    pub fn supervisor(&self) -> Option<ProcessId> {
+        Some(self.supervisor.load(Ordering::Acquire)).filter(|&pid| pid != 0)
+    }
+    /// Protects the process when the file name of its image is protected,
+    /// and registers it as the supervisor when it is started from the
+    /// supervisor's image. Returns whether it is protected.
+    pub fn on_process_created(&self, irql: &Passive, pid: ProcessId, image_path: &str) -> bool {
+        let file_name = image_path.rsplit('\\').next().unwrap_or(image_path);
+        let protected = self.is_protected(file_name);
+        if protected {
+            trace!("Protecting {image_path} {pid}");
+            self.running.write(irql).push(pid);
+        }
+        let is_supervisor = self.supervisor_path.as_ref()
+            .is_some_and(|supervisor_path| NtPath::parse(image_path).is_ok_and(|path| path.eq_ignore_case(supervisor_path)));
+        if !is_supervisor {
+            return protected;
+        }
+        //a second instance takes over from the first
+        let previous = self.supervisor.swap(pid, Ordering::AcqRel);
+        if previous != 0 {
+            info!("The supervisor {previous} is replaced by {pid}");
+        } else {
+            info!("The supervisor runs as {pid}");
+        }
+        true
+    }
+    pub fn on_process_exited(&self, irql: &Passive, pid: ProcessId) {
+        self.running.write(irql).retain(|&running| running != pid);
+        if self.supervisor.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+            info!("The supervisor {pid} has exited");
+        }
+    }
+    pub fn protect(&mut self, image_name: &str) {
+        if !self.is_protected(image_name) {
+            self.protected.push(String::from(image_name));
+        }
+    }
+    ///the rights stripped from process and from thread handles
+    pub const fn set_stripped_access(&mut self, process_access: u32, thread_access: u32) {
+        self.process_access = process_access;
+        self.thread_access = thread_access;
+    }
+    ///whether processes with this image file name are protected
+    #[must_use]
+    pub fn is_protected(&self, image_name: &str) -> bool {
+        self.protected.iter().any(|protected| str_eq_ignore_case(protected, image_name))
+    }
+    ///whether the running process is protected, by its name or as the supervisor
+    pub fn is_protected_process<I: AtMost<Apc>>(&self, irql: &I, pid: ProcessId) -> bool {
+        self.supervisor() == Some(pid) || self.running.read(irql).contains(&pid)
+    }
+    /// The pre-operation handler: strips the configured rights from a
+    /// user-mode handle another process opens to a protected one and logs
+    /// what was stripped. Returns the stripped rights.
+    pub fn check<K: KernelApi>(&self, kernel: &K, irql: &Apc, request: &mut HandleRequest) -> u32 {
+        if request.kernel_handle || request.requester_pid == request.target_pid {
+            return 0;
+        }
+        let mask = match request.object {
+            HandleObject::Process => self.process_access,
+            HandleObject::Thread => self.thread_access,
+        };
+        if request.desired_access() & mask == 0 {
+            return 0;
+        }
+        if !self.is_protected_process(irql, request.target_pid) {
+            return 0;
+        }
+        //only for the log
+        let target_name = kernel.process_image_name(irql, request.target_pid).unwrap_or_default();
+        let requester_name = kernel.process_image_name(irql, request.requester_pid).unwrap_or_default();
+        if self.supervisor() == Some(request.requester_pid) {
+            trace!("The supervisor {} opens {target_name} {} unrestricted", request.requester_pid, request.target_pid);
+            return 0;
+        }
+        let stripped = request.strip_access(mask);
+        info!(
+            "Stripped {stripped:#x} from the {:?} handle {requester_name} {} opens to {target_name} {}",
+            request.object,
+            request.requester_pid,
+            request.target_pid
+        );
+        stripped
+    }
+}
+
+impl Default for ProtectionPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::kernel::fake::FakeKernel;
+
+    const SUPERVISOR_PATH: &str = "\\Device\\HarddiskVolume2\\Tools\\emiter.exe";
+
+    fn policy() -> ProtectionPolicy {
+        let mut policy = ProtectionPolicy::new();
+        policy.set_supervisor_path(NtPath::parse(SUPERVISOR_PATH).unwrap());
+        policy.protect("firefox.exe");
+        policy
+    }
+
+    fn irql() -> Passive {
+        Passive::current().unwrap()
+    }
+
+    fn kernel() -> FakeKernel {
+        let kernel = FakeKernel::new();
+        kernel.add_process(8, "emiter.exe");
+        kernel.add_process(12, "firefox.exe");
+        //an impostor with the supervisor's name
+        kernel.add_process(16, "emiter.exe");
+        kernel.add_process(20, "notepad.exe");
+        kernel
+    }
+
+    //the rights left on a handle `requester` opens to `target` for terminating it
+    fn open(policy: &ProtectionPolicy, kernel: &FakeKernel, requester: ProcessId, target: ProcessId) -> u32 {
+        let mut request = HandleRequest::new(HandleObject::Process, target, requester, access::PROCESS_TERMINATE);
+        policy.check(kernel, &Apc::current().unwrap(), &mut request);
+        request.desired_access()
+    }
+
+    #[test]
+    fn the_supervisor_is_the_process_started_from_its_image() {
+        let policy = policy();
+        assert_eq!(policy.supervisor(), None);
+        assert!(!policy.on_process_created(&irql(), 16, "\\Device\\HarddiskVolume2\\Users\\x\\emiter.exe"));
+        assert!(policy.on_process_created(&irql(), 8, "\\DEVICE\\HarddiskVolume2\\tools\\EMITER.EXE"));
+        assert_eq!(policy.supervisor(), Some(8));
+        policy.on_process_exited(&irql(), 16);
+        assert_eq!(policy.supervisor(), Some(8));
+        policy.on_process_exited(&irql(), 8);
+        assert_eq!(policy.supervisor(), None);
+        //without a path nothing is the supervisor
+        assert!(!ProtectionPolicy::new().on_process_created(&irql(), 8, SUPERVISOR_PATH));
+    }
+
+    #[test]
+    fn only_the_supervisor_opens_protected_processes_unrestricted() {
+        let (policy, kernel) = (policy(), kernel());
+        policy.on_process_created(&irql(), 8, SUPERVISOR_PATH);
+        assert!(policy.on_process_created(&irql(), 12, "\\Device\\HarddiskVolume2\\Program Files\\Mozilla Firefox\\firefox.exe"));
+        assert_eq!(open(&policy, &kernel, 8, 12), access::PROCESS_TERMINATE);
+        assert_eq!(open(&policy, &kernel, 16, 12), 0);
+        assert_eq!(open(&policy, &kernel, 12, 12), access::PROCESS_TERMINATE);
+        assert_eq!(open(&policy, &kernel, 16, 20), access::PROCESS_TERMINATE);
+        //the supervisor is protected by its id, the impostor is not protected at all
+        assert_eq!(open(&policy, &kernel, 20, 8), 0);
+        assert_eq!(open(&policy, &kernel, 20, 16), access::PROCESS_TERMINATE);
+        policy.on_process_exited(&irql(), 8);
+        assert_eq!(open(&policy, &kernel, 8, 12), 0);
+        assert_eq!(open(&policy, &kernel, 20, 8), access::PROCESS_TERMINATE);
+    }
+
+    #[test]
+    fn processes_are_protected_by_the_whole_file_name_of_their_image() {
+        let mut policy = ProtectionPolicy::new();
+        policy.protect("averyverylongname.exe");
+        let kernel = FakeKernel::new();
+        //the kernel keeps 14 characters of the name
+        kernel.add_process(8, "averyverylongn");
+        kernel.add_process(12, "averyverylongn");
+        kernel.add_process(20, "notepad.exe");
+        assert!(policy.on_process_created(&irql(), 8, "\\Device\\HarddiskVolume2\\Tools\\AVeryVeryLongName.exe"));
+        assert!(!policy.on_process_created(&irql(), 12, "\\Device\\HarddiskVolume2\\Tools\\averyverylongnamf.exe"));
+        assert!(!policy.on_process_created(&irql(), 20, "\\Device\\HarddiskVolume2\\averyverylongname.exe\\notepad.exe"));
+        assert!(policy.is_protected_process(&irql(), 8));
+        assert_eq!(open(&policy, &kernel, 20, 8), 0);
+        assert_eq!(open(&policy, &kernel, 20, 12), access::PROCESS_TERMINATE);
+        policy.on_process_exited(&irql(), 8);
+        assert!(!policy.is_protected_process(&irql(), 8));
+        assert_eq!(open(&policy, &kernel, 20, 8), access::PROCESS_TERMINATE);
+    }
+}
@@ -0,0 +1,820 @@
+//! The process spy: the notify routines and object callbacks feed it, the
+//! watch list decides what it does with a process, and user mode talks to
+//! it through its device.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::{fmt, mem};
+use spy_protocol::device::SPY_DEVICE_NAME;
+use utils::config::{RegistryValue, REG_SZ};
+use utils::dispatch::{DeviceHandler, IoResult};
+use utils::ioctl::IoctlTable;
+use utils::kernel::{HandleRequest, ImageLoadInfo, ProcessCreateInfo, ProcessId, ProcessNotification, ThreadNotification};
+use utils::ring::ScheduledRing;
+use utils::sync::PushLock;
+use crate::config::{SpyConfig, WATCH_LIST_VALUE};
+use crate::events::{EventQueue, EventsOverflow, ProcessEvent, EVENT_CAPACITY, IOCTL_EVENTS_READ};
+use crate::protection::ProtectionPolicy;
+use crate::watch::{ProcessApprove, ProcessReject, RuleAction, WatchList, WatchRule, IOCTL_WATCH_ADD, IOCTL_WATCH_LIST, IOCTL_WATCH_REMOVE};
+use utils::{debug, error, info, log, trace, Apc, AtMost, Dispatch, EventKind, KernelApi, NtError, Passive, WorkQueue};
+
+pub const CREATE_EVENT_NAME: &str = concat!("\\BaseNamedObjects\\", "RustProcessSpyCreateEvent");
+pub const EXIT_EVENT_NAME: &str = concat!("\\BaseNamedObjects\\", "RustProcessSpyExitEvent");
+
+///how many notifications may wait for the worker before new ones are dropped
+pub const PENDING_CAPACITY: usize = 256;
+
+///the names a process is known by
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcessImage {
+    ///the name `EPROCESS` keeps, which is cut at 14 characters
+    pub short_name: String,
+    ///the NT path of the image file, when it could be found
+    pub full_path: Option<String>,
+}
+
+impl ProcessImage {
+    ///the whole file name of the image, or the short name while the path is unknown
+    #[must_use]
+    pub fn file_name(&self) -> &str {
+        self.full_path.as_deref()
+            .and_then(|path| path.rsplit('\\').next())
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&self.short_name)
+    }
+}
+
+impl Display for ProcessImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.full_path {
+            Some(path) => write!(f, "{} ({path})", self.short_name),
+            None => f.write_str(&self.short_name),
+        }
+    }
+}
+
+///what the notify routines hand to the worker
+#[derive(Debug)]
+enum SpyEvent {
+    ///the worker looks up the image of a created process; until then it only holds the path of the create notify info, if any
+    Process(ProcessEvent),
+    RemoteThread(ThreadNotification),
+    ImageLoad { pid: ProcessId, image_base: usize, image_size: usize, image_name: String },
+}
+
+///the main struct that control situation
+pub struct ProcessSpy<K: KernelApi> {
+    kernel: K,
+    device: K::Device,
+    //events to communicate with user-mode manager that should start/close corresponding process
+    create_event: K::Event,
+    exit_event: K::Event,
+    //notifications are queued here and handled in batches by one work item
+    pending: ScheduledRing<SpyEvent>,
+    protection: ProtectionPolicy,
+    //changed through the device, so only at PASSIVE_LEVEL
+    watch_list: PushLock<WatchList>,
+    audit_only: bool,
+    events: EventQueue,
+    //stopped by a suspend rule and waiting for user mode
+    suspended: PushLock<Vec<ProcessId>>,
+    ioctls: IoctlTable<Self>,
+}
+
+impl<K: KernelApi> ProcessSpy<K> {
+    /// Creates the two named events and the device user mode controls the
+    /// spy through, which also runs the work items.
+    ///
+    /// # Errors
+    /// When an event or the device cannot be created, such as
+    /// `STATUS_OBJECT_NAME_COLLISION` while another spy holds the device;
+    /// the events created by then are closed.
+    pub fn new(irql: &Passive, kernel: K, config: &SpyConfig) -> Result<Self, NtError> {
+        //each process is picked by a single waiter, and a signal raised while it is busy is kept
+        let create_event = kernel.create_named_event(irql, CREATE_EVENT_NAME, EventKind::Synchronization)?;
+        let exit_event = kernel.create_named_event(irql, EXIT_EVENT_NAME, EventKind::Synchronization)?;
+        let device = kernel.create_device(irql, SPY_DEVICE_NAME)?;
+        //the processes the watch list names are protected along with the supervisor
+        let mut protection = ProtectionPolicy::new();
+        //but not those it stops
+        let supervised = config.watch_list.rules().iter().filter(|rule| !rule.action().is_enforcing());
+        for image_name in supervised.filter_map(WatchRule::image_name) {
+            protection.protect(image_name);
+        }
+        for image_name in &config.protected_processes {
+            protection.protect(image_name);
+        }
+        if let Some(path) = &config.supervisor_path {
+            protection.set_supervisor_path(path.clone());
+        }
+        debug!("New spy is created");
+        Ok(Self {
+            kernel,
+            device,
+            create_event,
+            exit_event,
+            pending: ScheduledRing::new(PENDING_CAPACITY),
+            protection,
+            watch_list: PushLock::new(config.watch_list.clone()),
+            audit_only: config.audit_only,
+            events: EventQueue::new(EVENT_CAPACITY),
+            suspended: PushLock::new(Vec::new()),
+            ioctls: Self::ioctls(),
+        })
+    }
+    pub const fn kernel(&self) -> &K {
+        &self.kernel
+    }
+    ///how many notifications were lost because the worker fell behind
+    pub fn dropped_notifications(&self) -> usize {
+        self.pending.dropped()
+    }
+    ///the events of watched processes that wait for user mode
+    pub const fn events(&self) -> &EventQueue {
+        &self.events
+    }
+    ///the handler of the process notify registration
+    pub fn on_process_notification(&'static self, irql: &Passive, notification: &mut ProcessNotification<'_>) {
+        let mut event = ProcessEvent { pid: notification.pid(), timestamp: self.kernel.system_time(), ..ProcessEvent::default() };
+        if let ProcessNotification::Create(info) = notification {
+            event.is_created = true;
+            event.parent_pid = info.parent_pid;
+            event.creating_pid = info.creating_pid;
+            event.creating_tid = info.creating_tid;
+            event.image.full_path = info.image_file_name.map(|name| name.to_string_lossy());
+            if let Some(path) = &event.image.full_path {
+                self.protection.on_process_created(irql, info.pid, path);
+            }
+        } else if let ProcessNotification::Exit { image_name, image_path, .. } = notification {
+            //the process is gone by the time the worker runs
+            event.image = ProcessImage { short_name: mem::take(image_name), full_path: image_path.take() };
+            self.protection.on_process_exited(irql, event.pid);
+        }
+        trace!("Process {} is {}", event.pid, if event.is_created { "created" } else { "exiting" });
+        if let ProcessNotification::Create(info) = notification {
+            if self.deny_creation(irql, info, &mut event) {
+                //the process never runs
+                self.protection.on_process_exited(irql, event.pid);
+                self.events.push(event);
+                return;
+            }
+        }
+        self.queue(irql, SpyEvent::Process(event));
+    }
+    //the creation can only fail while the notify routine runs, so a deny rule is matched here and not in the worker
+    fn deny_creation(&self, irql: &Passive, info: &mut ProcessCreateInfo<'_>, event: &mut ProcessEvent) -> bool {
+        if self.audit_only || !self.watch_list.read(irql).rules().iter().any(|rule| rule.action() == RuleAction::Deny) {
+            return false;
+        }
+        let Ok(image) = self.process_image(irql, info.pid, event.image.full_path.clone()) else {
+            return false;
+        };
+        if self.matched_action(irql, &image) != Some(RuleAction::Deny) {
+            return false;
+        }
+        info.deny(NtError::ACCESS_DENIED);
+        info!("Denied the creation of {image} {} by {}", info.pid, info.creating_pid);
+        event.session_id = self.kernel.process_session_id(irql, info.pid).unwrap_or_default();
+        event.image = image;
+        event.action = RuleAction::Deny;
+        true
+    }
+    ///the handler of the thread notify registration; only threads created by another process are of interest
+    pub fn on_thread_notification(&'static self, irql: &Apc, notification: &ThreadNotification) {
+        if notification.is_remote() {
+            self.queue(irql, SpyEvent::RemoteThread(*notification));
+        }
+    }
+    ///the handler of the image load notify registration; drivers are ignored
+    pub fn on_image_load(&'static self, irql: &Passive, info: &ImageLoadInfo<'_>) {
+        if info.system_image {
+            return;
+        }
+        let image_name = info.image_name.map_or_else(String::new, |name| name.to_string_lossy());
+        let event = SpyEvent::ImageLoad { pid: info.pid, image_base: info.image_base, image_size: info.image_size, image_name };
+        self.queue(irql, event);
+    }
+    ///the pre-operation handler of the object callbacks
+    pub fn on_handle_request(&self, irql: &Apc, request: &mut HandleRequest) {
+        self.protection.check(&self.kernel, irql, request);
+    }
+    ///queues a notification; the lookup itself is deferred to a work item
+    pub fn notify<I: AtMost<Dispatch>>(&'static self, irql: &I, pid: ProcessId, is_created: bool) {
+        let event = ProcessEvent { pid, is_created, timestamp: self.kernel.system_time(), ..ProcessEvent::default() };
+        self.queue(irql, SpyEvent::Process(event));
+    }
+    fn queue<I: AtMost<Dispatch>>(&'static self, irql: &I, event: SpyEvent) {
+        let schedule = || {
+            let routine = Box::new(move |irql: &mut Passive| self.drain(irql));
+            self.kernel.queue_work(irql, self.device, WorkQueue::Delayed, routine)
+        };
+        if let Err(error) = self.pending.push(event, schedule) {
+            error!("Failed to queue spy worker {error}");
+        }
+    }
+    fn drain(&self, irql: &Passive) {
+        self.pending.drain(|event| match event {
+            SpyEvent::Process(event) => self.dispatch(irql, event),
+            SpyEvent::RemoteThread(thread) => self.dispatch_remote_thread(irql, &thread),
+            SpyEvent::ImageLoad { pid, image_base, image_size, image_name } => {
+                if self.trackable_image(irql, pid).is_some() {
+                    debug!("{image_name} is mapped into {pid} at {image_base:#x}, {image_size} bytes");
+                }
+            }
+        });
+    }
+    fn dispatch_remote_thread(&self, irql: &Passive, thread: &ThreadNotification) {
+        let Some(image) = self.trackable_image(irql, thread.pid) else {
+            return;
+        };
+        match self.process_image(irql, thread.current_pid, None) {
+            Ok(creator) => info!("Thread {} is created in {image} {} by {creator} {}", thread.tid, thread.pid, thread.current_pid),
+            Err(_) => info!("Thread {} is created in {image} {} by {}", thread.tid, thread.pid, thread.current_pid),
+        }
+    }
+    ///the image of the process if it is watched
+    fn trackable_image(&self, irql: &Passive, pid: ProcessId) -> Option<ProcessImage> {
+        let image = self.process_image(irql, pid, None).ok()?;
+        self.matched_action(irql, &image).is_some().then_some(image)
+    }
+    /// Both names of the process. The path is located by the kernel, or
+    /// else is `known_path`.
+    ///
+    /// # Errors
+    /// When there is no such process.
+    pub fn process_image(&self, irql: &Passive, pid: ProcessId, known_path: Option<String>) -> Result<ProcessImage, NtError> {
+        let short_name = self.kernel.process_image_name(irql, pid)?;
+        let full_path = match self.kernel.process_image_path(irql, pid) {
+            Ok(path) => Some(path),
+            Err(error) => {
+                trace!("Failed to locate the image of {short_name} {pid} {error}");
+                known_path
+            }
+        };
+        Ok(ProcessImage { short_name, full_path })
+    }
+    ///takes the action of the rule that matches the process, if any, and queues the event for user mode
+    pub fn dispatch(&self, irql: &Passive, mut event: ProcessEvent) {
+        let (pid, is_created) = (event.pid, event.is_created);
+        if !is_created {
+            //also when the process cannot be looked up any more
+            self.suspended.write(irql).retain(|&suspended| suspended != pid);
+        }
+        //an exiting process was named by its notification
+        if is_created {
+            event.image = match self.process_image(irql, pid, event.image.full_path.take()) {
+                Ok(image) => image,
+                Err(error) => {
+                    debug!("Failed to lookup process {pid} by id {error}");
+                    return;
+                }
+            };
+        }
+        let image = &event.image;
+        trace!("Process {image} is catched");
+        let Some(action) = self.matched_action(irql, image) else {
+            return;
+        };
+        event.session_id = self.kernel.process_session_id(irql, pid).unwrap_or_default();
+        event.action = action;
+        event.audited = self.audit_only && action.is_enforcing() && is_created;
+        let image = &event.image;
+        if event.audited {
+            info!("Would {action} {image} {pid}");
+            self.events.push(event);
+            return;
+        }
+        match action {
+            RuleAction::Signal if is_created => {
+                info!("Watched {image} {pid} is created");
+                self.kernel.set_event(irql, &self.create_event);
+            }
+            RuleAction::Signal => {
+                info!("Watched {image} {pid} has exited");
+                self.kernel.set_event(irql, &self.exit_event);
+            }
+            //queued like every other event
+            RuleAction::Record => {}
+            //the others act on the start of a process
+            _ if !is_created => {}
+            //only when the rule was added after the notify routine had run
+            RuleAction::Deny => debug!("{image} {pid} was created before it could be denied"),
+            RuleAction::Terminate => match self.kernel.terminate_process(irql, pid, NtError::ACCESS_DENIED) {
+                Ok(()) => info!("Terminated {image} {pid}"),
+                Err(error) => error!("Failed to terminate {image} {pid} {error}"),
+            },
+            RuleAction::Suspend => self.suspend(irql, pid, image),
+        }
+        self.events.push(event);
+    }
+    ///the action of the first rule that matches the image
+    fn matched_action(&self, irql: &Passive, image: &ProcessImage) -> Option<RuleAction> {
+        self.watch_list.read(irql).find(image.file_name(), image.full_path.as_deref()).map(WatchRule::action)
+    }
+    fn suspend(&self, irql: &Passive, pid: ProcessId, image: &ProcessImage) {
+        if let Err(error) = self.kernel.suspend_process(irql, pid) {
+            error!("Failed to suspend {image} {pid} {error}");
+            return;
+        }
+        info!("Suspended {image} {pid} until it is approved");
+        //user mode learns of the process from its event
+        self.suspended.write(irql).push(pid);
+    }
+    /// Resumes a process a suspend rule stopped, or terminates it when it
+    /// is not approved.
+    ///
+    /// # Errors
+    /// `STATUS_NOT_FOUND` when the process does not wait for approval, or
+    /// the error of the kernel call.
+    pub fn resolve_suspended(&self, irql: &Passive, pid: ProcessId, approved: bool) -> Result<(), NtError> {
+        {
+            let mut suspended = self.suspended.write(irql);
+            let index = suspended.iter().position(|&other| other == pid).ok_or(NtError::NOT_FOUND)?;
+            suspended.swap_remove(index);
+        }
+        if approved {
+            info!("Process {pid} is approved");
+            self.kernel.resume_process(irql, pid)
+        } else {
+            info!("Process {pid} is rejected");
+            self.kernel.terminate_process(irql, pid, NtError::ACCESS_DENIED)
+        }
+    }
+    ///the rules of the watch list, as they are stored
+    pub fn watch_rules(&self, irql: &Passive) -> Vec<String> {
+        self.watch_list.read(irql).to_strings()
+    }
+    /// Adds the rule to the watch list and stores the list in the driver
+    /// parameters. Returns `false` when the list holds the rule already.
+    ///
+    /// # Errors
+    /// `STATUS_INVALID_PARAMETER` when the list is full, or the error of
+    /// the registry write; the list is left as it was.
+    pub fn watch(&self, irql: &Passive, rule: WatchRule) -> Result<bool, NtError> {
+        self.update_watch_list(irql, |list| list.add(rule).map_err(|_| NtError::INVALID_PARAMETER))
+    }
+    /// Removes the rule from the watch list and stores the list in the
+    /// driver parameters. Returns `false` when the list does not hold it.
+    ///
+    /// # Errors
+    /// The error of the registry write; the list is left as it was.
+    pub fn unwatch(&self, irql: &Passive, rule: &WatchRule) -> Result<bool, NtError> {
+        self.update_watch_list(irql, |list| Ok(list.remove(rule)))
+    }
+    fn update_watch_list<F>(&self, irql: &Passive, update: F) -> Result<bool, NtError>
+    where
+        F: FnOnce(&mut WatchList) -> Result<bool, NtError>,
+    {
+        let mut list = self.watch_list.write(irql);
+        let previous = list.clone();
+        if !update(&mut list)? {
+            return Ok(false);
+        }
+        let rules = RegistryValue::MultiString(list.to_strings());
+        if let Err(error) = self.kernel.write_parameter(irql, WATCH_LIST_VALUE, &rules) {
+            error!("Failed to store the watch list {error}");
+            *list = previous;
+            return Err(error);
+        }
+        drop(list);
+        Ok(true)
+    }
+    ///the IOCTLs of the spy device, its own and those of the log
+    fn ioctls() -> IoctlTable<Self> {
+        let table = IoctlTable::<Self>::new()
+            .with_raw(IOCTL_EVENTS_READ, |spy, _, output| spy.events.read(output))
+            .with::<EventsOverflow, _>(|spy, ()| Ok(spy.events.overflowed() as u64))
+            .with_raw(IOCTL_WATCH_LIST, |spy, _, output| {
+                let rules = RegistryValue::MultiString(spy.watch_rules(&passive()?)).to_bytes();
+                output.get_mut(..rules.len()).ok_or(NtError::BUFFER_TOO_SMALL)?.copy_from_slice(&rules);
+                Ok(rules.len())
+            })
+            .with_raw(IOCTL_WATCH_ADD, |spy, input, _| {
+                let rule = parse_rule(input)?;
+                if spy.watch(&passive()?, rule.clone())? {
+                    info!("Watching {rule}");
+                }
+                Ok(0)
+            })
+            .with_raw(IOCTL_WATCH_REMOVE, |spy, input, _| {
+                let rule = parse_rule(input)?;
+                if !spy.unwatch(&passive()?, &rule)? {
+                    return Err(NtError::NOT_FOUND);
+                }
+                info!("No longer watching {rule}");
+                Ok(0)
+            })
+            .with::<ProcessApprove, _>(|spy, &pid| spy.resolve_suspended(&passive()?, process_id(pid)?, true))
+            .with::<ProcessReject, _>(|spy, &pid| spy.resolve_suspended(&passive()?, process_id(pid)?, false));
+        log::add_ioctls(table)
+    }
+    ///waits for the queued work, which borrows the spy, and releases the device
+    pub fn free(self, irql: &Passive) {
+        self.kernel.drain_work(irql);
+        //nobody is left to approve them
+        let suspended = mem::take(&mut *self.suspended.write(irql));
+        for pid in suspended {
+            if let Err(error) = self.kernel.resume_process(irql, pid) {
+                error!("Failed to resume {pid} {error}");
+            }
+        }
+        //the events are closed when dropped
+        self.kernel.delete_device(irql, self.device);
+        debug!("The spy is deleted");
+    }
+}
+
+///the spy device accepts handles, hands out the queued events on reads and serves the watch list, event and log IOCTLs
+impl<K: KernelApi> DeviceHandler for ProcessSpy<K> {
+    fn create(&self) -> IoResult {
+        Ok(0)
+    }
+    fn close(&self) -> IoResult {
+        Ok(0)
+    }
+    fn cleanup(&self) -> IoResult {
+        Ok(0)
+    }
+    fn read(&self, buffer: &mut [u8], _offset: u64) -> IoResult {
+        self.events.read(buffer)
+    }
+    fn device_control(&self, code: u32, input: &[u8], output: &mut [u8]) -> IoResult {
+        self.ioctls.dispatch(self, code, input, output)
+    }
+}
+
+//the registry is written and processes are resumed at PASSIVE_LEVEL only
+fn passive() -> Result<Passive, NtError> {
+    Passive::current().ok_or(NtError::UNSUCCESSFUL)
+}
+
+fn process_id(raw: u64) -> Result<ProcessId, NtError> {
+    ProcessId::try_from(raw).map_err(|_| NtError::INVALID_PARAMETER)
+}
+
+///the rule of a watch IOCTL, a `REG_SZ` string
+fn parse_rule(input: &[u8]) -> Result<WatchRule, NtError> {
+    let RegistryValue::String(rule) = RegistryValue::parse(WATCH_LIST_VALUE, REG_SZ, input)? else {
+        return Err(NtError::INVALID_PARAMETER);
+    };
+    WatchRule::parse(&rule).map_err(|error| {
+        debug!("Rejected watch rule {rule}: {error}");
+        NtError::INVALID_PARAMETER
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use utils::kernel::fake::FakeKernel;
+    use utils::ioctl::ControlCode;
+    use utils::unicode::{encode_utf16, UnicodeStr};
+    use crate::events::IOCTL_EVENTS_OVERFLOW;
+    use crate::watch::{IOCTL_PROCESS_APPROVE, IOCTL_PROCESS_REJECT};
+
+    fn irql() -> Passive {
+        Passive::current().unwrap()
+    }
+
+    fn spy_with(rules: &[&str]) -> &'static ProcessSpy<Arc<FakeKernel>> {
+        spy_with_config(&SpyConfig { watch_list: WatchList::parse(rules).unwrap(), ..SpyConfig::default() })
+    }
+
+    fn spy_with_config(config: &SpyConfig) -> &'static ProcessSpy<Arc<FakeKernel>> {
+        Box::leak(Box::new(ProcessSpy::new(&irql(), Arc::new(FakeKernel::new()), config).unwrap()))
+    }
+
+    //as the driver does on unload
+    fn free(spy: &'static ProcessSpy<Arc<FakeKernel>>) {
+        let spy = unsafe { Box::from_raw(core::ptr::from_ref(spy).cast_mut()) };
+        spy.free(&irql());
+    }
+
+    //starts the process through the notify routine of the spy, as the kernel would
+    fn start(spy: &'static ProcessSpy<Arc<FakeKernel>>, pid: ProcessId, path: &str) -> utils::Status {
+        let kernel = spy.kernel();
+        let registration = kernel.register_process_notify(&irql(), Box::new(move |irql: &mut Passive, notification: &mut ProcessNotification<'_>| {
+            spy.on_process_notification(irql, notification);
+        })).unwrap();
+        let path = encode_utf16(path);
+        let mut info = ProcessCreateInfo::new(pid, 4);
+        info.image_file_name = Some(UnicodeStr::new(&path));
+        let status = kernel.start_process(info);
+        drop(registration);
+        status
+    }
+
+    //the process records the spy has queued, oldest first
+    fn records(spy: &ProcessSpy<Arc<FakeKernel>>) -> Vec<spy_protocol::ProcessRecord> {
+        let mut buffer = alloc::vec![0u8; EVENT_CAPACITY * crate::events::RECORD_SIZE];
+        let length = spy.read(&mut buffer, 0).unwrap();
+        spy_protocol::records(&buffer[..length])
+            .filter_map(|record| match record {
+                Ok(spy_protocol::RecordRef::Process(record)) => Some(*record),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_creation_of_a_watched_process_is_signaled_by_the_worker() {
+        let spy = spy_with(&["firefox.exe"]);
+        let kernel = spy.kernel();
+        assert!(kernel.has_device(SPY_DEVICE_NAME));
+        kernel.set_system_time(1_000);
+        assert!(start(spy, 8, "\\Device\\HarddiskVolume2\\Program Files\\Mozilla Firefox\\firefox.exe").is_success());
+        assert!(start(spy, 12, "\\Device\\HarddiskVolume2\\Windows\\notepad.exe").is_success());
+        assert!(!kernel.wait_event(CREATE_EVENT_NAME));
+        assert!(spy.events().is_empty());
+        assert_eq!(kernel.run_pending_work(), 1);
+        assert!(kernel.wait_event(CREATE_EVENT_NAME));
+        assert!(!kernel.wait_event(EXIT_EVENT_NAME));
+        assert_eq!(spy.events().len(), 1);
+        let mut buffer = [0u8; 2 * crate::events::RECORD_SIZE];
+        assert_eq!(spy.read(&mut buffer, 0), Ok(crate::events::RECORD_SIZE));
+        let Ok((spy_protocol::RecordRef::Process(record), _)) = spy_protocol::decode(&buffer) else {
+            panic!("not a process record");
+        };
+        assert!(record.is_created());
+        assert_eq!((record.pid.get(), record.parent_pid.get(), record.header.timestamp.get()), (8, 4, 1_000));
+        assert_eq!(record.action(), Some(spy_protocol::ProcessAction::Signal));
+        assert!(record.image_name.eq_str("firefox.exe"));
+        assert_eq!(record.image_path.to_string(), "\\Device\\HarddiskVolume2\\Program Files\\Mozilla Firefox\\firefox.exe");
+        let kernel = kernel.clone();
+        free(spy);
+        assert_eq!(kernel.live_devices(), 0);
+        assert!(!kernel.has_device(SPY_DEVICE_NAME));
+    }
+
+    #[test]
+    fn control_codes_are_those_user_mode_sends() {
+        use spy_protocol::device;
+        let codes = [
+            (utils::log::IOCTL_LOG_READ, device::IOCTL_LOG_READ),
+            (utils::log::IOCTL_LOG_SET_LEVEL, device::IOCTL_LOG_SET_LEVEL),
+            (IOCTL_WATCH_ADD, device::IOCTL_WATCH_ADD),
+            (IOCTL_WATCH_REMOVE, device::IOCTL_WATCH_REMOVE),
+            (IOCTL_WATCH_LIST, device::IOCTL_WATCH_LIST),
+            (IOCTL_PROCESS_APPROVE, device::IOCTL_PROCESS_APPROVE),
+            (IOCTL_PROCESS_REJECT, device::IOCTL_PROCESS_REJECT),
+            (IOCTL_EVENTS_READ, device::IOCTL_EVENTS_READ),
+            (IOCTL_EVENTS_OVERFLOW, device::IOCTL_EVENTS_OVERFLOW),
+        ];
+        for (code, raw) in codes {
+            assert_eq!(code.raw(), raw, "{code:?}");
+        }
+    }
+
+    //ends the process through the notify routine of the spy, as the kernel would
+    fn exit(spy: &'static ProcessSpy<Arc<FakeKernel>>, pid: ProcessId) {
+        let kernel = spy.kernel();
+        let registration = kernel.register_process_notify(&irql(), Box::new(move |irql: &mut Passive, notification: &mut ProcessNotification<'_>| {
+            spy.on_process_notification(irql, notification);
+        })).unwrap();
+        kernel.exit_process(pid);
+        drop(registration);
+    }
+
+    #[test]
+    fn exits_are_recorded_with_the_names_the_notification_carried() {
+        let spy = spy_with(&["path:\\Device\\HarddiskVolume2\\Tools\\x.exe", "glob:fire*.exe"]);
+        let kernel = spy.kernel();
+        start(spy, 8, "\\Device\\HarddiskVolume2\\tools\\X.exe");
+        start(spy, 12, "\\Device\\HarddiskVolume2\\Program Files\\Mozilla Firefox\\firefox.exe");
+        kernel.run_pending_work();
+        assert_eq!(spy.events().len(), 2);
+        exit(spy, 8);
+        exit(spy, 12);
+        //the processes are gone before the worker runs
+        assert!(kernel.process_image_name(&irql(), 8).is_err());
+        kernel.run_pending_work();
+        assert_eq!(kernel.event(EXIT_EVENT_NAME).unwrap().signal_count, 2);
+        let mut buffer = [0u8; 4 * crate::events::RECORD_SIZE];
+        assert_eq!(spy.read(&mut buffer, 0), Ok(buffer.len()));
+        let exits: Vec<_> = spy_protocol::records(&buffer)
+            .filter_map(|record| match record {
+                Ok(spy_protocol::RecordRef::Process(record)) if !record.is_created() => Some(record),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(exits.len(), 2);
+        assert!(exits[0].pid.get() == 8 && exits[0].image_name.eq_str("X.exe"));
+        assert_eq!(exits[0].image_path.to_string(), "\\Device\\HarddiskVolume2\\tools\\X.exe");
+        assert!(exits[1].pid.get() == 12 && exits[1].image_name.eq_str("firefox.exe"));
+        free(spy);
+    }
+
+    #[test]
+    fn name_rules_see_the_whole_file_name() {
+        let spy = spy_with(&["averyverylongname.exe", "path:\\Device\\HarddiskVolume2\\tools\\x.exe"]);
+        let kernel = spy.kernel();
+        start(spy, 8, "\\Device\\HarddiskVolume2\\averyverylongname.exe");
+        start(spy, 12, "\\Device\\HarddiskVolume2\\Tools\\X.EXE");
+        start(spy, 16, "\\Device\\HarddiskVolume2\\averyverylongnamf.exe");
+        //watched processes are protected by the same whole name
+        assert!(spy.protection.is_protected_process(&irql(), 8));
+        assert!(!spy.protection.is_protected_process(&irql(), 16));
+        kernel.run_pending_work();
+        assert_eq!(spy.events().len(), 2);
+        assert_eq!(kernel.event(CREATE_EVENT_NAME).unwrap().signal_count, 2);
+    }
+
+    #[test]
+    fn notifications_are_handled_in_one_batch() {
+        let spy = spy_with(&["glob:*.exe"]);
+        let kernel = spy.kernel();
+        for pid in [8, 12, 16] {
+            start(spy, pid, "\\Device\\HarddiskVolume2\\a.exe");
+        }
+        assert_eq!(kernel.pending_work(), 1);
+        kernel.run_pending_work();
+        assert_eq!(spy.events().len(), 3);
+        assert_eq!(spy.dropped_notifications(), 0);
+    }
+
+    fn control(spy: &ProcessSpy<Arc<FakeKernel>>, code: ControlCode, input: &[u8], output: &mut [u8]) -> IoResult {
+        spy.device_control(code.raw(), input, output)
+    }
+
+    fn rule(text: &str) -> Vec<u8> {
+        RegistryValue::String(text.into()).to_bytes()
+    }
+
+    #[test]
+    fn the_watch_list_is_changed_and_listed_through_the_device() {
+        let spy = spy_with(&["a.exe"]);
+        let kernel = spy.kernel();
+        assert_eq!(control(spy, IOCTL_WATCH_ADD, &rule("glob:*.com"), &mut []), Ok(0));
+        assert_eq!(control(spy, IOCTL_WATCH_ADD, &rule("glob:*.com"), &mut []), Ok(0));
+        assert_eq!(control(spy, IOCTL_WATCH_ADD, &rule("nokind:x"), &mut []), Err(NtError::INVALID_PARAMETER));
+        assert_eq!(kernel.parameters().multi_string(WATCH_LIST_VALUE).unwrap().unwrap(), ["name:a.exe", "glob:*.com"]);
+        let listed = RegistryValue::MultiString(spy.watch_rules(&irql())).to_bytes();
+        let mut output = vec![0u8; listed.len()];
+        assert_eq!(control(spy, IOCTL_WATCH_LIST, &[], &mut output[..listed.len() - 1]), Err(NtError::BUFFER_TOO_SMALL));
+        assert_eq!(control(spy, IOCTL_WATCH_LIST, &[], &mut output), Ok(listed.len()));
+        assert_eq!(output, listed);
+        assert_eq!(control(spy, IOCTL_WATCH_REMOVE, &rule("a.exe"), &mut []), Ok(0));
+        assert_eq!(control(spy, IOCTL_WATCH_REMOVE, &rule("a.exe"), &mut []), Err(NtError::NOT_FOUND));
+        assert_eq!(spy.watch_rules(&irql()), ["glob:*.com"]);
+        free(spy);
+    }
+
+    //the typed IOCTLs want their input aligned
+    fn pid_input(pid: &u64) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(core::ptr::from_ref(pid).cast(), mem::size_of::<u64>()) }
+    }
+
+    #[test]
+    fn suspended_processes_are_approved_or_rejected_through_the_device() {
+        let spy = spy_with(&["suspend:a.exe"]);
+        let kernel = spy.kernel();
+        start(spy, 8, "\\Device\\HarddiskVolume2\\a.exe");
+        start(spy, 12, "\\Device\\HarddiskVolume2\\a.exe");
+        kernel.run_pending_work();
+        assert_eq!((kernel.suspend_count(8), kernel.suspend_count(12)), (1, 1));
+        assert_eq!(control(spy, IOCTL_PROCESS_APPROVE, &pid_input(&8)[..4], &mut []), Err(NtError::INVALID_PARAMETER));
+        assert_eq!(control(spy, IOCTL_PROCESS_APPROVE, pid_input(&8), &mut []), Ok(0));
+        assert_eq!(control(spy, IOCTL_PROCESS_REJECT, pid_input(&12), &mut []), Ok(0));
+        assert_eq!(control(spy, IOCTL_PROCESS_REJECT, pid_input(&12), &mut []), Err(NtError::NOT_FOUND));
+        assert_eq!(kernel.suspend_count(8), 0);
+        assert_eq!(kernel.exit_status(12), Some(NtError::ACCESS_DENIED));
+        free(spy);
+    }
+
+    #[test]
+    fn deny_rules_fail_the_creation_in_the_notify_routine() {
+        let spy = spy_with(&["deny:glob:*.scr"]);
+        let kernel = spy.kernel();
+        assert_eq!(start(spy, 8, "\\Device\\HarddiskVolume2\\evil.scr").code(), NtError::ACCESS_DENIED.code());
+        assert!(start(spy, 12, "\\Device\\HarddiskVolume2\\good.exe").is_success());
+        assert!(kernel.process_image_name(&irql(), 8).is_err());
+        //queued before the worker runs, which has nothing to add
+        let denied = records(spy);
+        assert_eq!(denied.len(), 1);
+        assert_eq!((denied[0].pid.get(), denied[0].action()), (8, Some(spy_protocol::ProcessAction::Deny)));
+        assert!(denied[0].image_name.eq_str("evil.scr") && !denied[0].is_audited());
+        kernel.run_pending_work();
+        assert_eq!(records(spy), []);
+        free(spy);
+    }
+
+    #[test]
+    fn terminate_rules_end_the_process_once_it_has_started() {
+        let spy = spy_with(&["terminate:a.exe"]);
+        let kernel = spy.kernel();
+        assert!(start(spy, 8, "\\Device\\HarddiskVolume2\\a.exe").is_success());
+        assert!(start(spy, 12, "\\Device\\HarddiskVolume2\\b.exe").is_success());
+        assert_eq!(kernel.exit_status(8), None);
+        kernel.run_pending_work();
+        assert_eq!(kernel.exit_status(8), Some(NtError::ACCESS_DENIED));
+        assert_eq!(kernel.exit_status(12), None);
+        let terminated = records(spy);
+        assert_eq!(terminated.len(), 1);
+        assert_eq!((terminated[0].pid.get(), terminated[0].action()), (8, Some(spy_protocol::ProcessAction::Terminate)));
+        free(spy);
+    }
+
+    #[test]
+    fn audit_only_records_what_the_enforcing_rules_would_do() {
+        let rules = ["deny:a.exe", "terminate:b.exe", "suspend:c.exe"];
+        let spy = spy_with_config(&SpyConfig { watch_list: WatchList::parse(&rules).unwrap(), audit_only: true, ..SpyConfig::default() });
+        let kernel = spy.kernel();
+        for (pid, name) in [(8, "a.exe"), (12, "b.exe"), (16, "c.exe")] {
+            assert!(start(spy, pid, &alloc::format!("\\Device\\HarddiskVolume2\\{name}")).is_success());
+        }
+        kernel.run_pending_work();
+        for pid in [8, 12, 16] {
+            assert!(kernel.process_image_name(&irql(), pid).is_ok());
+            assert_eq!((kernel.exit_status(pid), kernel.suspend_count(pid)), (None, 0));
+        }
+        let audited = records(spy);
+        let actions: Vec<_> = audited.iter().map(|record| (record.pid.get(), record.action())).collect();
+        assert_eq!(actions, [
+            (8, Some(spy_protocol::ProcessAction::Deny)),
+            (12, Some(spy_protocol::ProcessAction::Terminate)),
+            (16, Some(spy_protocol::ProcessAction::Suspend)),
+        ]);
+        assert!(audited.iter().all(|record| record.is_audited() && record.flags.get() & spy_protocol::ProcessRecord::AUDITED != 0));
+        //nothing waits for user mode to approve it
+        assert_eq!(control(spy, IOCTL_PROCESS_APPROVE, pid_input(&16), &mut []), Err(NtError::NOT_FOUND));
+        free(spy);
+    }
+
+    //the texts of the recent log messages, which are forgotten; only one test reads them
+    fn recent_log() -> Vec<String> {
+        let mut irql = Dispatch::current().unwrap();
+        core::iter::from_fn(|| log::pop_recent(&mut irql)).map(|message| String::from(message.text())).collect()
+    }
+
+    #[test]
+    fn remote_threads_into_watched_processes_are_reported() {
+        let spy = spy_with(&["a.exe"]);
+        let kernel = spy.kernel();
+        start(spy, 4008, "\\Device\\HarddiskVolume2\\a.exe");
+        start(spy, 4012, "\\Device\\HarddiskVolume2\\b.exe");
+        kernel.add_process(4020, "injector.exe");
+        kernel.run_pending_work();
+        let registration = kernel.register_thread_notify(&irql(), Box::new(move |irql: &mut Apc, notification: &ThreadNotification| {
+            spy.on_thread_notification(irql, notification);
+        })).unwrap();
+        let thread = |pid, tid, current_pid, created| kernel.notify_thread(&ThreadNotification { pid, tid, current_pid, created });
+        //the process creates a thread of its own, and one ends
+        thread(4008, 104, 4008, true);
+        thread(4008, 100, 4020, false);
+        assert!(spy.pending.is_empty());
+        thread(4008, 100, 4020, true);
+        thread(4012, 108, 4020, true);
+        assert_eq!(spy.pending.len(), 2);
+        let events = spy.events().len();
+        recent_log();
+        assert_eq!(kernel.run_pending_work(), 1);
+        let reported = recent_log();
+        assert!(reported.iter().any(|text| text.starts_with("Thread 100 is created in a.exe") && text.ends_with("4008 by injector.exe 4020")), "{reported:?}");
+        assert!(!reported.iter().any(|text| text.contains("Thread 104") || text.contains("Thread 108")), "{reported:?}");
+        assert_eq!(spy.events().len(), events);
+        drop(registration);
+        free(spy);
+    }
+
+    #[test]
+    fn image_loads_of_drivers_are_ignored() {
+        let spy = spy_with(&["a.exe"]);
+        let kernel = spy.kernel();
+        start(spy, 8, "\\Device\\HarddiskVolume2\\a.exe");
+        kernel.run_pending_work();
+        let events = spy.events().len();
+        let registration = kernel.register_image_notify(&irql(), Box::new(move |irql: &mut Passive, info: &ImageLoadInfo<'_>| {
+            spy.on_image_load(irql, info);
+        })).unwrap();
+        let path = encode_utf16("\\SystemRoot\\System32\\drivers\\x.sys");
+        kernel.load_image(&ImageLoadInfo { pid: 0, image_base: 0x1000, image_size: 0x2000, image_name: Some(UnicodeStr::new(&path)), system_image: true });
+        assert!(spy.pending.is_empty());
+        let path = encode_utf16("\\Device\\HarddiskVolume2\\Windows\\System32\\kernel32.dll");
+        for pid in [8, 12] {
+            kernel.load_image(&ImageLoadInfo { pid, image_base: 0x1000, image_size: 0x2000, image_name: Some(UnicodeStr::new(&path)), system_image: false });
+        }
+        assert_eq!(spy.pending.len(), 2);
+        assert_eq!(kernel.run_pending_work(), 1);
+        //image loads are only logged, whether the process is watched or not
+        assert!(spy.pending.is_empty());
+        assert_eq!(spy.events().len(), events);
+        drop(registration);
+        free(spy);
+    }
+
+    #[test]
+    fn events_and_the_log_are_served_by_the_same_table() {
+        let spy = spy_with(&[]);
+        let mut dropped = u64::MAX;
+        let output = unsafe { core::slice::from_raw_parts_mut(core::ptr::from_mut(&mut dropped).cast(), mem::size_of::<u64>()) };
+        assert_eq!(control(spy, IOCTL_EVENTS_OVERFLOW, &[], output), Ok(mem::size_of::<u64>()));
+        assert_eq!(dropped, 0);
+        assert_eq!(control(spy, IOCTL_EVENTS_READ, &[], &mut [0; crate::events::RECORD_SIZE]), Ok(0));
+        assert_eq!(control(spy, log::IOCTL_LOG_SET_LEVEL, &[], &mut []), Err(NtError::INVALID_PARAMETER));
+        assert!(spy.device_control(0x0022_0000, &[], &mut []).is_err());
+        free(spy);
+    }
+}
@@ -0,0 +1,437 @@
+//! The processes the spy watches, as a list of rules that user mode may
+//! change while the driver runs.
+//!
+//! A rule is written as `kind:pattern`, e.g. `glob:chrom*.exe`; without a
+//! kind it is a case-insensitive name. Name rules see the file name of the
+//! image and path rules its NT path, e.g.
+//! `dir:\Device\HarddiskVolume2\Program Files\Mozilla Firefox`. A rule
+//! may start with the action taken on a match, e.g. `deny:glob:*.scr`;
+//! without one it signals the events of the spy. The same text is used in the
+//! `WatchList` `REG_MULTI_SZ` that keeps the list across loads and in the
+//! buffers of the watch list IOCTLs, which carry it as UTF-16 like a
+//! `REG_SZ` and a `REG_MULTI_SZ`.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::fmt;
+use utils::ioctl::{ControlCode, Ioctl, RequiredAccess, TransferMethod};
+use crate::matcher::{MatchMode, Matcher};
+
+///the most rules a list holds, so user mode cannot grow it without bound
+pub const MAX_RULES: usize = 64;
+
+///adds the rule in the input buffer
+pub const IOCTL_WATCH_ADD: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x200,
+    TransferMethod::Buffered,
+    RequiredAccess::Write,
+);
+///removes the rule in the input buffer
+pub const IOCTL_WATCH_REMOVE: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x201,
+    TransferMethod::Buffered,
+    RequiredAccess::Write,
+);
+///copies every rule into the output buffer
+pub const IOCTL_WATCH_LIST: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x202,
+    TransferMethod::Buffered,
+    RequiredAccess::Read,
+);
+///resumes the process a `suspend` rule stopped; the input is its id as a `u64`
+pub const IOCTL_PROCESS_APPROVE: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x203,
+    TransferMethod::Buffered,
+    RequiredAccess::Write,
+);
+///terminates the process a `suspend` rule stopped; the input is as for [`IOCTL_PROCESS_APPROVE`]
+pub const IOCTL_PROCESS_REJECT: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x204,
+    TransferMethod::Buffered,
+    RequiredAccess::Write,
+);
+
+///[`IOCTL_PROCESS_APPROVE`]
+pub struct ProcessApprove;
+
+impl Ioctl for ProcessApprove {
+    const CODE: ControlCode = IOCTL_PROCESS_APPROVE;
+    type Input = u64;
+    type Output = ();
+}
+
+///[`IOCTL_PROCESS_REJECT`]
+pub struct ProcessReject;
+
+impl Ioctl for ProcessReject {
+    const CODE: ControlCode = IOCTL_PROCESS_REJECT;
+    type Input = u64;
+    type Output = ();
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RuleKind {
+    ///the image file name, compared as it is
+    Exact,
+    ///the image file name, ignoring case like the other kinds
+    CaseInsensitive,
+    ///the start of the image file name
+    Prefix,
+    ///the end of the image file name
+    Suffix,
+    ///the image file name against a pattern with `*` for any run of characters and `?` for one
+    Glob,
+    ///the NT path of the image
+    FullPath,
+    ///the NT path of a directory the image is in, at any depth
+    Directory,
+}
+
+impl RuleKind {
+    const ALL: [Self; 7] = [Self::Exact, Self::CaseInsensitive, Self::Prefix, Self::Suffix, Self::Glob, Self::FullPath, Self::Directory];
+
+    #[must_use]
+    pub const fn prefix(self) -> &'static str {
+        match self {
+            Self::Exact => "exact",
+            Self::CaseInsensitive => "name",
+            Self::Prefix => "prefix",
+            Self::Suffix => "suffix",
+            Self::Glob => "glob",
+            Self::FullPath => "path",
+            Self::Directory => "dir",
+        }
+    }
+    const fn match_mode(self) -> MatchMode {
+        match self {
+            Self::Exact => MatchMode::Exact,
+            Self::CaseInsensitive | Self::FullPath => MatchMode::CaseInsensitive,
+            Self::Prefix | Self::Directory => MatchMode::Prefix,
+            Self::Suffix => MatchMode::Suffix,
+            Self::Glob => MatchMode::Glob,
+        }
+    }
+}
+
+///what the spy does with a process a rule matches
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub enum RuleAction {
+    ///sets the create or exit event of the spy
+    #[default]
+    Signal,
+    ///only queues the event for user mode, which every match does
+    Record,
+    ///makes the creation fail, so the process never runs
+    Deny,
+    ///terminates the process once it has started
+    Terminate,
+    ///suspends the process until user mode approves or rejects it
+    Suspend,
+}
+
+impl RuleAction {
+    const ALL: [Self; 5] = [Self::Signal, Self::Record, Self::Deny, Self::Terminate, Self::Suspend];
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Signal => "signal",
+            Self::Record => "record",
+            Self::Deny => "deny",
+            Self::Terminate => "terminate",
+            Self::Suspend => "suspend",
+        }
+    }
+    ///whether the action stops the process, which the spy only records when it audits
+    #[must_use]
+    pub const fn is_enforcing(self) -> bool {
+        matches!(self, Self::Deny | Self::Terminate | Self::Suspend)
+    }
+}
+
+impl Display for RuleAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RuleError {
+    Empty,
+    UnknownKind,
+    ///a name rule holds a path
+    NotAName,
+    ///a path or directory rule does not hold an NT path
+    NotAPath,
+    ///the list holds [`MAX_RULES`] already
+    Full,
+}
+
+impl RuleError {
+    #[must_use]
+    pub const fn reason(self) -> &'static str {
+        match self {
+            Self::Empty => "a rule has no pattern",
+            Self::UnknownKind => "a rule has an unknown kind",
+            Self::NotAName => "a name rule is a path",
+            Self::NotAPath => "a path rule does not start with a backslash",
+            Self::Full => "the watch list is full",
+        }
+    }
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchRule {
+    kind: RuleKind,
+    matcher: Matcher,
+    action: RuleAction,
+}
+
+impl WatchRule {
+    /// # Errors
+    /// When the pattern is empty, or does not fit the kind: names must not
+    /// be paths and paths must start with a backslash.
+    pub fn new(kind: RuleKind, pattern: &str) -> Result<Self, RuleError> {
+        let mut pattern = String::from(pattern);
+        match kind {
+            RuleKind::FullPath | RuleKind::Directory if !pattern.starts_with('\\') => return Err(RuleError::NotAPath),
+            //so `\Apps` does not take in `\AppsOld`
+            RuleKind::Directory if !pattern.ends_with('\\') => pattern.push('\\'),
+            RuleKind::FullPath | RuleKind::Directory => {}
+            _ if pattern.contains(['\\', '/']) => return Err(RuleError::NotAName),
+            _ => {}
+        }
+        let matcher = Matcher::new(kind.match_mode(), &pattern).map_err(|_| RuleError::Empty)?;
+        Ok(Self { kind, matcher, action: RuleAction::default() })
+    }
+    #[must_use]
+    pub const fn with_action(mut self, action: RuleAction) -> Self {
+        self.action = action;
+        self
+    }
+    /// Parses `[action:]kind:pattern`, or a bare case-insensitive name
+    /// after the optional action.
+    ///
+    /// # Errors
+    /// Those of [`Self::new`], or [`RuleError::UnknownKind`].
+    pub fn parse(text: &str) -> Result<Self, RuleError> {
+        //image names cannot hold a colon, so a leading action is never mistaken for one
+        let (action, text) = text.split_once(':')
+            .and_then(|(name, rest)| {
+                let action = RuleAction::ALL.into_iter().find(|action| action.name().eq_ignore_ascii_case(name))?;
+                Some((action, rest))
+            })
+            .unwrap_or((RuleAction::Signal, text));
+        let Some((prefix, pattern)) = text.split_once(':') else {
+            return Ok(Self::new(RuleKind::CaseInsensitive, text)?.with_action(action));
+        };
+        let kind = RuleKind::ALL.into_iter()
+            .find(|kind| kind.prefix().eq_ignore_ascii_case(prefix))
+            .ok_or(RuleError::UnknownKind)?;
+        Ok(Self::new(kind, pattern)?.with_action(action))
+    }
+    #[must_use]
+    pub const fn kind(&self) -> RuleKind {
+        self.kind
+    }
+    #[must_use]
+    pub const fn action(&self) -> RuleAction {
+        self.action
+    }
+    #[must_use]
+    pub fn pattern(&self) -> &str {
+        self.matcher.pattern()
+    }
+    ///the image file name when the rule matches exactly one, which is then worth protecting
+    #[must_use]
+    pub fn image_name(&self) -> Option<&str> {
+        matches!(self.kind, RuleKind::Exact | RuleKind::CaseInsensitive).then_some(self.pattern())
+    }
+    ///path rules never match while the path of the image is unknown
+    #[must_use]
+    pub fn matches(&self, image_name: &str, image_path: Option<&str>) -> bool {
+        match self.kind {
+            RuleKind::FullPath | RuleKind::Directory => image_path.is_some_and(|path| self.matcher.is_match(path)),
+            _ => self.matcher.is_match(image_name),
+        }
+    }
+}
+
+impl Display for WatchRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        //rules that only signal are written as they were before there were actions
+        if self.action != RuleAction::Signal {
+            write!(f, "{}:", self.action)?;
+        }
+        write!(f, "{}:{}", self.kind.prefix(), self.pattern())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WatchList {
+    rules: Vec<WatchRule>,
+}
+
+impl WatchList {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { rules: Vec::new() }
+    }
+    /// Parses every entry with [`WatchRule::parse`], dropping duplicates.
+    ///
+    /// # Errors
+    /// The error of the first entry that is not a rule, or
+    /// [`RuleError::Full`] for more than [`MAX_RULES`] rules.
+    pub fn parse<S: AsRef<str>>(entries: &[S]) -> Result<Self, RuleError> {
+        let mut list = Self::new();
+        for entry in entries {
+            list.add(WatchRule::parse(entry.as_ref())?)?;
+        }
+        Ok(list)
+    }
+    #[must_use]
+    pub fn rules(&self) -> &[WatchRule] {
+        &self.rules
+    }
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.rules.len()
+    }
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    /// Returns `false` when the list holds the rule already.
+    ///
+    /// # Errors
+    /// [`RuleError::Full`] when the list holds [`MAX_RULES`] other rules.
+    pub fn add(&mut self, rule: WatchRule) -> Result<bool, RuleError> {
+        if self.rules.contains(&rule) {
+            return Ok(false);
+        }
+        if self.rules.len() >= MAX_RULES {
+            return Err(RuleError::Full);
+        }
+        self.rules.push(rule);
+        Ok(true)
+    }
+    ///returns `false` when the list does not hold the rule
+    pub fn remove(&mut self, rule: &WatchRule) -> bool {
+        let length = self.rules.len();
+        self.rules.retain(|other| other != rule);
+        self.rules.len() != length
+    }
+    ///the first rule that matches the image
+    #[must_use]
+    pub fn find(&self, image_name: &str, image_path: Option<&str>) -> Option<&WatchRule> {
+        self.rules.iter().find(|rule| rule.matches(image_name, image_path))
+    }
+    ///the rules as [`Self::parse`] takes them
+    #[must_use]
+    pub fn to_strings(&self) -> Vec<String> {
+        self.rules.iter().map(ToString::to_string).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    fn rule(text: &str) -> WatchRule {
+        WatchRule::parse(text).unwrap()
+    }
+
+    #[test]
+    fn rules_are_parsed_with_an_optional_action_and_kind() {
+        let bare = rule("firefox.exe");
+        assert_eq!((bare.action(), bare.kind(), bare.pattern()), (RuleAction::Signal, RuleKind::CaseInsensitive, "firefox.exe"));
+        let kind = rule("glob:chrom*.exe");
+        assert_eq!((kind.action(), kind.kind(), kind.pattern()), (RuleAction::Signal, RuleKind::Glob, "chrom*.exe"));
+        let action = rule("DENY:a.scr");
+        assert_eq!((action.action(), action.kind(), action.pattern()), (RuleAction::Deny, RuleKind::CaseInsensitive, "a.scr"));
+        let both = rule("suspend:path:\\Device\\HarddiskVolume2\\x.exe");
+        assert_eq!((both.action(), both.kind()), (RuleAction::Suspend, RuleKind::FullPath));
+        assert_eq!(both.image_name(), None);
+        assert_eq!(rule("exact:X.exe").image_name(), Some("X.exe"));
+        assert!(rule("prefix:fire").matches("Firefox.exe", None));
+        assert!(rule("suffix:.EXE").matches("firefox.exe", None));
+        assert!(!rule("exact:X.exe").matches("x.exe", None));
+    }
+
+    #[test]
+    fn rules_are_written_as_they_are_parsed() {
+        let texts = ["name:firefox.exe", "record:glob:*.exe", "terminate:dir:\\Device\\HarddiskVolume2\\Apps\\", "exact:X.exe"];
+        let list = WatchList::parse(&texts).unwrap();
+        assert_eq!(list.to_strings(), texts);
+        assert_eq!(WatchList::parse(&list.to_strings()), Ok(list));
+        //a bare name and one that only signals are written with the kind and without the action
+        assert_eq!(rule("signal:firefox.exe").to_string(), "name:firefox.exe");
+        assert_eq!(format!("{}", rule("dir:\\Apps")), "dir:\\Apps\\");
+    }
+
+    #[test]
+    fn malformed_rules_are_rejected() {
+        assert_eq!(WatchRule::parse("regex:.*"), Err(RuleError::UnknownKind));
+        assert_eq!(WatchRule::parse("deny:regex:.*"), Err(RuleError::UnknownKind));
+        assert_eq!(WatchRule::parse("\\Device\\x.exe"), Err(RuleError::NotAName));
+        assert_eq!(WatchRule::parse("glob:tools/*.exe"), Err(RuleError::NotAName));
+        assert_eq!(WatchRule::parse("path:x.exe"), Err(RuleError::NotAPath));
+        assert_eq!(WatchRule::parse("dir:C:\\Apps"), Err(RuleError::NotAPath));
+        assert_eq!(WatchRule::parse(""), Err(RuleError::Empty));
+        assert_eq!(WatchRule::parse("deny:name:"), Err(RuleError::Empty));
+        assert_eq!(WatchList::parse(&["a.exe", "path:x"]), Err(RuleError::NotAPath));
+    }
+
+    #[test]
+    fn lists_drop_duplicates_and_hold_at_most_max_rules() {
+        let mut list = WatchList::new();
+        assert_eq!(list.add(rule("a.exe")), Ok(true));
+        //the same names in another case
+        assert_eq!(list.add(rule("name:A.EXE")), Ok(false));
+        //but not with another action
+        assert_eq!(list.add(rule("deny:a.exe")), Ok(true));
+        assert_eq!(list.len(), 2);
+        for index in list.len()..MAX_RULES {
+            assert_eq!(list.add(rule(&format!("{index}.exe"))), Ok(true));
+        }
+        assert_eq!(list.add(rule("another.exe")), Err(RuleError::Full));
+        assert_eq!(list.add(rule("a.exe")), Ok(false));
+        let entries: Vec<String> = (0..=MAX_RULES).map(|index| format!("{index}.exe")).collect();
+        assert_eq!(WatchList::parse(&entries), Err(RuleError::Full));
+    }
+
+    #[test]
+    fn rules_are_removed() {
+        let mut list = WatchList::parse(&["a.exe", "deny:b.exe"]).unwrap();
+        assert!(!list.remove(&rule("b.exe")));
+        assert!(list.remove(&rule("deny:B.exe")));
+        assert!(list.remove(&rule("A.exe")));
+        assert!(list.is_empty());
+        assert!(!list.remove(&rule("a.exe")));
+    }
+
+    #[test]
+    fn directory_rules_take_in_whole_directories_only() {
+        let apps = rule("dir:\\Device\\HarddiskVolume2\\Apps");
+        assert_eq!(apps.pattern(), "\\Device\\HarddiskVolume2\\Apps\\");
+        assert!(apps.matches("x.exe", Some("\\Device\\HarddiskVolume2\\apps\\x.exe")));
+        assert!(apps.matches("x.exe", Some("\\Device\\HarddiskVolume2\\Apps\\Tools\\x.exe")));
+        assert!(!apps.matches("x.exe", Some("\\Device\\HarddiskVolume2\\AppsOld\\x.exe")));
+        assert!(!apps.matches("x.exe", None));
+        let list = WatchList::parse(&["dir:\\Device\\HarddiskVolume2\\Apps", "x.exe"]).unwrap();
+        assert_eq!(list.find("x.exe", Some("\\Device\\HarddiskVolume2\\AppsOld\\x.exe")).map(WatchRule::kind), Some(RuleKind::CaseInsensitive));
+    }
+}
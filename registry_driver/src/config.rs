@@ -0,0 +1,68 @@
+//! The settings of the logger, from the `Parameters` key of the service.
+
+use alloc::string::String;
+use utils::config::{ConfigError, FromParameters, Parameters};
+use crate::logger::{LOG_FILE_PATH, MAX_LOGGED_EVENTS};
+
+pub const LOG_FILE_PATH_VALUE: &str = "LogFilePath";
+pub const MAX_LOGGED_EVENTS_VALUE: &str = "MaxLoggedEvents";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggerConfig {
+    ///the NT path of the log file, `REG_SZ`
+    pub log_file_path: String,
+    ///the logger stops logging after this many notifications, `REG_QWORD` or `REG_DWORD`
+    pub max_logged_events: usize,
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self { log_file_path: String::from(LOG_FILE_PATH), max_logged_events: MAX_LOGGED_EVENTS }
+    }
+}
+
+impl FromParameters for LoggerConfig {
+    fn from_parameters(parameters: &Parameters) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        if let Some(log_file_path) = parameters.string(LOG_FILE_PATH_VALUE)? {
+            if !log_file_path.starts_with('\\') {
+                return Err(ConfigError::invalid(LOG_FILE_PATH_VALUE, "not an NT path such as \\DosDevices\\C:\\log.dat"));
+            }
+            config.log_file_path = String::from(log_file_path);
+        }
+        if let Some(max_logged_events) = parameters.qword(MAX_LOGGED_EVENTS_VALUE)? {
+            config.max_logged_events = usize::try_from(max_logged_events)
+                .ok()
+                .filter(|max| *max != 0)
+                .ok_or_else(|| ConfigError::invalid(MAX_LOGGED_EVENTS_VALUE, "zero or too large"))?;
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::config::{RegistryValue, REG_QWORD, REG_SZ};
+
+    #[test]
+    fn missing_values_keep_the_defaults() {
+        assert_eq!(LoggerConfig::from_parameters(&Parameters::new()), Ok(LoggerConfig::default()));
+    }
+
+    #[test]
+    fn values_are_checked() {
+        let mut parameters = Parameters::new();
+        parameters.insert(LOG_FILE_PATH_VALUE, RegistryValue::String("\\??\\D:\\registry.log".into()));
+        parameters.insert(MAX_LOGGED_EVENTS_VALUE, RegistryValue::Dword(10));
+        let config = LoggerConfig::from_parameters(&parameters).unwrap();
+        assert_eq!((config.log_file_path.as_str(), config.max_logged_events), ("\\??\\D:\\registry.log", 10));
+        parameters.insert(MAX_LOGGED_EVENTS_VALUE, RegistryValue::Qword(0));
+        assert!(matches!(LoggerConfig::from_parameters(&parameters), Err(ConfigError::Invalid { .. })));
+        parameters.insert(MAX_LOGGED_EVENTS_VALUE, RegistryValue::String("10".into()));
+        assert!(matches!(LoggerConfig::from_parameters(&parameters), Err(ConfigError::WrongType { expected: REG_QWORD, found: REG_SZ, .. })));
+        parameters.insert(MAX_LOGGED_EVENTS_VALUE, RegistryValue::Dword(10));
+        parameters.insert(LOG_FILE_PATH_VALUE, RegistryValue::String("D:\\registry.log".into()));
+        assert!(matches!(LoggerConfig::from_parameters(&parameters), Err(ConfigError::Invalid { .. })));
+    }
+}
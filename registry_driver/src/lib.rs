@@ -1,5 +1,4 @@
-#![feature(new_uninit)]
-#![no_std]
+#![cfg_attr(target_os = "windows", no_std)]
 #![cfg_attr(feature = "nightly", feature(hint_must_use))]
 // #![deny(warnings)]
 // #![deny(clippy::all)]
@@ -7,9 +6,12 @@
 #![warn(clippy::nursery)]
 #![warn(clippy::cargo)]
 #![allow(clippy::missing_safety_doc)]
+#[cfg(target_os = "windows")]
 mod driver;
+pub mod config;
+pub mod logger;
 
-#[cfg(not(test))]
+#[cfg(all(not(test), target_os = "windows"))]
 #[panic_handler]
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
     wdk::println!("Driver panics={info}");
@@ -18,10 +20,10 @@ pub fn panic(info: &core::panic::PanicInfo) -> ! {
 
 extern crate alloc;
 extern crate utils;
-#[cfg(not(test))]
-use wdk_alloc::WDKAllocator;
+#[cfg(all(not(test), target_os = "windows"))]
+use utils::pool::{NonPagedPool, PoolTag, TaggedAllocator};
 
 
-#[cfg(not(test))]
+#[cfg(all(not(test), target_os = "windows"))]
 #[global_allocator]
-static GLOBAL_ALLOCATOR: WDKAllocator = WDKAllocator;
\ No newline at end of file
+static GLOBAL_ALLOCATOR: TaggedAllocator<NonPagedPool> = TaggedAllocator::new(NonPagedPool, PoolTag::new(b"RLog"));
\ No newline at end of file
@@ -0,0 +1,263 @@
+//! The registry logger: the registry callback queues a line for each
+//! notification and a work item appends them to the log file.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spy_protocol::NameField;
+use utils::kernel::RegistryNotification;
+use utils::ring::ScheduledRing;
+use crate::config::LoggerConfig;
+use utils::{debug, error, KernelApi, NtError, Passive, Status, WorkQueue};
+
+///user mode reads and filters the log of the driver through `\\.\RustRegistryLogger`
+pub const LOGGER_DEVICE_NAME: &str = "RustRegistryLogger";
+
+///the default of [`LoggerConfig::log_file_path`]
+pub const LOG_FILE_PATH: &str = "\\DosDevices\\C:\\register-log.dat";
+
+///the default of [`LoggerConfig::max_logged_events`]
+pub const MAX_LOGGED_EVENTS: usize = 1000;
+
+///how many notifications may wait for the writer before new ones are dropped
+pub const PENDING_CAPACITY: usize = 128;
+
+//a notification as it waits for the writer, without any allocation
+enum LogEntry {
+    SetValue(NameField),
+    QueryValue(NameField),
+    Unknown,
+}
+
+impl LogEntry {
+    fn new(notification: &RegistryNotification<'_>) -> Self {
+        match notification {
+            RegistryNotification::PreSetValueKey { value_name } => Self::SetValue(NameField::from_units(value_name.as_units())),
+            RegistryNotification::PreQueryValueKey { value_name } => Self::QueryValue(NameField::from_units(value_name.as_units())),
+            RegistryNotification::Other(_) => Self::Unknown,
+        }
+    }
+    fn write_to(&self, log: &mut String) {
+        let _ = match self {
+            Self::SetValue(value_name) => writeln!(log, "The entry {value_name} will be changed"),
+            Self::QueryValue(value_name) => writeln!(log, "The key value {value_name} will be queried"),
+            Self::Unknown => writeln!(log, "Unknown registry info"),
+        };
+    }
+}
+
+//shared between the registry callback and the queued writes, so it lives until the last write is done
+struct LoggerShared<K: KernelApi> {
+    kernel: K,
+    device: K::Device,
+    log_file: Option<K::File>,
+    elapsed_time: AtomicUsize,
+    max_logged_events: usize,
+    pending: ScheduledRing<LogEntry>,
+}
+
+impl<K: KernelApi> LoggerShared<K> {
+    fn dispatch(self: &Arc<Self>, irql: &Passive, notification: &RegistryNotification<'_>) -> Status {
+        if self.elapsed_time.fetch_add(1, Ordering::Relaxed) >= self.max_logged_events {
+            return Status::SUCCESS;
+        }
+        let schedule = || {
+            let shared = Arc::clone(self);
+            let routine = Box::new(move |irql: &mut Passive| shared.drain(irql));
+            self.kernel.queue_work(irql, self.device, WorkQueue::Delayed, routine)
+        };
+        if let Err(error) = self.pending.push(LogEntry::new(notification), schedule) {
+            error!("Failed to queue log writing {error}");
+        }
+        Status::SUCCESS
+    }
+    fn drain(&self, irql: &Passive) {
+        let mut log = String::new();
+        self.pending.drain(|entry| entry.write_to(&mut log));
+        if !log.is_empty() {
+            self.write(irql, &log);
+        }
+    }
+    fn write(&self, irql: &Passive, message: &str) {
+        let Some(log_file) = &self.log_file else {
+            return;
+        };
+        if let Err(error) = self.kernel.append_file(irql, log_file, message.as_bytes()) {
+            error!("Failed to append log file with status={error}");
+        }
+    }
+}
+
+impl<K: KernelApi> Drop for LoggerShared<K> {
+    fn drop(&mut self) {
+        //the last reference goes away in `free` or in a queued write, both at PASSIVE_LEVEL
+        let irql = unsafe { Passive::new_unchecked() };
+        if let Some(log_file) = self.log_file.take() {
+            self.kernel.close_file(&irql, log_file);
+        }
+        self.kernel.delete_device(&irql, self.device);
+    }
+}
+
+pub struct RegisterLogger<K: KernelApi> {
+    shared: Arc<LoggerShared<K>>,
+    callback: K::RegistryCallback,
+}
+
+impl<K: KernelApi> RegisterLogger<K> {
+    /// Creates the device used for work items and the log IOCTLs, opens the log file and
+    /// registers the registry callback.
+    ///
+    /// # Errors
+    /// When the device cannot be created, the log file cannot be opened or
+    /// the callback cannot be registered; the device and the file are
+    /// released again.
+    pub fn new(irql: &Passive, kernel: K, config: &LoggerConfig) -> Result<Self, NtError> {
+        let device = kernel.create_device(irql, LOGGER_DEVICE_NAME).inspect_err(|error| {
+            error!("Failed to create IoCreateDevice with code={error}");
+        })?;
+        debug!("Device is created");
+        let log_file = match kernel.open_append_file(irql, &config.log_file_path) {
+            Ok(file) => file,
+            Err(error) => {
+                error!("Failed to create file for logger with status={error}");
+                kernel.delete_device(irql, device);
+                return Err(error);
+            }
+        };
+        let shared = Arc::new(LoggerShared {
+            kernel,
+            device,
+            log_file: Some(log_file),
+            elapsed_time: AtomicUsize::new(0),
+            max_logged_events: config.max_logged_events,
+            pending: ScheduledRing::new(PENDING_CAPACITY),
+        });
+        let handler_shared = Arc::clone(&shared);
+        let callback = shared.kernel.register_registry_callback(irql, Box::new(move |irql: &mut Passive, notification: &RegistryNotification<'_>| {
+            handler_shared.dispatch(irql, notification)
+        })).inspect_err(|error| {
+            error!("Failed to registry register callback with status={error}");
+        })?;
+        debug!("Logger is contructed");
+        Ok(Self { shared, callback })
+    }
+    pub fn kernel(&self) -> &K {
+        &self.shared.kernel
+    }
+    ///the number of notifications logged so far
+    pub fn elapsed_time(&self) -> usize {
+        //the counter keeps going past the cap, only the logging stops
+        usize::min(self.shared.elapsed_time.load(Ordering::Relaxed), self.shared.max_logged_events)
+    }
+    ///how many notifications were lost because the writer fell behind
+    pub fn dropped_notifications(&self) -> usize {
+        self.shared.pending.dropped()
+    }
+    ///unregisters the callback and waits for the queued writes, after which the file and device are released
+    pub fn free(self, irql: &Passive) {
+        self.shared.kernel.unregister_registry_callback(irql, self.callback);
+        self.shared.kernel.drain_work(irql);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::kernel::fake::FakeKernel;
+    use utils::unicode::{encode_utf16, UnicodeStr};
+
+    fn irql() -> Passive {
+        Passive::current().unwrap()
+    }
+
+    fn logger(max_logged_events: usize) -> RegisterLogger<Arc<FakeKernel>> {
+        let config = LoggerConfig { max_logged_events, ..LoggerConfig::default() };
+        RegisterLogger::new(&irql(), Arc::new(FakeKernel::new()), &config).unwrap()
+    }
+
+    fn set_value(kernel: &FakeKernel, name: &str) -> Status {
+        let name = encode_utf16(name);
+        kernel.notify_registry(&RegistryNotification::PreSetValueKey { value_name: UnicodeStr::new(&name) })
+    }
+
+    fn query_value(kernel: &FakeKernel, name: &str) -> Status {
+        let name = encode_utf16(name);
+        kernel.notify_registry(&RegistryNotification::PreQueryValueKey { value_name: UnicodeStr::new(&name) })
+    }
+
+    fn log_file(kernel: &FakeKernel) -> String {
+        String::from_utf8(kernel.file_contents(LOG_FILE_PATH).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn notifications_are_written_by_one_work_item() {
+        let logger = logger(MAX_LOGGED_EVENTS);
+        let kernel = logger.kernel().clone();
+        assert_eq!(kernel.registry_callbacks(), 1);
+        assert!(set_value(&kernel, "Start").is_success());
+        assert!(query_value(&kernel, "ImagePath").is_success());
+        assert!(kernel.notify_registry(&RegistryNotification::Other(42)).is_success());
+        //nothing is written in the callback itself
+        assert_eq!(log_file(&kernel), "");
+        assert_eq!(kernel.pending_work(), 1);
+        assert_eq!(kernel.run_pending_work(), 1);
+        assert_eq!(log_file(&kernel), "The entry Start will be changed\nThe key value ImagePath will be queried\nUnknown registry info\n");
+        assert!(set_value(&kernel, "Type").is_success());
+        assert_eq!(kernel.run_pending_work(), 1);
+        assert!(log_file(&kernel).ends_with("info\nThe entry Type will be changed\n"));
+        assert_eq!(logger.elapsed_time(), 4);
+    }
+
+    #[test]
+    fn logging_stops_at_the_cap() {
+        let logger = logger(2);
+        let kernel = logger.kernel().clone();
+        for name in ["First", "Second", "Third"] {
+            assert!(set_value(&kernel, name).is_success());
+        }
+        kernel.run_pending_work();
+        assert_eq!(log_file(&kernel), "The entry First will be changed\nThe entry Second will be changed\n");
+        assert_eq!(logger.elapsed_time(), 2);
+        assert_eq!(logger.dropped_notifications(), 0);
+    }
+
+    #[test]
+    fn notifications_are_dropped_while_the_writer_falls_behind() {
+        let logger = logger(MAX_LOGGED_EVENTS);
+        let kernel = logger.kernel().clone();
+        for _ in 0..PENDING_CAPACITY + 3 {
+            set_value(&kernel, "Start");
+        }
+        assert_eq!(logger.dropped_notifications(), 3);
+        kernel.run_pending_work();
+        assert_eq!(log_file(&kernel).lines().count(), PENDING_CAPACITY);
+    }
+
+    #[test]
+    fn free_writes_the_pending_notifications_and_releases_everything() {
+        let logger = logger(MAX_LOGGED_EVENTS);
+        let kernel = logger.kernel().clone();
+        set_value(&kernel, "Start");
+        assert_eq!((kernel.live_devices(), kernel.open_files()), (1, 1));
+        assert!(kernel.has_device(LOGGER_DEVICE_NAME));
+        logger.free(&irql());
+        assert_eq!(log_file(&kernel), "The entry Start will be changed\n");
+        assert_eq!((kernel.registry_callbacks(), kernel.live_devices(), kernel.open_files()), (0, 0, 0));
+        assert!(set_value(&kernel, "Type").is_success());
+        assert_eq!(kernel.pending_work(), 0);
+    }
+
+    #[test]
+    fn the_log_file_path_comes_from_the_config() {
+        let config = LoggerConfig { log_file_path: String::from("\\??\\D:\\registry.log"), ..LoggerConfig::default() };
+        let logger = RegisterLogger::new(&irql(), Arc::new(FakeKernel::new()), &config).unwrap();
+        let kernel = logger.kernel().clone();
+        query_value(&kernel, "Start");
+        logger.free(&irql());
+        assert_eq!(kernel.file_contents("\\??\\D:\\registry.log").unwrap(), b"The key value Start will be queried\n");
+        assert_eq!(kernel.file_contents(LOG_FILE_PATH), None);
+    }
+}
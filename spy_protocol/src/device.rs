@@ -0,0 +1,58 @@
+//! The device the process driver creates and the control codes user mode
+//! sends it.
+//!
+//! The codes are laid out as `CTL_CODE` does; the driver pins its own
+//! definitions to these.
+
+///the driver creates `\Device\<name>` and links `\DosDevices\<name>`, so user mode opens `\\.\<name>`
+pub const SPY_DEVICE_NAME: &str = "RustProcessSpy";
+///[`SPY_DEVICE_NAME`] as user mode opens it, nul-terminated
+pub const SPY_DEVICE_PATH: &[u8] = b"\\\\.\\RustProcessSpy\0";
+
+const FILE_DEVICE_UNKNOWN: u32 = 0x22;
+const FIRST_CUSTOM_FUNCTION: u32 = 0x800;
+const METHOD_BUFFERED: u32 = 0;
+const FILE_READ_ACCESS: u32 = 1;
+const FILE_WRITE_ACCESS: u32 = 2;
+
+const fn ctl_code(function: u32, access: u32) -> u32 {
+    (FILE_DEVICE_UNKNOWN << 16) | (access << 14) | ((FIRST_CUSTOM_FUNCTION + function) << 2) | METHOD_BUFFERED
+}
+
+///copies the oldest recent log messages, one per line, into the output buffer
+pub const IOCTL_LOG_READ: u32 = ctl_code(0x100, FILE_READ_ACCESS);
+///sets the maximum log level from a `u32`, 0 turning logging off
+pub const IOCTL_LOG_SET_LEVEL: u32 = ctl_code(0x101, FILE_WRITE_ACCESS);
+///adds the watch rule in the input buffer
+pub const IOCTL_WATCH_ADD: u32 = ctl_code(0x200, FILE_WRITE_ACCESS);
+///removes the watch rule in the input buffer
+pub const IOCTL_WATCH_REMOVE: u32 = ctl_code(0x201, FILE_WRITE_ACCESS);
+///copies every watch rule into the output buffer
+pub const IOCTL_WATCH_LIST: u32 = ctl_code(0x202, FILE_READ_ACCESS);
+///resumes the suspended process whose id is the `u64` input
+pub const IOCTL_PROCESS_APPROVE: u32 = ctl_code(0x203, FILE_WRITE_ACCESS);
+///terminates the suspended process whose id is the `u64` input
+pub const IOCTL_PROCESS_REJECT: u32 = ctl_code(0x204, FILE_WRITE_ACCESS);
+///moves the queued [`crate::ProcessRecord`]s into the output buffer
+pub const IOCTL_EVENTS_READ: u32 = ctl_code(0x205, FILE_READ_ACCESS);
+///copies how many events were dropped, as a `u64`
+pub const IOCTL_EVENTS_OVERFLOW: u32 = ctl_code(0x206, FILE_READ_ACCESS);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_match_ctl_code() {
+        //CTL_CODE(FILE_DEVICE_UNKNOWN, 0x900, METHOD_BUFFERED, FILE_READ_ACCESS)
+        assert_eq!(IOCTL_LOG_READ, 0x0022_6400);
+        assert_eq!(IOCTL_LOG_SET_LEVEL, 0x0022_A404);
+        assert_eq!(IOCTL_EVENTS_READ, 0x0022_6814);
+    }
+
+    #[test]
+    fn device_path_names_the_device() {
+        let path = core::str::from_utf8(SPY_DEVICE_PATH).unwrap();
+        assert_eq!(path.strip_prefix("\\\\.\\").and_then(|name| name.strip_suffix('\0')), Some(SPY_DEVICE_NAME));
+    }
+}
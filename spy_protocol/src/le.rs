@@ -0,0 +1,49 @@
+//! Little-endian integers stored as bytes.
+//!
+//! They have an alignment of one, so a record built from them has no padding
+//! and can be viewed in place from any byte buffer.
+
+use core::fmt::{Debug, Formatter};
+use core::fmt;
+
+macro_rules! little_endian {
+    ($($name: ident($type: ty, $size: literal)),*) => {
+        $(
+            #[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+            #[repr(transparent)]
+            pub struct $name([u8; $size]);
+
+            impl $name {
+                pub const fn new(value: $type) -> Self {
+                    Self(value.to_le_bytes())
+                }
+                pub const fn get(self) -> $type {
+                    <$type>::from_le_bytes(self.0)
+                }
+                pub fn set(&mut self, value: $type) {
+                    self.0 = value.to_le_bytes();
+                }
+            }
+
+            impl From<$type> for $name {
+                fn from(value: $type) -> Self {
+                    Self::new(value)
+                }
+            }
+
+            impl From<$name> for $type {
+                fn from(value: $name) -> Self {
+                    value.get()
+                }
+            }
+
+            impl Debug for $name {
+                fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                    Debug::fmt(&self.get(), f)
+                }
+            }
+        )*
+    };
+}
+
+little_endian!(Le16(u16, 2), Le32(u32, 4), Le64(u64, 8));
@@ -0,0 +1,636 @@
+//! The records the drivers hand to user mode.
+//!
+//! Every record starts with a [`RecordHeader`] carrying a magic value, the
+//! protocol version, the record kind and the length of the whole record.
+//! Records are plain bytes with a fixed layout: a producer sends
+//! [`Record::as_bytes`] as is and a consumer views the records in place with
+//! [`decode`] or [`records`]. Because the length is explicit, a reader skips
+//! kinds it does not know, and a newer version may append fields to a record
+//! without breaking older readers.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use core::fmt::{Debug, Display, Formatter};
+use core::{fmt, mem, slice};
+
+pub mod device;
+mod le;
+
+pub use le::{Le16, Le32, Le64};
+
+pub const RECORD_MAGIC: u32 = u32::from_le_bytes(*b"SPYR");
+///version 2 appended the creator, session, action and image path to [`ProcessRecord`]
+pub const PROTOCOL_VERSION: u16 = 2;
+///the longest name a record carries, in UTF-16 units (`MAX_PATH`)
+pub const MAX_NAME_UNITS: usize = 260;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum RecordKind {
+    ProcessCreate = 1,
+    ProcessExit = 2,
+    RegistryOperation = 3,
+}
+
+impl RecordKind {
+    pub const fn from_raw(kind: u16) -> Option<Self> {
+        match kind {
+            1 => Some(Self::ProcessCreate),
+            2 => Some(Self::ProcessExit),
+            3 => Some(Self::RegistryOperation),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum RegistryOperation {
+    SetValue = 1,
+    QueryValue = 2,
+}
+
+impl RegistryOperation {
+    pub const fn from_raw(operation: u32) -> Option<Self> {
+        match operation {
+            1 => Some(Self::SetValue),
+            2 => Some(Self::QueryValue),
+            _ => None,
+        }
+    }
+}
+
+///what the process driver did with a process one of its rules matched
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ProcessAction {
+    Signal = 1,
+    Record = 2,
+    Deny = 3,
+    Terminate = 4,
+    ///the process waits for user mode to approve it
+    Suspend = 5,
+}
+
+impl ProcessAction {
+    pub const fn from_raw(action: u16) -> Option<Self> {
+        match action {
+            1 => Some(Self::Signal),
+            2 => Some(Self::Record),
+            3 => Some(Self::Deny),
+            4 => Some(Self::Terminate),
+            5 => Some(Self::Suspend),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct RecordHeader {
+    pub magic: Le32,
+    pub version: Le16,
+    pub kind: Le16,
+    ///the size of the whole record, header included
+    pub length: Le32,
+    ///increases by one with every record, so a gap means records were dropped
+    pub sequence: Le32,
+    ///system time in 100ns intervals since 1601-01-01 UTC
+    pub timestamp: Le64,
+}
+
+impl RecordHeader {
+    pub const SIZE: usize = mem::size_of::<Self>();
+    const fn new(kind: RecordKind, length: usize) -> Self {
+        Self {
+            magic: Le32::new(RECORD_MAGIC),
+            version: Le16::new(PROTOCOL_VERSION),
+            kind: Le16::new(kind as u16),
+            length: Le32::new(length as u32),
+            sequence: Le32::new(0),
+            timestamp: Le64::new(0),
+        }
+    }
+    pub const fn kind(&self) -> Option<RecordKind> {
+        RecordKind::from_raw(self.kind.get())
+    }
+    pub const fn length(&self) -> usize {
+        self.length.get() as usize
+    }
+}
+
+///a UTF-16 name of at most [`MAX_NAME_UNITS`] units
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct NameField {
+    length: Le16,
+    units: [Le16; MAX_NAME_UNITS],
+}
+
+impl Default for NameField {
+    fn default() -> Self {
+        Self { length: Le16::new(0), units: [Le16::new(0); MAX_NAME_UNITS] }
+    }
+}
+
+impl NameField {
+    ///keeps the first [`MAX_NAME_UNITS`] units, never splitting a surrogate pair
+    pub fn new(name: &str) -> Self {
+        let mut field = Self::default();
+        let mut length = 0;
+        for character in name.chars() {
+            let mut buffer = [0u16; 2];
+            let units = character.encode_utf16(&mut buffer);
+            if length + units.len() > MAX_NAME_UNITS {
+                break;
+            }
+            for unit in units.iter() {
+                field.units[length] = Le16::new(*unit);
+                length += 1;
+            }
+        }
+        field.length = Le16::new(length as u16);
+        field
+    }
+    ///keeps the first [`MAX_NAME_UNITS`] units as they are
+    pub fn from_units(name: &[u16]) -> Self {
+        let mut field = Self::default();
+        let length = usize::min(name.len(), MAX_NAME_UNITS);
+        for (target, unit) in field.units.iter_mut().zip(&name[..length]) {
+            *target = Le16::new(*unit);
+        }
+        field.length = Le16::new(length as u16);
+        field
+    }
+    pub fn len(&self) -> usize {
+        usize::min(self.length.get() as usize, MAX_NAME_UNITS)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn units(&self) -> impl Iterator<Item = u16> + '_ {
+        self.units[..self.len()].iter().map(|unit| unit.get())
+    }
+    ///the name with unpaired surrogates replaced by `U+FFFD`
+    pub fn chars(&self) -> impl Iterator<Item = char> + '_ {
+        char::decode_utf16(self.units()).map(|unit| unit.unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+    pub fn eq_str(&self, name: &str) -> bool {
+        self.units().eq(name.encode_utf16())
+    }
+}
+
+impl Display for NameField {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.chars().try_for_each(|character| fmt::Write::write_char(f, character))
+    }
+}
+
+impl Debug for NameField {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+///a process was created or has exited
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct ProcessRecord {
+    pub header: RecordHeader,
+    pub pid: Le64,
+    ///zero when it is not known, as on exit
+    pub parent_pid: Le64,
+    ///the short name the kernel keeps for the process
+    pub image_name: NameField,
+    ///the process of the creating thread, which is not always the parent; zero on exit
+    pub creating_pid: Le64,
+    pub creating_tid: Le64,
+    pub session_id: Le32,
+    ///a [`ProcessAction`], zero for none
+    pub action: Le16,
+    pub flags: Le16,
+    ///the NT path of the image file, empty when it is not known
+    pub image_path: NameField,
+}
+
+impl ProcessRecord {
+    ///the action was only recorded, because the driver audits
+    pub const AUDITED: u16 = 0x1;
+
+    ///a record with the fields of version 1; the others are zero
+    pub fn new(is_created: bool, pid: u64, parent_pid: u64, image_name: &str) -> Self {
+        let kind = if is_created { RecordKind::ProcessCreate } else { RecordKind::ProcessExit };
+        Self {
+            header: RecordHeader::new(kind, mem::size_of::<Self>()),
+            pid: Le64::new(pid),
+            parent_pid: Le64::new(parent_pid),
+            image_name: NameField::new(image_name),
+            creating_pid: Le64::new(0),
+            creating_tid: Le64::new(0),
+            session_id: Le32::new(0),
+            action: Le16::new(0),
+            flags: Le16::new(0),
+            image_path: NameField::default(),
+        }
+    }
+    pub const fn is_created(&self) -> bool {
+        self.header.kind.get() == RecordKind::ProcessCreate as u16
+    }
+    pub const fn action(&self) -> Option<ProcessAction> {
+        ProcessAction::from_raw(self.action.get())
+    }
+    pub const fn is_audited(&self) -> bool {
+        self.flags.get() & Self::AUDITED != 0
+    }
+}
+
+///a registry value is about to be read or written
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct RegistryRecord {
+    pub header: RecordHeader,
+    pub pid: Le64,
+    pub operation: Le32,
+    pub value_name: NameField,
+}
+
+impl RegistryRecord {
+    pub fn new(operation: RegistryOperation, pid: u64, value_name: &str) -> Self {
+        Self {
+            header: RecordHeader::new(RecordKind::RegistryOperation, mem::size_of::<Self>()),
+            pid: Le64::new(pid),
+            operation: Le32::new(operation as u32),
+            value_name: NameField::new(value_name),
+        }
+    }
+    pub const fn operation(&self) -> Option<RegistryOperation> {
+        RegistryOperation::from_raw(self.operation.get())
+    }
+}
+
+/// A complete record that can be sent as its bytes.
+///
+/// # Safety
+/// The type must be `repr(C)`, start with a [`RecordHeader`] and consist
+/// only of the byte-array types of this crate, so it has an alignment of one,
+/// no padding, and any bytes of its size are a valid value.
+pub unsafe trait Record: Copy {
+    fn header(&self) -> &RecordHeader;
+    fn header_mut(&mut self) -> &mut RecordHeader;
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((self as *const Self).cast::<u8>(), mem::size_of::<Self>()) }
+    }
+    ///the name field of the record, checked when the record is decoded
+    fn name(&self) -> &NameField;
+    ///the second name field of a record that has one, checked like [`Self::name`]
+    fn path(&self) -> Option<&NameField> {
+        None
+    }
+}
+
+unsafe impl Record for ProcessRecord {
+    fn header(&self) -> &RecordHeader {
+        &self.header
+    }
+    fn header_mut(&mut self) -> &mut RecordHeader {
+        &mut self.header
+    }
+    fn name(&self) -> &NameField {
+        &self.image_name
+    }
+    fn path(&self) -> Option<&NameField> {
+        Some(&self.image_path)
+    }
+}
+
+unsafe impl Record for RegistryRecord {
+    fn header(&self) -> &RecordHeader {
+        &self.header
+    }
+    fn header_mut(&mut self) -> &mut RecordHeader {
+        &mut self.header
+    }
+    fn name(&self) -> &NameField {
+        &self.value_name
+    }
+}
+
+const _: () = {
+    assert!(RecordHeader::SIZE == 24);
+    assert!(mem::align_of::<ProcessRecord>() == 1);
+    assert!(mem::align_of::<RegistryRecord>() == 1);
+    assert!(mem::size_of::<ProcessRecord>() == RecordHeader::SIZE + 16 + 2 * (2 + 2 * MAX_NAME_UNITS) + 24);
+    assert!(mem::size_of::<RegistryRecord>() == RecordHeader::SIZE + 12 + 2 + 2 * MAX_NAME_UNITS);
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    ///the buffer ends before the record does
+    Truncated { needed: usize, available: usize },
+    BadMagic(u32),
+    UnsupportedVersion(u16),
+    ///the length is smaller than the record kind needs
+    BadLength(u32),
+    ///the name length is over [`MAX_NAME_UNITS`]
+    BadName(u16),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { needed, available } => {
+                write!(f, "record needs {needed} bytes, {available} available")
+            }
+            Self::BadMagic(magic) => write!(f, "bad record magic {magic:#010X}"),
+            Self::UnsupportedVersion(version) => write!(f, "unsupported protocol version {version}"),
+            Self::BadLength(length) => write!(f, "bad record length {length}"),
+            Self::BadName(length) => write!(f, "name of {length} units is too long"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+///a record viewed in place
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordRef<'a> {
+    Process(&'a ProcessRecord),
+    Registry(&'a RegistryRecord),
+    ///a kind this version of the protocol does not know
+    Unknown(&'a RecordHeader),
+}
+
+impl<'a> RecordRef<'a> {
+    pub const fn header(&self) -> &'a RecordHeader {
+        match self {
+            Self::Process(record) => &record.header,
+            Self::Registry(record) => &record.header,
+            Self::Unknown(header) => header,
+        }
+    }
+}
+
+//callers check that `bytes` holds a `T`; the alignment of every record is one
+unsafe fn view<T>(bytes: &[u8]) -> &T {
+    debug_assert!(bytes.len() >= mem::size_of::<T>() && mem::align_of::<T>() == 1);
+    &*bytes.as_ptr().cast::<T>()
+}
+
+fn view_record<R: Record>(bytes: &[u8], length: usize) -> Result<&R, DecodeError> {
+    if length < mem::size_of::<R>() {
+        return Err(DecodeError::BadLength(length as u32));
+    }
+    let record = unsafe { view::<R>(bytes) };
+    for name in core::iter::once(record.name()).chain(record.path()) {
+        let name_length = name.length.get();
+        if name_length as usize > MAX_NAME_UNITS {
+            return Err(DecodeError::BadName(name_length));
+        }
+    }
+    Ok(record)
+}
+
+/// Views the record at the start of `bytes` and returns it with its length.
+///
+/// Records of a newer version are accepted: the fields this version knows
+/// are read and the rest of the record is skipped.
+pub fn decode(bytes: &[u8]) -> Result<(RecordRef<'_>, usize), DecodeError> {
+    if bytes.len() < RecordHeader::SIZE {
+        return Err(DecodeError::Truncated { needed: RecordHeader::SIZE, available: bytes.len() });
+    }
+    let header = unsafe { view::<RecordHeader>(bytes) };
+    if header.magic.get() != RECORD_MAGIC {
+        return Err(DecodeError::BadMagic(header.magic.get()));
+    }
+    if header.version.get() == 0 {
+        return Err(DecodeError::UnsupportedVersion(0));
+    }
+    let length = header.length();
+    if length < RecordHeader::SIZE {
+        return Err(DecodeError::BadLength(header.length.get()));
+    }
+    if length > bytes.len() {
+        return Err(DecodeError::Truncated { needed: length, available: bytes.len() });
+    }
+    let record = match header.kind() {
+        Some(RecordKind::ProcessCreate | RecordKind::ProcessExit) => RecordRef::Process(view_record(bytes, length)?),
+        Some(RecordKind::RegistryOperation) => RecordRef::Registry(view_record(bytes, length)?),
+        None => RecordRef::Unknown(header),
+    };
+    Ok((record, length))
+}
+
+///the records packed one after another in `bytes`
+pub const fn records(bytes: &[u8]) -> Records<'_> {
+    Records { bytes }
+}
+
+///stops after the first record that fails to decode
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordRef<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        match decode(self.bytes) {
+            Ok((record, length)) => {
+                self.bytes = &self.bytes[length..];
+                Some(Ok(record))
+            }
+            Err(error) => {
+                self.bytes = &[];
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process_record() -> ProcessRecord {
+        let mut record = ProcessRecord::new(true, 8, 4, "firefox.exe");
+        record.header.sequence = Le32::new(7);
+        record.header.timestamp = Le64::new(1_000);
+        record.creating_pid = Le64::new(4);
+        record.creating_tid = Le64::new(12);
+        record.session_id = Le32::new(1);
+        record.action = Le16::new(ProcessAction::Suspend as u16);
+        record.flags = Le16::new(ProcessRecord::AUDITED);
+        record.image_path = NameField::new("\\Device\\HarddiskVolume2\\firefox.exe");
+        record
+    }
+
+    //a xorshift generator, so the fuzzing below is repeatable
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+    }
+
+    #[test]
+    fn records_round_trip_through_their_bytes() {
+        let process = process_record();
+        let registry = RegistryRecord::new(RegistryOperation::SetValue, 8, "LogLevel");
+        let mut bytes = process.as_bytes().to_vec();
+        bytes.extend_from_slice(registry.as_bytes());
+        let (record, length) = decode(&bytes).unwrap();
+        assert_eq!(length, mem::size_of::<ProcessRecord>());
+        let RecordRef::Process(decoded) = record else { panic!("not a process record") };
+        assert_eq!(*decoded, process);
+        assert!(decoded.is_created() && decoded.is_audited());
+        assert_eq!(decoded.action(), Some(ProcessAction::Suspend));
+        assert_eq!((decoded.header.sequence.get(), decoded.header.timestamp.get()), (7, 1_000));
+        assert_eq!(decoded.image_path.to_string(), "\\Device\\HarddiskVolume2\\firefox.exe");
+        let decoded: Vec<_> = records(&bytes).collect::<Result<_, _>>().unwrap();
+        assert_eq!(decoded, [RecordRef::Process(&process), RecordRef::Registry(&registry)]);
+        let RecordRef::Registry(decoded) = decoded[1] else { unreachable!() };
+        assert_eq!(decoded.operation(), Some(RegistryOperation::SetValue));
+        assert!(decoded.value_name.eq_str("LogLevel"));
+        assert_eq!(decoded.header().kind(), Some(RecordKind::RegistryOperation));
+    }
+
+    #[test]
+    fn exit_records_have_the_version_one_fields_only() {
+        let record = ProcessRecord::new(false, 8, 0, "a.exe");
+        assert!(!record.is_created());
+        assert_eq!(record.header.kind(), Some(RecordKind::ProcessExit));
+        assert_eq!(record.header.version.get(), PROTOCOL_VERSION);
+        assert_eq!(record.action(), None);
+        assert!(!record.is_audited());
+        assert!(record.image_path.is_empty());
+    }
+
+    #[test]
+    fn names_are_cut_to_the_field() {
+        let long = "a".repeat(MAX_NAME_UNITS + 10);
+        assert_eq!(NameField::new(&long).len(), MAX_NAME_UNITS);
+        //the pair that would cross the end is left out whole
+        let name = format!("{}\u{1F600}", "a".repeat(MAX_NAME_UNITS - 1));
+        let field = NameField::new(&name);
+        assert_eq!(field.len(), MAX_NAME_UNITS - 1);
+        assert!(field.chars().all(|character| character == 'a'));
+        let field = NameField::new("\u{1F600}é");
+        assert_eq!((field.len(), field.to_string()), (3, String::from("\u{1F600}é")));
+        assert_eq!(format!("{field:?}"), "\"\u{1F600}é\"");
+        //units are kept as they are, and an unpaired surrogate is shown as U+FFFD
+        let field = NameField::from_units(&[0x61, 0xD800, 0x62]);
+        assert_eq!(field.units().collect::<Vec<_>>(), [0x61, 0xD800, 0x62]);
+        assert_eq!(field.to_string(), "a\u{FFFD}b");
+        assert!(!field.eq_str("a\u{FFFD}b"));
+        assert_eq!(NameField::from_units(&[0x61; MAX_NAME_UNITS + 1]).len(), MAX_NAME_UNITS);
+        assert!(NameField::default().is_empty());
+    }
+
+    #[test]
+    fn newer_records_and_unknown_kinds_are_skipped_by_their_length() {
+        let process = process_record();
+        let mut bytes = process.as_bytes().to_vec();
+        //a newer version that appended eight bytes
+        bytes.extend_from_slice(&[0xEE; 8]);
+        let length = mem::size_of::<ProcessRecord>() + 8;
+        bytes[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+        bytes[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        //a kind this version does not know, with four bytes of its own
+        bytes.extend(RECORD_MAGIC.to_le_bytes());
+        bytes.extend(PROTOCOL_VERSION.to_le_bytes());
+        bytes.extend(99u16.to_le_bytes());
+        bytes.extend((RecordHeader::SIZE as u32 + 4).to_le_bytes());
+        bytes.extend_from_slice(&[0; RecordHeader::SIZE - 12 + 4]);
+        bytes.extend_from_slice(process.as_bytes());
+        let decoded: Vec<_> = records(&bytes).collect::<Result<_, _>>().unwrap();
+        assert_eq!(decoded.len(), 3);
+        let RecordRef::Process(newer) = decoded[0] else { panic!("not a process record") };
+        assert_eq!(newer.image_name, process.image_name);
+        let RecordRef::Unknown(header) = decoded[1] else { panic!("not an unknown record") };
+        assert_eq!((header.kind(), header.kind.get(), header.length()), (None, 99, RecordHeader::SIZE + 4));
+        assert_eq!(decoded[2], RecordRef::Process(&process));
+    }
+
+    #[test]
+    fn malformed_records_are_rejected() {
+        let process = process_record();
+        let size = mem::size_of::<ProcessRecord>();
+        let bytes = process.as_bytes();
+        assert_eq!(decode(&bytes[..10]), Err(DecodeError::Truncated { needed: RecordHeader::SIZE, available: 10 }));
+        assert_eq!(decode(&bytes[..size - 1]), Err(DecodeError::Truncated { needed: size, available: size - 1 }));
+        let with = |offset: usize, patch: &[u8]| {
+            let mut bytes = bytes.to_vec();
+            bytes[offset..offset + patch.len()].copy_from_slice(patch);
+            bytes
+        };
+        assert_eq!(decode(&with(0, b"XXXX")), Err(DecodeError::BadMagic(u32::from_le_bytes(*b"XXXX"))));
+        assert_eq!(decode(&with(4, &[0, 0])), Err(DecodeError::UnsupportedVersion(0)));
+        assert_eq!(decode(&with(8, &10u32.to_le_bytes())), Err(DecodeError::BadLength(10)));
+        //a header of a known kind with a length too short for the record
+        let short = (RecordHeader::SIZE as u32 + 8).to_le_bytes();
+        assert_eq!(decode(&with(8, &short)), Err(DecodeError::BadLength(RecordHeader::SIZE as u32 + 8)));
+        let name_offset = RecordHeader::SIZE + 16;
+        assert_eq!(decode(&with(name_offset, &261u16.to_le_bytes())), Err(DecodeError::BadName(261)));
+        let path_offset = size - 2 * MAX_NAME_UNITS - 2;
+        assert_eq!(decode(&with(path_offset, &u16::MAX.to_le_bytes())), Err(DecodeError::BadName(u16::MAX)));
+        //the iterator stops at the first error
+        let mut bytes = bytes.to_vec();
+        bytes.extend_from_slice(b"garbage");
+        let mut iterator = records(&bytes);
+        assert!(matches!(iterator.next(), Some(Ok(RecordRef::Process(_)))));
+        assert!(matches!(iterator.next(), Some(Err(DecodeError::Truncated { .. }))));
+        assert!(iterator.next().is_none());
+        assert_eq!(DecodeError::BadName(300).to_string(), "name of 300 units is too long");
+        assert_eq!(DecodeError::Truncated { needed: 24, available: 3 }.to_string(), "record needs 24 bytes, 3 available");
+    }
+
+    #[test]
+    fn decoding_arbitrary_bytes_never_panics() {
+        let mut random = Random(0x9E37_79B9_7F4A_7C15);
+        let mut valid = process_record().as_bytes().to_vec();
+        valid.extend_from_slice(RegistryRecord::new(RegistryOperation::QueryValue, 8, "a").as_bytes());
+        for round in 0..2_000 {
+            let bytes = if round % 2 == 0 {
+                //a valid stream with a few bytes changed and a random end
+                let mut bytes = valid.clone();
+                for _ in 0..=random.below(4) {
+                    let index = random.below(bytes.len());
+                    bytes[index] = random.next() as u8;
+                }
+                bytes.truncate(random.below(bytes.len() + 1));
+                bytes
+            } else {
+                let mut bytes: Vec<u8> = (0..random.below(64)).map(|_| random.next() as u8).collect();
+                //often with a good magic and a length that fits
+                if bytes.len() >= RecordHeader::SIZE && round % 3 != 0 {
+                    bytes[..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+                    let length = RecordHeader::SIZE + random.below(bytes.len() - RecordHeader::SIZE + 1);
+                    bytes[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+                }
+                bytes
+            };
+            let mut available = bytes.len();
+            for record in records(&bytes) {
+                let Ok(record) = record else { break };
+                let length = record.header().length();
+                assert!((RecordHeader::SIZE..=available).contains(&length));
+                available -= length;
+                if let RecordRef::Process(process) = record {
+                    assert!(process.image_name.len() <= MAX_NAME_UNITS && process.image_path.chars().count() <= MAX_NAME_UNITS);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Driver settings from the `Parameters` key of the service.
+//!
+//! Reading is split from parsing. [`read_parameters`] is the only part that
+//! touches the registry: it collects the raw values of the key into
+//! [`Parameters`]. The typed getters of [`Parameters`] and the
+//! [`FromParameters`] impls of the drivers turn them into settings, with
+//! defaults for missing values and a [`ConfigError`] for wrong ones, and run
+//! on a host as well.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use crate::status::{codes, NtError};
+use crate::unicode::decode_utf16;
+#[cfg(target_os = "windows")]
+use {
+    alloc::vec,
+    core::{mem, ptr, slice},
+    crate::irql::Passive,
+    crate::status::nt_result,
+    crate::sys::UNICODE_STRING,
+    crate::unicode::OwnedUnicodeString,
+    crate::WindowsUnicode,
+    wdk_sys::_KEY_VALUE_INFORMATION_CLASS::KeyValueFullInformation,
+    wdk_sys::ntddk::{ZwClose, ZwCreateKey, ZwEnumerateValueKey, ZwOpenKey, ZwSetValueKey},
+    wdk_sys::{
+        HANDLE, KEY_QUERY_VALUE, KEY_SET_VALUE, KEY_VALUE_FULL_INFORMATION, OBJECT_ATTRIBUTES, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+        REG_OPTION_NON_VOLATILE,
+    },
+};
+
+pub const PARAMETERS_KEY_NAME: &str = "Parameters";
+
+pub const REG_SZ: u32 = 1;
+pub const REG_EXPAND_SZ: u32 = 2;
+pub const REG_BINARY: u32 = 3;
+pub const REG_DWORD: u32 = 4;
+pub const REG_MULTI_SZ: u32 = 7;
+pub const REG_QWORD: u32 = 11;
+
+/// A registry value, decoded by its type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryValue {
+    ///`REG_SZ` or `REG_EXPAND_SZ`, which is not expanded
+    String(String),
+    MultiString(Vec<String>),
+    Dword(u32),
+    Qword(u64),
+    ///any other type, kept as it is
+    Other { kind: u32, data: Vec<u8> },
+}
+
+impl RegistryValue {
+    /// Decodes `data` stored as `kind`. Strings may lack the terminating nul.
+    ///
+    /// # Errors
+    /// [`ConfigError::Malformed`] when the data does not fit the type.
+    pub fn parse(name: &str, kind: u32, data: &[u8]) -> Result<Self, ConfigError> {
+        let malformed = || ConfigError::Malformed { name: name.to_string(), kind };
+        match kind {
+            REG_SZ | REG_EXPAND_SZ => {
+                let units = utf16_units(data).ok_or_else(malformed)?;
+                let end = units.iter().position(|unit| *unit == 0).unwrap_or(units.len());
+                decode_utf16(&units[..end]).map(Self::String).map_err(|_| malformed())
+            }
+            REG_MULTI_SZ => {
+                let units = utf16_units(data).ok_or_else(malformed)?;
+                //the list ends with an empty string
+                units.split(|unit| *unit == 0)
+                    .take_while(|string| !string.is_empty())
+                    .map(|string| decode_utf16(string).map_err(|_| malformed()))
+                    .collect::<Result<_, _>>()
+                    .map(Self::MultiString)
+            }
+            REG_DWORD => <[u8; 4]>::try_from(data).map(|data| Self::Dword(u32::from_le_bytes(data))).map_err(|_| malformed()),
+            REG_QWORD => <[u8; 8]>::try_from(data).map(|data| Self::Qword(u64::from_le_bytes(data))).map_err(|_| malformed()),
+            _ => Ok(Self::Other { kind, data: data.to_vec() }),
+        }
+    }
+    pub const fn kind(&self) -> u32 {
+        match self {
+            Self::String(_) => REG_SZ,
+            Self::MultiString(_) => REG_MULTI_SZ,
+            Self::Dword(_) => REG_DWORD,
+            Self::Qword(_) => REG_QWORD,
+            Self::Other { kind, .. } => *kind,
+        }
+    }
+    ///the data as the registry stores it, strings with their terminating nul
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let string_bytes = |bytes: &mut Vec<u8>, string: &str| {
+            bytes.extend(string.encode_utf16().chain([0]).flat_map(u16::to_le_bytes));
+        };
+        match self {
+            Self::String(string) => {
+                let mut bytes = Vec::new();
+                string_bytes(&mut bytes, string);
+                bytes
+            }
+            Self::MultiString(strings) => {
+                let mut bytes = Vec::new();
+                for string in strings {
+                    string_bytes(&mut bytes, string);
+                }
+                //the list ends with an empty string
+                string_bytes(&mut bytes, "");
+                bytes
+            }
+            Self::Dword(value) => value.to_le_bytes().to_vec(),
+            Self::Qword(value) => value.to_le_bytes().to_vec(),
+            Self::Other { data, .. } => data.clone(),
+        }
+    }
+}
+
+fn utf16_units(data: &[u8]) -> Option<Vec<u16>> {
+    let (units, rest) = data.as_chunks::<2>();
+    rest.is_empty().then(|| units.iter().map(|unit| u16::from_le_bytes(*unit)).collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    ///the data of the value does not fit its registry type
+    Malformed { name: String, kind: u32 },
+    ///the value is stored as `found` where `expected` is needed
+    WrongType { name: String, expected: u32, found: u32 },
+    ///the value has the right type but is out of range
+    Invalid { name: String, reason: &'static str },
+    ///the key could not be read
+    Registry(NtError),
+}
+
+impl ConfigError {
+    pub fn invalid(name: &str, reason: &'static str) -> Self {
+        Self::Invalid { name: name.to_string(), reason }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed { name, kind } => write!(f, "value {name} is not a valid value of type {kind}"),
+            Self::WrongType { name, expected, found } => write!(f, "value {name} has type {found} instead of {expected}"),
+            Self::Invalid { name, reason } => write!(f, "value {name} is invalid: {reason}"),
+            Self::Registry(error) => write!(f, "parameters cannot be read: {error}"),
+        }
+    }
+}
+
+impl From<ConfigError> for NtError {
+    fn from(error: ConfigError) -> Self {
+        match error {
+            ConfigError::WrongType { .. } => Self::new(codes::STATUS_OBJECT_TYPE_MISMATCH),
+            ConfigError::Malformed { .. } | ConfigError::Invalid { .. } => Self::INVALID_PARAMETER,
+            ConfigError::Registry(error) => error,
+        }
+    }
+}
+
+/// The values of a key. Names are compared ignoring ASCII case, as the
+/// registry does for the names it is usually given.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Parameters {
+    values: Vec<(String, RegistryValue)>,
+}
+
+impl Parameters {
+    pub const fn new() -> Self {
+        Self { values: Vec::new() }
+    }
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+    ///adds the value, replacing the one with the same name
+    pub fn insert(&mut self, name: &str, value: RegistryValue) {
+        match self.values.iter_mut().find(|(existing, _)| existing.eq_ignore_ascii_case(name)) {
+            Some((_, existing)) => *existing = value,
+            None => self.values.push((name.to_string(), value)),
+        }
+    }
+    /// Decodes and adds a value as the registry returns it.
+    ///
+    /// # Errors
+    /// See [`RegistryValue::parse`].
+    pub fn insert_raw(&mut self, name: &str, kind: u32, data: &[u8]) -> Result<(), ConfigError> {
+        let value = RegistryValue::parse(name, kind, data)?;
+        self.insert(name, value);
+        Ok(())
+    }
+    pub fn get(&self, name: &str) -> Option<&RegistryValue> {
+        self.values.iter()
+            .find(|(existing, _)| existing.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+    /// A `REG_SZ` or `REG_EXPAND_SZ` value, `None` if it is missing.
+    ///
+    /// # Errors
+    /// [`ConfigError::WrongType`] when it has another type.
+    pub fn string(&self, name: &str) -> Result<Option<&str>, ConfigError> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(RegistryValue::String(value)) => Ok(Some(value)),
+            Some(other) => Err(wrong_type(name, REG_SZ, other)),
+        }
+    }
+    /// A `REG_MULTI_SZ` value, `None` if it is missing.
+    ///
+    /// # Errors
+    /// [`ConfigError::WrongType`] when it has another type.
+    pub fn multi_string(&self, name: &str) -> Result<Option<&[String]>, ConfigError> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(RegistryValue::MultiString(value)) => Ok(Some(value)),
+            Some(other) => Err(wrong_type(name, REG_MULTI_SZ, other)),
+        }
+    }
+    /// A `REG_DWORD` value, `None` if it is missing.
+    ///
+    /// # Errors
+    /// [`ConfigError::WrongType`] when it has another type.
+    pub fn dword(&self, name: &str) -> Result<Option<u32>, ConfigError> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(RegistryValue::Dword(value)) => Ok(Some(*value)),
+            Some(other) => Err(wrong_type(name, REG_DWORD, other)),
+        }
+    }
+    /// A `REG_QWORD` value, `None` if it is missing. A `REG_DWORD` is
+    /// accepted too, since that is what most tools write for a number.
+    ///
+    /// # Errors
+    /// [`ConfigError::WrongType`] when it has another type.
+    pub fn qword(&self, name: &str) -> Result<Option<u64>, ConfigError> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(RegistryValue::Qword(value)) => Ok(Some(*value)),
+            Some(RegistryValue::Dword(value)) => Ok(Some(u64::from(*value))),
+            Some(other) => Err(wrong_type(name, REG_QWORD, other)),
+        }
+    }
+}
+
+fn wrong_type(name: &str, expected: u32, found: &RegistryValue) -> ConfigError {
+    ConfigError::WrongType { name: name.to_string(), expected, found: found.kind() }
+}
+
+/// A settings struct of a driver, with the defaults for what is missing.
+pub trait FromParameters: Sized {
+    /// # Errors
+    /// When a value has the wrong type or fails validation.
+    fn from_parameters(parameters: &Parameters) -> Result<Self, ConfigError>;
+}
+
+//closes the key when dropped
+#[cfg(target_os = "windows")]
+struct KeyHandle(HANDLE);
+
+#[cfg(target_os = "windows")]
+impl Drop for KeyHandle {
+    fn drop(&mut self) {
+        let _ = unsafe { ZwClose(self.0) };
+    }
+}
+
+///the path of the `Parameters` subkey of `registry_path`, the service key `DriverEntry` is given
+#[cfg(target_os = "windows")]
+pub fn parameters_key_path(registry_path: &UNICODE_STRING) -> String {
+    let mut key_path = String::from_unicode(registry_path);
+    key_path.push('\\');
+    key_path.push_str(PARAMETERS_KEY_NAME);
+    key_path
+}
+
+#[cfg(target_os = "windows")]
+fn key_attributes(key_path: &mut OwnedUnicodeString) -> OBJECT_ATTRIBUTES {
+    OBJECT_ATTRIBUTES {
+        Length: mem::size_of::<OBJECT_ATTRIBUTES>() as _,
+        RootDirectory: ptr::null_mut(),
+        ObjectName: key_path.as_mut_ptr(),
+        Attributes: OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+        SecurityDescriptor: ptr::null_mut(),
+        SecurityQualityOfService: ptr::null_mut(),
+    }
+}
+
+/// Reads every value of the `Parameters` subkey of `registry_path`, the
+/// service key `DriverEntry` is given. A missing subkey has no values.
+///
+/// # Errors
+/// [`ConfigError::Registry`] when the key cannot be read, or
+/// [`ConfigError::Malformed`] for a value that does not fit its type.
+#[cfg(target_os = "windows")]
+pub fn read_parameters(_irql: &Passive, registry_path: &UNICODE_STRING) -> Result<Parameters, ConfigError> {
+    let key_path = parameters_key_path(registry_path);
+    let mut key_path = OwnedUnicodeString::new(&key_path).map_err(|_| ConfigError::Registry(NtError::INVALID_PARAMETER))?;
+    let mut attributes = key_attributes(&mut key_path);
+    let mut key: HANDLE = ptr::null_mut();
+    match nt_result(unsafe { ZwOpenKey(&mut key, KEY_QUERY_VALUE, &mut attributes) }) {
+        Ok(_) => {}
+        Err(error) if error.code() == codes::STATUS_OBJECT_NAME_NOT_FOUND => return Ok(Parameters::new()),
+        Err(error) => return Err(ConfigError::Registry(error)),
+    }
+    let key = KeyHandle(key);
+    let mut parameters = Parameters::new();
+    //in words to keep the information header aligned
+    let mut buffer: Vec<u64> = vec![0; 64];
+    let mut index = 0;
+    loop {
+        let mut result_length = 0;
+        let status = unsafe {
+            ZwEnumerateValueKey(
+                key.0,
+                index,
+                KeyValueFullInformation,
+                buffer.as_mut_ptr().cast(),
+                (buffer.len() * mem::size_of::<u64>()) as _,
+                &mut result_length,
+            )
+        };
+        match status {
+            codes::STATUS_NO_MORE_ENTRIES => break,
+            codes::STATUS_BUFFER_OVERFLOW | codes::STATUS_BUFFER_TOO_SMALL => {
+                buffer.resize((result_length as usize).div_ceil(mem::size_of::<u64>()), 0);
+                continue;
+            }
+            status => nt_result(status).map_err(ConfigError::Registry)?,
+        };
+        let (name, kind, data) = unsafe {
+            let information = &*buffer.as_ptr().cast::<KEY_VALUE_FULL_INFORMATION>();
+            let name = slice::from_raw_parts(information.Name.as_ptr(), information.NameLength as usize / mem::size_of::<u16>());
+            let data = buffer.as_ptr().cast::<u8>().add(information.DataOffset as usize);
+            (name, information.Type, slice::from_raw_parts(data, information.DataLength as usize))
+        };
+        let name = decode_utf16(name).map_err(|_| ConfigError::Malformed { name: String::new(), kind })?;
+        parameters.insert_raw(&name, kind, data)?;
+        index += 1;
+    }
+    Ok(parameters)
+}
+
+/// Writes the value `name` of the key at `key_path`, usually the one
+/// [`parameters_key_path`] returns, creating the key when it is missing.
+///
+/// # Errors
+/// The status of the failed registry call.
+#[cfg(target_os = "windows")]
+pub fn write_parameter(_irql: &Passive, key_path: &str, name: &str, value: &RegistryValue) -> Result<(), NtError> {
+    let mut key_path = OwnedUnicodeString::new(key_path).map_err(|_| NtError::INVALID_PARAMETER)?;
+    let mut name = OwnedUnicodeString::new(name).map_err(|_| NtError::INVALID_PARAMETER)?;
+    let mut attributes = key_attributes(&mut key_path);
+    let mut key: HANDLE = ptr::null_mut();
+    let mut disposition = 0;
+    nt_result(unsafe {
+        ZwCreateKey(&mut key, KEY_SET_VALUE, &mut attributes, 0, ptr::null_mut(), REG_OPTION_NON_VOLATILE, &mut disposition)
+    })?;
+    let key = KeyHandle(key);
+    let mut data = value.to_bytes();
+    let data_size = u32::try_from(data.len()).map_err(|_| NtError::INVALID_PARAMETER)?;
+    nt_result(unsafe { ZwSetValueKey(key.0, name.as_mut_ptr(), 0, value.kind(), data.as_mut_ptr().cast(), data_size) })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::vec;
+
+    fn utf16(string: &str) -> Vec<u8> {
+        string.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn strings_are_read_with_or_without_their_nul() {
+        assert_eq!(RegistryValue::parse("a", REG_SZ, &utf16("é\u{1F600}\0")), Ok(RegistryValue::String("é\u{1F600}".into())));
+        assert_eq!(RegistryValue::parse("a", REG_SZ, &utf16("abc")), Ok(RegistryValue::String("abc".into())));
+        //anything after the nul is ignored
+        assert_eq!(RegistryValue::parse("a", REG_EXPAND_SZ, &utf16("%SystemRoot%\0junk")), Ok(RegistryValue::String("%SystemRoot%".into())));
+        assert_eq!(RegistryValue::parse("a", REG_SZ, &[]), Ok(RegistryValue::String(String::new())));
+        let malformed = Err(ConfigError::Malformed { name: "a".into(), kind: REG_SZ });
+        assert_eq!(RegistryValue::parse("a", REG_SZ, &[0x61, 0, 0x62]), malformed);
+        //an unpaired surrogate
+        assert_eq!(RegistryValue::parse("a", REG_SZ, &[0x00, 0xD8, 0x61, 0x00]), malformed);
+    }
+
+    #[test]
+    fn multi_strings_end_at_the_empty_string() {
+        let value = RegistryValue::parse("a", REG_MULTI_SZ, &utf16("one\0two\0\0three\0")).unwrap();
+        assert_eq!(value, RegistryValue::MultiString(vec!["one".into(), "two".into()]));
+        //without the final nuls too
+        assert_eq!(RegistryValue::parse("a", REG_MULTI_SZ, &utf16("one\0two")).unwrap(), value);
+        assert_eq!(RegistryValue::parse("a", REG_MULTI_SZ, &utf16("\0")), Ok(RegistryValue::MultiString(Vec::new())));
+        assert_eq!(RegistryValue::parse("a", REG_MULTI_SZ, &[0]), Err(ConfigError::Malformed { name: "a".into(), kind: REG_MULTI_SZ }));
+    }
+
+    #[test]
+    fn numbers_have_their_exact_size() {
+        assert_eq!(RegistryValue::parse("a", REG_DWORD, &7u32.to_le_bytes()), Ok(RegistryValue::Dword(7)));
+        assert_eq!(RegistryValue::parse("a", REG_QWORD, &(1u64 << 40).to_le_bytes()), Ok(RegistryValue::Qword(1 << 40)));
+        assert!(RegistryValue::parse("a", REG_DWORD, &[1, 0, 0]).is_err());
+        assert!(RegistryValue::parse("a", REG_DWORD, &7u64.to_le_bytes()).is_err());
+        assert!(RegistryValue::parse("a", REG_QWORD, &7u32.to_le_bytes()).is_err());
+        assert_eq!(RegistryValue::parse("a", REG_BINARY, &[1, 2]), Ok(RegistryValue::Other { kind: REG_BINARY, data: vec![1, 2] }));
+    }
+
+    #[test]
+    fn values_round_trip_through_their_bytes() {
+        let values = [
+            RegistryValue::String("C:\\log.dat".into()),
+            RegistryValue::String(String::new()),
+            RegistryValue::MultiString(vec!["a.exe".into(), "glob:*.com".into()]),
+            RegistryValue::MultiString(Vec::new()),
+            RegistryValue::Dword(u32::MAX),
+            RegistryValue::Qword(u64::MAX),
+            RegistryValue::Other { kind: REG_BINARY, data: vec![0, 1, 2] },
+        ];
+        for value in values {
+            assert_eq!(RegistryValue::parse("a", value.kind(), &value.to_bytes()).as_ref(), Ok(&value));
+        }
+        assert_eq!(RegistryValue::String("ab".into()).to_bytes(), [0x61, 0, 0x62, 0, 0, 0]);
+        assert_eq!(RegistryValue::MultiString(Vec::new()).to_bytes(), [0, 0]);
+    }
+
+    #[test]
+    fn names_ignore_case_and_are_replaced() {
+        let mut parameters = Parameters::new();
+        assert!(parameters.is_empty());
+        parameters.insert("LogLevel", RegistryValue::Dword(1));
+        parameters.insert("LOGLEVEL", RegistryValue::Dword(2));
+        parameters.insert_raw("Path", REG_SZ, &utf16("\\x\0")).unwrap();
+        assert!(parameters.insert_raw("Broken", REG_DWORD, &[1]).is_err());
+        assert_eq!(parameters.len(), 2);
+        assert_eq!(parameters.dword("loglevel"), Ok(Some(2)));
+        assert_eq!(parameters.string("path"), Ok(Some("\\x")));
+        assert_eq!(parameters.get("Broken"), None);
+    }
+
+    #[test]
+    fn getters_check_the_type() {
+        let mut parameters = Parameters::new();
+        parameters.insert("Dword", RegistryValue::Dword(5));
+        parameters.insert("Qword", RegistryValue::Qword(6));
+        parameters.insert("List", RegistryValue::MultiString(vec!["a".into()]));
+        assert_eq!(parameters.dword("Missing"), Ok(None));
+        assert_eq!(parameters.string("Missing"), Ok(None));
+        assert_eq!(parameters.multi_string("List"), Ok(Some(&["a".into()][..])));
+        //a number written as a DWORD is a fine QWORD, not the other way round
+        assert_eq!(parameters.qword("Dword"), Ok(Some(5)));
+        assert_eq!(parameters.qword("Qword"), Ok(Some(6)));
+        let error = parameters.dword("Qword").unwrap_err();
+        assert_eq!(error, ConfigError::WrongType { name: "Qword".into(), expected: REG_DWORD, found: REG_QWORD });
+        assert_eq!(format!("{error}"), "value Qword has type 11 instead of 4");
+        assert_eq!(NtError::from(error), NtError::new(codes::STATUS_OBJECT_TYPE_MISMATCH));
+        assert!(parameters.string("List").is_err());
+        assert!(parameters.multi_string("Dword").is_err());
+        assert_eq!(NtError::from(ConfigError::invalid("a", "why")), NtError::INVALID_PARAMETER);
+        assert_eq!(format!("{}", ConfigError::invalid("a", "why")), "value a is invalid: why");
+    }
+}
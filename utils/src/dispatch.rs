@@ -0,0 +1,333 @@
+//! IRP dispatch shared by the drivers.
+//!
+//! A driver implements [`DeviceHandler`] and calls [`install`]; every major
+//! function then goes through one trampoline that decodes the IRP into a
+//! [`Request`], calls the matching handler method and completes the IRP with
+//! the returned [`Completion`].
+
+use crate::status::{NtError, Status};
+#[cfg(target_os = "windows")]
+use {
+    alloc::vec,
+    core::{mem, ptr, slice},
+    crate::get_current_io_stack_location,
+    crate::ioctl::{ControlCode, TransferMethod},
+    crate::irql::{Dispatch, Passive},
+    crate::sync::SpinLock,
+    wdk_sys::_MEMORY_CACHING_TYPE::MmCached,
+    wdk_sys::_MM_PAGE_PRIORITY::NormalPagePriority,
+    wdk_sys::_MODE::KernelMode,
+    wdk_sys::ntddk::{IofCompleteRequest, MmMapLockedPagesSpecifyCache},
+    wdk_sys::{DEVICE_OBJECT, DO_BUFFERED_IO, DO_DIRECT_IO, DRIVER_OBJECT, FALSE, IO_NO_INCREMENT, IRP, IRP_MJ_CLEANUP, IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL, IRP_MJ_READ, IRP_MJ_WRITE, MDL_MAPPED_TO_SYSTEM_VA, MDL_SOURCE_IS_NONPAGED_POOL, MdlMappingNoExecute, NTSTATUS, PMDL, PVOID},
+};
+
+///the number of bytes transferred, reported in `IoStatus.Information`
+pub type IoResult = Result<usize, NtError>;
+
+/// A decoded IRP. The buffers are already mapped into system space and
+/// their lengths are the ones the caller passed.
+#[derive(Debug)]
+pub enum Request<'a> {
+    Create,
+    Close,
+    Cleanup,
+    Read { buffer: &'a mut [u8], offset: u64 },
+    Write { data: &'a [u8], offset: u64 },
+    DeviceControl { code: u32, input: &'a [u8], output: &'a mut [u8] },
+    ///a major function without a handler method
+    Other(u8),
+}
+
+///what the IRP is completed with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub status: Status,
+    pub information: usize,
+}
+
+impl Completion {
+    pub const fn success(information: usize) -> Self {
+        Self { status: Status::SUCCESS, information }
+    }
+    pub const fn failure(error: NtError) -> Self {
+        Self { status: error.status(), information: 0 }
+    }
+}
+
+impl From<IoResult> for Completion {
+    fn from(result: IoResult) -> Self {
+        match result {
+            Ok(information) => Self::success(information),
+            Err(error) => Self::failure(error),
+        }
+    }
+}
+
+/// The requests a device serves. Every method fails with
+/// `STATUS_NOT_SUPPORTED` unless the device overrides it.
+pub trait DeviceHandler: Send + Sync {
+    fn create(&self) -> IoResult {
+        Err(NtError::NOT_SUPPORTED)
+    }
+    fn close(&self) -> IoResult {
+        Err(NtError::NOT_SUPPORTED)
+    }
+    ///the last handle to a file object was closed; requests may still be pending
+    fn cleanup(&self) -> IoResult {
+        Err(NtError::NOT_SUPPORTED)
+    }
+    ///returns how many bytes of `buffer` were filled
+    fn read(&self, _buffer: &mut [u8], _offset: u64) -> IoResult {
+        Err(NtError::NOT_SUPPORTED)
+    }
+    ///returns how many bytes of `data` were consumed
+    fn write(&self, _data: &[u8], _offset: u64) -> IoResult {
+        Err(NtError::NOT_SUPPORTED)
+    }
+    ///returns how many bytes of `output` were filled
+    fn device_control(&self, _code: u32, _input: &[u8], _output: &mut [u8]) -> IoResult {
+        Err(NtError::NOT_SUPPORTED)
+    }
+}
+
+///calls the handler method for the request; a reported size over the caller's buffer is cut to the buffer and logged
+pub fn dispatch_request<H: DeviceHandler + ?Sized>(handler: &H, request: Request<'_>) -> Completion {
+    let (result, capacity) = match request {
+        Request::Create => (handler.create(), 0),
+        Request::Close => (handler.close(), 0),
+        Request::Cleanup => (handler.cleanup(), 0),
+        Request::Read { buffer, offset } => {
+            let capacity = buffer.len();
+            (handler.read(buffer, offset), capacity)
+        }
+        Request::Write { data, offset } => (handler.write(data, offset), data.len()),
+        Request::DeviceControl { code, input, output } => {
+            let capacity = output.len();
+            (handler.device_control(code, input, output), capacity)
+        }
+        Request::Other(_) => (Err(NtError::NOT_SUPPORTED), 0),
+    };
+    match result {
+        //the I/O manager would copy that many bytes back to the caller
+        Ok(information) if information > capacity => {
+            crate::error!("The handler reported {information} bytes for a {capacity} byte buffer");
+            Completion::success(capacity)
+        }
+        result => Completion::from(result),
+    }
+}
+
+//requests may arrive at DISPATCH_LEVEL, e.g. reads sent by another driver
+#[cfg(target_os = "windows")]
+static HANDLER: SpinLock<Option<&'static dyn DeviceHandler>> = SpinLock::new(None);
+
+///routes every major function of the driver to `handler`
+#[cfg(target_os = "windows")]
+pub fn install(driver: &mut DRIVER_OBJECT, handler: &'static dyn DeviceHandler) {
+    //`DriverEntry` runs at PASSIVE_LEVEL
+    let mut irql = unsafe { Passive::new_unchecked() };
+    *HANDLER.lock(&mut irql) = Some(handler);
+    for function in &mut driver.MajorFunction {
+        *function = Some(dispatch_irp);
+    }
+}
+
+///forgets the handler; requests that arrive afterwards fail with `STATUS_NOT_SUPPORTED`
+#[cfg(target_os = "windows")]
+pub fn uninstall() {
+    //as does the unload routine
+    let mut irql = unsafe { Passive::new_unchecked() };
+    HANDLER.lock(&mut irql).take();
+}
+
+///sets `IoStatus` and completes the IRP, returning the status the dispatch routine must return
+#[cfg(target_os = "windows")]
+pub unsafe fn complete_request(irp: *mut IRP, completion: Completion) -> NTSTATUS {
+    let status_block = &mut (*irp).IoStatus;
+    status_block.__bindgen_anon_1.Status = completion.status.code();
+    status_block.Information = completion.information as _;
+    IofCompleteRequest(irp, IO_NO_INCREMENT as _);
+    completion.status.code()
+}
+
+#[cfg(target_os = "windows")]
+unsafe extern "C" fn dispatch_irp(device: *mut DEVICE_OBJECT, irp: *mut IRP) -> NTSTATUS {
+    //dispatch routines are called at or below DISPATCH_LEVEL;
+    //the lock is not held while the handler runs, a read may take a while
+    let mut irql = Dispatch::new_unchecked();
+    let handler = *HANDLER.lock(&mut irql);
+    let completion = match handler {
+        Some(handler) => decode_and_dispatch(handler, device, &mut *irp),
+        None => Completion::failure(NtError::NOT_SUPPORTED),
+    };
+    complete_request(irp, completion)
+}
+
+#[cfg(target_os = "windows")]
+unsafe fn decode_and_dispatch(handler: &dyn DeviceHandler, device: *mut DEVICE_OBJECT, irp: &mut IRP) -> Completion {
+    let stack = &*get_current_io_stack_location(irp);
+    let request = match u32::from(stack.MajorFunction) {
+        IRP_MJ_CREATE => Request::Create,
+        IRP_MJ_CLOSE => Request::Close,
+        IRP_MJ_CLEANUP => Request::Cleanup,
+        IRP_MJ_READ => {
+            let parameters = stack.Parameters.Read;
+            match transfer_buffer(device, irp, parameters.Length as usize) {
+                Ok(buffer) => Request::Read { buffer, offset: parameters.ByteOffset.QuadPart as u64 },
+                Err(error) => return Completion::failure(error),
+            }
+        }
+        IRP_MJ_WRITE => {
+            let parameters = stack.Parameters.Write;
+            match transfer_buffer(device, irp, parameters.Length as usize) {
+                Ok(data) => Request::Write { data, offset: parameters.ByteOffset.QuadPart as u64 },
+                Err(error) => return Completion::failure(error),
+            }
+        }
+        IRP_MJ_DEVICE_CONTROL => {
+            let parameters = stack.Parameters.DeviceIoControl;
+            let code = parameters.IoControlCode;
+            let input_length = parameters.InputBufferLength as usize;
+            let output_length = parameters.OutputBufferLength as usize;
+            let system_buffer = irp.AssociatedIrp.SystemBuffer;
+            match ControlCode::from_raw(code).method() {
+                TransferMethod::Buffered => {
+                    //input and output share the system buffer, so the input is copied out first,
+                    //into words to keep the alignment the system buffer had
+                    let input_source = match byte_slice(system_buffer, input_length) {
+                        Ok(input) => input,
+                        Err(error) => return Completion::failure(error),
+                    };
+                    let mut input_copy = vec![0u64; input_length.div_ceil(mem::size_of::<u64>())];
+                    let input = slice::from_raw_parts_mut(input_copy.as_mut_ptr().cast::<u8>(), input_length);
+                    input.copy_from_slice(input_source);
+                    return match byte_slice(system_buffer, output_length) {
+                        Ok(output) => dispatch_request(handler, Request::DeviceControl { code, input, output }),
+                        Err(error) => Completion::failure(error),
+                    };
+                }
+                TransferMethod::InDirect | TransferMethod::OutDirect => {
+                    let input = byte_slice(system_buffer, input_length);
+                    let output = byte_slice(mdl_address(irp.MdlAddress), output_length);
+                    match (input, output) {
+                        (Ok(input), Ok(output)) => Request::DeviceControl { code, input, output },
+                        (Err(error), _) | (_, Err(error)) => return Completion::failure(error),
+                    }
+                }
+                //METHOD_NEITHER passes raw user addresses, which no handler is prepared for
+                TransferMethod::Neither => return Completion::failure(NtError::NOT_SUPPORTED),
+            }
+        }
+        other => Request::Other(other as u8),
+    };
+    dispatch_request(handler, request)
+}
+
+///the read or write buffer, according to the I/O method of the device
+#[cfg(target_os = "windows")]
+unsafe fn transfer_buffer<'a>(device: *mut DEVICE_OBJECT, irp: &IRP, length: usize) -> Result<&'a mut [u8], NtError> {
+    let flags = (*device).Flags;
+    if flags & DO_BUFFERED_IO != 0 {
+        byte_slice(irp.AssociatedIrp.SystemBuffer, length)
+    } else if flags & DO_DIRECT_IO != 0 {
+        byte_slice(mdl_address(irp.MdlAddress), length)
+    } else {
+        Err(NtError::NOT_SUPPORTED)
+    }
+}
+
+#[cfg(target_os = "windows")]
+unsafe fn byte_slice<'a>(buffer: PVOID, length: usize) -> Result<&'a mut [u8], NtError> {
+    if length == 0 {
+        Ok(&mut [])
+    } else if buffer.is_null() {
+        Err(NtError::INSUFFICIENT_RESOURCES)
+    } else {
+        Ok(slice::from_raw_parts_mut(buffer.cast(), length))
+    }
+}
+
+///`MmGetSystemAddressForMdlSafe`, which is a macro and missing from the bindings
+#[cfg(target_os = "windows")]
+unsafe fn mdl_address(mdl: PMDL) -> PVOID {
+    if mdl.is_null() {
+        return ptr::null_mut();
+    }
+    let flags = (*mdl).MdlFlags as u32;
+    if flags & (MDL_MAPPED_TO_SYSTEM_VA | MDL_SOURCE_IS_NONPAGED_POOL) != 0 {
+        (*mdl).MappedSystemVa
+    } else {
+        MmMapLockedPagesSpecifyCache(
+            mdl,
+            KernelMode as _,
+            MmCached,
+            ptr::null_mut(),
+            FALSE as _,
+            NormalPagePriority as u32 | MdlMappingNoExecute,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::codes;
+
+    struct Echo {
+        //reported on top of what was actually transferred
+        overreport: usize,
+    }
+
+    impl DeviceHandler for Echo {
+        fn create(&self) -> IoResult {
+            Ok(0)
+        }
+        fn read(&self, buffer: &mut [u8], offset: u64) -> IoResult {
+            buffer.fill(offset as u8);
+            Ok(buffer.len() + self.overreport)
+        }
+        fn device_control(&self, code: u32, input: &[u8], output: &mut [u8]) -> IoResult {
+            if code != 7 {
+                return Err(NtError::new(codes::STATUS_INVALID_DEVICE_REQUEST));
+            }
+            let length = usize::min(input.len(), output.len());
+            output[..length].copy_from_slice(&input[..length]);
+            Ok(length + self.overreport)
+        }
+    }
+
+    #[test]
+    fn requests_reach_their_handler_method() {
+        let handler = Echo { overreport: 0 };
+        assert_eq!(dispatch_request(&handler, Request::Create), Completion::success(0));
+        let mut buffer = [0u8; 4];
+        assert_eq!(dispatch_request(&handler, Request::Read { buffer: &mut buffer, offset: 9 }), Completion::success(4));
+        assert_eq!(buffer, [9; 4]);
+        let mut output = [0u8; 8];
+        let request = Request::DeviceControl { code: 7, input: b"ping", output: &mut output };
+        assert_eq!(dispatch_request(&handler, request), Completion::success(4));
+        assert_eq!(&output[..4], b"ping");
+        let request = Request::DeviceControl { code: 8, input: &[], output: &mut [] };
+        assert_eq!(dispatch_request(&handler, request), Completion::failure(NtError::new(codes::STATUS_INVALID_DEVICE_REQUEST)));
+    }
+
+    #[test]
+    fn methods_without_an_override_are_not_supported() {
+        let handler = Echo { overreport: 0 };
+        let not_supported = Completion::failure(NtError::NOT_SUPPORTED);
+        assert_eq!(dispatch_request(&handler, Request::Close), not_supported);
+        assert_eq!(dispatch_request(&handler, Request::Cleanup), not_supported);
+        assert_eq!(dispatch_request(&handler, Request::Write { data: b"x", offset: 0 }), not_supported);
+        assert_eq!(dispatch_request(&handler, Request::Other(0x16)), not_supported);
+    }
+
+    #[test]
+    fn an_overreported_size_is_cut_to_the_buffer() {
+        let handler = Echo { overreport: 3 };
+        let mut buffer = [0u8; 4];
+        assert_eq!(dispatch_request(&handler, Request::Read { buffer: &mut buffer, offset: 0 }), Completion::success(4));
+        let mut output = [0u8; 2];
+        let request = Request::DeviceControl { code: 7, input: b"ping", output: &mut output };
+        assert_eq!(dispatch_request(&handler, request), Completion::success(2));
+    }
+}
@@ -0,0 +1,374 @@
+//! Device I/O control codes and typed IOCTL handlers.
+//!
+//! [`ControlCode`] is the `CTL_CODE` macro and its inverse. An [`Ioctl`]
+//! ties a code to plain-data input and output types; an [`IoctlTable`] maps
+//! codes to handlers and checks the caller's buffers before a handler sees
+//! them. IOCTLs without a fixed layout, such as a list of records, are added
+//! to the table with [`IoctlTable::with_raw`] and check their buffers
+//! themselves.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt::{Debug, Display, Formatter};
+use core::{fmt, mem, ptr};
+use crate::dispatch::IoResult;
+use crate::status::{codes, NtError};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TransferMethod {
+    ///`METHOD_BUFFERED`: both buffers are copied through one system buffer
+    Buffered = 0,
+    ///`METHOD_IN_DIRECT`: the output buffer is locked and read by the driver
+    InDirect = 1,
+    ///`METHOD_OUT_DIRECT`: the output buffer is locked and written by the driver
+    OutDirect = 2,
+    ///`METHOD_NEITHER`: raw user addresses
+    Neither = 3,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RequiredAccess {
+    ///`FILE_ANY_ACCESS`
+    Any = 0,
+    ///`FILE_READ_DATA`
+    Read = 1,
+    ///`FILE_WRITE_DATA`
+    Write = 2,
+    ///`FILE_READ_DATA | FILE_WRITE_DATA`
+    ReadWrite = 3,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct ControlCode(u32);
+
+impl ControlCode {
+    ///`FILE_DEVICE_UNKNOWN`, the device type of the drivers' devices
+    pub const DEVICE_UNKNOWN: u16 = 0x22;
+    ///function numbers below this one are reserved for Microsoft
+    pub const FIRST_CUSTOM_FUNCTION: u16 = 0x800;
+    const MAX_FUNCTION: u16 = 0xFFF;
+
+    /// `CTL_CODE(device_type, function, method, access)`.
+    ///
+    /// # Panics
+    /// If `function` does not fit the 12 bits of the code.
+    pub const fn new(device_type: u16, function: u16, method: TransferMethod, access: RequiredAccess) -> Self {
+        assert!(function <= Self::MAX_FUNCTION, "the IOCTL function number has 12 bits");
+        Self((device_type as u32) << 16 | (access as u32) << 14 | (function as u32) << 2 | method as u32)
+    }
+    pub const fn from_raw(code: u32) -> Self {
+        Self(code)
+    }
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+    pub const fn device_type(self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+    pub const fn function(self) -> u16 {
+        ((self.0 >> 2) & Self::MAX_FUNCTION as u32) as u16
+    }
+    pub const fn method(self) -> TransferMethod {
+        match self.0 & 3 {
+            0 => TransferMethod::Buffered,
+            1 => TransferMethod::InDirect,
+            2 => TransferMethod::OutDirect,
+            _ => TransferMethod::Neither,
+        }
+    }
+    pub const fn access(self) -> RequiredAccess {
+        match (self.0 >> 14) & 3 {
+            0 => RequiredAccess::Any,
+            1 => RequiredAccess::Read,
+            2 => RequiredAccess::Write,
+            _ => RequiredAccess::ReadWrite,
+        }
+    }
+    ///whether the function number is outside the range reserved for Microsoft
+    pub const fn is_custom(self) -> bool {
+        self.function() >= Self::FIRST_CUSTOM_FUNCTION
+    }
+}
+
+impl From<u32> for ControlCode {
+    fn from(code: u32) -> Self {
+        Self(code)
+    }
+}
+
+impl From<ControlCode> for u32 {
+    fn from(code: ControlCode) -> Self {
+        code.0
+    }
+}
+
+impl Display for ControlCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010X}", self.0)
+    }
+}
+
+impl Debug for ControlCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CTL_CODE({:#06X}, {:#05X}, {:?}, {:?})",
+            self.device_type(),
+            self.function(),
+            self.method(),
+            self.access()
+        )
+    }
+}
+
+/// Plain data that may be copied to and from an IOCTL buffer.
+///
+/// # Safety
+/// Every bit pattern of the size of the type must be a valid value, and the
+/// type must not contain padding, pointers or references.
+pub unsafe trait Pod: Copy + Send + Sync + 'static {}
+
+macro_rules! pod {
+    ($($type: ty),*) => {
+        $(unsafe impl Pod for $type {})*
+    };
+}
+
+pod!((), u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// A device control request with a fixed code and layout.
+pub trait Ioctl {
+    const CODE: ControlCode;
+    type Input: Pod;
+    type Output: Pod;
+}
+
+///checks the size and alignment of an IOCTL buffer for `T`
+pub fn check_buffer<T: Pod>(buffer: &[u8], too_small: NtError) -> Result<(), NtError> {
+    if buffer.len() < mem::size_of::<T>() {
+        return Err(too_small);
+    }
+    if buffer.as_ptr().align_offset(mem::align_of::<T>()) != 0 {
+        return Err(NtError::new(codes::STATUS_DATATYPE_MISALIGNMENT));
+    }
+    Ok(())
+}
+
+type ErasedHandler<C> = Box<dyn Fn(&C, &[u8], &mut [u8]) -> IoResult + Send + Sync>;
+
+struct IoctlEntry<C: ?Sized> {
+    code: ControlCode,
+    handler: ErasedHandler<C>,
+}
+
+/// The IOCTLs a device serves, each with a typed handler that receives a
+/// `context` (usually the device itself).
+///
+/// Before a handler runs the table checks that the input buffer holds an
+/// aligned `Input` (`STATUS_INVALID_PARAMETER` otherwise) and that the
+/// output buffer can hold an aligned `Output` (`STATUS_BUFFER_TOO_SMALL`);
+/// misaligned buffers fail with `STATUS_DATATYPE_MISALIGNMENT`.
+pub struct IoctlTable<C: ?Sized> {
+    entries: Vec<IoctlEntry<C>>,
+}
+
+impl<C: ?Sized> Default for IoctlTable<C> {
+    fn default() -> Self {
+        Self { entries: Vec::new() }
+    }
+}
+
+impl<C: ?Sized> IoctlTable<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds the handler of `I`.
+    ///
+    /// # Panics
+    /// If the code is already registered or uses `METHOD_NEITHER`, which the
+    /// dispatch module refuses.
+    #[must_use]
+    pub fn with<I, F>(mut self, handler: F) -> Self
+    where
+        I: Ioctl,
+        F: Fn(&C, &I::Input) -> Result<I::Output, NtError> + Send + Sync + 'static,
+    {
+        assert!(I::CODE.method() != TransferMethod::Neither, "METHOD_NEITHER IOCTLs are not supported");
+        assert!(self.find(I::CODE).is_none(), "IOCTL {:?} is registered twice", I::CODE);
+        let handler = move |context: &C, input: &[u8], output: &mut [u8]| {
+            check_buffer::<I::Input>(input, NtError::INVALID_PARAMETER)?;
+            check_buffer::<I::Output>(output, NtError::BUFFER_TOO_SMALL)?;
+            let input = unsafe { ptr::read(input.as_ptr().cast::<I::Input>()) };
+            let response = handler(context, &input)?;
+            unsafe { ptr::write(output.as_mut_ptr().cast::<I::Output>(), response) };
+            Ok(mem::size_of::<I::Output>())
+        };
+        self.entries.push(IoctlEntry { code: I::CODE, handler: Box::new(handler) });
+        self
+    }
+    /// Adds a handler that gets the buffers as they are, for an IOCTL
+    /// whose input or output has no fixed size.
+    ///
+    /// # Panics
+    /// As for [`Self::with`].
+    #[must_use]
+    pub fn with_raw<F>(mut self, code: ControlCode, handler: F) -> Self
+    where
+        F: Fn(&C, &[u8], &mut [u8]) -> IoResult + Send + Sync + 'static,
+    {
+        assert!(code.method() != TransferMethod::Neither, "METHOD_NEITHER IOCTLs are not supported");
+        assert!(self.find(code).is_none(), "IOCTL {code:?} is registered twice");
+        self.entries.push(IoctlEntry { code, handler: Box::new(handler) });
+        self
+    }
+    pub fn contains(&self, code: ControlCode) -> bool {
+        self.find(code).is_some()
+    }
+    pub fn codes(&self) -> impl Iterator<Item = ControlCode> + '_ {
+        self.entries.iter().map(|entry| entry.code)
+    }
+    ///runs the handler of `code`, failing with `STATUS_INVALID_DEVICE_REQUEST` for unknown codes
+    pub fn dispatch(&self, context: &C, code: u32, input: &[u8], output: &mut [u8]) -> IoResult {
+        let entry = self.find(ControlCode::from_raw(code))
+            .ok_or(NtError::new(codes::STATUS_INVALID_DEVICE_REQUEST))?;
+        (entry.handler)(context, input, output)
+    }
+    fn find(&self, code: ControlCode) -> Option<&IoctlEntry<C>> {
+        self.entries.iter().find(|entry| entry.code == code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use core::cell::Cell;
+
+    const DOUBLE: ControlCode = ControlCode::new(
+        ControlCode::DEVICE_UNKNOWN,
+        ControlCode::FIRST_CUSTOM_FUNCTION + 1,
+        TransferMethod::Buffered,
+        RequiredAccess::ReadWrite,
+    );
+
+    struct Double;
+
+    impl Ioctl for Double {
+        const CODE: ControlCode = DOUBLE;
+        type Input = u32;
+        type Output = u64;
+    }
+
+    //the context counts the calls
+    fn table() -> IoctlTable<Cell<u32>> {
+        IoctlTable::new()
+            .with::<Double, _>(|calls: &Cell<u32>, &input| {
+                calls.set(calls.get() + 1);
+                if input == u32::MAX {
+                    return Err(NtError::INVALID_PARAMETER);
+                }
+                Ok(u64::from(input) * 2)
+            })
+            .with_raw(ControlCode::from_raw(0x0022_2008), |_, input, output| {
+                output[..input.len()].copy_from_slice(input);
+                Ok(input.len())
+            })
+    }
+
+    //aligned for a `u64`, so that a slice of it may be misaligned on purpose
+    fn aligned() -> [u64; 2] {
+        [0; 2]
+    }
+
+    fn bytes(words: &mut [u64]) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr().cast(), mem::size_of_val(words)) }
+    }
+
+    #[test]
+    fn codes_are_encoded_as_ctl_code_does() {
+        //IOCTL_DISK_GET_DRIVE_GEOMETRY
+        let code = ControlCode::new(7, 0, TransferMethod::Buffered, RequiredAccess::Any);
+        assert_eq!(code.raw(), 0x0007_0000);
+        //FSCTL_GET_REPARSE_POINT
+        assert_eq!(ControlCode::new(9, 42, TransferMethod::Buffered, RequiredAccess::Any).raw(), 0x0009_00A8);
+        assert_eq!(DOUBLE.raw(), 0x0022_E004);
+        assert_eq!(
+            (DOUBLE.device_type(), DOUBLE.function(), DOUBLE.method(), DOUBLE.access()),
+            (0x22, 0x801, TransferMethod::Buffered, RequiredAccess::ReadWrite)
+        );
+        assert!(DOUBLE.is_custom());
+        assert!(!code.is_custom());
+        for method in [TransferMethod::Buffered, TransferMethod::InDirect, TransferMethod::OutDirect, TransferMethod::Neither] {
+            for access in [RequiredAccess::Any, RequiredAccess::Read, RequiredAccess::Write, RequiredAccess::ReadWrite] {
+                let code = ControlCode::new(0xFFFF, 0xFFF, method, access);
+                assert_eq!((code.device_type(), code.function(), code.method(), code.access()), (0xFFFF, 0xFFF, method, access));
+                assert_eq!(ControlCode::from(u32::from(code)), code);
+            }
+        }
+        assert_eq!(format!("{DOUBLE}"), "0x0022E004");
+        assert_eq!(format!("{DOUBLE:?}"), "CTL_CODE(0x0022, 0x801, Buffered, ReadWrite)");
+    }
+
+    #[test]
+    #[should_panic(expected = "12 bits")]
+    fn function_numbers_have_twelve_bits() {
+        let _ = ControlCode::new(0x22, 0x1000, TransferMethod::Buffered, RequiredAccess::Any);
+    }
+
+    #[test]
+    fn typed_handlers_get_aligned_buffers_of_their_size() {
+        let table = table();
+        let calls = Cell::new(0);
+        let (mut input, mut output) = (aligned(), aligned());
+        bytes(&mut input)[..4].copy_from_slice(&21u32.to_ne_bytes());
+        assert_eq!(table.dispatch(&calls, DOUBLE.raw(), &bytes(&mut input)[..4], bytes(&mut output)), Ok(8));
+        assert_eq!(output[0], 42);
+        //a longer input is fine, a shorter one is not
+        assert_eq!(table.dispatch(&calls, DOUBLE.raw(), bytes(&mut input), bytes(&mut output)), Ok(8));
+        assert_eq!(table.dispatch(&calls, DOUBLE.raw(), &bytes(&mut input)[..3], bytes(&mut output)), Err(NtError::INVALID_PARAMETER));
+        assert_eq!(table.dispatch(&calls, DOUBLE.raw(), &bytes(&mut input)[..4], &mut bytes(&mut output)[..7]), Err(NtError::BUFFER_TOO_SMALL));
+        let misaligned = NtError::new(codes::STATUS_DATATYPE_MISALIGNMENT);
+        assert_eq!(table.dispatch(&calls, DOUBLE.raw(), &bytes(&mut input)[1..5], bytes(&mut output)), Err(misaligned));
+        assert_eq!(table.dispatch(&calls, DOUBLE.raw(), &bytes(&mut input)[..4], &mut bytes(&mut output)[4..]), Err(misaligned));
+        //only the two calls with valid buffers reached the handler
+        assert_eq!(calls.get(), 2);
+        bytes(&mut input)[..4].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert_eq!(table.dispatch(&calls, DOUBLE.raw(), &bytes(&mut input)[..4], bytes(&mut output)), Err(NtError::INVALID_PARAMETER));
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn raw_handlers_get_the_buffers_as_they_are() {
+        let table = table();
+        let calls = Cell::new(0);
+        let mut output = [0u8; 4];
+        assert_eq!(table.dispatch(&calls, 0x0022_2008, &[1, 2, 3], &mut output), Ok(3));
+        assert_eq!(output, [1, 2, 3, 0]);
+        assert_eq!(table.dispatch(&calls, 0x0022_2008, &[], &mut []), Ok(0));
+        assert_eq!(calls.get(), 0);
+    }
+
+    #[test]
+    fn unknown_codes_are_invalid_requests() {
+        let table = table();
+        assert!(table.contains(DOUBLE));
+        assert_eq!(table.codes().collect::<Vec<_>>(), [DOUBLE, ControlCode::from_raw(0x0022_2008)]);
+        let status = table.dispatch(&Cell::new(0), 0x0022_200C, &[], &mut []);
+        assert_eq!(status, Err(NtError::new(codes::STATUS_INVALID_DEVICE_REQUEST)));
+    }
+
+    #[test]
+    #[should_panic(expected = "registered twice")]
+    fn codes_are_registered_once() {
+        let _ = table().with::<Double, _>(|_, _| Ok(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "METHOD_NEITHER")]
+    fn neither_is_refused() {
+        let _ = table().with_raw(ControlCode::from_raw(0x0022_2013), |_, _, _| Ok(0));
+    }
+}
@@ -0,0 +1,92 @@
+//! Zero-sized tokens that prove the current IRQL.
+//!
+//! A token is the caller's evidence that the processor runs at or below a
+//! level: [`Passive`] for `PASSIVE_LEVEL`, [`Apc`] for `APC_LEVEL` and
+//! [`Dispatch`] for `DISPATCH_LEVEL`. Wrappers of routines with an IRQL
+//! requirement take the token they need, so calling them from the wrong
+//! context does not compile. A lower token can stand in for a higher one,
+//! which is what [`AtMost`] expresses.
+//!
+//! Tokens are neither `Copy` nor `Send`. Callbacks and work items receive
+//! `&mut` to theirs, and anything that raises the IRQL (a spin lock, see
+//! [`crate::sync::SpinLock`]) borrows it mutably, so the lower token cannot
+//! be used until the IRQL is lowered again.
+
+use core::marker::PhantomData;
+#[cfg(target_os = "windows")]
+use wdk_sys::ntddk::KeGetCurrentIrql;
+
+pub const PASSIVE_LEVEL: u8 = 0;
+pub const APC_LEVEL: u8 = 1;
+pub const DISPATCH_LEVEL: u8 = 2;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// An IRQL token; the level is the highest IRQL the token allows.
+pub trait Irql: sealed::Sealed {
+    const LEVEL: u8;
+}
+
+/// Implemented by the tokens that may be used where `L` is required.
+pub trait AtMost<L: Irql>: Irql {}
+
+///`KeGetCurrentIrql`; a host thread always runs at `PASSIVE_LEVEL`
+#[cfg(target_os = "windows")]
+pub fn current_irql() -> u8 {
+    unsafe { KeGetCurrentIrql() }
+}
+
+///`KeGetCurrentIrql`; a host thread always runs at `PASSIVE_LEVEL`
+#[cfg(not(target_os = "windows"))]
+pub const fn current_irql() -> u8 {
+    PASSIVE_LEVEL
+}
+
+macro_rules! irql_tokens {
+    ($($(#[$attribute: meta])* $name: ident = $level: ident: [$($higher: ident),*]),*) => {
+        $(
+            $(#[$attribute])*
+            #[derive(Debug)]
+            pub struct $name {
+                //not `Send`: the IRQL belongs to the processor the token was made on
+                _not_send: PhantomData<*const ()>,
+            }
+
+            impl $name {
+                /// Creates the token without looking at the IRQL.
+                ///
+                /// # Safety
+                /// The caller must run at or below the level of the token, e.g.
+                /// because the system documents it for the callback it is in.
+                pub const unsafe fn new_unchecked() -> Self {
+                    Self { _not_send: PhantomData }
+                }
+                ///the token, if the IRQL is at or below its level right now
+                pub fn current() -> Option<Self> {
+                    (current_irql() <= $level).then(|| unsafe { Self::new_unchecked() })
+                }
+            }
+
+            impl sealed::Sealed for $name {}
+
+            impl Irql for $name {
+                const LEVEL: u8 = $level;
+            }
+
+            impl AtMost<$name> for $name {}
+
+            $(impl AtMost<$higher> for $name {})*
+        )*
+    };
+}
+
+irql_tokens!(
+    ///`PASSIVE_LEVEL`: file I/O, waits and pageable code are allowed
+    Passive = PASSIVE_LEVEL: [Apc, Dispatch],
+    ///at most `APC_LEVEL`: normal kernel APCs are disabled, paging still works
+    Apc = APC_LEVEL: [Dispatch],
+    ///at most `DISPATCH_LEVEL`: no waits and no pageable memory
+    Dispatch = DISPATCH_LEVEL: []
+);
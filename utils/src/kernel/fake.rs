@@ -0,0 +1,854 @@
+//! An in-memory [`KernelApi`] for running driver logic off-target.
+//!
+//! Work items are queued until [`FakeKernel::run_pending_work`] is called, so
+//! the deferred part of a callback can be observed separately from the part
+//! that runs in the callback itself.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::config::{Parameters, RegistryValue};
+use crate::irql::{Apc, AtMost, Dispatch, Passive};
+use crate::kernel::{EventKind, HandlePostHandler, HandlePreHandler, HandleRequest, HandleResult, ImageHandler, ImageLoadInfo, KernelApi, ProcessCreateInfo, ProcessHandler, ProcessId, ProcessNotification, RegistryHandler, RegistryNotification, ThreadHandler, ThreadNotification, WorkRoutine};
+use crate::status::{codes, NtError, Status};
+use crate::work::WorkQueue;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FakeDevice(usize);
+
+///an open handle to a fake event; dropping it closes the handle
+#[derive(Debug)]
+pub struct FakeEvent(Arc<Mutex<FakeEventState>>);
+
+impl Drop for FakeEvent {
+    fn drop(&mut self) {
+        self.0.lock().open_handles -= 1;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FakeFile(String);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct FakeRegistryCallback(usize);
+
+///removes its notify handler when dropped
+pub struct FakeNotify<H: ?Sized> {
+    handlers: HandlerList<H>,
+    id: usize,
+}
+
+impl<H: ?Sized> Drop for FakeNotify<H> {
+    fn drop(&mut self) {
+        self.handlers.lock().retain(|(id, _)| *id != self.id);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeEventState {
+    pub name: String,
+    pub kind: EventKind,
+    pub signaled: bool,
+    ///how many times the event was set or pulsed
+    pub signal_count: usize,
+    pub open_handles: usize,
+}
+
+type SharedRegistryHandler = Arc<dyn Fn(&mut Passive, &RegistryNotification<'_>) -> Status + Send + Sync>;
+
+type ProcessHandlerFn = dyn Fn(&mut Passive, &mut ProcessNotification<'_>) + Send + Sync;
+
+type ThreadHandlerFn = dyn Fn(&mut Apc, &ThreadNotification) + Send + Sync;
+
+type ImageHandlerFn = dyn Fn(&mut Passive, &ImageLoadInfo<'_>) + Send + Sync;
+
+///the callbacks of one object callback registration
+pub struct FakeObjectHandlers {
+    altitude: String,
+    pre: HandlePreHandler,
+    post: Option<HandlePostHandler>,
+}
+
+//shared with the registrations, which remove themselves when dropped
+type HandlerList<H> = Arc<Mutex<Vec<(usize, Arc<H>)>>>;
+
+//the handlers are called without the lock held, so they may register or drop registrations
+fn snapshot<H: ?Sized>(handlers: &HandlerList<H>) -> Vec<Arc<H>> {
+    handlers.lock().iter().map(|(_, handler)| handler.clone()).collect()
+}
+
+///the length of `EPROCESS::ImageFileName` without the nul, which is what the kernel keeps of the name
+const SHORT_NAME_LEN: usize = 14;
+
+#[derive(Default)]
+struct FakeState {
+    next_id: usize,
+    devices: Vec<(usize, String)>,
+    events: Vec<Arc<Mutex<FakeEventState>>>,
+    processes: BTreeMap<ProcessId, String>,
+    image_paths: BTreeMap<ProcessId, String>,
+    suspensions: BTreeMap<ProcessId, usize>,
+    sessions: BTreeMap<ProcessId, u32>,
+    system_time: u64,
+    exit_statuses: BTreeMap<ProcessId, NtError>,
+    files: BTreeMap<String, Vec<u8>>,
+    open_files: Vec<String>,
+    pending_work: VecDeque<WorkRoutine>,
+    parameters: Parameters,
+    registry_handlers: Vec<(usize, SharedRegistryHandler)>,
+    process_handlers: HandlerList<ProcessHandlerFn>,
+    thread_handlers: HandlerList<ThreadHandlerFn>,
+    image_handlers: HandlerList<ImageHandlerFn>,
+    object_handlers: HandlerList<FakeObjectHandlers>,
+}
+
+impl FakeState {
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+    //like the notify routine wrappers, which keep the handler of a single registration
+    fn register<H: ?Sized>(&mut self, handlers: fn(&Self) -> &HandlerList<H>, handler: Arc<H>) -> Result<FakeNotify<H>, NtError> {
+        let id = self.next_id();
+        let list = handlers(self);
+        let mut registered = list.lock();
+        if !registered.is_empty() {
+            return Err(NtError::new(codes::STATUS_OBJECT_NAME_COLLISION));
+        }
+        registered.push((id, handler));
+        Ok(FakeNotify { handlers: list.clone(), id })
+    }
+}
+
+//there is no APC to disable off-target either
+fn host_apc_irql() -> Apc {
+    Apc::current().expect("host threads run at PASSIVE_LEVEL")
+}
+
+//there is no IRQL off-target, every thread may do what `PASSIVE_LEVEL` allows
+fn host_irql() -> Passive {
+    Passive::current().expect("host threads run at PASSIVE_LEVEL")
+}
+
+#[derive(Default)]
+pub struct FakeKernel {
+    state: Mutex<FakeState>,
+}
+
+impl FakeKernel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn add_process(&self, pid: ProcessId, image_name: &str) {
+        self.state.lock().processes.insert(pid, image_name.to_string());
+    }
+    pub fn remove_process(&self, pid: ProcessId) {
+        let mut state = self.state.lock();
+        state.processes.remove(&pid);
+        state.image_paths.remove(&pid);
+        state.suspensions.remove(&pid);
+        state.sessions.remove(&pid);
+    }
+    ///processes are in session zero until they are moved
+    pub fn set_session_id(&self, pid: ProcessId, session_id: u32) {
+        self.state.lock().sessions.insert(pid, session_id);
+    }
+    ///the time `system_time` returns from now on
+    pub fn set_system_time(&self, time: u64) {
+        self.state.lock().system_time = time;
+    }
+    ///how many times the process is suspended, zero when it runs
+    pub fn suspend_count(&self, pid: ProcessId) -> usize {
+        self.state.lock().suspensions.get(&pid).copied().unwrap_or(0)
+    }
+    ///the status a process ended by `terminate_process` exited with
+    pub fn exit_status(&self, pid: ProcessId) -> Option<NtError> {
+        self.state.lock().exit_statuses.get(&pid).copied()
+    }
+    ///the values written with `write_parameter`, as a driver would read them on its next load
+    pub fn parameters(&self) -> Parameters {
+        self.state.lock().parameters.clone()
+    }
+    pub fn event(&self, name: &str) -> Option<FakeEventState> {
+        self.find_event(name).map(|event| event.lock().clone())
+    }
+    ///a wait with a zero timeout by a consumer that was not blocked, as a user-mode poll would do
+    pub fn wait_event(&self, name: &str) -> bool {
+        let Some(event) = self.find_event(name) else {
+            return false;
+        };
+        let mut event = event.lock();
+        let signaled = event.signaled;
+        if event.kind == EventKind::Synchronization {
+            event.signaled = false;
+        }
+        signaled
+    }
+    fn find_event(&self, name: &str) -> Option<Arc<Mutex<FakeEventState>>> {
+        self.state.lock().events.iter()
+            .find(|event| event.lock().name == name)
+            .cloned()
+    }
+    pub fn file_contents(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().files.get(path).cloned()
+    }
+    pub fn live_devices(&self) -> usize {
+        self.state.lock().devices.len()
+    }
+    ///whether `\\.\<name>` opens a live device
+    pub fn has_device(&self, name: &str) -> bool {
+        self.state.lock().devices.iter().any(|(_, device)| device.eq_ignore_ascii_case(name))
+    }
+    pub fn open_files(&self) -> usize {
+        self.state.lock().open_files.len()
+    }
+    pub fn registry_callbacks(&self) -> usize {
+        self.state.lock().registry_handlers.len()
+    }
+    pub fn pending_work(&self) -> usize {
+        self.state.lock().pending_work.len()
+    }
+    ///runs queued work items (including ones queued while running) and returns how many ran
+    pub fn run_pending_work(&self) -> usize {
+        let mut irql = host_irql();
+        let mut count = 0;
+        loop {
+            //the lock must not be held while the routine queues more work
+            let routine = self.state.lock().pending_work.pop_front();
+            let Some(routine) = routine else {
+                return count;
+            };
+            routine(&mut irql);
+            count += 1;
+        }
+    }
+    ///delivers the notification to every registered callback, stopping at the first failure
+    pub fn notify_registry(&self, notification: &RegistryNotification<'_>) -> Status {
+        let handlers: Vec<SharedRegistryHandler> = self.state.lock().registry_handlers.iter()
+            .map(|(_, handler)| handler.clone())
+            .collect();
+        let mut irql = host_irql();
+        for handler in handlers {
+            let status = handler(&mut irql, notification);
+            if !status.is_success() {
+                return status;
+            }
+        }
+        Status::SUCCESS
+    }
+    pub fn process_notify_routines(&self) -> usize {
+        self.state.lock().process_handlers.lock().len()
+    }
+    /// Starts a process: it is added under the short name the kernel
+    /// derives from the image file and with its full path, so the process
+    /// handlers can look it up, and removed again when one of them denies
+    /// the creation. Returns the creation status.
+    pub fn start_process(&self, info: ProcessCreateInfo<'_>) -> Status {
+        let pid = info.pid;
+        let image_path = info.image_file_name.map(|image| image.to_string_lossy());
+        let short_name = info.image_file_name
+            .and_then(|image| image.file_name())
+            .map(|name| name.to_string_lossy().chars().take(SHORT_NAME_LEN).collect::<String>())
+            .unwrap_or_default();
+        self.add_process(pid, &short_name);
+        if let Some(image_path) = image_path {
+            self.state.lock().image_paths.insert(pid, image_path);
+        }
+        let mut notification = ProcessNotification::Create(info);
+        self.notify_process(&mut notification);
+        let ProcessNotification::Create(info) = notification else {
+            unreachable!("the handlers cannot change the kind of notification");
+        };
+        if info.is_denied() {
+            self.remove_process(pid);
+        }
+        info.creation_status()
+    }
+    ///tells the process handlers that the process exits, with the names it was known by, and removes it
+    pub fn exit_process(&self, pid: ProcessId) {
+        let (image_name, image_path) = {
+            let state = self.state.lock();
+            (state.processes.get(&pid).cloned().unwrap_or_default(), state.image_paths.get(&pid).cloned())
+        };
+        self.notify_process(&mut ProcessNotification::Exit { pid, image_name, image_path });
+        self.remove_process(pid);
+    }
+    fn notify_process(&self, notification: &mut ProcessNotification<'_>) {
+        let handlers = snapshot(&self.state.lock().process_handlers);
+        let mut irql = host_irql();
+        for handler in handlers {
+            handler(&mut irql, notification);
+        }
+    }
+    pub fn thread_notify_routines(&self) -> usize {
+        self.state.lock().thread_handlers.lock().len()
+    }
+    ///tells the thread handlers that a thread was created or ends
+    pub fn notify_thread(&self, notification: &ThreadNotification) {
+        let handlers = snapshot(&self.state.lock().thread_handlers);
+        let mut irql = host_apc_irql();
+        for handler in handlers {
+            handler(&mut irql, notification);
+        }
+    }
+    pub fn image_notify_routines(&self) -> usize {
+        self.state.lock().image_handlers.lock().len()
+    }
+    pub fn object_callbacks(&self) -> usize {
+        self.state.lock().object_handlers.lock().len()
+    }
+    /// Opens a handle: the pre handlers see the request from the highest
+    /// altitude down, then the post handlers see the outcome. Returns the
+    /// access the handle is granted.
+    pub fn open_handle(&self, mut request: HandleRequest) -> u32 {
+        let handlers = snapshot(&self.state.lock().object_handlers);
+        let mut irql = host_apc_irql();
+        for handlers in &handlers {
+            (handlers.pre)(&mut irql, &mut request);
+        }
+        let result = HandleResult {
+            operation: request.operation,
+            object: request.object,
+            target_pid: request.target_pid,
+            requester_pid: request.requester_pid,
+            kernel_handle: request.kernel_handle,
+            status: Status::SUCCESS,
+            granted_access: request.desired_access(),
+        };
+        for handlers in &handlers {
+            if let Some(post) = &handlers.post {
+                post(&mut irql, &result);
+            }
+        }
+        result.granted_access
+    }
+    ///tells the image handlers that an image was mapped
+    pub fn load_image(&self, info: &ImageLoadInfo<'_>) {
+        let handlers = snapshot(&self.state.lock().image_handlers);
+        let mut irql = host_irql();
+        for handler in handlers {
+            handler(&mut irql, info);
+        }
+    }
+}
+
+impl KernelApi for FakeKernel {
+    type Device = FakeDevice;
+    type Event = FakeEvent;
+    type File = FakeFile;
+    type RegistryCallback = FakeRegistryCallback;
+    type ProcessNotify = FakeNotify<ProcessHandlerFn>;
+    type ThreadNotify = FakeNotify<ThreadHandlerFn>;
+    type ImageNotify = FakeNotify<ImageHandlerFn>;
+    type ObjectCallbacks = FakeNotify<FakeObjectHandlers>;
+
+    fn create_device(&self, _irql: &Passive, name: &str) -> Result<Self::Device, NtError> {
+        let mut state = self.state.lock();
+        //the symbolic link lives in the case-insensitive \DosDevices directory
+        if state.devices.iter().any(|(_, device)| device.eq_ignore_ascii_case(name)) {
+            return Err(NtError::new(codes::STATUS_OBJECT_NAME_COLLISION));
+        }
+        let id = state.next_id();
+        state.devices.push((id, name.to_string()));
+        Ok(FakeDevice(id))
+    }
+
+    fn delete_device(&self, _irql: &Passive, device: Self::Device) {
+        self.state.lock().devices.retain(|(id, _)| *id != device.0);
+    }
+
+    fn create_named_event(&self, _irql: &Passive, name: &str, kind: EventKind) -> Result<Self::Event, NtError> {
+        //a named event lives while any handle to it is open, like the object manager keeps it
+        let event = self.find_event(name).filter(|event| event.lock().open_handles > 0);
+        let event = event.unwrap_or_else(|| {
+            let event = Arc::new(Mutex::new(FakeEventState {
+                name: name.to_string(),
+                kind,
+                signaled: false,
+                signal_count: 0,
+                open_handles: 0,
+            }));
+            let mut state = self.state.lock();
+            state.events.retain(|old| old.lock().name != name);
+            state.events.push(event.clone());
+            event
+        });
+        {
+            let mut state = event.lock();
+            state.open_handles += 1;
+            state.signaled = false;
+        }
+        Ok(FakeEvent(event))
+    }
+
+    fn set_event<I: AtMost<Dispatch>>(&self, _irql: &I, event: &Self::Event) {
+        let mut state = event.0.lock();
+        state.signal_count += 1;
+        state.signaled = true;
+    }
+
+    fn reset_event<I: AtMost<Dispatch>>(&self, _irql: &I, event: &Self::Event) {
+        event.0.lock().signaled = false;
+    }
+
+    fn pulse_event<I: AtMost<Dispatch>>(&self, _irql: &I, event: &Self::Event) {
+        //nobody is ever blocked on a fake event, so a pulse releases no one
+        let mut state = event.0.lock();
+        state.signal_count += 1;
+        state.signaled = false;
+    }
+
+    fn event_signal_count(&self, event: &Self::Event) -> usize {
+        event.0.lock().signal_count
+    }
+
+    fn system_time(&self) -> u64 {
+        self.state.lock().system_time
+    }
+
+    fn queue_work<I: AtMost<Dispatch>>(&self, _irql: &I, device: Self::Device, _queue: WorkQueue, routine: WorkRoutine) -> Result<(), NtError> {
+        let mut state = self.state.lock();
+        if !state.devices.iter().any(|(id, _)| *id == device.0) {
+            return Err(NtError::new(codes::STATUS_DEVICE_DOES_NOT_EXIST));
+        }
+        state.pending_work.push_back(routine);
+        Ok(())
+    }
+
+    fn drain_work(&self, _irql: &Passive) {
+        //there are no worker threads, so draining means running the work here
+        self.run_pending_work();
+    }
+
+    fn process_image_name<I: AtMost<Apc>>(&self, _irql: &I, pid: ProcessId) -> Result<String, NtError> {
+        self.state.lock().processes.get(&pid)
+            .cloned()
+            .ok_or(NtError::new(codes::STATUS_INVALID_CID))
+    }
+
+    fn process_image_path(&self, _irql: &Passive, pid: ProcessId) -> Result<String, NtError> {
+        let state = self.state.lock();
+        if !state.processes.contains_key(&pid) {
+            return Err(NtError::new(codes::STATUS_INVALID_CID));
+        }
+        //processes added by name only have no image file
+        state.image_paths.get(&pid).cloned().ok_or(NtError::new(codes::STATUS_OBJECT_NAME_NOT_FOUND))
+    }
+
+    fn process_session_id<I: AtMost<Apc>>(&self, _irql: &I, pid: ProcessId) -> Result<u32, NtError> {
+        let state = self.state.lock();
+        if !state.processes.contains_key(&pid) {
+            return Err(NtError::new(codes::STATUS_INVALID_CID));
+        }
+        Ok(state.sessions.get(&pid).copied().unwrap_or(0))
+    }
+
+    fn terminate_process(&self, _irql: &Passive, pid: ProcessId, exit_status: NtError) -> Result<(), NtError> {
+        {
+            let mut state = self.state.lock();
+            if !state.processes.contains_key(&pid) {
+                return Err(NtError::new(codes::STATUS_INVALID_CID));
+            }
+            state.exit_statuses.insert(pid, exit_status);
+        }
+        //the handlers see the exit as they would for any other
+        self.exit_process(pid);
+        Ok(())
+    }
+
+    fn suspend_process(&self, _irql: &Passive, pid: ProcessId) -> Result<(), NtError> {
+        let mut state = self.state.lock();
+        if !state.processes.contains_key(&pid) {
+            return Err(NtError::new(codes::STATUS_INVALID_CID));
+        }
+        *state.suspensions.entry(pid).or_default() += 1;
+        Ok(())
+    }
+
+    fn resume_process(&self, _irql: &Passive, pid: ProcessId) -> Result<(), NtError> {
+        let mut state = self.state.lock();
+        if !state.processes.contains_key(&pid) {
+            return Err(NtError::new(codes::STATUS_INVALID_CID));
+        }
+        if let Some(count) = state.suspensions.get_mut(&pid) {
+            *count -= 1;
+            if *count == 0 {
+                state.suspensions.remove(&pid);
+            }
+        }
+        Ok(())
+    }
+
+    fn open_append_file(&self, _irql: &Passive, path: &str) -> Result<Self::File, NtError> {
+        let mut state = self.state.lock();
+        state.files.entry(path.to_string()).or_default();
+        state.open_files.push(path.to_string());
+        Ok(FakeFile(path.to_string()))
+    }
+
+    fn append_file(&self, _irql: &Passive, file: &Self::File, data: &[u8]) -> Result<(), NtError> {
+        let mut state = self.state.lock();
+        if !state.open_files.contains(&file.0) {
+            return Err(NtError::new(codes::STATUS_FILE_CLOSED));
+        }
+        state.files.entry(file.0.clone()).or_default().extend_from_slice(data);
+        Ok(())
+    }
+
+    fn close_file(&self, _irql: &Passive, file: Self::File) {
+        let open_files = &mut self.state.lock().open_files;
+        if let Some(index) = open_files.iter().position(|path| *path == file.0) {
+            open_files.remove(index);
+        }
+    }
+
+    fn write_parameter(&self, _irql: &Passive, name: &str, value: &RegistryValue) -> Result<(), NtError> {
+        self.state.lock().parameters.insert(name, value.clone());
+        Ok(())
+    }
+
+    fn register_registry_callback(&self, _irql: &Passive, handler: RegistryHandler) -> Result<Self::RegistryCallback, NtError> {
+        let mut state = self.state.lock();
+        let id = state.next_id();
+        state.registry_handlers.push((id, Arc::from(handler)));
+        Ok(FakeRegistryCallback(id))
+    }
+
+    fn unregister_registry_callback(&self, _irql: &Passive, callback: Self::RegistryCallback) {
+        self.state.lock().registry_handlers.retain(|(id, _)| *id != callback.0);
+    }
+
+    fn register_process_notify(&self, _irql: &Passive, handler: ProcessHandler) -> Result<Self::ProcessNotify, NtError> {
+        self.state.lock().register(|state| &state.process_handlers, Arc::from(handler))
+    }
+
+    fn register_thread_notify(&self, _irql: &Passive, handler: ThreadHandler) -> Result<Self::ThreadNotify, NtError> {
+        self.state.lock().register(|state| &state.thread_handlers, Arc::from(handler))
+    }
+
+    fn register_image_notify(&self, _irql: &Passive, handler: ImageHandler) -> Result<Self::ImageNotify, NtError> {
+        self.state.lock().register(|state| &state.image_handlers, Arc::from(handler))
+    }
+
+    fn register_object_callbacks(&self, _irql: &Passive, altitude: &str, pre: HandlePreHandler, post: Option<HandlePostHandler>) -> Result<Self::ObjectCallbacks, NtError> {
+        let mut state = self.state.lock();
+        let id = state.next_id();
+        let mut handlers = state.object_handlers.lock();
+        //altitudes are unique, and the callbacks are called from the highest one down; the fake compares them as strings
+        let Err(index) = handlers.binary_search_by(|(_, registered)| altitude.cmp(&registered.altitude)) else {
+            return Err(NtError::new(codes::STATUS_OBJECT_NAME_COLLISION));
+        };
+        handlers.insert(index, (id, Arc::new(FakeObjectHandlers { altitude: altitude.to_string(), pre, post })));
+        drop(handlers);
+        Ok(FakeNotify { handlers: state.object_handlers.clone(), id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use crate::kernel::{access, HandleObject};
+    use crate::unicode::{encode_utf16, UnicodeStr};
+
+    fn irql() -> Passive {
+        host_irql()
+    }
+
+    #[test]
+    fn synchronization_events_release_one_wait_per_signal() {
+        let kernel = FakeKernel::new();
+        let event = kernel.create_named_event(&irql(), "\\BaseNamedObjects\\Sync", EventKind::Synchronization).unwrap();
+        assert!(!kernel.wait_event("\\BaseNamedObjects\\Sync"));
+        kernel.set_event(&irql(), &event);
+        kernel.set_event(&irql(), &event);
+        //two signals before a wait are coalesced
+        assert!(kernel.wait_event("\\BaseNamedObjects\\Sync"));
+        assert!(!kernel.wait_event("\\BaseNamedObjects\\Sync"));
+        assert_eq!(kernel.event_signal_count(&event), 2);
+        assert!(!kernel.wait_event("\\BaseNamedObjects\\Missing"));
+    }
+
+    #[test]
+    fn notification_events_stay_signaled_until_reset() {
+        let kernel = FakeKernel::new();
+        let event = kernel.create_named_event(&irql(), "Manual", EventKind::Notification).unwrap();
+        kernel.set_event(&irql(), &event);
+        assert!(kernel.wait_event("Manual"));
+        assert!(kernel.wait_event("Manual"));
+        kernel.reset_event(&irql(), &event);
+        assert!(!kernel.wait_event("Manual"));
+        //nobody waits, so a pulse is lost but still counted
+        kernel.pulse_event(&irql(), &event);
+        assert!(!kernel.wait_event("Manual"));
+        assert_eq!(kernel.event("Manual").unwrap().signal_count, 2);
+    }
+
+    #[test]
+    fn a_named_event_lives_while_a_handle_is_open() {
+        let kernel = FakeKernel::new();
+        let first = kernel.create_named_event(&irql(), "Shared", EventKind::Synchronization).unwrap();
+        kernel.set_event(&irql(), &first);
+        let second = kernel.create_named_event(&irql(), "Shared", EventKind::Notification).unwrap();
+        let state = kernel.event("Shared").unwrap();
+        //opened again: the same event, cleared, with its kind kept
+        assert_eq!((state.open_handles, state.kind, state.signaled, state.signal_count), (2, EventKind::Synchronization, false, 1));
+        drop(first);
+        drop(second);
+        assert_eq!(kernel.event("Shared").unwrap().open_handles, 0);
+        let _third = kernel.create_named_event(&irql(), "Shared", EventKind::Notification).unwrap();
+        let state = kernel.event("Shared").unwrap();
+        assert_eq!((state.open_handles, state.kind, state.signal_count), (1, EventKind::Notification, 0));
+    }
+
+    #[test]
+    fn work_runs_when_asked_including_work_it_queues() {
+        let kernel = Arc::new(FakeKernel::new());
+        let device = kernel.create_device(&irql(), "Worker").unwrap();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let (inner_kernel, inner_runs) = (kernel.clone(), runs.clone());
+        kernel.queue_work(&irql(), device, WorkQueue::Delayed, Box::new(move |irql: &mut Passive| {
+            inner_runs.fetch_add(1, Ordering::Relaxed);
+            let again = inner_runs.clone();
+            inner_kernel.queue_work(irql, device, WorkQueue::Critical, Box::new(move |_: &mut Passive| {
+                again.fetch_add(10, Ordering::Relaxed);
+            })).unwrap();
+        })).unwrap();
+        assert_eq!(kernel.pending_work(), 1);
+        assert_eq!(runs.load(Ordering::Relaxed), 0);
+        assert_eq!(kernel.run_pending_work(), 2);
+        assert_eq!(runs.load(Ordering::Relaxed), 11);
+        assert_eq!(kernel.run_pending_work(), 0);
+    }
+
+    #[test]
+    fn devices_are_linked_by_name_until_deleted() {
+        let kernel = FakeKernel::new();
+        let device = kernel.create_device(&irql(), "Spy").unwrap();
+        assert!(kernel.has_device("spy"));
+        let collision = kernel.create_device(&irql(), "SPY");
+        assert_eq!(collision.unwrap_err(), NtError::new(codes::STATUS_OBJECT_NAME_COLLISION));
+        kernel.delete_device(&irql(), device);
+        assert!(!kernel.has_device("Spy"));
+        let again = kernel.create_device(&irql(), "Spy").unwrap();
+        assert!(kernel.has_device("Spy"));
+        kernel.delete_device(&irql(), again);
+    }
+
+    #[test]
+    fn work_needs_a_live_device() {
+        let kernel = FakeKernel::new();
+        let device = kernel.create_device(&irql(), "Worker").unwrap();
+        assert_eq!(kernel.live_devices(), 1);
+        kernel.delete_device(&irql(), device);
+        assert_eq!(kernel.live_devices(), 0);
+        assert!(!kernel.has_device("Worker"));
+        let queued = kernel.queue_work(&irql(), device, WorkQueue::Delayed, Box::new(|_: &mut Passive| {}));
+        assert_eq!(queued, Err(NtError::new(codes::STATUS_DEVICE_DOES_NOT_EXIST)));
+        assert_eq!(kernel.pending_work(), 0);
+    }
+
+    #[test]
+    fn started_processes_have_both_names() {
+        let kernel = FakeKernel::new();
+        let path = encode_utf16("\\Device\\HarddiskVolume2\\Program Files\\averyverylongname.exe");
+        let mut info = ProcessCreateInfo::new(40, 4);
+        info.image_file_name = Some(UnicodeStr::new(&path));
+        assert!(kernel.start_process(info).is_success());
+        assert_eq!(kernel.process_image_name(&irql(), 40).unwrap(), "averyverylongn");
+        assert_eq!(kernel.process_image_path(&irql(), 40).unwrap(), "\\Device\\HarddiskVolume2\\Program Files\\averyverylongname.exe");
+        assert_eq!(kernel.process_session_id(&irql(), 40), Ok(0));
+        kernel.set_session_id(40, 2);
+        assert_eq!(kernel.process_session_id(&irql(), 40), Ok(2));
+        kernel.add_process(41, "System");
+        assert_eq!(kernel.process_image_path(&irql(), 41), Err(NtError::new(codes::STATUS_OBJECT_NAME_NOT_FOUND)));
+        kernel.exit_process(40);
+        assert_eq!(kernel.process_image_name(&irql(), 40), Err(NtError::new(codes::STATUS_INVALID_CID)));
+        assert_eq!(kernel.process_image_path(&irql(), 40), Err(NtError::new(codes::STATUS_INVALID_CID)));
+        assert_eq!(kernel.process_session_id(&irql(), 40), Err(NtError::new(codes::STATUS_INVALID_CID)));
+    }
+
+    #[test]
+    fn process_handlers_see_the_process_and_may_deny_it() {
+        let kernel = Arc::new(FakeKernel::new());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let (handler_kernel, handler_seen) = (kernel.clone(), seen.clone());
+        let registration = kernel.register_process_notify(&irql(), Box::new(move |irql: &mut Passive, notification: &mut ProcessNotification<'_>| {
+            let name = handler_kernel.process_image_name(irql, notification.pid()).unwrap_or_default();
+            handler_seen.lock().push((notification.pid(), name.clone()));
+            if let ProcessNotification::Create(info) = notification {
+                if name == "evil.exe" {
+                    info.deny(NtError::ACCESS_DENIED);
+                }
+            }
+        })).unwrap();
+        assert_eq!(kernel.process_notify_routines(), 1);
+        let evil = encode_utf16("\\??\\C:\\evil.exe");
+        let mut info = ProcessCreateInfo::new(8, 4);
+        info.image_file_name = Some(UnicodeStr::new(&evil));
+        assert_eq!(kernel.start_process(info), NtError::ACCESS_DENIED.status());
+        assert!(kernel.process_image_name(&irql(), 8).is_err());
+        assert!(kernel.start_process(ProcessCreateInfo::new(12, 4)).is_success());
+        kernel.exit_process(12);
+        assert_eq!(*seen.lock(), [(8, "evil.exe".to_string()), (12, String::new()), (12, String::new())]);
+        drop(registration);
+        assert_eq!(kernel.process_notify_routines(), 0);
+        kernel.start_process(ProcessCreateInfo::new(16, 4));
+        assert_eq!(seen.lock().len(), 3);
+    }
+
+    #[test]
+    fn notify_routines_are_registered_once() {
+        let kernel = FakeKernel::new();
+        let first = kernel.register_thread_notify(&irql(), Box::new(|_: &mut Apc, _: &ThreadNotification| {})).unwrap();
+        let second = kernel.register_thread_notify(&irql(), Box::new(|_: &mut Apc, _: &ThreadNotification| {}));
+        assert_eq!(second.err(), Some(NtError::new(codes::STATUS_OBJECT_NAME_COLLISION)));
+        drop(first);
+        assert_eq!(kernel.thread_notify_routines(), 0);
+        let _image = kernel.register_image_notify(&irql(), Box::new(|_: &mut Passive, _: &ImageLoadInfo<'_>| {})).unwrap();
+        assert_eq!(kernel.image_notify_routines(), 1);
+        assert!(kernel.register_image_notify(&irql(), Box::new(|_: &mut Passive, _: &ImageLoadInfo<'_>| {})).is_err());
+    }
+
+    #[test]
+    fn thread_and_image_handlers_are_called() {
+        let kernel = FakeKernel::new();
+        let remote = Arc::new(AtomicUsize::new(0));
+        let counted = remote.clone();
+        let _thread = kernel.register_thread_notify(&irql(), Box::new(move |_: &mut Apc, notification: &ThreadNotification| {
+            if notification.is_remote() {
+                counted.fetch_add(1, Ordering::Relaxed);
+            }
+        })).unwrap();
+        kernel.notify_thread(&ThreadNotification { pid: 8, tid: 100, current_pid: 8, created: true });
+        kernel.notify_thread(&ThreadNotification { pid: 8, tid: 104, current_pid: 12, created: true });
+        kernel.notify_thread(&ThreadNotification { pid: 8, tid: 104, current_pid: 12, created: false });
+        assert_eq!(remote.load(Ordering::Relaxed), 1);
+        let sizes = Arc::new(AtomicUsize::new(0));
+        let counted = sizes.clone();
+        let _image = kernel.register_image_notify(&irql(), Box::new(move |_: &mut Passive, info: &ImageLoadInfo<'_>| {
+            counted.fetch_add(info.image_size, Ordering::Relaxed);
+        })).unwrap();
+        kernel.load_image(&ImageLoadInfo { pid: 8, image_base: 0x1000, image_size: 0x2000, image_name: None, system_image: false });
+        assert_eq!(sizes.load(Ordering::Relaxed), 0x2000);
+    }
+
+    #[test]
+    fn terminated_and_suspended_processes() {
+        let kernel = FakeKernel::new();
+        kernel.add_process(8, "notepad.exe");
+        kernel.suspend_process(&irql(), 8).unwrap();
+        kernel.suspend_process(&irql(), 8).unwrap();
+        assert_eq!(kernel.suspend_count(8), 2);
+        kernel.resume_process(&irql(), 8).unwrap();
+        kernel.resume_process(&irql(), 8).unwrap();
+        kernel.resume_process(&irql(), 8).unwrap();
+        assert_eq!(kernel.suspend_count(8), 0);
+        let exits = Arc::new(AtomicUsize::new(0));
+        let counted = exits.clone();
+        let _registration = kernel.register_process_notify(&irql(), Box::new(move |_: &mut Passive, notification: &mut ProcessNotification<'_>| {
+            if let ProcessNotification::Exit { image_name, image_path, .. } = notification {
+                //added by name only, so there is no path to hand over
+                assert_eq!((image_name.as_str(), image_path.as_deref()), ("notepad.exe", None));
+                counted.fetch_add(1, Ordering::Relaxed);
+            }
+        })).unwrap();
+        kernel.terminate_process(&irql(), 8, NtError::ACCESS_DENIED).unwrap();
+        assert_eq!(kernel.exit_status(8), Some(NtError::ACCESS_DENIED));
+        assert_eq!(exits.load(Ordering::Relaxed), 1);
+        let missing = Err(NtError::new(codes::STATUS_INVALID_CID));
+        assert_eq!(kernel.terminate_process(&irql(), 8, NtError::ACCESS_DENIED), missing);
+        assert_eq!(kernel.suspend_process(&irql(), 8), missing);
+        assert_eq!(kernel.resume_process(&irql(), 8), missing);
+    }
+
+    #[test]
+    fn files_are_appended_while_open() {
+        let kernel = FakeKernel::new();
+        let file = kernel.open_append_file(&irql(), "\\DosDevices\\C:\\log.dat").unwrap();
+        kernel.append_file(&irql(), &file, b"first\n").unwrap();
+        let again = kernel.open_append_file(&irql(), "\\DosDevices\\C:\\log.dat").unwrap();
+        kernel.append_file(&irql(), &again, b"second\n").unwrap();
+        assert_eq!(kernel.open_files(), 2);
+        kernel.close_file(&irql(), again);
+        kernel.append_file(&irql(), &file, b"third\n").unwrap();
+        kernel.close_file(&irql(), file);
+        assert_eq!(kernel.open_files(), 0);
+        assert_eq!(kernel.file_contents("\\DosDevices\\C:\\log.dat").unwrap(), b"first\nsecond\nthird\n");
+        let closed = FakeFile("\\DosDevices\\C:\\log.dat".to_string());
+        assert_eq!(kernel.append_file(&irql(), &closed, b"x"), Err(NtError::new(codes::STATUS_FILE_CLOSED)));
+        assert_eq!(kernel.file_contents("\\DosDevices\\C:\\other.dat"), None);
+    }
+
+    #[test]
+    fn registry_callbacks_stop_at_the_first_failure() {
+        let kernel = FakeKernel::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let first = kernel.register_registry_callback(&irql(), Box::new(move |_: &mut Passive, notification: &RegistryNotification<'_>| {
+            counted.fetch_add(1, Ordering::Relaxed);
+            match notification {
+                RegistryNotification::PreSetValueKey { .. } => NtError::ACCESS_DENIED.status(),
+                _ => Status::SUCCESS,
+            }
+        })).unwrap();
+        let counted = calls.clone();
+        let _second = kernel.register_registry_callback(&irql(), Box::new(move |_: &mut Passive, _: &RegistryNotification<'_>| {
+            counted.fetch_add(100, Ordering::Relaxed);
+            Status::SUCCESS
+        })).unwrap();
+        assert_eq!(kernel.registry_callbacks(), 2);
+        let name = encode_utf16("Start");
+        let set = RegistryNotification::PreSetValueKey { value_name: UnicodeStr::new(&name) };
+        assert_eq!(kernel.notify_registry(&set), NtError::ACCESS_DENIED.status());
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(kernel.notify_registry(&RegistryNotification::Other(3)).is_success());
+        assert_eq!(calls.load(Ordering::Relaxed), 102);
+        kernel.unregister_registry_callback(&irql(), first);
+        assert!(kernel.notify_registry(&set).is_success());
+        assert_eq!(kernel.registry_callbacks(), 1);
+    }
+
+    #[test]
+    fn object_callbacks_run_from_the_highest_altitude_down() {
+        let kernel = FakeKernel::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let pre = |altitude: &'static str, mask: u32| -> HandlePreHandler {
+            let order = order.clone();
+            Box::new(move |_: &mut Apc, request: &mut HandleRequest| {
+                order.lock().push(altitude);
+                request.strip_access(mask);
+            })
+        };
+        let granted = Arc::new(AtomicUsize::new(0));
+        let seen = granted.clone();
+        let post: HandlePostHandler = Box::new(move |_: &mut Apc, result: &HandleResult| {
+            seen.store(result.granted_access as usize, Ordering::Relaxed);
+        });
+        let low = kernel.register_object_callbacks(&irql(), "321000", pre("321000", access::PROCESS_TERMINATE), None).unwrap();
+        let _high = kernel.register_object_callbacks(&irql(), "385201", pre("385201", access::PROCESS_VM_WRITE), Some(post)).unwrap();
+        assert!(kernel.register_object_callbacks(&irql(), "321000", pre("321000", 0), None).is_err());
+        assert_eq!(kernel.object_callbacks(), 2);
+        let wanted = access::PROCESS_TERMINATE | access::PROCESS_VM_WRITE | access::PROCESS_VM_READ;
+        let request = HandleRequest::new(HandleObject::Process, 8, 12, wanted);
+        assert_eq!(kernel.open_handle(request.clone()), access::PROCESS_VM_READ);
+        assert_eq!(*order.lock(), ["385201", "321000"]);
+        assert_eq!(granted.load(Ordering::Relaxed), access::PROCESS_VM_READ as usize);
+        drop(low);
+        assert_eq!(kernel.open_handle(request), access::PROCESS_TERMINATE | access::PROCESS_VM_READ);
+    }
+
+    #[test]
+    fn parameters_and_time() {
+        let kernel: Arc<FakeKernel> = Arc::new(FakeKernel::new());
+        kernel.write_parameter(&irql(), "LogLevel", &RegistryValue::Dword(4)).unwrap();
+        kernel.write_parameter(&irql(), "LogLevel", &RegistryValue::Dword(5)).unwrap();
+        assert_eq!(kernel.parameters().dword("LogLevel"), Ok(Some(5)));
+        assert_eq!(kernel.system_time(), 0);
+        kernel.set_system_time(133_000_000_000_000_000);
+        assert_eq!(KernelApi::system_time(&kernel), 133_000_000_000_000_000);
+    }
+}
@@ -0,0 +1,409 @@
+//! The kernel services the drivers depend on, behind one trait.
+//!
+//! Driver logic is written against [`KernelApi`] and gets either the real
+//! [`wdk::WdkKernel`] inside a driver or the in-memory [`fake::FakeKernel`]
+//! when it runs as an ordinary host program.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use crate::config::RegistryValue;
+use crate::irql::{Apc, AtMost, Dispatch, Passive};
+use crate::status::{NtError, Status};
+use crate::unicode::UnicodeStr;
+use crate::work::WorkQueue;
+
+pub mod fake;
+#[cfg(target_os = "windows")]
+pub mod wdk;
+
+///the value of a process `HANDLE` as passed to process notify routines
+pub type ProcessId = usize;
+
+///the value of a thread `HANDLE` as passed to thread notify routines
+pub type ThreadId = usize;
+
+/// How a signaled event treats its waiters, see [`crate::KernelEvent`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    ///manual reset: stays signaled and releases every waiter until it is reset
+    Notification,
+    ///auto reset: releases a single waiter and resets itself
+    Synchronization,
+}
+
+///runs on a system worker thread, at `PASSIVE_LEVEL`
+pub type WorkRoutine = Box<dyn FnOnce(&mut Passive) + Send>;
+
+///registry callbacks run at `PASSIVE_LEVEL` in the thread that made the registry call
+pub type RegistryHandler = Box<dyn Fn(&mut Passive, &RegistryNotification<'_>) -> Status + Send + Sync>;
+
+/// A configuration manager notification, decoded from `REG_NOTIFY_CLASS`
+/// and the matching information structure.
+#[derive(Debug)]
+pub enum RegistryNotification<'a> {
+    PreSetValueKey { value_name: UnicodeStr<'a> },
+    PreQueryValueKey { value_name: UnicodeStr<'a> },
+    ///any other class, carried as the raw `REG_NOTIFY_CLASS` value
+    Other(i32),
+}
+
+impl RegistryNotification<'_> {
+    pub const PRE_SET_VALUE_KEY: i32 = 1;
+    pub const PRE_QUERY_VALUE_KEY: i32 = 8;
+}
+
+///process notify routines run at `PASSIVE_LEVEL`, a creation in the thread that creates the process
+pub type ProcessHandler = Box<dyn Fn(&mut Passive, &mut ProcessNotification<'_>) + Send + Sync>;
+
+/// What the process notify routine is told, see [`KernelApi::register_process_notify`].
+#[derive(Debug)]
+pub enum ProcessNotification<'a> {
+    Create(ProcessCreateInfo<'a>),
+    ///the names are read from the process while it still exists, the worker cannot look them up later
+    Exit {
+        pid: ProcessId,
+        ///the image file name the kernel keeps for the process
+        image_name: String,
+        ///the NT path of the image file, when it could be located
+        image_path: Option<String>,
+    },
+}
+
+impl ProcessNotification<'_> {
+    pub const fn pid(&self) -> ProcessId {
+        match self {
+            Self::Create(info) => info.pid,
+            Self::Exit { pid, .. } => *pid,
+        }
+    }
+}
+
+/// A process that is being created, decoded from `PS_CREATE_NOTIFY_INFO`.
+/// The handler may [`deny`](Self::deny) the creation.
+#[derive(Debug)]
+pub struct ProcessCreateInfo<'a> {
+    pub pid: ProcessId,
+    pub parent_pid: ProcessId,
+    ///the process of the thread that creates the new one, which is not always the parent
+    pub creating_pid: ProcessId,
+    pub creating_tid: ThreadId,
+    ///the full path of the image file, when the system knows it
+    pub image_file_name: Option<UnicodeStr<'a>>,
+    pub command_line: Option<UnicodeStr<'a>>,
+    ///whether `image_file_name` is the exact name that was used to open the file
+    pub file_open_name_available: bool,
+    creation_status: Status,
+}
+
+impl<'a> ProcessCreateInfo<'a> {
+    ///a creation by the parent itself, with no image name or command line
+    pub const fn new(pid: ProcessId, parent_pid: ProcessId) -> Self {
+        Self {
+            pid,
+            parent_pid,
+            creating_pid: parent_pid,
+            creating_tid: 0,
+            image_file_name: None,
+            command_line: None,
+            file_open_name_available: false,
+            creation_status: Status::SUCCESS,
+        }
+    }
+    ///makes the creation fail with `error`; the process never runs
+    pub fn deny(&mut self, error: NtError) {
+        self.creation_status = error.status();
+    }
+    pub const fn creation_status(&self) -> Status {
+        self.creation_status
+    }
+    pub const fn is_denied(&self) -> bool {
+        !self.creation_status.is_success()
+    }
+}
+
+///thread notify routines run at `PASSIVE_LEVEL` or `APC_LEVEL`, in the thread that creates or ends the thread
+pub type ThreadHandler = Box<dyn Fn(&mut Apc, &ThreadNotification) + Send + Sync>;
+
+/// A thread that is created or ends, see [`KernelApi::register_thread_notify`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ThreadNotification {
+    pub pid: ProcessId,
+    pub tid: ThreadId,
+    ///the process the notify routine runs in, which creates or ends the thread
+    pub current_pid: ProcessId,
+    pub created: bool,
+}
+
+impl ThreadNotification {
+    ///a thread created by another process; the first thread of a new process is created by its parent, so it counts
+    pub const fn is_remote(&self) -> bool {
+        self.created && self.current_pid != self.pid
+    }
+}
+
+///image load notify routines run at `PASSIVE_LEVEL`, in the thread that maps the image
+pub type ImageHandler = Box<dyn Fn(&mut Passive, &ImageLoadInfo<'_>) + Send + Sync>;
+
+/// An image that is mapped, decoded from the arguments of the image load
+/// notify routine and `IMAGE_INFO`.
+#[derive(Debug)]
+pub struct ImageLoadInfo<'a> {
+    ///zero for a driver
+    pub pid: ProcessId,
+    pub image_base: usize,
+    pub image_size: usize,
+    ///the full path of the image file, when the system knows it
+    pub image_name: Option<UnicodeStr<'a>>,
+    ///mapped into system space, which is the case of drivers
+    pub system_image: bool,
+}
+
+/// Access rights of process and thread handles, for the object callbacks.
+pub mod access {
+    pub const PROCESS_TERMINATE: u32 = 0x0001;
+    pub const PROCESS_CREATE_THREAD: u32 = 0x0002;
+    pub const PROCESS_VM_OPERATION: u32 = 0x0008;
+    pub const PROCESS_VM_READ: u32 = 0x0010;
+    pub const PROCESS_VM_WRITE: u32 = 0x0020;
+    pub const PROCESS_SUSPEND_RESUME: u32 = 0x0800;
+    pub const THREAD_TERMINATE: u32 = 0x0001;
+    pub const THREAD_SUSPEND_RESUME: u32 = 0x0002;
+    pub const THREAD_SET_CONTEXT: u32 = 0x0010;
+}
+
+///the kind of object a handle is opened to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HandleObject {
+    Process,
+    Thread,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HandleOperation {
+    Create,
+    Duplicate,
+}
+
+///object callbacks run at `PASSIVE_LEVEL` or `APC_LEVEL`, in the thread that opens or duplicates the handle
+pub type HandlePreHandler = Box<dyn Fn(&mut Apc, &mut HandleRequest) + Send + Sync>;
+
+///runs after the handle operation, whether it succeeded or not
+pub type HandlePostHandler = Box<dyn Fn(&mut Apc, &HandleResult) + Send + Sync>;
+
+/// A handle that is about to be opened or duplicated, decoded from
+/// `OB_PRE_OPERATION_INFORMATION`. The handler may
+/// [`strip_access`](Self::strip_access) from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleRequest {
+    pub operation: HandleOperation,
+    pub object: HandleObject,
+    ///the process, or the process the thread belongs to
+    pub target_pid: ProcessId,
+    ///the process that opens the handle
+    pub requester_pid: ProcessId,
+    pub kernel_handle: bool,
+    ///the access that was asked for, before any callback stripped it
+    pub original_access: u32,
+    desired_access: u32,
+}
+
+impl HandleRequest {
+    ///a user-mode handle being opened with `access`
+    pub const fn new(object: HandleObject, target_pid: ProcessId, requester_pid: ProcessId, access: u32) -> Self {
+        Self {
+            operation: HandleOperation::Create,
+            object,
+            target_pid,
+            requester_pid,
+            kernel_handle: false,
+            original_access: access,
+            desired_access: access,
+        }
+    }
+    ///the access the handle is going to be opened with
+    pub const fn desired_access(&self) -> u32 {
+        self.desired_access
+    }
+    ///removes `mask` from the desired access and returns the bits that were actually removed
+    pub fn strip_access(&mut self, mask: u32) -> u32 {
+        let stripped = self.desired_access & mask;
+        self.desired_access &= !mask;
+        stripped
+    }
+}
+
+/// The outcome of a handle operation, decoded from `OB_POST_OPERATION_INFORMATION`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleResult {
+    pub operation: HandleOperation,
+    pub object: HandleObject,
+    pub target_pid: ProcessId,
+    pub requester_pid: ProcessId,
+    pub kernel_handle: bool,
+    pub status: Status,
+    ///meaningless when the operation failed
+    pub granted_access: u32,
+}
+
+/// Every method takes the token of the highest IRQL its routine may be
+/// called at, see [`crate::irql`].
+pub trait KernelApi: Send + Sync + 'static {
+    type Device: Copy + Send + Sync;
+    type Event: Send + Sync;
+    type File: Send + Sync;
+    type RegistryCallback: Send + Sync;
+    ///keeps the process notify routine registered until it is dropped, which must happen at `PASSIVE_LEVEL`
+    type ProcessNotify: Send + Sync;
+    ///keeps the thread notify routine registered until it is dropped, at `PASSIVE_LEVEL`
+    type ThreadNotify: Send + Sync;
+    ///keeps the image load notify routine registered until it is dropped, at `PASSIVE_LEVEL`
+    type ImageNotify: Send + Sync;
+    ///keeps the object callbacks registered until it is dropped, at `PASSIVE_LEVEL`
+    type ObjectCallbacks: Send + Sync;
+
+    ///creates the device object `\Device\<name>` of the driver and the symbolic link `\DosDevices\<name>`
+    ///user mode opens it by, as `\\.\<name>`
+    fn create_device(&self, irql: &Passive, name: &str) -> Result<Self::Device, NtError>;
+    ///deletes the symbolic link and then the device object
+    fn delete_device(&self, irql: &Passive, device: Self::Device);
+
+    ///creates (or opens) the named event in the not-signaled state; dropping the event closes it
+    fn create_named_event(&self, irql: &Passive, name: &str, kind: EventKind) -> Result<Self::Event, NtError>;
+    fn set_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event);
+    fn reset_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event);
+    fn pulse_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event);
+    ///how many times the event was set or pulsed
+    fn event_signal_count(&self, event: &Self::Event) -> usize;
+
+    ///the system time in 100ns intervals since 1601-01-01 UTC, at any IRQL
+    fn system_time(&self) -> u64;
+
+    ///runs `routine` later at `PASSIVE_LEVEL` on a system worker thread
+    fn queue_work<I: AtMost<Dispatch>>(&self, irql: &I, device: Self::Device, queue: WorkQueue, routine: WorkRoutine) -> Result<(), NtError>;
+    ///waits until all the work queued through this kernel has run, see [`crate::WorkTracker::wait_drained`]
+    fn drain_work(&self, irql: &Passive);
+
+    ///the image file name the kernel keeps for the process
+    fn process_image_name<I: AtMost<Apc>>(&self, irql: &I, pid: ProcessId) -> Result<String, NtError>;
+    ///the NT path of the image file of the process, such as `\Device\HarddiskVolume2\Windows\notepad.exe`
+    fn process_image_path(&self, irql: &Passive, pid: ProcessId) -> Result<String, NtError>;
+    ///the terminal services session the process runs in
+    fn process_session_id<I: AtMost<Apc>>(&self, irql: &I, pid: ProcessId) -> Result<u32, NtError>;
+    ///ends the process as if it had exited with `exit_status`
+    fn terminate_process(&self, irql: &Passive, pid: ProcessId, exit_status: NtError) -> Result<(), NtError>;
+    ///stops every thread of the process; suspensions nest and each is undone by one [`Self::resume_process`]
+    fn suspend_process(&self, irql: &Passive, pid: ProcessId) -> Result<(), NtError>;
+    fn resume_process(&self, irql: &Passive, pid: ProcessId) -> Result<(), NtError>;
+
+    ///opens the file for appending, creating it when it does not exist
+    fn open_append_file(&self, irql: &Passive, path: &str) -> Result<Self::File, NtError>;
+    fn append_file(&self, irql: &Passive, file: &Self::File, data: &[u8]) -> Result<(), NtError>;
+    fn close_file(&self, irql: &Passive, file: Self::File);
+
+    ///writes a value of the `Parameters` key of the driver, so it is read again on the next load
+    fn write_parameter(&self, irql: &Passive, name: &str, value: &RegistryValue) -> Result<(), NtError>;
+
+    fn register_registry_callback(&self, irql: &Passive, handler: RegistryHandler) -> Result<Self::RegistryCallback, NtError>;
+    fn unregister_registry_callback(&self, irql: &Passive, callback: Self::RegistryCallback);
+
+    ///registers `handler` for process creation and exit; a driver may have one registration at a time
+    fn register_process_notify(&self, irql: &Passive, handler: ProcessHandler) -> Result<Self::ProcessNotify, NtError>;
+    ///registers `handler` for thread creation and exit; one registration at a time
+    fn register_thread_notify(&self, irql: &Passive, handler: ThreadHandler) -> Result<Self::ThreadNotify, NtError>;
+    ///registers `handler` for images mapped into any process or into system space; one registration at a time
+    fn register_image_notify(&self, irql: &Passive, handler: ImageHandler) -> Result<Self::ImageNotify, NtError>;
+    ///registers handle callbacks for processes and threads; `altitude` orders them among the other drivers' ones
+    fn register_object_callbacks(&self, irql: &Passive, altitude: &str, pre: HandlePreHandler, post: Option<HandlePostHandler>) -> Result<Self::ObjectCallbacks, NtError>;
+}
+
+///lets the caller keep a handle to the kernel it gave away, e.g. to inspect a fake one
+impl<K: KernelApi> KernelApi for Arc<K> {
+    type Device = K::Device;
+    type Event = K::Event;
+    type File = K::File;
+    type RegistryCallback = K::RegistryCallback;
+    type ProcessNotify = K::ProcessNotify;
+    type ThreadNotify = K::ThreadNotify;
+    type ImageNotify = K::ImageNotify;
+    type ObjectCallbacks = K::ObjectCallbacks;
+
+    fn create_device(&self, irql: &Passive, name: &str) -> Result<Self::Device, NtError> {
+        (**self).create_device(irql, name)
+    }
+    fn delete_device(&self, irql: &Passive, device: Self::Device) {
+        (**self).delete_device(irql, device);
+    }
+    fn create_named_event(&self, irql: &Passive, name: &str, kind: EventKind) -> Result<Self::Event, NtError> {
+        (**self).create_named_event(irql, name, kind)
+    }
+    fn set_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event) {
+        (**self).set_event(irql, event);
+    }
+    fn reset_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event) {
+        (**self).reset_event(irql, event);
+    }
+    fn pulse_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event) {
+        (**self).pulse_event(irql, event);
+    }
+    fn event_signal_count(&self, event: &Self::Event) -> usize {
+        (**self).event_signal_count(event)
+    }
+    fn system_time(&self) -> u64 {
+        (**self).system_time()
+    }
+    fn queue_work<I: AtMost<Dispatch>>(&self, irql: &I, device: Self::Device, queue: WorkQueue, routine: WorkRoutine) -> Result<(), NtError> {
+        (**self).queue_work(irql, device, queue, routine)
+    }
+    fn drain_work(&self, irql: &Passive) {
+        (**self).drain_work(irql);
+    }
+    fn process_image_name<I: AtMost<Apc>>(&self, irql: &I, pid: ProcessId) -> Result<String, NtError> {
+        (**self).process_image_name(irql, pid)
+    }
+    fn process_image_path(&self, irql: &Passive, pid: ProcessId) -> Result<String, NtError> {
+        (**self).process_image_path(irql, pid)
+    }
+    fn process_session_id<I: AtMost<Apc>>(&self, irql: &I, pid: ProcessId) -> Result<u32, NtError> {
+        (**self).process_session_id(irql, pid)
+    }
+    fn terminate_process(&self, irql: &Passive, pid: ProcessId, exit_status: NtError) -> Result<(), NtError> {
+        (**self).terminate_process(irql, pid, exit_status)
+    }
+    fn suspend_process(&self, irql: &Passive, pid: ProcessId) -> Result<(), NtError> {
+        (**self).suspend_process(irql, pid)
+    }
+    fn resume_process(&self, irql: &Passive, pid: ProcessId) -> Result<(), NtError> {
+        (**self).resume_process(irql, pid)
+    }
+    fn open_append_file(&self, irql: &Passive, path: &str) -> Result<Self::File, NtError> {
+        (**self).open_append_file(irql, path)
+    }
+    fn append_file(&self, irql: &Passive, file: &Self::File, data: &[u8]) -> Result<(), NtError> {
+        (**self).append_file(irql, file, data)
+    }
+    fn close_file(&self, irql: &Passive, file: Self::File) {
+        (**self).close_file(irql, file);
+    }
+    fn write_parameter(&self, irql: &Passive, name: &str, value: &RegistryValue) -> Result<(), NtError> {
+        (**self).write_parameter(irql, name, value)
+    }
+    fn register_registry_callback(&self, irql: &Passive, handler: RegistryHandler) -> Result<Self::RegistryCallback, NtError> {
+        (**self).register_registry_callback(irql, handler)
+    }
+    fn unregister_registry_callback(&self, irql: &Passive, callback: Self::RegistryCallback) {
+        (**self).unregister_registry_callback(irql, callback);
+    }
+    fn register_process_notify(&self, irql: &Passive, handler: ProcessHandler) -> Result<Self::ProcessNotify, NtError> {
+        (**self).register_process_notify(irql, handler)
+    }
+    fn register_thread_notify(&self, irql: &Passive, handler: ThreadHandler) -> Result<Self::ThreadNotify, NtError> {
+        (**self).register_thread_notify(irql, handler)
+    }
+    fn register_image_notify(&self, irql: &Passive, handler: ImageHandler) -> Result<Self::ImageNotify, NtError> {
+        (**self).register_image_notify(irql, handler)
+    }
+    fn register_object_callbacks(&self, irql: &Passive, altitude: &str, pre: HandlePreHandler, post: Option<HandlePostHandler>) -> Result<Self::ObjectCallbacks, NtError> {
+        (**self).register_object_callbacks(irql, altitude, pre, post)
+    }
+}
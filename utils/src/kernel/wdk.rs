@@ -0,0 +1,642 @@
+//! [`KernelApi`] backed by the real WDK routines.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::ffi::CStr;
+use core::mem::MaybeUninit;
+use core::ptr::NonNull;
+use core::{mem, ptr};
+use wdk::nt_success;
+use wdk_sys::_CREATE_FILE_TYPE::CreateFileTypeNone;
+use wdk_sys::_OB_PREOP_CALLBACK_STATUS::OB_PREOP_SUCCESS;
+use wdk_sys::ntddk::{CmRegisterCallback, CmUnRegisterCallback, ExFreePool, IoCreateDevice, IoCreateFile, IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink, KeQuerySystemTimePrecise, MmGetSystemRoutineAddress, ObRegisterCallbacks, ObUnRegisterCallbacks, ObfDereferenceObject, PsGetCurrentProcessId, PsGetProcessId, PsGetThreadProcessId, PsLookupProcessByProcessId, PsRemoveCreateThreadNotifyRoutine, PsRemoveLoadImageNotifyRoutine, PsSetCreateProcessNotifyRoutineEx, PsSetCreateThreadNotifyRoutine, PsSetLoadImageNotifyRoutineEx, SeLocateProcessImageName, ZwClose, ZwOpenProcess, ZwTerminateProcess, ZwWriteFile};
+use wdk_sys::{BOOLEAN, CLIENT_ID, DEVICE_OBJECT, DO_BUFFERED_IO, DO_DEVICE_INITIALIZING, DRIVER_OBJECT, FALSE, FILE_ATTRIBUTE_NORMAL, FILE_DEVICE_SECURE_OPEN, FILE_DEVICE_UNKNOWN, FILE_OPEN_IF, FILE_SEQUENTIAL_ONLY, FILE_SHARE_READ, FILE_WRITE_TO_END_OF_FILE, GENERIC_WRITE, HANDLE, IO_STATUS_BLOCK, LARGE_INTEGER, NTSTATUS, OBJECT_ATTRIBUTES, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, OB_CALLBACK_REGISTRATION, OB_FLT_REGISTRATION_VERSION, OB_OPERATION_HANDLE_CREATE, OB_OPERATION_HANDLE_DUPLICATE, OB_OPERATION_REGISTRATION, OB_PREOP_CALLBACK_STATUS, PCHAR, PCUNICODE_STRING, PEPROCESS, PIMAGE_INFO, POBJECT_TYPE, POB_POST_OPERATION_INFORMATION, POB_PRE_OPERATION_INFORMATION, PPS_CREATE_NOTIFY_INFO, PS_IMAGE_NOTIFY_CONFLICTING_ARCHITECTURE, PUNICODE_STRING, PVOID, PsProcessType, PsThreadType, REG_QUERY_VALUE_KEY_INFORMATION, REG_SET_VALUE_KEY_INFORMATION, STATUS_NO_SUCH_MEMBER, STATUS_UNEXPECTED_IO_ERROR, TRUE, ULONG};
+use crate::config::{self, RegistryValue};
+use crate::irql::{Apc, AtMost, Dispatch, Passive};
+use crate::sys::UNICODE_STRING;
+use crate::kernel::{access, EventKind, HandleObject, HandleOperation, HandlePostHandler, HandlePreHandler, HandleRequest, HandleResult, ImageHandler, ImageLoadInfo, KernelApi, ProcessCreateInfo, ProcessHandler, ProcessId, ProcessNotification, RegistryHandler, RegistryNotification, ThreadHandler, ThreadId, ThreadNotification};
+use crate::status::{codes, nt_result, NtError, Status};
+use crate::sync::PushLock;
+use crate::unicode::{OwnedUnicodeString, UnicodeStr};
+use crate::work::{WorkItem, WorkQueue, WorkTracker};
+use crate::{KernelEvent, WindowsUnicode};
+
+type ProcessNameResolver = unsafe extern "system" fn(PEPROCESS) -> PCHAR;
+///`PsSuspendProcess` and `PsResumeProcess`
+type ProcessRoutine = unsafe extern "system" fn(PEPROCESS) -> NTSTATUS;
+type SessionIdResolver = unsafe extern "system" fn(PEPROCESS) -> ULONG;
+
+///`T` must be the function pointer type of the exported routine `name`
+unsafe fn find_system_routine<T>(name: &str) -> Result<T, NtError> {
+    let mut routine_name = name.to_string().to_unicode();
+    let address: PVOID = MmGetSystemRoutineAddress(routine_name.as_mut_ptr());
+    if address.is_null() {
+        crate::error!("Failed to find {name}");
+        Err(NtError::new(STATUS_NO_SUCH_MEMBER))
+    } else {
+        Ok(mem::transmute_copy::<PVOID, T>(&address))
+    }
+}
+
+//calls `routine` with the referenced process object
+//the path is allocated by the kernel for the caller
+unsafe fn locate_image_path(process: PEPROCESS) -> Result<String, NtError> {
+    let mut image_path: PUNICODE_STRING = ptr::null_mut();
+    nt_result(SeLocateProcessImageName(process, &mut image_path))?;
+    let path = UnicodeStr::from_unicode(&*image_path).to_string_lossy();
+    ExFreePool(image_path.cast());
+    Ok(path)
+}
+
+fn with_process<T>(pid: ProcessId, routine: impl FnOnce(PEPROCESS) -> T) -> Result<T, NtError> {
+    let mut process: PEPROCESS = ptr::null_mut();
+    nt_result(unsafe { PsLookupProcessByProcessId(pid as HANDLE, &mut process) })?;
+    let result = routine(process);
+    unsafe { ObfDereferenceObject(process.cast()) };
+    Ok(result)
+}
+
+#[derive(Copy, Clone)]
+pub struct WdkDevice(NonNull<DEVICE_OBJECT>);
+
+unsafe impl Send for WdkDevice {}
+
+unsafe impl Sync for WdkDevice {}
+
+impl WdkDevice {
+    pub fn as_ptr(self) -> *mut DEVICE_OBJECT {
+        self.0.as_ptr()
+    }
+}
+
+pub struct WdkFile(HANDLE);
+
+unsafe impl Send for WdkFile {}
+
+unsafe impl Sync for WdkFile {}
+
+pub struct WdkRegistryCallback {
+    cookie: LARGE_INTEGER,
+    handler: *mut RegistryHandler,
+}
+
+unsafe impl Send for WdkRegistryCallback {}
+
+unsafe impl Sync for WdkRegistryCallback {}
+
+unsafe extern "C" fn registry_trampoline(context: PVOID, first: PVOID, second: PVOID) -> NTSTATUS {
+    let handler = &*context.cast::<RegistryHandler>();
+    //registry callbacks run at PASSIVE_LEVEL
+    let mut irql = Passive::new_unchecked();
+    let notification = match first as i32 {
+        RegistryNotification::PRE_SET_VALUE_KEY => {
+            let info = &*second.cast::<REG_SET_VALUE_KEY_INFORMATION>();
+            RegistryNotification::PreSetValueKey { value_name: UnicodeStr::from_unicode(&*info.ValueName) }
+        }
+        RegistryNotification::PRE_QUERY_VALUE_KEY => {
+            let info = &*second.cast::<REG_QUERY_VALUE_KEY_INFORMATION>();
+            RegistryNotification::PreQueryValueKey { value_name: UnicodeStr::from_unicode(&*info.ValueName) }
+        }
+        other => RegistryNotification::Other(other),
+    };
+    handler(&mut irql, &notification).code()
+}
+
+//notify routines have no context, so the handler of the one registration of each kind lives here;
+//the routines run at PASSIVE_LEVEL or APC_LEVEL, and so do registering and unregistering them
+//with `PsGetProcessImageFileName`, which names an exiting process while it still exists
+static PROCESS_HANDLER: PushLock<Option<(ProcessNameResolver, ProcessHandler)>> = PushLock::new(None);
+static THREAD_HANDLER: PushLock<Option<ThreadHandler>> = PushLock::new(None);
+static IMAGE_HANDLER: PushLock<Option<ImageHandler>> = PushLock::new(None);
+
+//stores the handler, then lets `register` add the routine; the handler is taken back if that fails
+fn install_handler<H>(irql: &Passive, slot: &PushLock<Option<H>>, handler: H, register: impl FnOnce() -> NTSTATUS) -> Result<(), NtError> {
+    {
+        let mut slot = slot.write(irql);
+        if slot.is_some() {
+            return Err(NtError::new(codes::STATUS_OBJECT_NAME_COLLISION));
+        }
+        *slot = Some(handler);
+    }
+    nt_result(register()).map_err(|error| {
+        slot.write(irql).take();
+        error
+    })
+}
+
+//the registrations are dropped at PASSIVE_LEVEL, as their types require
+fn take_handler<H>(slot: &PushLock<Option<H>>) {
+    let irql = unsafe { Passive::new_unchecked() };
+    slot.write(&irql).take();
+}
+
+///unregisters the process notify routine when dropped
+pub struct ProcessNotifyRegistration(());
+
+impl Drop for ProcessNotifyRegistration {
+    fn drop(&mut self) {
+        let _ = unsafe { PsSetCreateProcessNotifyRoutineEx(Some(process_trampoline), TRUE as BOOLEAN) };
+        //the routine is not called any more once it is removed
+        take_handler(&PROCESS_HANDLER);
+    }
+}
+
+///unregisters the thread notify routine when dropped
+pub struct ThreadNotifyRegistration(());
+
+impl Drop for ThreadNotifyRegistration {
+    fn drop(&mut self) {
+        let _ = unsafe { PsRemoveCreateThreadNotifyRoutine(Some(thread_trampoline)) };
+        take_handler(&THREAD_HANDLER);
+    }
+}
+
+///unregisters the image load notify routine when dropped
+pub struct ImageNotifyRegistration(());
+
+impl Drop for ImageNotifyRegistration {
+    fn drop(&mut self) {
+        let _ = unsafe { PsRemoveLoadImageNotifyRoutine(Some(image_trampoline)) };
+        take_handler(&IMAGE_HANDLER);
+    }
+}
+
+unsafe fn optional_unicode<'a>(unicode: PCUNICODE_STRING) -> Option<UnicodeStr<'a>> {
+    if unicode.is_null() {
+        None
+    } else {
+        Some(UnicodeStr::from_unicode(&*unicode))
+    }
+}
+
+unsafe extern "C" fn process_trampoline(process: PEPROCESS, pid: HANDLE, create_info: PPS_CREATE_NOTIFY_INFO) {
+    //process notify routines run at PASSIVE_LEVEL
+    let mut irql = Passive::new_unchecked();
+    let handler = PROCESS_HANDLER.read(&irql);
+    let Some((name_resolver, handler)) = handler.as_ref() else {
+        return;
+    };
+    let pid = pid as ProcessId;
+    let Some(create_info) = create_info.as_mut() else {
+        let image_name = CStr::from_ptr(name_resolver(process)).to_string_lossy().into_owned();
+        let image_path = locate_image_path(process).ok();
+        handler(&mut irql, &mut ProcessNotification::Exit { pid, image_name, image_path });
+        return;
+    };
+    let mut info = ProcessCreateInfo::new(pid, create_info.ParentProcessId as ProcessId);
+    info.creating_pid = create_info.CreatingThreadId.UniqueProcess as ProcessId;
+    info.creating_tid = create_info.CreatingThreadId.UniqueThread as ThreadId;
+    info.image_file_name = optional_unicode(create_info.ImageFileName);
+    info.command_line = optional_unicode(create_info.CommandLine);
+    info.file_open_name_available = create_info.__bindgen_anon_1.__bindgen_anon_1.FileOpenNameAvailable() != 0;
+    let mut notification = ProcessNotification::Create(info);
+    handler(&mut irql, &mut notification);
+    if let ProcessNotification::Create(info) = notification {
+        if info.is_denied() {
+            create_info.CreationStatus = info.creation_status().code();
+        }
+    }
+}
+
+unsafe extern "C" fn thread_trampoline(pid: HANDLE, tid: HANDLE, create: BOOLEAN) {
+    //thread notify routines run at PASSIVE_LEVEL or APC_LEVEL
+    let mut irql = Apc::new_unchecked();
+    let handler = THREAD_HANDLER.read(&irql);
+    let Some(handler) = handler.as_ref() else {
+        return;
+    };
+    let notification = ThreadNotification {
+        pid: pid as ProcessId,
+        tid: tid as ThreadId,
+        current_pid: PsGetCurrentProcessId() as ProcessId,
+        created: create != FALSE as BOOLEAN,
+    };
+    handler(&mut irql, &notification);
+}
+
+unsafe extern "C" fn image_trampoline(image_name: PUNICODE_STRING, pid: HANDLE, image_info: PIMAGE_INFO) {
+    //image load notify routines run at PASSIVE_LEVEL
+    let mut irql = Passive::new_unchecked();
+    let handler = IMAGE_HANDLER.read(&irql);
+    let Some(handler) = handler.as_ref() else {
+        return;
+    };
+    let image_info = &*image_info;
+    let info = ImageLoadInfo {
+        pid: pid as ProcessId,
+        image_base: image_info.ImageBase as usize,
+        image_size: image_info.ImageSize as usize,
+        image_name: optional_unicode(image_name),
+        system_image: image_info.__bindgen_anon_1.__bindgen_anon_1.SystemModeImage() != 0,
+    };
+    handler(&mut irql, &info);
+}
+
+struct ObjectHandlers {
+    pre: HandlePreHandler,
+    post: Option<HandlePostHandler>,
+}
+
+///unregisters the object callbacks when dropped
+pub struct WdkObjectCallbacks {
+    handle: PVOID,
+    handlers: *mut ObjectHandlers,
+}
+
+unsafe impl Send for WdkObjectCallbacks {}
+
+unsafe impl Sync for WdkObjectCallbacks {}
+
+impl Drop for WdkObjectCallbacks {
+    fn drop(&mut self) {
+        unsafe {
+            ObUnRegisterCallbacks(self.handle);
+            //no operation can reach the handlers once the callbacks are unregistered
+            let _ = Box::from_raw(self.handlers);
+        }
+    }
+}
+
+unsafe fn handle_target(object_type: POBJECT_TYPE, object: PVOID) -> Option<(HandleObject, ProcessId)> {
+    if object_type == *PsProcessType {
+        Some((HandleObject::Process, PsGetProcessId(object.cast()) as ProcessId))
+    } else if object_type == *PsThreadType {
+        Some((HandleObject::Thread, PsGetThreadProcessId(object.cast()) as ProcessId))
+    } else {
+        None
+    }
+}
+
+const fn handle_operation(operation: u32) -> HandleOperation {
+    if operation == OB_OPERATION_HANDLE_DUPLICATE {
+        HandleOperation::Duplicate
+    } else {
+        HandleOperation::Create
+    }
+}
+
+unsafe extern "C" fn object_pre_trampoline(context: PVOID, info: POB_PRE_OPERATION_INFORMATION) -> OB_PREOP_CALLBACK_STATUS {
+    let handlers = &*context.cast::<ObjectHandlers>();
+    let info = &mut *info;
+    let Some((object, target_pid)) = handle_target(info.ObjectType, info.Object) else {
+        return OB_PREOP_SUCCESS;
+    };
+    //object callbacks run at PASSIVE_LEVEL or APC_LEVEL
+    let mut irql = Apc::new_unchecked();
+    let operation = handle_operation(info.Operation);
+    let parameters = &mut *info.Parameters;
+    let (desired_access, original_access) = match operation {
+        HandleOperation::Create => {
+            let access = &mut parameters.CreateHandleInformation;
+            (&mut access.DesiredAccess, access.OriginalDesiredAccess)
+        }
+        HandleOperation::Duplicate => {
+            let access = &mut parameters.DuplicateHandleInformation;
+            (&mut access.DesiredAccess, access.OriginalDesiredAccess)
+        }
+    };
+    let mut request = HandleRequest {
+        operation,
+        object,
+        target_pid,
+        requester_pid: PsGetCurrentProcessId() as ProcessId,
+        kernel_handle: info.__bindgen_anon_1.__bindgen_anon_1.KernelHandle() != 0,
+        original_access,
+        desired_access: *desired_access,
+    };
+    (handlers.pre)(&mut irql, &mut request);
+    *desired_access = request.desired_access();
+    OB_PREOP_SUCCESS
+}
+
+unsafe extern "C" fn object_post_trampoline(context: PVOID, info: POB_POST_OPERATION_INFORMATION) {
+    let handlers = &*context.cast::<ObjectHandlers>();
+    let Some(post) = handlers.post.as_ref() else {
+        return;
+    };
+    let info = &*info;
+    let Some((object, target_pid)) = handle_target(info.ObjectType, info.Object) else {
+        return;
+    };
+    //object callbacks run at PASSIVE_LEVEL or APC_LEVEL
+    let mut irql = Apc::new_unchecked();
+    let operation = handle_operation(info.Operation);
+    let parameters = &*info.Parameters;
+    let granted_access = match operation {
+        HandleOperation::Create => parameters.CreateHandleInformation.GrantedAccess,
+        HandleOperation::Duplicate => parameters.DuplicateHandleInformation.GrantedAccess,
+    };
+    let result = HandleResult {
+        operation,
+        object,
+        target_pid,
+        requester_pid: PsGetCurrentProcessId() as ProcessId,
+        kernel_handle: info.__bindgen_anon_1.__bindgen_anon_1.KernelHandle() != 0,
+        status: Status::new(info.ReturnStatus),
+        granted_access,
+    };
+    post(&mut irql, &result);
+}
+
+pub struct WdkKernel {
+    driver: NonNull<DRIVER_OBJECT>,
+    pid_resolver: ProcessNameResolver,
+    suspend_routine: ProcessRoutine,
+    resume_routine: ProcessRoutine,
+    session_resolver: SessionIdResolver,
+    work: Arc<WorkTracker>,
+    //copied, the registry path `DriverEntry` is given does not outlive it
+    parameters_key: String,
+}
+
+unsafe impl Send for WdkKernel {}
+
+unsafe impl Sync for WdkKernel {}
+
+impl WdkKernel {
+    ///`registry_path` is the service key `DriverEntry` is given
+    pub fn new(driver: &mut DRIVER_OBJECT, registry_path: &UNICODE_STRING) -> Result<Self, NtError> {
+        Ok(Self {
+            driver: NonNull::from(driver),
+            pid_resolver: unsafe { find_system_routine("PsGetProcessImageFileName")? },
+            suspend_routine: unsafe { find_system_routine("PsSuspendProcess")? },
+            resume_routine: unsafe { find_system_routine("PsResumeProcess")? },
+            session_resolver: unsafe { find_system_routine("PsGetProcessSessionId")? },
+            work: Arc::new(WorkTracker::new()),
+            parameters_key: config::parameters_key_path(registry_path),
+        })
+    }
+}
+
+impl KernelApi for WdkKernel {
+    type Device = WdkDevice;
+    type Event = KernelEvent;
+    type File = WdkFile;
+    type RegistryCallback = WdkRegistryCallback;
+    type ProcessNotify = ProcessNotifyRegistration;
+    type ThreadNotify = ThreadNotifyRegistration;
+    type ImageNotify = ImageNotifyRegistration;
+    type ObjectCallbacks = WdkObjectCallbacks;
+
+    fn create_device(&self, _irql: &Passive, name: &str) -> Result<Self::Device, NtError> {
+        let to_unicode = |path: String| OwnedUnicodeString::new(&path).map_err(|_| NtError::INVALID_PARAMETER);
+        let mut device_name = to_unicode(format!("\\Device\\{name}"))?;
+        let mut link_name = Box::new(to_unicode(format!("\\DosDevices\\{name}"))?);
+        let mut device: *mut DEVICE_OBJECT = ptr::null_mut();
+        nt_result(unsafe {
+            IoCreateDevice(
+                self.driver.as_ptr(),
+                //the extension keeps the link name until the device is deleted
+                mem::size_of::<*mut OwnedUnicodeString>() as ULONG,
+                device_name.as_mut_ptr(),
+                FILE_DEVICE_UNKNOWN,
+                FILE_DEVICE_SECURE_OPEN,
+                FALSE as BOOLEAN,
+                &mut device,
+            )
+        })?;
+        let mut device = NonNull::new(device).ok_or(NtError::new(STATUS_UNEXPECTED_IO_ERROR))?;
+        if let Err(error) = nt_result(unsafe { IoCreateSymbolicLink(link_name.as_mut_ptr(), device_name.as_mut_ptr()) }) {
+            crate::error!("Failed to link {name} {error}");
+            unsafe { IoDeleteDevice(device.as_ptr()) };
+            return Err(error);
+        }
+        unsafe {
+            let device = device.as_mut();
+            device.DeviceExtension.cast::<*mut OwnedUnicodeString>().write(Box::into_raw(link_name));
+            //the dispatch module hands read and write buffers out of the system buffer
+            device.Flags |= DO_BUFFERED_IO;
+            device.Flags &= !DO_DEVICE_INITIALIZING;
+        }
+        crate::debug!("Device {name} is created");
+        Ok(WdkDevice(device))
+    }
+
+    fn delete_device(&self, _irql: &Passive, device: Self::Device) {
+        unsafe {
+            let mut link_name = Box::from_raw(device.0.as_ref().DeviceExtension.cast::<*mut OwnedUnicodeString>().read());
+            let _ = IoDeleteSymbolicLink(link_name.as_mut_ptr());
+            IoDeleteDevice(device.as_ptr());
+        }
+    }
+
+    fn create_named_event(&self, irql: &Passive, name: &str, kind: EventKind) -> Result<Self::Event, NtError> {
+        KernelEvent::new(irql, name, kind)
+    }
+
+    fn set_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event) {
+        event.set(irql);
+    }
+
+    fn reset_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event) {
+        event.reset(irql);
+    }
+
+    fn pulse_event<I: AtMost<Dispatch>>(&self, irql: &I, event: &Self::Event) {
+        event.pulse(irql);
+    }
+
+    fn event_signal_count(&self, event: &Self::Event) -> usize {
+        event.signal_count()
+    }
+
+    fn system_time(&self) -> u64 {
+        let mut time = LARGE_INTEGER::default();
+        unsafe {
+            KeQuerySystemTimePrecise(&mut time);
+            time.QuadPart as u64
+        }
+    }
+
+    fn queue_work<I: AtMost<Dispatch>>(&self, irql: &I, device: Self::Device, queue: WorkQueue, routine: WorkRoutine) -> Result<(), NtError> {
+        WorkItem::new(irql, device.as_ptr(), routine)?
+            .tracked_by(&self.work)
+            .queue(irql, queue);
+        Ok(())
+    }
+
+    fn drain_work(&self, irql: &Passive) {
+        self.work.wait_drained(irql);
+    }
+
+    fn process_image_name<I: AtMost<Apc>>(&self, _irql: &I, pid: ProcessId) -> Result<String, NtError> {
+        let mut process: PEPROCESS = ptr::null_mut();
+        nt_result(unsafe { PsLookupProcessByProcessId(pid as HANDLE, &mut process) })?;
+        let name = unsafe {
+            let name = CStr::from_ptr((self.pid_resolver)(process)).to_string_lossy().into_owned();
+            ObfDereferenceObject(process.cast());
+            name
+        };
+        Ok(name)
+    }
+
+    fn process_image_path(&self, _irql: &Passive, pid: ProcessId) -> Result<String, NtError> {
+        with_process(pid, |process| unsafe { locate_image_path(process) })?
+    }
+
+    fn process_session_id<I: AtMost<Apc>>(&self, _irql: &I, pid: ProcessId) -> Result<u32, NtError> {
+        with_process(pid, |process| unsafe { (self.session_resolver)(process) })
+    }
+
+    fn terminate_process(&self, _irql: &Passive, pid: ProcessId, exit_status: NtError) -> Result<(), NtError> {
+        let mut process: HANDLE = ptr::null_mut();
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: mem::size_of::<OBJECT_ATTRIBUTES>() as _,
+            RootDirectory: ptr::null_mut(),
+            ObjectName: ptr::null_mut(),
+            Attributes: OBJ_KERNEL_HANDLE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        };
+        let mut client_id = CLIENT_ID { UniqueProcess: pid as HANDLE, UniqueThread: ptr::null_mut() };
+        nt_result(unsafe { ZwOpenProcess(&mut process, access::PROCESS_TERMINATE, &mut attributes, &mut client_id) })?;
+        let status = unsafe { ZwTerminateProcess(process, exit_status.code()) };
+        unsafe { ZwClose(process) };
+        nt_result(status).map(|_| ())
+    }
+
+    fn suspend_process(&self, _irql: &Passive, pid: ProcessId) -> Result<(), NtError> {
+        nt_result(with_process(pid, |process| unsafe { (self.suspend_routine)(process) })?).map(|_| ())
+    }
+
+    fn resume_process(&self, _irql: &Passive, pid: ProcessId) -> Result<(), NtError> {
+        nt_result(with_process(pid, |process| unsafe { (self.resume_routine)(process) })?).map(|_| ())
+    }
+
+    fn open_append_file(&self, _irql: &Passive, path: &str) -> Result<Self::File, NtError> {
+        let mut file: HANDLE = ptr::null_mut();
+        let mut io_status_block = IO_STATUS_BLOCK::default();
+        let mut file_name = path.to_string().to_unicode();
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: mem::size_of::<OBJECT_ATTRIBUTES>() as _,
+            RootDirectory: ptr::null_mut(),
+            ObjectName: file_name.as_mut_ptr(),
+            Attributes: OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        };
+        nt_result(unsafe {
+            IoCreateFile(
+                &mut file,
+                GENERIC_WRITE,
+                &mut attributes,
+                &mut io_status_block,
+                ptr::null_mut(),
+                FILE_ATTRIBUTE_NORMAL,
+                FILE_SHARE_READ,
+                FILE_OPEN_IF,
+                FILE_SEQUENTIAL_ONLY,
+                ptr::null_mut(),
+                0,
+                CreateFileTypeNone,
+                ptr::null_mut(),
+                0,
+            )
+        })?;
+        Ok(WdkFile(file))
+    }
+
+    fn append_file(&self, _irql: &Passive, file: &Self::File, data: &[u8]) -> Result<(), NtError> {
+        let mut io_status_block = MaybeUninit::<IO_STATUS_BLOCK>::uninit();
+        let mut offset = LARGE_INTEGER::default();
+        unsafe {
+            offset.u.HighPart = -1;
+            offset.u.LowPart = FILE_WRITE_TO_END_OF_FILE;
+        }
+        let status = unsafe {
+            ZwWriteFile(file.0,
+                        ptr::null_mut(),
+                        None,
+                        ptr::null_mut(),
+                        io_status_block.as_mut_ptr(),
+                        data.as_ptr() as _,
+                        data.len() as ULONG,
+                        &mut offset,
+                        ptr::null_mut(),
+            )
+        };
+        nt_result(status).map(|_| ())
+    }
+
+    fn close_file(&self, _irql: &Passive, file: Self::File) {
+        let _ = unsafe { ZwClose(file.0) };
+    }
+
+    fn write_parameter(&self, irql: &Passive, name: &str, value: &RegistryValue) -> Result<(), NtError> {
+        config::write_parameter(irql, &self.parameters_key, name, value)
+    }
+
+    fn register_registry_callback(&self, _irql: &Passive, handler: RegistryHandler) -> Result<Self::RegistryCallback, NtError> {
+        let handler = Box::into_raw(Box::new(handler));
+        let mut cookie = LARGE_INTEGER::default();
+        let status = unsafe { CmRegisterCallback(Some(registry_trampoline), handler.cast(), &mut cookie) };
+        if !nt_success(status) {
+            let _ = unsafe { Box::from_raw(handler) };
+            return Err(NtError::new(status));
+        }
+        Ok(WdkRegistryCallback { cookie, handler })
+    }
+
+    fn unregister_registry_callback(&self, _irql: &Passive, callback: Self::RegistryCallback) {
+        unsafe {
+            let _ = CmUnRegisterCallback(callback.cookie);
+            //no notification can reach the handler once the callback is unregistered
+            let _ = Box::from_raw(callback.handler);
+        }
+    }
+
+    fn register_process_notify(&self, irql: &Passive, handler: ProcessHandler) -> Result<Self::ProcessNotify, NtError> {
+        let register = || unsafe { PsSetCreateProcessNotifyRoutineEx(Some(process_trampoline), FALSE as BOOLEAN) };
+        if let Err(error) = install_handler(irql, &PROCESS_HANDLER, (self.pid_resolver, handler), register) {
+            if error.code() == codes::STATUS_ACCESS_DENIED {
+                crate::error!("The Ex process notify routine needs a driver image linked with /INTEGRITYCHECK");
+            }
+            return Err(error);
+        }
+        Ok(ProcessNotifyRegistration(()))
+    }
+
+    //the routine that is not Ex runs in the creating thread, which tells remote threads apart
+    fn register_thread_notify(&self, irql: &Passive, handler: ThreadHandler) -> Result<Self::ThreadNotify, NtError> {
+        let register = || unsafe { PsSetCreateThreadNotifyRoutine(Some(thread_trampoline)) };
+        install_handler(irql, &THREAD_HANDLER, handler, register)?;
+        Ok(ThreadNotifyRegistration(()))
+    }
+
+    fn register_image_notify(&self, irql: &Passive, handler: ImageHandler) -> Result<Self::ImageNotify, NtError> {
+        //images of the other architecture are reported too, e.g. an x86 DLL in a WoW64 process
+        let flags = PS_IMAGE_NOTIFY_CONFLICTING_ARCHITECTURE as _;
+        let register = || unsafe { PsSetLoadImageNotifyRoutineEx(Some(image_trampoline), flags) };
+        install_handler(irql, &IMAGE_HANDLER, handler, register)?;
+        Ok(ImageNotifyRegistration(()))
+    }
+
+    fn register_object_callbacks(&self, _irql: &Passive, altitude: &str, pre: HandlePreHandler, post: Option<HandlePostHandler>) -> Result<Self::ObjectCallbacks, NtError> {
+        let altitude = OwnedUnicodeString::new(altitude).map_err(|_| NtError::INVALID_PARAMETER)?;
+        let post_operation = post.as_ref().map(|_| object_post_trampoline as unsafe extern "C" fn(_, _));
+        let handlers = Box::into_raw(Box::new(ObjectHandlers { pre, post }));
+        let operation = |object_type| OB_OPERATION_REGISTRATION {
+            ObjectType: object_type,
+            Operations: OB_OPERATION_HANDLE_CREATE | OB_OPERATION_HANDLE_DUPLICATE,
+            PreOperation: Some(object_pre_trampoline),
+            PostOperation: post_operation,
+        };
+        let mut operations = unsafe { [operation(PsProcessType), operation(PsThreadType)] };
+        //the registration, altitude included, is copied by the object manager
+        let mut registration = OB_CALLBACK_REGISTRATION {
+            Version: OB_FLT_REGISTRATION_VERSION as _,
+            OperationRegistrationCount: operations.len() as _,
+            Altitude: *altitude,
+            RegistrationContext: handlers.cast(),
+            OperationRegistration: operations.as_mut_ptr(),
+        };
+        let mut handle = ptr::null_mut();
+        if let Err(error) = nt_result(unsafe { ObRegisterCallbacks(&mut registration, &mut handle) }) {
+            let _ = unsafe { Box::from_raw(handlers) };
+            return Err(error);
+        }
+        Ok(WdkObjectCallbacks { handle, handlers })
+    }
+}
@@ -1,86 +1,159 @@
 #![no_std]
 
 use alloc::string::String;
-use alloc::vec::Vec;
-use core::{mem, ptr, slice};
-use wdk::println;
-use wdk_sys::ntddk::{IoCreateNotificationEvent, KeClearEvent, KeSetEvent, PsSetCreateProcessNotifyRoutine, RtlInitUnicodeString, ZwClose};
-use wdk_sys::{BOOLEAN, FALSE, HANDLE, IRP, NTSTATUS, PCREATE_PROCESS_NOTIFY_ROUTINE, PIO_STACK_LOCATION, PKEVENT, STATUS_UNEXPECTED_IO_ERROR, TRUE, UNICODE_STRING};
+#[cfg(target_os = "windows")]
+use core::ptr;
+#[cfg(target_os = "windows")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(target_os = "windows")]
+use wdk_sys::ntddk::{IoCreateNotificationEvent, IoCreateSynchronizationEvent, KeClearEvent, KePulseEvent, KeReadStateEvent, KeSetEvent, ZwClose};
+#[cfg(target_os = "windows")]
+use wdk_sys::{BOOLEAN, FALSE, HANDLE, IRP, PIO_STACK_LOCATION, PKEVENT, STATUS_UNEXPECTED_IO_ERROR};
+use sys::UNICODE_STRING;
 
 extern crate alloc;
+//the host build of the locks maps them to std
+#[cfg(not(target_os = "windows"))]
+extern crate std;
 
+pub mod config;
+pub mod dispatch;
+pub mod ioctl;
+pub mod irql;
+pub mod kernel;
+pub mod log;
+pub mod path;
+pub mod pool;
+pub mod ring;
+pub mod status;
+pub mod sync;
+pub mod sys;
+pub mod timer;
+pub mod unicode;
+pub mod work;
+
+pub use irql::{Apc, AtMost, Dispatch, Passive};
+pub use kernel::{EventKind, KernelApi};
+pub use status::{nt_result, NtError, Status};
+
+#[cfg(target_os = "windows")]
+pub use wdk::println;
+
+/// Off-target stand-in for `wdk::println!`: the arguments are type-checked and dropped.
+#[cfg(not(target_os = "windows"))]
+#[macro_export]
+macro_rules! println {
+    () => {};
+    ($($arg:tt)*) => {{
+        let _ = format_args!($($arg)*);
+    }};
+}
+pub use unicode::{OwnedUnicodeString, UnicodeError, UnicodeStr};
+#[cfg(target_os = "windows")]
+pub use work::WorkItem;
+pub use work::{WorkQueue, WorkTracker};
+pub use timer::{Timer, TimerMode};
+
+/// A named kernel event shared with user mode.
+///
+/// * `set` signals the event. A notification event stays signaled until
+///   `reset` and releases every waiter; a synchronization event releases
+///   exactly one waiter and resets itself, or stays signaled until the next
+///   wait when nobody is waiting. Either way a signal is never lost, but
+///   several signals before a wait are coalesced into one.
+/// * `reset` returns the event to the not-signaled state.
+/// * `pulse` releases the waiters that are blocked right now and leaves the
+///   event not signaled, so a consumer that is not waiting misses it.
+///
+/// Every `set` and `pulse` is counted, so comparing `signal_count` with the
+/// number of completed waits tells how many signals were coalesced.
+/// The handle is closed on drop, which must happen at `PASSIVE_LEVEL`.
+#[cfg(target_os = "windows")]
 pub struct KernelEvent {
     handle: HANDLE,
     event: PKEVENT,
+    kind: EventKind,
+    signals: AtomicUsize,
 }
 
+#[cfg(target_os = "windows")]
+unsafe impl Send for KernelEvent {}
+
+#[cfg(target_os = "windows")]
+unsafe impl Sync for KernelEvent {}
+
+#[cfg(target_os = "windows")]
 impl KernelEvent {
-    pub fn new(event_name: &String) -> Result<Self, NTSTATUS> {
+    pub fn new(_irql: &Passive, event_name: &str, kind: EventKind) -> Result<Self, NtError> {
         let mut handle: HANDLE = ptr::null_mut();
-        let mut unicode_event_name = event_name.to_unicode();
-        println!("The unicode len={} and buf_len={}", unicode_event_name.Length, unicode_event_name.MaximumLength);
+        let mut unicode_event_name = OwnedUnicodeString::new(event_name)
+            .map_err(|_| NtError::INVALID_PARAMETER)?;
         let event = unsafe {
-            IoCreateNotificationEvent(&mut unicode_event_name, &mut handle)
+            match kind {
+                EventKind::Notification => IoCreateNotificationEvent(unicode_event_name.as_mut_ptr(), &mut handle),
+                EventKind::Synchronization => IoCreateSynchronizationEvent(unicode_event_name.as_mut_ptr(), &mut handle),
+            }
         };
         if handle.is_null() || event.is_null() {
-            println!("Event or handle is null");
-            return Err(STATUS_UNEXPECTED_IO_ERROR);
+            crate::error!("Event or handle of {event_name} is null");
+            return Err(NtError::new(STATUS_UNEXPECTED_IO_ERROR));
         }
-        println!("Event {event_name} is created");
+        crate::debug!("Event {event_name} is created");
+        //the event may already exist and be signaled
         unsafe { KeClearEvent(event) };
-        Ok(Self { handle, event })
+        Ok(Self { handle, event, kind, signals: AtomicUsize::new(0) })
     }
-    pub fn raise(&mut self) {
-        unsafe {
-            KeSetEvent(self.event, 0, FALSE as BOOLEAN);
-            KeClearEvent(self.event);
-        }
+    pub const fn kind(&self) -> EventKind {
+        self.kind
+    }
+    pub fn set<I: AtMost<Dispatch>>(&self, _irql: &I) {
+        self.signals.fetch_add(1, Ordering::Relaxed);
+        unsafe { KeSetEvent(self.event, 0, FALSE as BOOLEAN) };
+    }
+    pub fn reset<I: AtMost<Dispatch>>(&self, _irql: &I) {
+        unsafe { KeClearEvent(self.event) };
+    }
+    pub fn pulse<I: AtMost<Dispatch>>(&self, _irql: &I) {
+        self.signals.fetch_add(1, Ordering::Relaxed);
+        unsafe { KePulseEvent(self.event, 0, FALSE as BOOLEAN) };
     }
-    pub unsafe fn free(&mut self) {
-        let _ = ZwClose(self.handle);
+    pub fn is_signaled(&self) -> bool {
+        unsafe { KeReadStateEvent(self.event) != 0 }
+    }
+    ///how many times the event was set or pulsed since it was created
+    pub fn signal_count(&self) -> usize {
+        self.signals.load(Ordering::Relaxed)
     }
 }
 
+#[cfg(target_os = "windows")]
+impl Drop for KernelEvent {
+    fn drop(&mut self) {
+        let _ = unsafe { ZwClose(self.handle) };
+    }
+}
+
+#[cfg(target_os = "windows")]
 pub fn get_current_io_stack_location(irp: &IRP) -> PIO_STACK_LOCATION {
     unsafe {
         irp.Tail.Overlay.__bindgen_anon_2.__bindgen_anon_1.CurrentStackLocation
     }
 }
 
-pub fn add_notify_callback(callback: PCREATE_PROCESS_NOTIFY_ROUTINE) -> NTSTATUS {
-    unsafe { PsSetCreateProcessNotifyRoutine(callback, FALSE as BOOLEAN) }
-}
-
-pub fn remove_notify_callback(callback: PCREATE_PROCESS_NOTIFY_ROUTINE) -> NTSTATUS {
-    unsafe { PsSetCreateProcessNotifyRoutine(callback, TRUE as BOOLEAN) }
-}
 pub trait WindowsUnicode {
-    fn to_unicode(&self) -> UNICODE_STRING;
+    /// # Panics
+    /// If the value is longer than `unicode::MAX_UNICODE_LEN` UTF-16 units.
+    fn to_unicode(&self) -> OwnedUnicodeString;
     fn from_unicode(value: &UNICODE_STRING) -> Self;
 }
 
 impl WindowsUnicode for String {
-    fn to_unicode(&self) -> UNICODE_STRING {
-        let bytes = self.as_bytes();
-        let mut buffer = Vec::<u16>::with_capacity(bytes.len() + 1);
-        for byte in bytes {
-            buffer.push(u16::from(*byte));
-        }
-        buffer.push(0u16);
-        let mut result = UNICODE_STRING::default();
-        unsafe {
-            RtlInitUnicodeString(&mut result,
-                                 Vec::leak(buffer).as_ptr());
-        };
-        result
+    fn to_unicode(&self) -> OwnedUnicodeString {
+        OwnedUnicodeString::new(self).expect("The string should fit UNICODE_STRING")
     }
 
     fn from_unicode(unicode: &UNICODE_STRING) -> Self {
-        let slice = unsafe {
-            slice::from_raw_parts(
-                unicode.Buffer,
-                unicode.Length as usize / mem::size_of_val(&(*unicode.Buffer)))
-        };
-        String::from_utf16_lossy(slice)
+        let units = unsafe { unicode::unicode_units(unicode) };
+        unicode::decode_utf16_lossy(units)
     }
 }
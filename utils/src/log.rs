@@ -0,0 +1,557 @@
+//! Leveled logging that never allocates.
+//!
+//! The [`error!`](crate::error), [`warn!`](crate::warn), [`info!`](crate::info),
+//! [`debug!`](crate::debug) and [`trace!`](crate::trace) macros tag a message
+//! with its level and the module it comes from. A message passes when its
+//! level is within the filter of the longest matching target prefix, or the
+//! global maximum level otherwise; both can change at any time. A message
+//! that passes is formatted into a fixed buffer on the stack, printed to the
+//! debugger and kept in a ring of recent messages that user mode fetches with
+//! [`IOCTL_LOG_READ`]. Nothing here touches the heap, so logging is safe up
+//! to `DISPATCH_LEVEL`.
+
+use core::fmt::{Display, Formatter, Write};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use crate::config::{ConfigError, Parameters};
+use crate::dispatch::IoResult;
+use crate::ioctl::{ControlCode, Ioctl, IoctlTable, RequiredAccess, TransferMethod};
+use crate::irql::{AtMost, Dispatch};
+use crate::status::NtError;
+use crate::sync::SpinLock;
+#[cfg(target_os = "windows")]
+use wdk_sys::ntddk::DbgPrint;
+
+///a longer message is cut at a character boundary
+pub const MESSAGE_LEN: usize = 160;
+///how many messages are kept for user mode; the oldest is overwritten first
+pub const RECENT_CAPACITY: usize = 64;
+///how many target prefixes may have a filter of their own
+pub const MAX_TARGETS: usize = 8;
+
+///copies the oldest recent messages, one per line, into the output buffer and forgets them
+pub const IOCTL_LOG_READ: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x100,
+    TransferMethod::Buffered,
+    RequiredAccess::Read,
+);
+///sets the global maximum level from a `u32`, 0 turning logging off
+pub const IOCTL_LOG_SET_LEVEL: ControlCode = ControlCode::new(
+    ControlCode::DEVICE_UNKNOWN,
+    ControlCode::FIRST_CUSTOM_FUNCTION + 0x101,
+    TransferMethod::Buffered,
+    RequiredAccess::Write,
+);
+
+///the registry value under the `Parameters` key of the driver that holds the level
+pub const LEVEL_VALUE_NAME: &str = "LogLevel";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        }
+    }
+    ///the raw filter value: 0 is off, 1 to 5 are the levels
+    pub fn filter_from_raw(raw: u32) -> Result<Option<Self>, NtError> {
+        match raw {
+            0 => Ok(None),
+            1 => Ok(Some(Self::Error)),
+            2 => Ok(Some(Self::Warn)),
+            3 => Ok(Some(Self::Info)),
+            4 => Ok(Some(Self::Debug)),
+            5 => Ok(Some(Self::Trace)),
+            _ => Err(NtError::INVALID_PARAMETER),
+        }
+    }
+    const fn filter_to_raw(filter: Option<Self>) -> u8 {
+        match filter {
+            Some(level) => level as u8,
+            None => 0,
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+///`None` turns logging off
+pub fn set_max_level(filter: Option<Level>) {
+    MAX_LEVEL.store(Level::filter_to_raw(filter), Ordering::Relaxed);
+}
+
+pub fn max_level() -> Option<Level> {
+    Level::filter_from_raw(u32::from(MAX_LEVEL.load(Ordering::Relaxed))).unwrap_or(None)
+}
+
+struct TargetFilter {
+    claimed: AtomicBool,
+    target: spin::Once<&'static str>,
+    level: AtomicU8,
+}
+
+static TARGETS: [TargetFilter; MAX_TARGETS] = [const {
+    TargetFilter { claimed: AtomicBool::new(false), target: spin::Once::new(), level: AtomicU8::new(0) }
+}; MAX_TARGETS];
+
+/// Gives the modules under `target` (a module path such as
+/// `process_driver::spy`) a filter of their own, or changes it.
+///
+/// # Errors
+/// `STATUS_INSUFFICIENT_RESOURCES` when [`MAX_TARGETS`] other targets already have one.
+pub fn set_target_level(target: &'static str, filter: Option<Level>) -> Result<(), NtError> {
+    let raw = Level::filter_to_raw(filter);
+    for slot in &TARGETS {
+        if slot.target.get() == Some(&target) {
+            slot.level.store(raw, Ordering::Relaxed);
+            return Ok(());
+        }
+    }
+    for slot in &TARGETS {
+        if !slot.claimed.swap(true, Ordering::AcqRel) {
+            slot.level.store(raw, Ordering::Relaxed);
+            slot.target.call_once(|| target);
+            return Ok(());
+        }
+    }
+    Err(NtError::INSUFFICIENT_RESOURCES)
+}
+
+///whether a message of `level` from `target` would be logged
+pub fn enabled(level: Level, target: &str) -> bool {
+    let mut longest = 0;
+    let mut raw = MAX_LEVEL.load(Ordering::Relaxed);
+    for slot in &TARGETS {
+        let Some(prefix) = slot.target.get() else {
+            continue;
+        };
+        if prefix.len() >= longest && is_target_prefix(prefix, target) {
+            longest = prefix.len();
+            raw = slot.level.load(Ordering::Relaxed);
+        }
+    }
+    level as u8 <= raw
+}
+
+//`a::b` covers `a::b` and `a::b::c`, not `a::bc`
+fn is_target_prefix(prefix: &str, target: &str) -> bool {
+    target.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// A logged message as it is kept for user mode.
+#[derive(Copy, Clone)]
+pub struct Message {
+    ///counts every message that passed the filter, so a gap shows overwritten ones
+    pub sequence: u64,
+    pub level: Level,
+    pub target: &'static str,
+    len: usize,
+    text: [u8; MESSAGE_LEN],
+}
+
+impl Message {
+    const EMPTY: Self = Self { sequence: 0, level: Level::Error, target: "", len: 0, text: [0; MESSAGE_LEN] };
+
+    pub fn text(&self) -> &str {
+        core::str::from_utf8(&self.text[..self.len]).unwrap_or_default()
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}: {}", self.sequence, self.level, self.target, self.text())
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("sequence", &self.sequence)
+            .field("level", &self.level)
+            .field("target", &self.target)
+            .field("text", &self.text())
+            .finish()
+    }
+}
+
+//keeps the text that fits, cut at a character boundary, and one byte for the terminating nul
+struct MessageWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let room = self.buffer.len().saturating_sub(self.len + 1);
+        let mut take = usize::min(room, text.len());
+        while !text.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buffer[self.len..self.len + take].copy_from_slice(&text.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+//writes whole lines only, failing once the next one does not fit
+struct LineWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for LineWriter<'_> {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        let end = self.len + text.len();
+        if end > self.buffer.len() {
+            return Err(fmt::Error);
+        }
+        self.buffer[self.len..end].copy_from_slice(text.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+struct RecentMessages {
+    slots: [Message; RECENT_CAPACITY],
+    start: usize,
+    len: usize,
+}
+
+impl RecentMessages {
+    fn push(&mut self, message: Message) {
+        if self.len == RECENT_CAPACITY {
+            self.start = (self.start + 1) % RECENT_CAPACITY;
+            self.len -= 1;
+            OVERWRITTEN.fetch_add(1, Ordering::Relaxed);
+        }
+        self.slots[(self.start + self.len) % RECENT_CAPACITY] = message;
+        self.len += 1;
+    }
+    fn front(&self) -> Option<&Message> {
+        (self.len != 0).then(|| &self.slots[self.start])
+    }
+    fn pop_front(&mut self) {
+        self.start = (self.start + 1) % RECENT_CAPACITY;
+        self.len -= 1;
+    }
+}
+
+static RECENT: SpinLock<RecentMessages> = SpinLock::new(RecentMessages {
+    slots: [Message::EMPTY; RECENT_CAPACITY],
+    start: 0,
+    len: 0,
+});
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static OVERWRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+///how many messages were overwritten before user mode read them
+pub fn overwritten_messages() -> usize {
+    OVERWRITTEN.load(Ordering::Relaxed)
+}
+
+///what the macros expand to
+pub fn log(level: Level, target: &'static str, args: fmt::Arguments<'_>) {
+    if !enabled(level, target) {
+        return;
+    }
+    let mut message = Message {
+        sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        level,
+        target,
+        len: 0,
+        text: [0; MESSAGE_LEN],
+    };
+    let mut writer = MessageWriter { buffer: &mut message.text, len: 0 };
+    let _ = writer.write_fmt(args);
+    message.len = writer.len;
+    print(&message);
+    //above DISPATCH_LEVEL the message only goes to the debugger
+    if let Some(mut irql) = Dispatch::current() {
+        RECENT.lock(&mut irql).push(message);
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn print(message: &Message) {
+    //the text is nul terminated by the writer, the target is not
+    unsafe {
+        DbgPrint(
+            c"%s %.*s: %s\n".as_ptr(),
+            level_name(message.level).as_ptr(),
+            message.target.len() as i32,
+            message.target.as_ptr(),
+            message.text.as_ptr(),
+        );
+    }
+}
+
+#[cfg(target_os = "windows")]
+const fn level_name(level: Level) -> &'static core::ffi::CStr {
+    match level {
+        Level::Error => c"ERROR",
+        Level::Warn => c"WARN",
+        Level::Info => c"INFO",
+        Level::Debug => c"DEBUG",
+        Level::Trace => c"TRACE",
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn print(_message: &Message) {}
+
+///removes the oldest recent message and returns it
+pub fn pop_recent<I: AtMost<Dispatch>>(irql: &mut I) -> Option<Message> {
+    let mut recent = RECENT.lock(irql);
+    let message = recent.front().copied();
+    if message.is_some() {
+        recent.pop_front();
+    }
+    message
+}
+
+/// Writes the oldest recent messages into `output`, one line each, as long
+/// as whole lines fit, and returns how many bytes were written.
+///
+/// # Errors
+/// `STATUS_BUFFER_TOO_SMALL` when not even the oldest message fits.
+pub fn read_recent<I: AtMost<Dispatch>>(irql: &mut I, output: &mut [u8]) -> IoResult {
+    let mut recent = RECENT.lock(irql);
+    let mut writer = LineWriter { buffer: output, len: 0 };
+    while let Some(message) = recent.front() {
+        let written = writer.len;
+        if writeln!(writer, "{message}").is_err() {
+            writer.len = written;
+            break;
+        }
+        recent.pop_front();
+    }
+    if writer.len == 0 && recent.front().is_some() {
+        return Err(NtError::BUFFER_TOO_SMALL);
+    }
+    Ok(writer.len)
+}
+
+///[`IOCTL_LOG_SET_LEVEL`], with the raw filter of [`Level::filter_from_raw`] as input
+pub struct SetLevel;
+
+impl Ioctl for SetLevel {
+    const CODE: ControlCode = IOCTL_LOG_SET_LEVEL;
+    type Input = u32;
+    type Output = ();
+}
+
+///adds [`IOCTL_LOG_READ`] and [`IOCTL_LOG_SET_LEVEL`] to the IOCTLs of a device
+pub fn add_ioctls<C: ?Sized>(table: IoctlTable<C>) -> IoctlTable<C> {
+    table
+        .with_raw(IOCTL_LOG_READ, |_, _, output| {
+            let mut irql = Dispatch::current().ok_or(NtError::UNSUCCESSFUL)?;
+            read_recent(&mut irql, output)
+        })
+        .with::<SetLevel, _>(|_, raw| {
+            set_max_level(Level::filter_from_raw(*raw)?);
+            Ok(())
+        })
+}
+
+/// Sets the maximum level from the `LogLevel` `REG_DWORD` of the driver
+/// parameters and returns it; without the value the level stays as it is.
+///
+/// # Errors
+/// When the value is not a `REG_DWORD` or not a level; the level is left as it was.
+pub fn load_max_level(parameters: &Parameters) -> Result<Option<Level>, ConfigError> {
+    let Some(raw) = parameters.dword(LEVEL_VALUE_NAME)? else {
+        return Ok(max_level());
+    };
+    let filter = Level::filter_from_raw(raw).map_err(|_| ConfigError::invalid(LEVEL_VALUE_NAME, "not a log level"))?;
+    set_max_level(filter);
+    Ok(filter)
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level: expr, $($arg:tt)+) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Error, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Warn, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Info, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Debug, $($arg)+)
+    };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::log!($crate::log::Level::Trace, $($arg)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use crate::config::RegistryValue;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    //the filters and the recent messages are global
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    //a target of its own, so turning the global level off filters out what other tests log
+    const TARGET: &str = "log_tests";
+
+    fn dispatch() -> Dispatch {
+        Dispatch::current().unwrap()
+    }
+
+    //serializes the test and leaves the recent messages to the ones it logs
+    fn quiet() -> MutexGuard<'static, ()> {
+        let serial = SERIAL.lock().unwrap_or_else(PoisonError::into_inner);
+        set_max_level(None);
+        set_target_level(TARGET, Some(Level::Trace)).unwrap();
+        while pop_recent(&mut dispatch()).is_some() {}
+        serial
+    }
+
+    #[test]
+    fn targets_cover_their_submodules_only() {
+        assert!(is_target_prefix("a::b", "a::b"));
+        assert!(is_target_prefix("a::b", "a::b::c"));
+        assert!(!is_target_prefix("a::b", "a::bc"));
+        assert!(!is_target_prefix("a::b::c", "a::b"));
+        assert!(!is_target_prefix("b", "a::b"));
+    }
+
+    #[test]
+    fn the_longest_target_prefix_decides() {
+        let _serial = quiet();
+        set_target_level("outer", Some(Level::Warn)).unwrap();
+        set_target_level("outer::inner", Some(Level::Debug)).unwrap();
+        assert!(enabled(Level::Warn, "outer::other") && !enabled(Level::Info, "outer::other"));
+        assert!(enabled(Level::Debug, "outer::inner::deep") && !enabled(Level::Trace, "outer::inner"));
+        //neither prefix covers it, so the global level, which is off, applies
+        assert!(!enabled(Level::Error, "outerx"));
+        set_target_level("outer::inner", None).unwrap();
+        assert!(!enabled(Level::Error, "outer::inner"));
+        assert!(enabled(Level::Error, "outer"));
+        set_max_level(Some(Level::Info));
+        assert!(enabled(Level::Info, "elsewhere") && !enabled(Level::Debug, "elsewhere"));
+        assert!(!enabled(Level::Info, "outer::inner"));
+        set_max_level(None);
+    }
+
+    #[test]
+    fn messages_are_cut_at_a_character_boundary_before_the_nul() {
+        let mut buffer = [0u8; 6];
+        let mut writer = MessageWriter { buffer: &mut buffer, len: 0 };
+        writer.write_str("ab").unwrap();
+        writer.write_str("\u{e9}\u{e9}").unwrap();
+        //five bytes of room take one two-byte character, not half of the next
+        assert_eq!(writer.len, 4);
+        assert_eq!(&buffer, b"ab\xc3\xa9\0\0");
+        let _serial = quiet();
+        log(Level::Info, TARGET, format_args!("{}", "\u{e9}".repeat(MESSAGE_LEN)));
+        let message = pop_recent(&mut dispatch()).unwrap();
+        assert_eq!(message.text().len(), MESSAGE_LEN - 2);
+        assert!(message.text().chars().all(|character| character == '\u{e9}'));
+        assert_eq!(message.text[MESSAGE_LEN - 1], 0);
+    }
+
+    #[test]
+    fn the_oldest_recent_messages_are_overwritten_and_counted() {
+        let _serial = quiet();
+        let overwritten = overwritten_messages();
+        for index in 0..RECENT_CAPACITY + 3 {
+            log(Level::Debug, TARGET, format_args!("{index}"));
+        }
+        assert_eq!(overwritten_messages(), overwritten + 3);
+        let mut irql = dispatch();
+        let first = pop_recent(&mut irql).unwrap();
+        assert_eq!((first.text(), first.level, first.target), ("3", Level::Debug, TARGET));
+        let mut last = first;
+        let mut kept = 1;
+        while let Some(message) = pop_recent(&mut irql) {
+            assert_eq!(message.sequence, last.sequence + 1);
+            (last, kept) = (message, kept + 1);
+        }
+        assert_eq!((kept, last.text()), (RECENT_CAPACITY, format!("{}", RECENT_CAPACITY + 2).as_str()));
+    }
+
+    #[test]
+    fn reads_hand_out_whole_lines_only() {
+        let _serial = quiet();
+        log(Level::Warn, TARGET, format_args!("first"));
+        log(Level::Error, TARGET, format_args!("second"));
+        let mut irql = dispatch();
+        let first_line = RECENT.lock(&mut irql).front().map(|message| format!("{message}\n")).unwrap();
+        assert!(first_line.ends_with(" WARN log_tests: first\n"));
+        let mut output = [0u8; 256];
+        assert_eq!(read_recent(&mut irql, &mut output[..first_line.len() - 1]), Err(NtError::BUFFER_TOO_SMALL));
+        //the second line does not fit behind the first and waits for the next read
+        let length = read_recent(&mut irql, &mut output[..first_line.len() + 5]).unwrap();
+        assert_eq!(&output[..length], first_line.as_bytes());
+        let length = read_recent(&mut irql, &mut output).unwrap();
+        assert!(core::str::from_utf8(&output[..length]).unwrap().ends_with(" ERROR log_tests: second\n"));
+        assert_eq!(read_recent(&mut irql, &mut output), Ok(0));
+    }
+
+    #[test]
+    fn the_level_is_loaded_from_the_parameters() {
+        let _serial = quiet();
+        set_max_level(Some(Level::Warn));
+        let mut parameters = Parameters::new();
+        assert_eq!(load_max_level(&parameters), Ok(Some(Level::Warn)));
+        parameters.insert(LEVEL_VALUE_NAME, RegistryValue::String("5".into()));
+        assert!(matches!(load_max_level(&parameters), Err(ConfigError::WrongType { .. })));
+        assert_eq!(max_level(), Some(Level::Warn));
+        parameters.insert(LEVEL_VALUE_NAME, RegistryValue::Dword(6));
+        assert_eq!(load_max_level(&parameters), Err(ConfigError::invalid(LEVEL_VALUE_NAME, "not a log level")));
+        assert_eq!(max_level(), Some(Level::Warn));
+        parameters.insert(LEVEL_VALUE_NAME, RegistryValue::Dword(5));
+        assert_eq!(load_max_level(&parameters), Ok(Some(Level::Trace)));
+        parameters.insert(LEVEL_VALUE_NAME, RegistryValue::Dword(0));
+        assert_eq!(load_max_level(&parameters), Ok(None));
+        assert_eq!(max_level(), None);
+    }
+}
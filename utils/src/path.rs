@@ -0,0 +1,462 @@
+//! Parsing of the path forms the object manager and the configuration manager
+//! hand to drivers, and conversion between them.
+//!
+//! File paths may arrive as `\??\C:\x`, `\DosDevices\C:\x`, `\GLOBAL??\C:\x`,
+//! `C:\x` or `\Device\HarddiskVolumeN\x`; registry paths as
+//! `\REGISTRY\MACHINE\x`, `\REGISTRY\USER\<SID>\x` or their `HKLM`/`HKU` short
+//! forms. Drive letters and volume devices are only related through a
+//! [`VolumeMap`], so the mapping can be swapped for a fixed table off-target.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use crate::unicode::str_eq_ignore_case;
+
+const SEPARATOR: char = '\\';
+
+const DOS_DEVICE_PREFIXES: [&str; 3] = ["\\??\\", "\\DosDevices\\", "\\GLOBAL??\\"];
+const DEVICE_PREFIX: &str = "\\Device\\";
+const REGISTRY_MACHINE: &str = "\\REGISTRY\\MACHINE";
+const REGISTRY_USER: &str = "\\REGISTRY\\USER";
+const MACHINE_ALIASES: [&str; 2] = ["HKLM", "HKEY_LOCAL_MACHINE"];
+const USER_ALIASES: [&str; 2] = ["HKU", "HKEY_USERS"];
+const CLASSES_ALIASES: [&str; 2] = ["HKCR", "HKEY_CLASSES_ROOT"];
+const CLASSES_KEY: &str = "SOFTWARE\\Classes";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PathError {
+    Empty,
+    ///the path does not start with any of the known roots
+    UnsupportedRoot,
+    ///a drive letter was expected right after the DOS device prefix
+    InvalidDrive,
+    ///`\Device\` is not followed by a device name
+    MissingDevice,
+    ///more `..` components than there are parents
+    EscapesRoot,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::Empty => "path is empty",
+            Self::UnsupportedRoot => "path root is not supported",
+            Self::InvalidDrive => "drive letter is invalid",
+            Self::MissingDevice => "device name is missing",
+            Self::EscapesRoot => "path escapes its root",
+        };
+        f.write_str(message)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegistryHive {
+    ///`\REGISTRY\MACHINE`, also known as `HKLM`
+    Machine,
+    ///`\REGISTRY\USER`, also known as `HKU`; the first component of the rest is the SID
+    Users,
+}
+
+impl RegistryHive {
+    pub const fn kernel_root(self) -> &'static str {
+        match self {
+            Self::Machine => REGISTRY_MACHINE,
+            Self::Users => REGISTRY_USER,
+        }
+    }
+    pub const fn short_root(self) -> &'static str {
+        match self {
+            Self::Machine => MACHINE_ALIASES[0],
+            Self::Users => USER_ALIASES[0],
+        }
+    }
+}
+
+/// Resolves drive letters to volume devices and back.
+///
+/// Device names are given without the `\Device\` prefix, e.g. `HarddiskVolume3`.
+pub trait VolumeMap {
+    fn device_for_drive(&self, letter: char) -> Option<String>;
+    fn drive_for_device(&self, device: &str) -> Option<char>;
+}
+
+/// A fixed drive-to-device table.
+pub struct StaticVolumeMap<'a> {
+    entries: &'a [(char, &'a str)],
+}
+
+impl<'a> StaticVolumeMap<'a> {
+    pub const fn new(entries: &'a [(char, &'a str)]) -> Self {
+        Self { entries }
+    }
+}
+
+impl VolumeMap for StaticVolumeMap<'_> {
+    fn device_for_drive(&self, letter: char) -> Option<String> {
+        self.entries.iter()
+            .find(|(drive, _)| drive.eq_ignore_ascii_case(&letter))
+            .map(|(_, device)| (*device).to_string())
+    }
+
+    fn drive_for_device(&self, device: &str) -> Option<char> {
+        self.entries.iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(device))
+            .map(|(drive, _)| drive.to_ascii_uppercase())
+    }
+}
+
+/// A parsed and normalized path.
+///
+/// `rest` never has leading, trailing or doubled separators and contains no
+/// `.` or `..` components.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NtPath {
+    ///a path below a drive letter, the letter is always uppercase
+    Drive { letter: char, rest: String },
+    ///a path below a named device such as `HarddiskVolume3`
+    Device { device: String, rest: String },
+    Registry { hive: RegistryHive, rest: String },
+}
+
+impl NtPath {
+    pub fn parse(path: &str) -> Result<Self, PathError> {
+        if path.is_empty() {
+            return Err(PathError::Empty);
+        }
+        if let Some(rest) = DOS_DEVICE_PREFIXES.iter().find_map(|prefix| strip_prefix_ignore_case(path, prefix)) {
+            return Self::parse_drive(rest);
+        }
+        if let Some(rest) = strip_prefix_ignore_case(path, DEVICE_PREFIX) {
+            let (device, rest) = split_first(rest);
+            if device.is_empty() {
+                return Err(PathError::MissingDevice);
+            }
+            return Ok(Self::Device { device: device.to_string(), rest: normalize(rest)? });
+        }
+        if let Some(rest) = strip_root(path, REGISTRY_MACHINE) {
+            return Ok(Self::Registry { hive: RegistryHive::Machine, rest: normalize(rest)? });
+        }
+        if let Some(rest) = strip_root(path, REGISTRY_USER) {
+            return Ok(Self::Registry { hive: RegistryHive::Users, rest: normalize(rest)? });
+        }
+        if let Some(rest) = MACHINE_ALIASES.iter().find_map(|alias| strip_root(path, alias)) {
+            return Ok(Self::Registry { hive: RegistryHive::Machine, rest: normalize(rest)? });
+        }
+        if let Some(rest) = USER_ALIASES.iter().find_map(|alias| strip_root(path, alias)) {
+            return Ok(Self::Registry { hive: RegistryHive::Users, rest: normalize(rest)? });
+        }
+        if let Some(rest) = CLASSES_ALIASES.iter().find_map(|alias| strip_root(path, alias)) {
+            let rest = join(CLASSES_KEY, &normalize(rest)?);
+            return Ok(Self::Registry { hive: RegistryHive::Machine, rest });
+        }
+        Self::parse_drive(path)
+    }
+    fn parse_drive(path: &str) -> Result<Self, PathError> {
+        let mut symbols = path.chars();
+        let (Some(letter), Some(':')) = (symbols.next(), symbols.next()) else {
+            return Err(PathError::UnsupportedRoot);
+        };
+        if !letter.is_ascii_alphabetic() {
+            return Err(PathError::InvalidDrive);
+        }
+        let rest = symbols.as_str();
+        if !rest.is_empty() && !rest.starts_with(SEPARATOR) {
+            //`C:dir` is relative to the current directory of the drive
+            return Err(PathError::UnsupportedRoot);
+        }
+        Ok(Self::Drive { letter: letter.to_ascii_uppercase(), rest: normalize(rest)? })
+    }
+    pub fn rest(&self) -> &str {
+        match self {
+            Self::Drive { rest, .. } | Self::Device { rest, .. } | Self::Registry { rest, .. } => rest,
+        }
+    }
+    ///the last component, if the path is not a bare root
+    pub fn file_name(&self) -> Option<&str> {
+        self.rest().rsplit(SEPARATOR).next().filter(|name| !name.is_empty())
+    }
+    ///the kernel form: `\??\C:\x`, `\Device\HarddiskVolume3\x` or `\REGISTRY\MACHINE\x`
+    pub fn to_nt(&self) -> String {
+        match self {
+            Self::Drive { letter, rest } => join(&alloc::format!("{}{letter}:", DOS_DEVICE_PREFIXES[0]), rest),
+            Self::Device { device, rest } => join(&alloc::format!("{DEVICE_PREFIX}{device}"), rest),
+            Self::Registry { hive, rest } => join(hive.kernel_root(), rest),
+        }
+    }
+    ///the `\DosDevices\C:\x` form, only for drive paths
+    pub fn to_dos_devices(&self) -> Option<String> {
+        match self {
+            Self::Drive { letter, rest } => Some(join(&alloc::format!("{}{letter}:", DOS_DEVICE_PREFIXES[1]), rest)),
+            _ => None,
+        }
+    }
+    ///the user-mode form: `C:\x` or `HKLM\x`; device paths have none
+    pub fn to_win32(&self) -> Option<String> {
+        match self {
+            Self::Drive { letter, rest } => Some(alloc::format!("{letter}:{SEPARATOR}{rest}")),
+            Self::Device { .. } => None,
+            Self::Registry { hive, rest } => Some(join(hive.short_root(), rest)),
+        }
+    }
+    ///rewrites a drive path to the volume device behind it
+    pub fn to_device(&self, volumes: &impl VolumeMap) -> Option<Self> {
+        match self {
+            Self::Drive { letter, rest } => volumes.device_for_drive(*letter)
+                .map(|device| Self::Device { device, rest: rest.clone() }),
+            Self::Device { .. } => Some(self.clone()),
+            Self::Registry { .. } => None,
+        }
+    }
+    ///rewrites a volume device path to the drive letter it is mounted at
+    pub fn to_drive(&self, volumes: &impl VolumeMap) -> Option<Self> {
+        match self {
+            Self::Device { device, rest } => volumes.drive_for_device(device)
+                .map(|letter| Self::Drive { letter, rest: rest.clone() }),
+            Self::Drive { .. } => Some(self.clone()),
+            Self::Registry { .. } => None,
+        }
+    }
+    ///for `HKU` paths, the SID of the user the key belongs to
+    pub fn user_sid(&self) -> Option<&str> {
+        match self {
+            Self::Registry { hive: RegistryHive::Users, rest } => {
+                Some(split_first(rest).0).filter(|sid| !sid.is_empty())
+            }
+            _ => None,
+        }
+    }
+    ///compares two paths ignoring case, folded with [`upcase`] as [`UnicodeStr`] comparisons are
+    ///
+    ///[`upcase`]: crate::unicode::upcase
+    ///[`UnicodeStr`]: crate::unicode::UnicodeStr
+    pub fn eq_ignore_case(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Drive { letter, rest }, Self::Drive { letter: other_letter, rest: other_rest }) =>
+                letter == other_letter && str_eq_ignore_case(rest, other_rest),
+            (Self::Device { device, rest }, Self::Device { device: other_device, rest: other_rest }) =>
+                str_eq_ignore_case(device, other_device) && str_eq_ignore_case(rest, other_rest),
+            (Self::Registry { hive, rest }, Self::Registry { hive: other_hive, rest: other_rest }) =>
+                hive == other_hive && str_eq_ignore_case(rest, other_rest),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for NtPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_nt())
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = path.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix).then(|| &path[prefix.len()..])
+}
+
+///strips a root that must be followed by a separator or the end of the path
+fn strip_root<'a>(path: &'a str, root: &str) -> Option<&'a str> {
+    let rest = strip_prefix_ignore_case(path, root)?;
+    (rest.is_empty() || rest.starts_with(SEPARATOR)).then_some(rest)
+}
+
+fn split_first(path: &str) -> (&str, &str) {
+    let path = path.trim_start_matches(SEPARATOR);
+    path.split_once(SEPARATOR).unwrap_or((path, ""))
+}
+
+fn join(root: &str, rest: &str) -> String {
+    if rest.is_empty() {
+        root.to_string()
+    } else {
+        alloc::format!("{root}{SEPARATOR}{rest}")
+    }
+}
+
+///drops empty and `.` components and resolves `..` against the preceding one
+pub fn normalize(path: &str) -> Result<String, PathError> {
+    let mut components = Vec::new();
+    for component in path.split(SEPARATOR) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop().ok_or(PathError::EscapesRoot)?;
+            }
+            _ => components.push(component),
+        }
+    }
+    Ok(components.join("\\"))
+}
+
+/// Resolves drives through the `\GLOBAL??` symbolic links of the running system.
+#[cfg(target_os = "windows")]
+pub struct SymbolicLinkVolumeMap;
+
+#[cfg(target_os = "windows")]
+impl VolumeMap for SymbolicLinkVolumeMap {
+    fn device_for_drive(&self, letter: char) -> Option<String> {
+        use core::{mem, ptr};
+        use wdk::nt_success;
+        use wdk_sys::ntddk::{ZwClose, ZwOpenSymbolicLinkObject, ZwQuerySymbolicLinkObject};
+        use wdk_sys::{GENERIC_READ, HANDLE, OBJECT_ATTRIBUTES, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, UNICODE_STRING};
+        use crate::unicode::{decode_utf16_lossy, unicode_units, OwnedUnicodeString};
+
+        let link = alloc::format!("{}{letter}:", DOS_DEVICE_PREFIXES[2]);
+        let mut link_name = OwnedUnicodeString::new(&link).ok()?;
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: mem::size_of::<OBJECT_ATTRIBUTES>() as _,
+            RootDirectory: ptr::null_mut(),
+            ObjectName: link_name.as_mut_ptr(),
+            Attributes: OBJ_CASE_INSENSITIVE | OBJ_KERNEL_HANDLE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        };
+        let mut handle: HANDLE = ptr::null_mut();
+        let status = unsafe { ZwOpenSymbolicLinkObject(&mut handle, GENERIC_READ, &mut attributes) };
+        if !nt_success(status) {
+            return None;
+        }
+        let mut buffer = alloc::vec![0u16; 260];
+        let mut target = UNICODE_STRING {
+            Length: 0,
+            MaximumLength: (buffer.len() * mem::size_of::<u16>()) as u16,
+            Buffer: buffer.as_mut_ptr(),
+        };
+        let status = unsafe { ZwQuerySymbolicLinkObject(handle, &mut target, ptr::null_mut()) };
+        let _ = unsafe { ZwClose(handle) };
+        if !nt_success(status) {
+            return None;
+        }
+        let target = decode_utf16_lossy(unsafe { unicode_units(&target) });
+        strip_prefix_ignore_case(&target, DEVICE_PREFIX).map(ToString::to_string)
+    }
+
+    fn drive_for_device(&self, device: &str) -> Option<char> {
+        ('A'..='Z').find(|letter| {
+            self.device_for_drive(*letter)
+                .is_some_and(|name| name.eq_ignore_ascii_case(device))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOLUMES: StaticVolumeMap<'static> = StaticVolumeMap::new(&[('C', "HarddiskVolume3"), ('d', "HarddiskVolume5")]);
+
+    fn parse(path: &str) -> NtPath {
+        NtPath::parse(path).unwrap()
+    }
+
+    fn drive(letter: char, rest: &str) -> NtPath {
+        NtPath::Drive { letter, rest: rest.to_string() }
+    }
+
+    fn device(device: &str, rest: &str) -> NtPath {
+        NtPath::Device { device: device.to_string(), rest: rest.to_string() }
+    }
+
+    #[test]
+    fn every_dos_form_parses_to_the_same_drive_path() {
+        for path in ["\\??\\C:\\Windows\\notepad.exe", "\\DosDevices\\c:\\Windows\\notepad.exe", "\\global??\\C:\\Windows\\notepad.exe", "C:\\Windows\\notepad.exe", "c:\\\\Windows\\.\\System32\\..\\notepad.exe\\"] {
+            assert_eq!(parse(path), drive('C', "Windows\\notepad.exe"), "{path}");
+        }
+        assert_eq!(parse("C:"), drive('C', ""));
+        assert_eq!(parse("C:\\"), drive('C', ""));
+    }
+
+    #[test]
+    fn device_paths() {
+        assert_eq!(parse("\\Device\\HarddiskVolume3\\Windows\\notepad.exe"), device("HarddiskVolume3", "Windows\\notepad.exe"));
+        assert_eq!(parse("\\device\\Mup"), device("Mup", ""));
+        assert_eq!(NtPath::parse("\\Device\\"), Err(PathError::MissingDevice));
+    }
+
+    #[test]
+    fn registry_paths_and_their_aliases() {
+        let run = NtPath::Registry { hive: RegistryHive::Machine, rest: "SOFTWARE\\Microsoft\\Run".to_string() };
+        for path in ["\\REGISTRY\\MACHINE\\SOFTWARE\\Microsoft\\Run", "\\Registry\\Machine\\SOFTWARE\\Microsoft\\Run", "HKLM\\SOFTWARE\\Microsoft\\Run", "HKEY_LOCAL_MACHINE\\SOFTWARE\\Microsoft\\Run"] {
+            assert_eq!(parse(path), run, "{path}");
+        }
+        assert_eq!(parse("HKCR\\.exe"), NtPath::Registry { hive: RegistryHive::Machine, rest: "SOFTWARE\\Classes\\.exe".to_string() });
+        let user = parse("HKU\\S-1-5-21-1\\Software");
+        assert_eq!(user.to_nt(), "\\REGISTRY\\USER\\S-1-5-21-1\\Software");
+        assert_eq!(user.user_sid(), Some("S-1-5-21-1"));
+        assert_eq!(parse("\\REGISTRY\\USER").user_sid(), None);
+        assert_eq!(run.user_sid(), None);
+        //a root is only a root when a separator or the end follows it
+        assert_eq!(NtPath::parse("HKLMX\\SOFTWARE"), Err(PathError::UnsupportedRoot));
+        assert_eq!(NtPath::parse("\\REGISTRY\\MACHINEX"), Err(PathError::UnsupportedRoot));
+    }
+
+    #[test]
+    fn invalid_paths() {
+        assert_eq!(NtPath::parse(""), Err(PathError::Empty));
+        assert_eq!(NtPath::parse("Windows\\notepad.exe"), Err(PathError::UnsupportedRoot));
+        assert_eq!(NtPath::parse("C:Windows"), Err(PathError::UnsupportedRoot));
+        assert_eq!(NtPath::parse("1:\\Windows"), Err(PathError::InvalidDrive));
+        assert_eq!(NtPath::parse("\\??\\Windows"), Err(PathError::UnsupportedRoot));
+        assert_eq!(NtPath::parse("C:\\Windows\\..\\.."), Err(PathError::EscapesRoot));
+        assert_eq!(NtPath::parse("\\Device\\HarddiskVolume3\\.."), Err(PathError::EscapesRoot));
+    }
+
+    #[test]
+    fn normalization() {
+        assert_eq!(normalize("").unwrap(), "");
+        assert_eq!(normalize("\\a\\\\b\\.\\c\\..\\d\\").unwrap(), "a\\b\\d");
+        assert_eq!(normalize("a\\..").unwrap(), "");
+        assert_eq!(normalize(".."), Err(PathError::EscapesRoot));
+        assert_eq!(normalize("...\\a").unwrap(), "...\\a");
+    }
+
+    #[test]
+    fn every_form_is_written_back() {
+        let path = parse("c:\\Windows\\notepad.exe");
+        assert_eq!(path.to_nt(), "\\??\\C:\\Windows\\notepad.exe");
+        assert_eq!(path.to_dos_devices().unwrap(), "\\DosDevices\\C:\\Windows\\notepad.exe");
+        assert_eq!(path.to_win32().unwrap(), "C:\\Windows\\notepad.exe");
+        assert_eq!(path.file_name(), Some("notepad.exe"));
+        assert_eq!(alloc::format!("{path}"), path.to_nt());
+        assert_eq!(parse("C:\\").to_win32().unwrap(), "C:\\");
+        assert_eq!(parse("C:\\").file_name(), None);
+        let volume = parse("\\Device\\HarddiskVolume3\\x");
+        assert_eq!(volume.to_win32(), None);
+        assert_eq!(volume.to_dos_devices(), None);
+        assert_eq!(parse("HKLM\\SOFTWARE").to_win32().unwrap(), "HKLM\\SOFTWARE");
+        for path in ["\\??\\C:\\Windows\\notepad.exe", "\\Device\\HarddiskVolume3\\x\\y", "\\REGISTRY\\MACHINE\\SOFTWARE", "\\REGISTRY\\USER\\S-1-5-18"] {
+            assert_eq!(parse(path).to_nt(), path);
+            assert_eq!(parse(&parse(path).to_nt()), parse(path));
+        }
+    }
+
+    #[test]
+    fn conversion_through_the_volume_table() {
+        let path = parse("C:\\Windows\\notepad.exe");
+        let on_volume = path.to_device(&VOLUMES).unwrap();
+        assert_eq!(on_volume, device("HarddiskVolume3", "Windows\\notepad.exe"));
+        assert_eq!(on_volume.to_nt(), "\\Device\\HarddiskVolume3\\Windows\\notepad.exe");
+        assert_eq!(on_volume.to_drive(&VOLUMES).unwrap(), path);
+        assert_eq!(parse("\\Device\\harddiskvolume5\\x").to_drive(&VOLUMES).unwrap(), drive('D', "x"));
+        assert_eq!(parse("D:\\x").to_device(&VOLUMES).unwrap(), device("HarddiskVolume5", "x"));
+        assert_eq!(parse("E:\\x").to_device(&VOLUMES), None);
+        assert_eq!(parse("\\Device\\Mup\\server\\share").to_drive(&VOLUMES), None);
+        assert_eq!(on_volume.to_device(&VOLUMES).unwrap(), on_volume);
+        assert_eq!(path.to_drive(&VOLUMES).unwrap(), path);
+        assert_eq!(parse("HKLM\\x").to_device(&VOLUMES), None);
+        assert_eq!(parse("HKLM\\x").to_drive(&VOLUMES), None);
+    }
+
+    #[test]
+    fn comparison_ignores_ascii_case() {
+        assert!(parse("C:\\Windows\\NOTEPAD.exe").eq_ignore_case(&parse("c:\\windows\\notepad.EXE")));
+        assert!(parse("\\Device\\HarddiskVolume3\\x").eq_ignore_case(&parse("\\Device\\HARDDISKVOLUME3\\X")));
+        assert!(parse("HKLM\\Software").eq_ignore_case(&parse("\\REGISTRY\\MACHINE\\SOFTWARE")));
+        assert!(!parse("HKLM\\Software").eq_ignore_case(&parse("HKU\\Software")));
+        assert!(!parse("C:\\x").eq_ignore_case(&parse("D:\\x")));
+        assert!(parse("C:\\\u{e9}t\u{e9}\\\u{c9}diteur.exe").eq_ignore_case(&parse("c:\\\u{c9}T\u{c9}\\\u{e9}DITEUR.EXE")));
+        //different forms of one file are only equal once converted
+        let volume = parse("\\Device\\HarddiskVolume3\\x");
+        assert!(!parse("C:\\x").eq_ignore_case(&volume));
+        assert!(parse("C:\\x").to_device(&VOLUMES).unwrap().eq_ignore_case(&volume));
+    }
+}
@@ -0,0 +1,424 @@
+//! A global allocator that charges every allocation to a pool tag.
+//!
+//! [`TaggedAllocator`] forwards to a [`PoolBackend`], the tagged non-paged
+//! pool inside a driver or any `GlobalAlloc` on a host, and keeps live bytes
+//! and allocation counts per tag. Allocations through the global allocator
+//! get the default tag; a subsystem gets its own with [`TaggedAllocator::pool`]
+//! and `Box::new_in`, `Vec::new_in` and friends. The bookkeeping is a fixed
+//! table of atomics, so it never allocates itself.
+
+use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::fmt::{Debug, Display, Formatter};
+use core::fmt;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+#[cfg(target_os = "windows")]
+use core::ptr;
+#[cfg(target_os = "windows")]
+use wdk_sys::ntddk::{ExAllocatePool2, ExFreePoolWithTag};
+#[cfg(target_os = "windows")]
+use wdk_sys::{MEMORY_ALLOCATION_ALIGNMENT, POOL_FLAG_NON_PAGED};
+
+///how many distinct tags an allocator keeps apart; later tags are charged to the default one and counted
+pub const MAX_TAGS: usize = 16;
+
+/// A four character pool tag, as `ExAllocatePool2` takes it and `poolmon`
+/// shows it.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct PoolTag(u32);
+
+impl PoolTag {
+    /// # Panics
+    /// If all four bytes are zero, which marks a free slot.
+    pub const fn new(tag: &[u8; 4]) -> Self {
+        let tag = u32::from_le_bytes(*tag);
+        assert!(tag != 0, "a pool tag must not be zero");
+        Self(tag)
+    }
+    pub const fn raw(self) -> u32 {
+        self.0
+    }
+}
+
+impl Display for PoolTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for byte in self.0.to_le_bytes() {
+            let char = if byte.is_ascii_graphic() || byte == b' ' { char::from(byte) } else { '.' };
+            write!(f, "{char}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for PoolTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "PoolTag({self})")
+    }
+}
+
+///a snapshot of the counters of one tag
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TagStats {
+    pub tag: PoolTag,
+    pub live_bytes: usize,
+    pub live_allocations: usize,
+    ///every successful allocation since the allocator was created
+    pub total_allocations: usize,
+    pub failed_allocations: usize,
+}
+
+impl TagStats {
+    pub const fn has_leaks(&self) -> bool {
+        self.live_allocations != 0
+    }
+}
+
+/// Where the memory comes from.
+///
+/// # Safety
+/// `allocate` must return null or a block that fits `layout`, and `free`
+/// must accept every block `allocate` returned with the same tag and layout.
+pub unsafe trait PoolBackend: Sync {
+    /// # Safety
+    /// The same as `GlobalAlloc::alloc`.
+    unsafe fn allocate(&self, tag: PoolTag, layout: Layout) -> *mut u8;
+    /// # Safety
+    /// `block` was returned by `allocate` with the same tag and layout.
+    unsafe fn free(&self, tag: PoolTag, block: *mut u8, layout: Layout);
+}
+
+///any global allocator can back the pool, the tag is only used for the bookkeeping
+unsafe impl<A: GlobalAlloc + Sync> PoolBackend for A {
+    unsafe fn allocate(&self, _tag: PoolTag, layout: Layout) -> *mut u8 {
+        self.alloc(layout)
+    }
+    unsafe fn free(&self, _tag: PoolTag, block: *mut u8, layout: Layout) {
+        self.dealloc(block, layout);
+    }
+}
+
+///the non-paged, non-executable pool, with the tag passed to the kernel
+#[cfg(target_os = "windows")]
+#[derive(Debug, Default)]
+pub struct NonPagedPool;
+
+#[cfg(target_os = "windows")]
+unsafe impl PoolBackend for NonPagedPool {
+    unsafe fn allocate(&self, tag: PoolTag, layout: Layout) -> *mut u8 {
+        //the pool does not align blocks beyond MEMORY_ALLOCATION_ALIGNMENT
+        if layout.align() > MEMORY_ALLOCATION_ALIGNMENT as usize {
+            return ptr::null_mut();
+        }
+        ExAllocatePool2(POOL_FLAG_NON_PAGED, layout.size() as _, tag.raw()).cast()
+    }
+    unsafe fn free(&self, tag: PoolTag, block: *mut u8, _layout: Layout) {
+        ExFreePoolWithTag(block.cast(), tag.raw());
+    }
+}
+
+struct TagSlot {
+    //zero while the slot is free
+    tag: AtomicU32,
+    live_bytes: AtomicUsize,
+    live_allocations: AtomicUsize,
+    total_allocations: AtomicUsize,
+    failed_allocations: AtomicUsize,
+}
+
+impl TagSlot {
+    const fn new(tag: u32) -> Self {
+        Self {
+            tag: AtomicU32::new(tag),
+            live_bytes: AtomicUsize::new(0),
+            live_allocations: AtomicUsize::new(0),
+            total_allocations: AtomicUsize::new(0),
+            failed_allocations: AtomicUsize::new(0),
+        }
+    }
+    fn stats(&self, tag: PoolTag) -> TagStats {
+        TagStats {
+            tag,
+            live_bytes: self.live_bytes.load(Ordering::Relaxed),
+            live_allocations: self.live_allocations.load(Ordering::Relaxed),
+            total_allocations: self.total_allocations.load(Ordering::Relaxed),
+            failed_allocations: self.failed_allocations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct TaggedAllocator<B> {
+    backend: B,
+    default_tag: PoolTag,
+    //the first slot always belongs to the default tag
+    slots: [TagSlot; MAX_TAGS],
+    //allocations charged to the default tag because every slot was taken
+    overflowed: AtomicUsize,
+}
+
+impl<B: PoolBackend> TaggedAllocator<B> {
+    pub const fn new(backend: B, default_tag: PoolTag) -> Self {
+        let mut slots = [const { TagSlot::new(0) }; MAX_TAGS];
+        slots[0] = TagSlot::new(default_tag.raw());
+        Self { backend, default_tag, slots, overflowed: AtomicUsize::new(0) }
+    }
+    pub const fn default_tag(&self) -> PoolTag {
+        self.default_tag
+    }
+    ///an `Allocator` that charges everything it allocates to `tag`
+    pub const fn pool(&self, tag: PoolTag) -> Pool<'_, B> {
+        Pool { allocator: self, tag }
+    }
+    /// # Safety
+    /// The same as `GlobalAlloc::alloc`; the block must be freed with
+    /// [`Self::free_tagged`] and the same tag.
+    pub unsafe fn allocate_tagged(&self, tag: PoolTag, layout: Layout) -> *mut u8 {
+        let slot = self.slot(tag).unwrap_or_else(|| {
+            self.overflowed.fetch_add(1, Ordering::Relaxed);
+            &self.slots[0]
+        });
+        let block = self.backend.allocate(tag, layout);
+        if block.is_null() {
+            slot.failed_allocations.fetch_add(1, Ordering::Relaxed);
+        } else {
+            slot.live_bytes.fetch_add(layout.size(), Ordering::Relaxed);
+            slot.live_allocations.fetch_add(1, Ordering::Relaxed);
+            slot.total_allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        block
+    }
+    /// # Safety
+    /// The same as `GlobalAlloc::dealloc`, with the tag the block was allocated with.
+    pub unsafe fn free_tagged(&self, tag: PoolTag, block: *mut u8, layout: Layout) {
+        let slot = self.slot(tag).unwrap_or(&self.slots[0]);
+        self.backend.free(tag, block, layout);
+        slot.live_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+        slot.live_allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+    ///the counters of `tag`, or `None` if nothing was ever allocated with it or it found no free slot
+    pub fn stats(&self, tag: PoolTag) -> Option<TagStats> {
+        self.tags().find(|stats| stats.tag == tag)
+    }
+    ///the counters of every tag that was used, the default one first
+    pub fn tags(&self) -> impl Iterator<Item = TagStats> + '_ {
+        self.slots.iter().filter_map(|slot| {
+            let tag = slot.tag.load(Ordering::Acquire);
+            (tag != 0).then(|| slot.stats(PoolTag(tag)))
+        })
+    }
+    ///how many allocations were charged to the default tag because their own found no free slot
+    pub fn overflowed_allocations(&self) -> usize {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+    ///prints the tags with live allocations and returns how many allocations are live in total
+    pub fn report_leaks(&self) -> usize {
+        let overflowed = self.overflowed_allocations();
+        if overflowed != 0 {
+            crate::warn!(
+                "{overflowed} allocations of tags beyond the first {MAX_TAGS} were charged to {}",
+                self.default_tag
+            );
+        }
+        let mut leaked = 0;
+        for stats in self.tags().filter(TagStats::has_leaks) {
+            crate::warn!(
+                "Pool tag {} leaks {} allocations of {} bytes",
+                stats.tag,
+                stats.live_allocations,
+                stats.live_bytes
+            );
+            leaked += stats.live_allocations;
+        }
+        if leaked == 0 {
+            crate::info!("No pool allocations are leaked");
+        }
+        leaked
+    }
+    //a tag keeps its slot forever, so an allocation and its free always find the same one
+    fn slot(&self, tag: PoolTag) -> Option<&TagSlot> {
+        for slot in &self.slots {
+            let current = slot.tag.load(Ordering::Acquire);
+            if current == tag.0 {
+                return Some(slot);
+            }
+            if current == 0 {
+                match slot.tag.compare_exchange(0, tag.0, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => return Some(slot),
+                    Err(current) if current == tag.0 => return Some(slot),
+                    Err(_) => {}
+                }
+            }
+        }
+        None
+    }
+}
+
+unsafe impl<B: PoolBackend> GlobalAlloc for TaggedAllocator<B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate_tagged(self.default_tag, layout)
+    }
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        self.free_tagged(self.default_tag, block, layout);
+    }
+}
+
+impl<B> Debug for TaggedAllocator<B> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaggedAllocator")
+            .field("default_tag", &self.default_tag)
+            .field("overflowed", &self.overflowed.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+///one tag of a [`TaggedAllocator`]
+#[derive(Copy, Clone)]
+pub struct Pool<'a, B> {
+    allocator: &'a TaggedAllocator<B>,
+    tag: PoolTag,
+}
+
+impl<B> Pool<'_, B> {
+    pub const fn tag(&self) -> PoolTag {
+        self.tag
+    }
+}
+
+unsafe impl<B: PoolBackend> Allocator for Pool<'_, B> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.size() == 0 {
+            return Ok(NonNull::slice_from_raw_parts(layout.dangling_ptr(), 0));
+        }
+        let block = unsafe { self.allocator.allocate_tagged(self.tag, layout) };
+        NonNull::new(block)
+            .map(|block| NonNull::slice_from_raw_parts(block, layout.size()))
+            .ok_or(AllocError)
+    }
+    unsafe fn deallocate(&self, block: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            self.allocator.free_tagged(self.tag, block.as_ptr(), layout);
+        }
+    }
+}
+
+impl<B> Debug for Pool<'_, B> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool").field("tag", &self.tag).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::format;
+    use alloc::vec::Vec;
+    use std::alloc::System;
+
+    const DEFAULT: PoolTag = PoolTag::new(b"Test");
+    const EVENTS: PoolTag = PoolTag::new(b"Evnt");
+
+    fn allocator() -> TaggedAllocator<System> {
+        TaggedAllocator::new(System, DEFAULT)
+    }
+
+    //refuses every allocation
+    struct Exhausted;
+
+    unsafe impl PoolBackend for Exhausted {
+        unsafe fn allocate(&self, _tag: PoolTag, _layout: Layout) -> *mut u8 {
+            core::ptr::null_mut()
+        }
+        unsafe fn free(&self, _tag: PoolTag, _block: *mut u8, _layout: Layout) {
+            unreachable!("nothing was allocated");
+        }
+    }
+
+    #[test]
+    fn tags_are_shown_as_poolmon_does() {
+        assert_eq!(format!("{DEFAULT}"), "Test");
+        assert_eq!(format!("{:?}", PoolTag::new(b"a\0\x7Fz")), "PoolTag(a..z)");
+        assert_eq!(DEFAULT.raw(), u32::from_le_bytes(*b"Test"));
+    }
+
+    #[test]
+    fn allocations_are_charged_to_their_tag_until_freed() {
+        let allocator = allocator();
+        let pool = allocator.pool(EVENTS);
+        assert_eq!(pool.tag(), EVENTS);
+        assert_eq!(allocator.tags().map(|stats| stats.tag).collect::<Vec<_>>(), [DEFAULT]);
+        assert_eq!(allocator.stats(EVENTS), None);
+        let boxed = Box::new_in([0u64; 4], pool);
+        let mut list = Vec::new_in(pool);
+        list.extend_from_slice(&[1u8; 100]);
+        let stats = allocator.stats(EVENTS).unwrap();
+        assert_eq!((stats.live_allocations, stats.total_allocations, stats.failed_allocations), (2, 2, 0));
+        assert_eq!(stats.live_bytes, 32 + list.capacity());
+        let layout = Layout::new::<u32>();
+        let block = unsafe { allocator.alloc(layout) };
+        assert!(!block.is_null());
+        assert_eq!(allocator.stats(DEFAULT).unwrap().live_bytes, 4);
+        assert_eq!(allocator.report_leaks(), 3);
+        unsafe { allocator.dealloc(block, layout) };
+        drop(boxed);
+        drop(list);
+        let stats = allocator.stats(EVENTS).unwrap();
+        assert_eq!((stats.live_bytes, stats.live_allocations, stats.total_allocations), (0, 0, 2));
+        assert!(!stats.has_leaks());
+        assert_eq!(allocator.report_leaks(), 0);
+        assert_eq!(allocator.tags().map(|stats| stats.tag).collect::<Vec<_>>(), [DEFAULT, EVENTS]);
+    }
+
+    #[test]
+    fn zero_sized_allocations_are_not_charged() {
+        let allocator = allocator();
+        let boxed = Box::new_in((), allocator.pool(EVENTS));
+        drop(boxed);
+        assert_eq!(allocator.stats(EVENTS), None);
+    }
+
+    #[test]
+    fn failures_are_counted() {
+        let allocator = TaggedAllocator::new(Exhausted, DEFAULT);
+        assert!(allocator.pool(EVENTS).allocate(Layout::new::<u32>()).is_err());
+        let stats = allocator.stats(EVENTS).unwrap();
+        assert_eq!((stats.live_allocations, stats.total_allocations, stats.failed_allocations), (0, 0, 1));
+    }
+
+    #[test]
+    fn tags_beyond_the_table_are_charged_to_the_default_one_and_counted() {
+        let allocator = allocator();
+        let tags: Vec<_> = (1..=MAX_TAGS as u8).map(|index| PoolTag::new(&[b'T', b'a', b'g', b'A' + index])).collect();
+        let boxes: Vec<_> = tags.iter().map(|&tag| Box::new_in(1u32, allocator.pool(tag))).collect();
+        assert_eq!(allocator.tags().count(), MAX_TAGS);
+        //the last tag found no free slot
+        let last = *tags.last().unwrap();
+        assert_eq!(allocator.stats(last), None);
+        assert_eq!(allocator.overflowed_allocations(), 1);
+        assert_eq!(allocator.stats(DEFAULT).unwrap().live_allocations, 1);
+        //its free finds the default slot again
+        drop(boxes);
+        assert_eq!(allocator.stats(DEFAULT).unwrap().live_allocations, 0);
+        assert_eq!(allocator.report_leaks(), 0);
+        assert_eq!(allocator.overflowed_allocations(), 1);
+    }
+
+    #[test]
+    fn concurrent_tags_share_the_table() {
+        let allocator = allocator();
+        std::thread::scope(|scope| {
+            for index in 0..4u8 {
+                let allocator = &allocator;
+                scope.spawn(move || {
+                    let pool = allocator.pool(PoolTag::new(&[b'T', b'h', b'r', b'0' + index]));
+                    for _ in 0..1_000 {
+                        drop(Box::new_in([0u8; 16], pool));
+                    }
+                });
+            }
+        });
+        assert_eq!(allocator.tags().count(), 5);
+        assert!(allocator.tags().skip(1).all(|stats| stats.total_allocations == 1_000 && !stats.has_leaks()));
+        assert_eq!(allocator.overflowed_allocations(), 0);
+    }
+}
@@ -0,0 +1,419 @@
+//! A bounded lock-free queue for handing events from callbacks to a worker.
+//!
+//! The slots are allocated once, when the ring is created, so pushing never
+//! allocates and never blocks: when the ring is full the value is handed back
+//! and counted as dropped. It is Dmitry Vyukov's bounded queue, where each
+//! slot carries a sequence number telling whether it is ready to be written
+//! or read; any number of producers and consumers may use it, though the
+//! drivers drain it from a single work item.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::fmt::{Debug, Formatter};
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+pub struct Ring<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    enqueue: AtomicUsize,
+    dequeue: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+unsafe impl<T: Send> Send for Ring<T> {}
+
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T> Ring<T> {
+    /// Allocates a ring of `capacity` slots.
+    ///
+    /// # Panics
+    /// If `capacity` is not a power of two.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity.is_power_of_two(), "the ring capacity must be a power of two");
+        let slots: Vec<Slot<T>> = (0..capacity)
+            .map(|index| Slot { sequence: AtomicUsize::new(index), value: UnsafeCell::new(MaybeUninit::uninit()) })
+            .collect();
+        Self {
+            slots: slots.into_boxed_slice(),
+            mask: capacity - 1,
+            enqueue: AtomicUsize::new(0),
+            dequeue: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+    ///a snapshot, it may be stale by the time it is read when other threads use the ring
+    pub fn len(&self) -> usize {
+        let dequeue = self.dequeue.load(Ordering::Acquire);
+        let enqueue = self.enqueue.load(Ordering::Acquire);
+        usize::min(enqueue.wrapping_sub(dequeue), self.capacity())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    ///how many values were refused because the ring was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+    ///returns the drop count and starts counting again from zero
+    pub fn take_dropped(&self) -> usize {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+    ///stores the value, or hands it back and counts a drop when the ring is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut position = self.enqueue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let lag = sequence.wrapping_sub(position) as isize;
+            if lag == 0 {
+                match self.enqueue.compare_exchange_weak(position, position.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(position.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => position = current,
+                }
+            } else if lag < 0 {
+                //the slot still holds the value from one lap ago
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Err(value);
+            } else {
+                position = self.enqueue.load(Ordering::Relaxed);
+            }
+        }
+    }
+    pub fn pop(&self) -> Option<T> {
+        let mut position = self.dequeue.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[position & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let lag = sequence.wrapping_sub(position.wrapping_add(1)) as isize;
+            if lag == 0 {
+                match self.dequeue.compare_exchange_weak(position, position.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        //the slot is free for the producer one lap ahead
+                        slot.sequence.store(position.wrapping_add(self.mask + 1), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(current) => position = current,
+                }
+            } else if lag < 0 {
+                return None;
+            } else {
+                position = self.dequeue.load(Ordering::Relaxed);
+            }
+        }
+    }
+    ///pops at most `limit` values into `consumer` and returns how many were popped
+    pub fn drain(&self, limit: usize, mut consumer: impl FnMut(T)) -> usize {
+        let mut count = 0;
+        while count < limit {
+            let Some(value) = self.pop() else {
+                break;
+            };
+            consumer(value);
+            count += 1;
+        }
+        count
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T> Debug for Ring<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ring")
+            .field("capacity", &self.capacity())
+            .field("len", &self.len())
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+/// A [`Ring`] drained by a single scheduled consumer, usually a work item.
+///
+/// The first push into an idle ring schedules the consumer, later pushes
+/// only add to what it will find. The consumer clears the flag before it
+/// drains, so a value pushed while it runs schedules it again and nothing is
+/// left behind.
+pub struct ScheduledRing<T> {
+    ring: Ring<T>,
+    scheduled: AtomicBool,
+}
+
+impl<T> ScheduledRing<T> {
+    /// # Panics
+    /// If `capacity` is not a power of two.
+    pub fn new(capacity: usize) -> Self {
+        Self { ring: Ring::new(capacity), scheduled: AtomicBool::new(false) }
+    }
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+    ///how many values were refused because the ring was full
+    pub fn dropped(&self) -> usize {
+        self.ring.dropped()
+    }
+    /// Stores the value and calls `schedule` unless the consumer is already
+    /// scheduled. A value refused by a full ring is only counted.
+    ///
+    /// # Errors
+    /// The error of `schedule`; the next push tries to schedule again.
+    pub fn push<E>(&self, value: T, schedule: impl FnOnce() -> Result<(), E>) -> Result<(), E> {
+        if self.ring.push(value).is_err() || self.scheduled.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        schedule().inspect_err(|_| self.scheduled.store(false, Ordering::Release))
+    }
+    ///called by the scheduled consumer, pops every value into `consumer` and returns how many were popped
+    pub fn drain(&self, consumer: impl FnMut(T)) -> usize {
+        //cleared first, so a value pushed from now on schedules another drain
+        self.scheduled.store(false, Ordering::Release);
+        self.ring.drain(usize::MAX, consumer)
+    }
+}
+
+impl<T> Debug for ScheduledRing<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScheduledRing")
+            .field("ring", &self.ring)
+            .field("scheduled", &self.scheduled.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use std::thread;
+
+    const PRODUCERS: usize = 4;
+    const PER_PRODUCER: usize = 20_000;
+
+    //a value that knows its producer, so the consumers can check the order
+    fn value(producer: usize, index: usize) -> usize {
+        producer * PER_PRODUCER + index
+    }
+
+    //a refused value is pushed again when `retry` is set, and lost otherwise
+    fn spawn_producers(ring: &Arc<Ring<usize>>, retry: bool) -> Vec<thread::JoinHandle<()>> {
+        (0..PRODUCERS)
+            .map(|producer| {
+                let ring = Arc::clone(ring);
+                thread::spawn(move || {
+                    for index in 0..PER_PRODUCER {
+                        let mut value = value(producer, index);
+                        while let Err(refused) = ring.push(value) {
+                            if !retry {
+                                break;
+                            }
+                            value = refused;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn values_come_out_in_order() {
+        let ring = Ring::new(4);
+        assert_eq!((ring.capacity(), ring.len()), (4, 0));
+        //several laps, so the sequence numbers wrap the slots
+        for lap in 0..10 {
+            for index in 0..3 {
+                ring.push(lap * 3 + index).unwrap();
+            }
+            assert_eq!(ring.len(), 3);
+            for index in 0..3 {
+                assert_eq!(ring.pop(), Some(lap * 3 + index));
+            }
+            assert!(ring.is_empty());
+        }
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn a_full_ring_hands_the_value_back() {
+        let ring = Ring::new(2);
+        ring.push(1).unwrap();
+        ring.push(2).unwrap();
+        assert_eq!(ring.push(3), Err(3));
+        assert_eq!(ring.push(4), Err(4));
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.dropped(), 2);
+        assert_eq!(ring.take_dropped(), 2);
+        assert_eq!(ring.dropped(), 0);
+        let mut drained = Vec::new();
+        assert_eq!(ring.drain(1, |value| drained.push(value)), 1);
+        ring.push(5).unwrap();
+        assert_eq!(ring.drain(usize::MAX, |value| drained.push(value)), 2);
+        assert_eq!(drained, [1, 2, 5]);
+        assert_eq!(alloc::format!("{ring:?}"), "Ring { capacity: 2, len: 0, dropped: 0 }");
+    }
+
+    #[test]
+    fn values_left_in_the_ring_are_dropped_with_it() {
+        let value = Arc::new(());
+        let ring = Ring::new(8);
+        for _ in 0..5 {
+            ring.push(Arc::clone(&value)).unwrap();
+        }
+        drop(ring.pop());
+        assert_eq!(Arc::strong_count(&value), 5);
+        drop(ring);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "power of two")]
+    fn the_capacity_is_a_power_of_two() {
+        let _ = Ring::<u8>::new(12);
+    }
+
+    #[test]
+    fn many_producers_and_one_consumer_keep_each_producer_in_order() {
+        let ring = Arc::new(Ring::new(64));
+        let producers = spawn_producers(&ring, true);
+        let mut next = [0; PRODUCERS];
+        let mut received = 0;
+        while received < PRODUCERS * PER_PRODUCER {
+            let Some(value) = ring.pop() else {
+                thread::yield_now();
+                continue;
+            };
+            let (producer, index) = (value / PER_PRODUCER, value % PER_PRODUCER);
+            assert_eq!(index, next[producer], "producer {producer} is out of order");
+            next[producer] += 1;
+            received += 1;
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(next, [PER_PRODUCER; PRODUCERS]);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn many_consumers_see_every_value_once() {
+        const CONSUMERS: usize = 3;
+        let ring = Arc::new(Ring::new(32));
+        let producers = spawn_producers(&ring, true);
+        let remaining = Arc::new(AtomicUsize::new(PRODUCERS * PER_PRODUCER));
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let (ring, remaining) = (Arc::clone(&ring), Arc::clone(&remaining));
+                thread::spawn(move || {
+                    let mut seen = Vec::new();
+                    while remaining.load(Ordering::Relaxed) > 0 {
+                        match ring.pop() {
+                            Some(value) => {
+                                seen.push(value);
+                                remaining.fetch_sub(1, Ordering::Relaxed);
+                            }
+                            None => thread::yield_now(),
+                        }
+                    }
+                    seen
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let mut seen = vec![false; PRODUCERS * PER_PRODUCER];
+        for consumer in consumers {
+            for value in consumer.join().unwrap() {
+                assert!(!seen[value], "{value} was popped twice");
+                seen[value] = true;
+            }
+        }
+        assert!(seen.into_iter().all(|seen| seen));
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn refused_values_are_counted_under_contention() {
+        let ring = Arc::new(Ring::new(16));
+        let producers = spawn_producers(&ring, false);
+        let mut popped = 0;
+        while !producers.iter().all(thread::JoinHandle::is_finished) {
+            popped += ring.drain(usize::MAX, drop);
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        popped += ring.drain(usize::MAX, drop);
+        assert_eq!(popped + ring.dropped(), PRODUCERS * PER_PRODUCER);
+    }
+
+    #[test]
+    fn the_consumer_is_scheduled_once_until_it_drains() {
+        let ring = ScheduledRing::new(4);
+        let mut scheduled = 0;
+        for value in 0..3 {
+            ring.push(value, || -> Result<(), ()> {
+                scheduled += 1;
+                Ok(())
+            }).unwrap();
+        }
+        assert_eq!(scheduled, 1);
+        let mut values = Vec::new();
+        assert_eq!(ring.drain(|value| values.push(value)), 3);
+        assert_eq!(values, [0, 1, 2]);
+        ring.push(3, || -> Result<(), ()> {
+            scheduled += 1;
+            Ok(())
+        }).unwrap();
+        assert_eq!(scheduled, 2);
+    }
+
+    #[test]
+    fn a_failed_schedule_is_retried_by_the_next_push() {
+        let ring = ScheduledRing::new(4);
+        assert_eq!(ring.push(0, || Err("no work item")), Err("no work item"));
+        let mut scheduled = false;
+        ring.push(1, || -> Result<(), &str> {
+            scheduled = true;
+            Ok(())
+        }).unwrap();
+        assert!(scheduled);
+        assert_eq!(ring.len(), 2);
+    }
+
+    #[test]
+    fn a_full_ring_neither_schedules_nor_fails() {
+        let ring = ScheduledRing::new(2);
+        ring.push(0, || Ok::<_, ()>(())).unwrap();
+        assert_eq!(ring.drain(drop), 1);
+        ring.push(1, || Err::<(), _>(())).unwrap_err();
+        ring.push(2, || Err::<(), _>(())).unwrap_err();
+        assert_eq!(ring.push(3, || Err::<(), _>(())), Ok(()));
+        assert_eq!(ring.dropped(), 1);
+        assert_eq!(ring.len(), 2);
+    }
+}
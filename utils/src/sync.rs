@@ -0,0 +1,600 @@
+//! Kernel locks whose guards carry the IRQL they run at.
+//!
+//! | lock | kernel object | callable at | while held |
+//! |------|---------------|-------------|------------|
+//! | [`SpinLock`] | `KSPIN_LOCK` | `DISPATCH_LEVEL` | `DISPATCH_LEVEL` |
+//! | [`FastMutex`] | `FAST_MUTEX` | `APC_LEVEL` | `APC_LEVEL` |
+//! | [`PushLock`] | `EX_PUSH_LOCK` | `APC_LEVEL` | unchanged, APCs disabled |
+//! | [`Resource`] | `ERESOURCE` | `APC_LEVEL` | unchanged, APCs disabled |
+//!
+//! Locks that raise the IRQL borrow the caller's token mutably and hand out
+//! the token of the raised level instead. The others leave the caller its
+//! token. Off-target every lock is a `std` mutex or reader-writer lock, so
+//! the same code runs in host tests.
+
+use core::cell::UnsafeCell;
+use core::fmt::{Debug, Formatter};
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use crate::irql::{Apc, AtMost, Dispatch};
+#[cfg(target_os = "windows")]
+use {
+    alloc::boxed::Box,
+    wdk_sys::_EVENT_TYPE::SynchronizationEvent,
+    wdk_sys::ntddk::{
+        ExAcquireFastMutex, ExAcquirePushLockExclusiveEx, ExAcquirePushLockSharedEx, ExAcquireResourceExclusiveLite,
+        ExAcquireResourceSharedLite, ExDeleteResourceLite, ExInitializeResourceLite, ExIsResourceAcquiredExclusiveLite,
+        ExIsResourceAcquiredSharedLite, ExReleaseFastMutex, ExReleasePushLockExclusiveEx, ExReleasePushLockSharedEx,
+        ExReleaseResourceLite, KeAcquireSpinLockRaiseToDpc, KeEnterCriticalRegion, KeInitializeEvent, KeLeaveCriticalRegion,
+        KeReleaseSpinLock,
+    },
+    wdk_sys::{BOOLEAN, ERESOURCE, FALSE, FAST_MUTEX, FM_LOCK_BIT, KIRQL, KSPIN_LOCK, TRUE, ULONG_PTR},
+};
+#[cfg(not(target_os = "windows"))]
+use std::sync::{LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+//a panic under a host lock must not wedge the other tests
+#[cfg(not(target_os = "windows"))]
+fn ignore_poison<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
+
+macro_rules! guard_deref {
+    ($guard: ident, $lock: ident) => {
+        impl<T> Deref for $guard<'_, T> {
+            type Target = T;
+
+            fn deref(&self) -> &T {
+                unsafe { &*self.$lock.value.get() }
+            }
+        }
+    };
+    ($guard: ident, $lock: ident, mut) => {
+        guard_deref!($guard, $lock);
+
+        impl<T> DerefMut for $guard<'_, T> {
+            fn deref_mut(&mut self) -> &mut T {
+                unsafe { &mut *self.$lock.value.get() }
+            }
+        }
+    };
+}
+
+macro_rules! lock_traits {
+    ($lock: ident, $($sync_bound: ident)?) => {
+        unsafe impl<T: Send> Send for $lock<T> {}
+
+        unsafe impl<T: Send $(+ $sync_bound)?> Sync for $lock<T> {}
+
+        impl<T> Debug for $lock<T> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+                f.debug_struct(stringify!($lock)).finish_non_exhaustive()
+            }
+        }
+    };
+}
+
+/// A `KSPIN_LOCK` around a value; a std mutex off-target.
+///
+/// Locking raises the IRQL to `DISPATCH_LEVEL`. The caller's token stays
+/// mutably borrowed by the guard, which hands out a [`Dispatch`] token
+/// instead, so nothing that needs a lower IRQL can run under the lock.
+pub struct SpinLock<T> {
+    #[cfg(target_os = "windows")]
+    lock: UnsafeCell<KSPIN_LOCK>,
+    #[cfg(not(target_os = "windows"))]
+    lock: Mutex<()>,
+    value: UnsafeCell<T>,
+}
+
+lock_traits!(SpinLock,);
+
+impl<T> SpinLock<T> {
+    ///a zeroed lock, which is what `KeInitializeSpinLock` does
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(target_os = "windows")]
+            lock: UnsafeCell::new(0),
+            #[cfg(not(target_os = "windows"))]
+            lock: Mutex::new(()),
+            value: UnsafeCell::new(value),
+        }
+    }
+    ///spins until the lock is free; callable at or below `DISPATCH_LEVEL`
+    pub fn lock<'a, I: AtMost<Dispatch>>(&'a self, _irql: &'a mut I) -> SpinLockGuard<'a, T> {
+        SpinLockGuard {
+            #[cfg(target_os = "windows")]
+            old_irql: unsafe { KeAcquireSpinLockRaiseToDpc(self.lock.get()) },
+            #[cfg(not(target_os = "windows"))]
+            _host: ignore_poison(self.lock.lock()),
+            lock: self,
+            irql: unsafe { Dispatch::new_unchecked() },
+            _caller_irql: PhantomData,
+        }
+    }
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+    ///no lock is needed, the borrow is exclusive
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for SpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+///releases the lock and restores the IRQL when dropped
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    #[cfg(target_os = "windows")]
+    old_irql: KIRQL,
+    #[cfg(not(target_os = "windows"))]
+    _host: MutexGuard<'a, ()>,
+    irql: Dispatch,
+    //the caller's token is unusable until the IRQL is restored
+    _caller_irql: PhantomData<&'a mut ()>,
+}
+
+impl<T> SpinLockGuard<'_, T> {
+    ///the token for what may run under the lock
+    pub fn irql(&mut self) -> &mut Dispatch {
+        &mut self.irql
+    }
+}
+
+guard_deref!(SpinLockGuard, lock, mut);
+
+#[cfg(target_os = "windows")]
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { KeReleaseSpinLock(self.lock.lock.get(), self.old_irql) };
+    }
+}
+
+/// A `FAST_MUTEX` around a value; a std mutex off-target.
+///
+/// Locking raises the IRQL to `APC_LEVEL`, so like with [`SpinLock`] the
+/// guard borrows the caller's token and hands out an [`Apc`] one. The mutex
+/// is not recursive, locking it again in the same thread deadlocks.
+pub struct FastMutex<T> {
+    //boxed, the event inside must not move once it is initialized
+    #[cfg(target_os = "windows")]
+    mutex: Box<UnsafeCell<FAST_MUTEX>>,
+    #[cfg(not(target_os = "windows"))]
+    mutex: Mutex<()>,
+    value: UnsafeCell<T>,
+}
+
+lock_traits!(FastMutex,);
+
+impl<T> FastMutex<T> {
+    ///what `ExInitializeFastMutex` does, which is inline in the headers
+    pub fn new(value: T) -> Self {
+        #[cfg(target_os = "windows")]
+        let mutex = {
+            let mutex = Box::new(UnsafeCell::new(FAST_MUTEX { Count: FM_LOCK_BIT as _, ..FAST_MUTEX::default() }));
+            unsafe { KeInitializeEvent(&mut (*mutex.get()).Event, SynchronizationEvent, FALSE as BOOLEAN) };
+            mutex
+        };
+        #[cfg(not(target_os = "windows"))]
+        let mutex = Mutex::new(());
+        Self { mutex, value: UnsafeCell::new(value) }
+    }
+    ///waits until the mutex is free; callable at or below `APC_LEVEL`
+    pub fn lock<'a, I: AtMost<Apc>>(&'a self, _irql: &'a mut I) -> FastMutexGuard<'a, T> {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            ExAcquireFastMutex(self.mutex.get());
+        }
+        FastMutexGuard {
+            #[cfg(not(target_os = "windows"))]
+            _host: ignore_poison(self.mutex.lock()),
+            mutex: self,
+            irql: unsafe { Apc::new_unchecked() },
+            _caller_irql: PhantomData,
+        }
+    }
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+///releases the mutex and restores the IRQL when dropped
+pub struct FastMutexGuard<'a, T> {
+    mutex: &'a FastMutex<T>,
+    #[cfg(not(target_os = "windows"))]
+    _host: MutexGuard<'a, ()>,
+    irql: Apc,
+    _caller_irql: PhantomData<&'a mut ()>,
+}
+
+impl<T> FastMutexGuard<'_, T> {
+    pub fn irql(&mut self) -> &mut Apc {
+        &mut self.irql
+    }
+}
+
+guard_deref!(FastMutexGuard, mutex, mut);
+
+#[cfg(target_os = "windows")]
+impl<T> Drop for FastMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ExReleaseFastMutex(self.mutex.mutex.get()) };
+    }
+}
+
+/// An `EX_PUSH_LOCK` around a value; a std reader-writer lock off-target.
+///
+/// The lock is taken in a critical region: normal kernel APCs are disabled
+/// but the IRQL stays, so the caller keeps its token. It is small, can be
+/// made in a `static`, and is not recursive.
+pub struct PushLock<T> {
+    #[cfg(target_os = "windows")]
+    lock: UnsafeCell<ULONG_PTR>,
+    #[cfg(not(target_os = "windows"))]
+    lock: RwLock<()>,
+    value: UnsafeCell<T>,
+}
+
+lock_traits!(PushLock, Sync);
+
+impl<T> PushLock<T> {
+    ///a zeroed lock, which is what `ExInitializePushLock` does
+    pub const fn new(value: T) -> Self {
+        Self {
+            #[cfg(target_os = "windows")]
+            lock: UnsafeCell::new(0),
+            #[cfg(not(target_os = "windows"))]
+            lock: RwLock::new(()),
+            value: UnsafeCell::new(value),
+        }
+    }
+    ///shared access; callable at or below `APC_LEVEL`
+    pub fn read<I: AtMost<Apc>>(&self, _irql: &I) -> PushLockReadGuard<'_, T> {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            KeEnterCriticalRegion();
+            ExAcquirePushLockSharedEx(self.lock.get(), 0);
+        }
+        PushLockReadGuard {
+            #[cfg(not(target_os = "windows"))]
+            _host: ignore_poison(self.lock.read()),
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+    ///exclusive access; callable at or below `APC_LEVEL`
+    pub fn write<I: AtMost<Apc>>(&self, _irql: &I) -> PushLockWriteGuard<'_, T> {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            KeEnterCriticalRegion();
+            ExAcquirePushLockExclusiveEx(self.lock.get(), 0);
+        }
+        PushLockWriteGuard {
+            #[cfg(not(target_os = "windows"))]
+            _host: ignore_poison(self.lock.write()),
+            lock: self,
+            _not_send: PhantomData,
+        }
+    }
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+impl<T: Default> Default for PushLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+///releases the shared access and leaves the critical region when dropped
+pub struct PushLockReadGuard<'a, T> {
+    lock: &'a PushLock<T>,
+    #[cfg(not(target_os = "windows"))]
+    _host: RwLockReadGuard<'a, ()>,
+    //the critical region belongs to the thread
+    _not_send: PhantomData<*const ()>,
+}
+
+guard_deref!(PushLockReadGuard, lock);
+
+#[cfg(target_os = "windows")]
+impl<T> Drop for PushLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ExReleasePushLockSharedEx(self.lock.lock.get(), 0);
+            KeLeaveCriticalRegion();
+        }
+    }
+}
+
+///releases the exclusive access and leaves the critical region when dropped
+pub struct PushLockWriteGuard<'a, T> {
+    lock: &'a PushLock<T>,
+    #[cfg(not(target_os = "windows"))]
+    _host: RwLockWriteGuard<'a, ()>,
+    _not_send: PhantomData<*const ()>,
+}
+
+guard_deref!(PushLockWriteGuard, lock, mut);
+
+#[cfg(target_os = "windows")]
+impl<T> Drop for PushLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ExReleasePushLockExclusiveEx(self.lock.lock.get(), 0);
+            KeLeaveCriticalRegion();
+        }
+    }
+}
+
+/// An `ERESOURCE` around a value; a std reader-writer lock off-target.
+///
+/// Like [`PushLock`] it is taken in a critical region and leaves the
+/// caller its token. The kernel lets a thread take a resource again, which
+/// would hand out a second reference next to a mutable one, so that panics.
+pub struct Resource<T> {
+    //boxed, the resource is linked into a system list once it is initialized
+    #[cfg(target_os = "windows")]
+    resource: Box<UnsafeCell<ERESOURCE>>,
+    #[cfg(not(target_os = "windows"))]
+    resource: RwLock<()>,
+    value: UnsafeCell<T>,
+}
+
+lock_traits!(Resource, Sync);
+
+impl<T> Resource<T> {
+    ///callable at or below `DISPATCH_LEVEL`, like dropping the resource
+    pub fn new(value: T) -> Self {
+        #[cfg(target_os = "windows")]
+        let resource = {
+            let resource = Box::new(UnsafeCell::new(ERESOURCE::default()));
+            //never fails, the status is kept for compatibility only
+            let _ = unsafe { ExInitializeResourceLite(resource.get()) };
+            resource
+        };
+        #[cfg(not(target_os = "windows"))]
+        let resource = RwLock::new(());
+        Self { resource, value: UnsafeCell::new(value) }
+    }
+    /// Shared access; callable at or below `APC_LEVEL`.
+    ///
+    /// # Panics
+    /// If the thread holds the resource exclusively.
+    pub fn read<I: AtMost<Apc>>(&self, _irql: &I) -> ResourceReadGuard<'_, T> {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            KeEnterCriticalRegion();
+            if ExIsResourceAcquiredExclusiveLite(self.resource.get()) != FALSE as BOOLEAN {
+                KeLeaveCriticalRegion();
+                panic!("the resource is already held exclusively by this thread");
+            }
+            ExAcquireResourceSharedLite(self.resource.get(), TRUE as BOOLEAN);
+        }
+        ResourceReadGuard {
+            #[cfg(not(target_os = "windows"))]
+            _host: ignore_poison(self.resource.read()),
+            resource: self,
+            _not_send: PhantomData,
+        }
+    }
+    /// Exclusive access; callable at or below `APC_LEVEL`.
+    ///
+    /// # Panics
+    /// If the thread holds the resource already.
+    pub fn write<I: AtMost<Apc>>(&self, _irql: &I) -> ResourceWriteGuard<'_, T> {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            KeEnterCriticalRegion();
+            if ExIsResourceAcquiredSharedLite(self.resource.get()) != 0 {
+                KeLeaveCriticalRegion();
+                panic!("the resource is already held by this thread");
+            }
+            ExAcquireResourceExclusiveLite(self.resource.get(), TRUE as BOOLEAN);
+        }
+        ResourceWriteGuard {
+            #[cfg(not(target_os = "windows"))]
+            _host: ignore_poison(self.resource.write()),
+            resource: self,
+            _not_send: PhantomData,
+        }
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl<T> Drop for Resource<T> {
+    fn drop(&mut self) {
+        let _ = unsafe { ExDeleteResourceLite(self.resource.get()) };
+    }
+}
+
+///releases the shared access and leaves the critical region when dropped
+pub struct ResourceReadGuard<'a, T> {
+    resource: &'a Resource<T>,
+    #[cfg(not(target_os = "windows"))]
+    _host: RwLockReadGuard<'a, ()>,
+    _not_send: PhantomData<*const ()>,
+}
+
+guard_deref!(ResourceReadGuard, resource);
+
+///releases the exclusive access and leaves the critical region when dropped
+pub struct ResourceWriteGuard<'a, T> {
+    resource: &'a Resource<T>,
+    #[cfg(not(target_os = "windows"))]
+    _host: RwLockWriteGuard<'a, ()>,
+    _not_send: PhantomData<*const ()>,
+}
+
+guard_deref!(ResourceWriteGuard, resource, mut);
+
+macro_rules! release_resource {
+    ($($guard: ident),*) => {
+        $(
+            #[cfg(target_os = "windows")]
+            impl<T> Drop for $guard<'_, T> {
+                fn drop(&mut self) {
+                    unsafe {
+                        ExReleaseResourceLite(self.resource.resource.get());
+                        KeLeaveCriticalRegion();
+                    }
+                }
+            }
+        )*
+    };
+}
+
+release_resource!(ResourceReadGuard, ResourceWriteGuard);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::irql::Passive;
+    use alloc::vec::Vec;
+    use std::sync::Barrier;
+    use std::thread;
+
+    const THREADS: usize = 4;
+    const ROUNDS: usize = 10_000;
+
+    fn passive() -> Passive {
+        Passive::current().unwrap()
+    }
+
+    #[test]
+    fn spin_lock_guards_hand_out_a_dispatch_token() {
+        static COUNTER: SpinLock<usize> = SpinLock::new(0);
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    let mut irql = passive();
+                    for _ in 0..ROUNDS {
+                        let mut counter = COUNTER.lock(&mut irql);
+                        let _: &mut Dispatch = counter.irql();
+                        *counter += 1;
+                    }
+                });
+            }
+        });
+        let mut irql = passive();
+        assert_eq!(*COUNTER.lock(&mut irql), THREADS * ROUNDS);
+        let mut lock = SpinLock::<Vec<u8>>::default();
+        lock.get_mut().push(1);
+        assert_eq!(lock.into_inner(), [1]);
+    }
+
+    #[test]
+    fn fast_mutexes_exclude_each_other() {
+        let counter = FastMutex::new(0);
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    let mut irql = passive();
+                    for _ in 0..ROUNDS {
+                        let mut counter = counter.lock(&mut irql);
+                        let _: &mut Apc = counter.irql();
+                        *counter += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.into_inner(), THREADS * ROUNDS);
+    }
+
+    #[test]
+    fn push_locks_share_readers_and_exclude_writers() {
+        static VALUE: PushLock<(usize, usize)> = PushLock::new((0, 0));
+        //every reader holds the lock until all of them have it
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    let irql = passive();
+                    let value = VALUE.read(&irql);
+                    barrier.wait();
+                    assert_eq!(*value, (0, 0));
+                });
+            }
+        });
+        thread::scope(|scope| {
+            for _ in 0..THREADS {
+                scope.spawn(|| {
+                    let irql = passive();
+                    for _ in 0..ROUNDS {
+                        let mut value = VALUE.write(&irql);
+                        value.0 += 1;
+                        value.1 += 1;
+                        drop(value);
+                        //a reader never sees a half written pair
+                        let value = VALUE.read(&irql);
+                        assert_eq!(value.0, value.1);
+                    }
+                });
+            }
+        });
+        assert_eq!(*VALUE.read(&passive()), (THREADS * ROUNDS, THREADS * ROUNDS));
+    }
+
+    #[test]
+    fn resources_share_readers_and_exclude_writers() {
+        let mut resource = Resource::new(Vec::new());
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|scope| {
+            for index in 0..THREADS {
+                let (resource, barrier) = (&resource, &barrier);
+                scope.spawn(move || {
+                    let irql = passive();
+                    {
+                        let value = resource.read(&irql);
+                        barrier.wait();
+                        assert!(value.is_empty());
+                    }
+                    barrier.wait();
+                    resource.write(&irql).push(index);
+                });
+            }
+        });
+        resource.get_mut().sort_unstable();
+        assert_eq!(*resource.read(&passive()), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn a_panic_under_a_lock_does_not_poison_it() {
+        let lock = PushLock::new(1);
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                let _value = lock.write(&passive());
+                panic!("under the lock");
+            }).join()
+        });
+        assert!(result.is_err());
+        *lock.write(&passive()) += 1;
+        assert_eq!(lock.into_inner(), 2);
+        let mutex = FastMutex::new(1);
+        let _ = thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut irql = passive();
+                let _value = mutex.lock(&mut irql);
+                panic!("under the mutex");
+            }).join()
+        });
+        let mut irql = passive();
+        assert_eq!(*mutex.lock(&mut irql), 1);
+    }
+}
@@ -0,0 +1,32 @@
+//! Raw kernel types used by the platform-neutral parts of the crate.
+//!
+//! On the driver target these are the `wdk_sys` definitions themselves. On any
+//! other host a layout-compatible stand-in is provided so that string, path and
+//! status handling can be built and exercised without the WDK.
+
+#[cfg(target_os = "windows")]
+pub use wdk_sys::{NTSTATUS, UNICODE_STRING};
+
+#[cfg(not(target_os = "windows"))]
+pub type NTSTATUS = i32;
+
+#[cfg(not(target_os = "windows"))]
+#[allow(non_snake_case, non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct UNICODE_STRING {
+    pub Length: u16,
+    pub MaximumLength: u16,
+    pub Buffer: *mut u16,
+}
+
+#[cfg(not(target_os = "windows"))]
+impl Default for UNICODE_STRING {
+    fn default() -> Self {
+        Self {
+            Length: 0,
+            MaximumLength: 0,
+            Buffer: core::ptr::null_mut(),
+        }
+    }
+}
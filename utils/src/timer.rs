@@ -0,0 +1,342 @@
+//! One-shot and periodic timers that run a closure.
+//!
+//! A [`Timer`] owns a `KTIMER` and the `KDPC` it queues. The closure runs in
+//! the DPC at `DISPATCH_LEVEL` and is handed the [`Dispatch`] token; work
+//! that needs `PASSIVE_LEVEL` goes through [`Timer::with_work`], whose DPC
+//! only queues a work item. Dropping a timer cancels it and waits until none
+//! of its DPCs and work items is running any more, so a timer must be
+//! dropped at `PASSIVE_LEVEL`. Off-target a started timer is a std thread.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt::{Debug, Formatter};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use crate::irql::{AtMost, Dispatch, Passive};
+use crate::kernel::KernelApi;
+use crate::work::{WorkQueue, WorkTracker};
+#[cfg(target_os = "windows")]
+use {
+    core::cell::UnsafeCell,
+    core::ptr,
+    wdk_sys::_TIMER_TYPE::NotificationTimer,
+    wdk_sys::ntddk::{KeCancelTimer, KeFlushQueuedDpcs, KeInitializeDpc, KeInitializeTimerEx, KeSetTimerEx},
+    wdk_sys::{BOOLEAN, FALSE, KDPC, KTIMER, LARGE_INTEGER, PVOID},
+};
+#[cfg(not(target_os = "windows"))]
+use {
+    alloc::vec::Vec,
+    std::sync::{Condvar, Mutex, PoisonError},
+    std::thread::{self, JoinHandle},
+    std::time::Instant,
+};
+
+pub type TimerRoutine = Box<dyn Fn(&mut Dispatch) + Send + Sync>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TimerMode {
+    ///fires once, when the due time has passed
+    #[default]
+    OneShot,
+    ///fires when the due time has passed and then once every period
+    Periodic(Duration),
+}
+
+impl TimerMode {
+    ///the period as `KeSetTimerEx` takes it, in whole milliseconds but at least one; zero when one-shot
+    pub fn period_ms(self) -> i32 {
+        match self {
+            Self::OneShot => 0,
+            Self::Periodic(period) => i32::try_from(period.as_millis()).unwrap_or(i32::MAX).max(1),
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+struct KernelTimer {
+    timer: UnsafeCell<KTIMER>,
+    dpc: UnsafeCell<KDPC>,
+    routine: TimerRoutine,
+}
+
+#[cfg(target_os = "windows")]
+unsafe extern "C" fn dpc_trampoline(_dpc: *mut KDPC, context: PVOID, _first: PVOID, _second: PVOID) {
+    let timer = &*context.cast::<KernelTimer>();
+    //DPCs run at DISPATCH_LEVEL
+    (timer.routine)(&mut Dispatch::new_unchecked());
+}
+
+#[cfg(not(target_os = "windows"))]
+#[derive(Default)]
+struct HostState {
+    //bumped by every start and cancel, a thread that sees another one stops
+    generation: u64,
+    set: bool,
+}
+
+#[cfg(not(target_os = "windows"))]
+struct HostTimer {
+    routine: TimerRoutine,
+    state: Mutex<HostState>,
+    wake: Condvar,
+}
+
+#[cfg(not(target_os = "windows"))]
+impl HostTimer {
+    fn restart(&self, set: bool) -> (u64, bool) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.generation += 1;
+        let was_set = core::mem::replace(&mut state.set, set);
+        self.wake.notify_all();
+        (state.generation, was_set)
+    }
+    fn run(&self, generation: u64, due: Duration, mode: TimerMode) {
+        let mut deadline = Instant::now() + due;
+        loop {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            loop {
+                if state.generation != generation {
+                    return;
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self.wake.wait_timeout(state, deadline - now).unwrap_or_else(PoisonError::into_inner).0;
+            }
+            if mode == TimerMode::OneShot {
+                state.set = false;
+            }
+            drop(state);
+            (self.routine)(&mut unsafe { Dispatch::new_unchecked() });
+            match mode {
+                TimerMode::OneShot => return,
+                TimerMode::Periodic(period) => deadline += period.max(Duration::from_millis(1)),
+            }
+        }
+    }
+}
+
+//completes the tracked work when the routine has run or was dropped without running
+struct WorkCompletion {
+    tracker: Arc<WorkTracker>,
+    //set by the tick that queued the run, so the next tick may queue another
+    scheduled: Arc<AtomicBool>,
+}
+
+impl Drop for WorkCompletion {
+    fn drop(&mut self) {
+        self.scheduled.store(false, Ordering::Release);
+        self.tracker.complete();
+    }
+}
+
+pub struct Timer {
+    #[cfg(target_os = "windows")]
+    kernel: Box<KernelTimer>,
+    #[cfg(not(target_os = "windows"))]
+    host: Arc<HostTimer>,
+    #[cfg(not(target_os = "windows"))]
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    work: Option<Arc<WorkTracker>>,
+}
+
+#[cfg(target_os = "windows")]
+unsafe impl Send for Timer {}
+
+#[cfg(target_os = "windows")]
+unsafe impl Sync for Timer {}
+
+impl Timer {
+    ///a timer that is not set yet; `routine` runs at `DISPATCH_LEVEL` every time it fires
+    pub fn new<I, F>(_irql: &I, routine: F) -> Self
+    where
+        I: AtMost<Dispatch>,
+        F: Fn(&mut Dispatch) + Send + Sync + 'static,
+    {
+        #[cfg(target_os = "windows")]
+        {
+            let kernel = Box::new(KernelTimer {
+                timer: UnsafeCell::new(KTIMER::default()),
+                dpc: UnsafeCell::new(KDPC::default()),
+                routine: Box::new(routine),
+            });
+            unsafe {
+                KeInitializeTimerEx(kernel.timer.get(), NotificationTimer);
+                KeInitializeDpc(kernel.dpc.get(), Some(dpc_trampoline), ptr::from_ref(&*kernel).cast_mut().cast());
+            }
+            Self { kernel, work: None }
+        }
+        #[cfg(not(target_os = "windows"))]
+        {
+            let host = HostTimer { routine: Box::new(routine), state: Mutex::default(), wake: Condvar::new() };
+            Self { host: Arc::new(host), threads: Mutex::default(), work: None }
+        }
+    }
+    /// A timer whose `routine` runs in a work item at `PASSIVE_LEVEL`.
+    ///
+    /// The timer only queues the work item. If it fires again before the
+    /// previous run has finished, that tick is skipped, so runs never
+    /// overlap. On a host with the fake kernel the queued runs must be run
+    /// before the timer is dropped, which waits for them.
+    pub fn with_work<I, K, F>(irql: &I, kernel: K, device: K::Device, queue: WorkQueue, routine: F) -> Self
+    where
+        I: AtMost<Dispatch>,
+        K: KernelApi + Send + Sync + 'static,
+        F: Fn(&mut Passive) + Send + Sync + 'static,
+    {
+        let tracker = Arc::new(WorkTracker::new());
+        let work_tracker = Arc::clone(&tracker);
+        let scheduled = Arc::new(AtomicBool::new(false));
+        let routine = Arc::new(routine);
+        let mut timer = Self::new(irql, move |irql: &mut Dispatch| {
+            //DPCs of one timer may run on two processors at once, so only one of them claims the run
+            if scheduled.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                return;
+            }
+            work_tracker.begin();
+            let completion = WorkCompletion { tracker: Arc::clone(&work_tracker), scheduled: Arc::clone(&scheduled) };
+            let routine = Arc::clone(&routine);
+            let work = Box::new(move |irql: &mut Passive| {
+                routine(irql);
+                drop(completion);
+            });
+            if let Err(error) = kernel.queue_work(&*irql, device, queue, work) {
+                crate::error!("Failed to queue the work of a timer {error}");
+            }
+        });
+        timer.work = Some(tracker);
+        timer
+    }
+    /// Sets the timer to fire once `due` has passed, replacing what it was
+    /// set to before. Returns whether it was set already.
+    pub fn start<I: AtMost<Dispatch>>(&self, _irql: &I, due: Duration, mode: TimerMode) -> bool {
+        #[cfg(target_os = "windows")]
+        {
+            //negative for a relative time, in 100ns units
+            let due = LARGE_INTEGER { QuadPart: -i64::try_from(due.as_nanos() / 100).unwrap_or(i64::MAX) };
+            unsafe { KeSetTimerEx(self.kernel.timer.get(), due, mode.period_ms(), self.kernel.dpc.get()) != FALSE as BOOLEAN }
+        }
+        #[cfg(not(target_os = "windows"))]
+        {
+            let (generation, was_set) = self.host.restart(true);
+            let host = Arc::clone(&self.host);
+            let thread = thread::spawn(move || host.run(generation, due, mode));
+            let mut threads = self.threads.lock().unwrap_or_else(PoisonError::into_inner);
+            threads.retain(|thread| !thread.is_finished());
+            threads.push(thread);
+            was_set
+        }
+    }
+    /// Stops the timer. Returns whether it was set; a routine that is
+    /// already queued or running still finishes.
+    pub fn cancel<I: AtMost<Dispatch>>(&self, _irql: &I) -> bool {
+        #[cfg(target_os = "windows")]
+        {
+            unsafe { KeCancelTimer(self.kernel.timer.get()) != FALSE as BOOLEAN }
+        }
+        #[cfg(not(target_os = "windows"))]
+        {
+            self.host.restart(false).1
+        }
+    }
+    //nothing fires and nothing runs any more once this returns
+    fn wait_idle(&self, irql: &Passive) {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            KeFlushQueuedDpcs();
+        }
+        #[cfg(not(target_os = "windows"))]
+        for thread in self.threads.lock().unwrap_or_else(PoisonError::into_inner).drain(..) {
+            let _ = thread.join();
+        }
+        if let Some(tracker) = &self.work {
+            tracker.wait_drained(irql);
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        //timers are dropped at PASSIVE_LEVEL, which KeFlushQueuedDpcs requires
+        let irql = unsafe { Passive::new_unchecked() };
+        self.cancel(&irql);
+        self.wait_idle(&irql);
+    }
+}
+
+impl Debug for Timer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timer").field("passive", &self.work.is_some()).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::fake::FakeKernel;
+    use core::sync::atomic::AtomicUsize;
+
+    fn passive() -> Passive {
+        Passive::current().unwrap()
+    }
+
+    #[test]
+    fn a_one_shot_timer_fires_once() {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fired);
+        let timer = Timer::new(&passive(), move |_: &mut Dispatch| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert!(!timer.start(&passive(), Duration::from_millis(1), TimerMode::OneShot));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!timer.cancel(&passive()));
+        drop(timer);
+        assert_eq!(fired.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn a_cancelled_timer_does_not_fire() {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&fired);
+        let timer = Timer::new(&passive(), move |_: &mut Dispatch| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        timer.start(&passive(), Duration::from_secs(60), TimerMode::OneShot);
+        assert!(timer.start(&passive(), Duration::from_secs(60), TimerMode::Periodic(Duration::from_secs(1))));
+        assert!(timer.cancel(&passive()));
+        drop(timer);
+        assert_eq!(fired.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn ticks_are_skipped_while_a_run_is_queued() {
+        let kernel = Arc::new(FakeKernel::new());
+        let device = kernel.create_device(&passive(), "Timer").unwrap();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&runs);
+        let timer = Timer::with_work(&passive(), Arc::clone(&kernel), device, WorkQueue::Delayed, move |_: &mut Passive| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        timer.start(&passive(), Duration::from_millis(1), TimerMode::Periodic(Duration::from_millis(1)));
+        std::thread::sleep(Duration::from_millis(50));
+        //many ticks, one run
+        assert_eq!(kernel.pending_work(), 1);
+        assert_eq!(kernel.run_pending_work(), 1);
+        std::thread::sleep(Duration::from_millis(50));
+        timer.cancel(&passive());
+        assert_eq!(kernel.run_pending_work(), 1);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+        drop(timer);
+        kernel.delete_device(&passive(), device);
+    }
+
+    #[test]
+    fn periods_are_whole_milliseconds() {
+        assert_eq!(TimerMode::OneShot.period_ms(), 0);
+        assert_eq!(TimerMode::Periodic(Duration::from_micros(10)).period_ms(), 1);
+        assert_eq!(TimerMode::Periodic(Duration::from_millis(1500)).period_ms(), 1500);
+        assert_eq!(TimerMode::Periodic(Duration::MAX).period_ms(), i32::MAX);
+    }
+}
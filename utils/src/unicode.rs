@@ -0,0 +1,562 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::{Deref, DerefMut};
+use core::str::FromStr;
+use core::{fmt, mem, slice};
+use crate::sys::UNICODE_STRING;
+#[cfg(target_os = "windows")]
+use wdk_sys::ntddk::RtlUpcaseUnicodeChar;
+
+#[cfg(not(target_os = "windows"))]
+mod upcase_table;
+
+/// The longest string (in UTF-16 code units) that still fits into a
+/// `UNICODE_STRING` together with its terminating nul.
+pub const MAX_UNICODE_LEN: usize = (u16::MAX as usize / mem::size_of::<u16>()) - 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnicodeError {
+    ///the string needs this many UTF-16 units, more than `MAX_UNICODE_LEN`
+    TooLong(usize),
+    ///the unit at this index is a surrogate without its pair
+    UnpairedSurrogate(usize),
+}
+
+impl fmt::Display for UnicodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong(len) => write!(f, "string of {len} UTF-16 units does not fit UNICODE_STRING"),
+            Self::UnpairedSurrogate(index) => write!(f, "unpaired surrogate at index {index}"),
+        }
+    }
+}
+
+///encodes the string as UTF-16 (surrogate pairs included) without the nul terminator
+pub fn encode_utf16(value: &str) -> Vec<u16> {
+    value.encode_utf16().collect()
+}
+
+///decodes UTF-16 units, replacing unpaired surrogates with U+FFFD
+pub fn decode_utf16_lossy(units: &[u16]) -> String {
+    char::decode_utf16(units.iter().copied())
+        .map(|unit| unit.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+///decodes UTF-16 units, failing on the first unpaired surrogate
+pub fn decode_utf16(units: &[u16]) -> Result<String, UnicodeError> {
+    let mut result = String::with_capacity(units.len());
+    let mut index = 0;
+    for unit in char::decode_utf16(units.iter().copied()) {
+        match unit {
+            Ok(symbol) => {
+                result.push(symbol);
+                index += symbol.len_utf16();
+            }
+            Err(_) => return Err(UnicodeError::UnpairedSurrogate(index)),
+        }
+    }
+    Ok(result)
+}
+
+/// Returns the code units described by `unicode`.
+///
+/// # Safety
+/// `unicode.Buffer` must point to at least `unicode.Length` readable bytes
+/// that stay alive and unchanged for `'a`.
+pub unsafe fn unicode_units<'a>(unicode: &UNICODE_STRING) -> &'a [u16] {
+    if unicode.Buffer.is_null() || unicode.Length == 0 {
+        return &[];
+    }
+    slice::from_raw_parts(unicode.Buffer, unicode.Length as usize / mem::size_of::<u16>())
+}
+
+/// A `UNICODE_STRING` that owns its nul-terminated buffer.
+///
+/// The buffer is released together with the value, so nothing is leaked
+/// when a name is built only for the duration of a single API call.
+/// Dereferences to `UNICODE_STRING`, which lets it be handed to any routine
+/// expecting `PUNICODE_STRING` or `PCUNICODE_STRING`.
+pub struct OwnedUnicodeString {
+    raw: UNICODE_STRING,
+    //the last unit is always nul and is not counted by `raw.Length`
+    buffer: Vec<u16>,
+}
+
+//the raw string only ever points into the owned buffer
+unsafe impl Send for OwnedUnicodeString {}
+
+unsafe impl Sync for OwnedUnicodeString {}
+
+impl OwnedUnicodeString {
+    pub fn new(value: &str) -> Result<Self, UnicodeError> {
+        Self::from_units(encode_utf16(value))
+    }
+    ///takes ownership of already encoded units (without the nul terminator)
+    pub fn from_units(mut units: Vec<u16>) -> Result<Self, UnicodeError> {
+        let len = units.len();
+        if len > MAX_UNICODE_LEN {
+            return Err(UnicodeError::TooLong(len));
+        }
+        units.push(0u16);
+        let byte_len = len * mem::size_of::<u16>();
+        let raw = UNICODE_STRING {
+            Length: byte_len as u16,
+            MaximumLength: (byte_len + mem::size_of::<u16>()) as u16,
+            Buffer: units.as_mut_ptr(),
+        };
+        Ok(Self { raw, buffer: units })
+    }
+    ///the code units without the nul terminator
+    pub fn as_units(&self) -> &[u16] {
+        let len = usize::min(self.len(), self.buffer.len() - 1);
+        &self.buffer[..len]
+    }
+    ///the number of UTF-16 code units
+    pub const fn len(&self) -> usize {
+        self.raw.Length as usize / mem::size_of::<u16>()
+    }
+    pub const fn is_empty(&self) -> bool {
+        self.raw.Length == 0
+    }
+    pub const fn as_ptr(&self) -> *const UNICODE_STRING {
+        &self.raw
+    }
+    pub fn as_mut_ptr(&mut self) -> *mut UNICODE_STRING {
+        &mut self.raw
+    }
+    pub fn to_string_lossy(&self) -> String {
+        decode_utf16_lossy(self.as_units())
+    }
+    pub fn as_unicode_str(&self) -> UnicodeStr<'_> {
+        UnicodeStr::new(self.as_units())
+    }
+}
+
+impl Deref for OwnedUnicodeString {
+    type Target = UNICODE_STRING;
+
+    fn deref(&self) -> &Self::Target {
+        &self.raw
+    }
+}
+
+///callees may only touch the buffer contents within `MaximumLength`,
+///replacing `Buffer` itself is not supported
+impl DerefMut for OwnedUnicodeString {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.raw
+    }
+}
+
+impl Clone for OwnedUnicodeString {
+    fn clone(&self) -> Self {
+        Self::from_units(self.as_units().to_vec()).expect("The source string already fits")
+    }
+}
+
+impl PartialEq for OwnedUnicodeString {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_units() == other.as_units()
+    }
+}
+
+impl Eq for OwnedUnicodeString {}
+
+impl FromStr for OwnedUnicodeString {
+    type Err = UnicodeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::new(value)
+    }
+}
+
+impl fmt::Display for OwnedUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for symbol in char::decode_utf16(self.as_units().iter().copied()) {
+            fmt::Write::write_char(f, symbol.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for OwnedUnicodeString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+/// Upcases a single UTF-16 code unit with the upcase table of the kernel,
+/// as `RtlEqualUnicodeString` and the file systems fold names.
+///
+/// On the target this is `RtlUpcaseUnicodeChar`; the host build looks the
+/// unit up in a copy of the table. The table is older than the Unicode
+/// `core` ships and leaves some letters alone that `char::to_uppercase`
+/// maps (`ı`, `ſ`, `µ`, the titlecase `ǅ`). Every unit maps to exactly one
+/// unit and surrogates are never touched, so lengths and indices survive
+/// case folding.
+#[cfg(target_os = "windows")]
+pub fn upcase(unit: u16) -> u16 {
+    unsafe { RtlUpcaseUnicodeChar(unit) }
+}
+
+/// Upcases a single UTF-16 code unit with the upcase table of the kernel,
+/// see the target version.
+#[cfg(not(target_os = "windows"))]
+pub fn upcase(unit: u16) -> u16 {
+    upcase_table::UPCASE[usize::from(unit)]
+}
+
+///[`upcase`] for a `char`, leaving the characters outside the BMP as is
+pub fn upcase_char(symbol: char) -> char {
+    u16::try_from(u32::from(symbol)).ok()
+        .and_then(|unit| char::from_u32(upcase(unit).into()))
+        .unwrap_or(symbol)
+}
+
+///compares two strings as [`UnicodeStr::eq_ignore_case`] compares their UTF-16 forms
+pub fn str_eq_ignore_case(first: &str, second: &str) -> bool {
+    first.chars().map(upcase_char).eq(second.chars().map(upcase_char))
+}
+
+fn units_equal(first: &[u16], second: &[u16], ignore_case: bool) -> bool {
+    if ignore_case {
+        first.len() == second.len()
+            && first.iter().zip(second).all(|(left, right)| left == right || upcase(*left) == upcase(*right))
+    } else {
+        first == second
+    }
+}
+
+/// A borrowed view over UTF-16 text, typically the buffer of a `UNICODE_STRING`.
+///
+/// Nothing is copied; comparisons that ignore case fold both sides with
+/// [`upcase`], unit by unit.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct UnicodeStr<'a> {
+    units: &'a [u16],
+}
+
+impl<'a> UnicodeStr<'a> {
+    pub const PATH_SEPARATOR: u16 = b'\\' as u16;
+
+    pub const fn new(units: &'a [u16]) -> Self {
+        Self { units }
+    }
+    /// Wraps the buffer of `unicode` without copying it.
+    ///
+    /// # Safety
+    /// Same as [`unicode_units`]: the buffer must stay valid for `'a`.
+    pub unsafe fn from_unicode(unicode: &UNICODE_STRING) -> Self {
+        Self::new(unicode_units(unicode))
+    }
+    pub const fn as_units(&self) -> &'a [u16] {
+        self.units
+    }
+    pub const fn len(&self) -> usize {
+        self.units.len()
+    }
+    pub const fn is_empty(&self) -> bool {
+        self.units.is_empty()
+    }
+    pub fn to_string_lossy(&self) -> String {
+        decode_utf16_lossy(self.units)
+    }
+    pub fn eq_ignore_case(&self, other: UnicodeStr<'_>) -> bool {
+        units_equal(self.units, other.units, true)
+    }
+    pub fn starts_with(&self, prefix: UnicodeStr<'_>, ignore_case: bool) -> bool {
+        self.units.len() >= prefix.len()
+            && units_equal(&self.units[..prefix.len()], prefix.units, ignore_case)
+    }
+    pub fn ends_with(&self, suffix: UnicodeStr<'_>, ignore_case: bool) -> bool {
+        self.units.len() >= suffix.len()
+            && units_equal(&self.units[self.units.len() - suffix.len()..], suffix.units, ignore_case)
+    }
+    ///the unit index of the first occurrence of `needle`, an empty needle is found at 0
+    pub fn find(&self, needle: UnicodeStr<'_>, ignore_case: bool) -> Option<usize> {
+        if needle.len() > self.len() {
+            return None;
+        }
+        (0..=self.len() - needle.len())
+            .find(|&start| units_equal(&self.units[start..start + needle.len()], needle.units, ignore_case))
+    }
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        Self::new(&self.units[start..end])
+    }
+    ///the non-empty parts between backslashes, so `\Device\\x\` yields `Device` and `x`
+    pub fn components(&self) -> Components<'a> {
+        Components { rest: self.units }
+    }
+    ///the last path component, if any
+    pub fn file_name(&self) -> Option<UnicodeStr<'a>> {
+        self.components().next_back()
+    }
+}
+
+impl<'a> From<&'a [u16]> for UnicodeStr<'a> {
+    fn from(units: &'a [u16]) -> Self {
+        Self::new(units)
+    }
+}
+
+impl<'a> From<&'a OwnedUnicodeString> for UnicodeStr<'a> {
+    fn from(value: &'a OwnedUnicodeString) -> Self {
+        value.as_unicode_str()
+    }
+}
+
+impl fmt::Display for UnicodeStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for symbol in char::decode_utf16(self.units.iter().copied()) {
+            fmt::Write::write_char(f, symbol.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for UnicodeStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{self}\"")
+    }
+}
+
+///iterator over the components of a backslash separated path
+#[derive(Clone)]
+pub struct Components<'a> {
+    rest: &'a [u16],
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = UnicodeStr<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            let end = self.rest.iter()
+                .position(|&unit| unit == UnicodeStr::PATH_SEPARATOR)
+                .unwrap_or(self.rest.len());
+            let component = &self.rest[..end];
+            self.rest = self.rest.get(end + 1..).unwrap_or(&[]);
+            if !component.is_empty() {
+                return Some(UnicodeStr::new(component));
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for Components<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            let start = self.rest.iter()
+                .rposition(|&unit| unit == UnicodeStr::PATH_SEPARATOR)
+                .map_or(0, |separator| separator + 1);
+            let component = &self.rest[start..];
+            self.rest = &self.rest[..start.saturating_sub(1)];
+            if !component.is_empty() {
+                return Some(UnicodeStr::new(component));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn utf16_round_trip() {
+        for text in ["", "firefox.exe", "\\Device\\HarddiskVolume3\\Windows", "\u{e9}t\u{e9}", "\u{4f60}\u{597d}", "\u{1f600} and \u{10ffff}"] {
+            let units = encode_utf16(text);
+            assert_eq!(units.len(), text.chars().map(char::len_utf16).sum::<usize>());
+            assert_eq!(decode_utf16(&units).unwrap(), text);
+            assert_eq!(decode_utf16_lossy(&units), text);
+        }
+    }
+
+    #[test]
+    fn characters_outside_the_bmp_become_surrogate_pairs() {
+        assert_eq!(encode_utf16("\u{1f600}"), [0xd83d, 0xde00]);
+        assert_eq!(encode_utf16("a\u{10000}"), [0x61, 0xd800, 0xdc00]);
+    }
+
+    #[test]
+    fn unpaired_surrogates() {
+        assert_eq!(decode_utf16(&[0x61, 0xd83d]), Err(UnicodeError::UnpairedSurrogate(1)));
+        assert_eq!(decode_utf16(&[0xd83d, 0xde00, 0xde00, 0x61]), Err(UnicodeError::UnpairedSurrogate(2)));
+        assert_eq!(decode_utf16_lossy(&[0x61, 0xde00, 0x62]), "a\u{fffd}b");
+        assert_eq!(decode_utf16_lossy(&[0xd800]), "\u{fffd}");
+    }
+
+    #[test]
+    fn owned_string_is_nul_terminated_and_counts_bytes() {
+        let owned = OwnedUnicodeString::new("a\u{1f600}").unwrap();
+        assert_eq!(owned.Length, 6);
+        assert_eq!(owned.MaximumLength, 8);
+        assert_eq!(owned.len(), 3);
+        assert_eq!(owned.as_units(), [0x61, 0xd83d, 0xde00]);
+        let raw = unsafe { slice::from_raw_parts(owned.Buffer, owned.MaximumLength as usize / 2) };
+        assert_eq!(raw, [0x61, 0xd83d, 0xde00, 0]);
+        assert_eq!(unsafe { unicode_units(&owned) }, owned.as_units());
+        assert_eq!(owned.to_string_lossy(), "a\u{1f600}");
+        assert_eq!(owned.to_string(), "a\u{1f600}");
+    }
+
+    #[test]
+    fn empty_owned_string() {
+        let owned = OwnedUnicodeString::new("").unwrap();
+        assert!(owned.is_empty());
+        assert_eq!((owned.Length, owned.MaximumLength), (0, 2));
+        assert_eq!(unsafe { *owned.Buffer }, 0);
+        assert_eq!(unsafe { unicode_units(&UNICODE_STRING::default()) }, [0u16; 0]);
+    }
+
+    #[test]
+    fn the_longest_string_still_fits() {
+        let owned = OwnedUnicodeString::from_units(vec![0x61; MAX_UNICODE_LEN]).unwrap();
+        assert_eq!(owned.Length as usize, MAX_UNICODE_LEN * 2);
+        assert_eq!(owned.MaximumLength, u16::MAX - 1);
+        assert!(matches!(OwnedUnicodeString::from_units(vec![0x61; MAX_UNICODE_LEN + 1]), Err(UnicodeError::TooLong(len)) if len == MAX_UNICODE_LEN + 1));
+        //one unit below the limit, but two units once encoded
+        let text: String = core::iter::repeat_n('a', MAX_UNICODE_LEN - 1).chain(['\u{1f600}']).collect();
+        assert_eq!(OwnedUnicodeString::new(&text).err(), Some(UnicodeError::TooLong(MAX_UNICODE_LEN + 1)));
+    }
+
+    #[test]
+    fn clones_own_their_buffer() {
+        let first: OwnedUnicodeString = "notepad.exe".parse().unwrap();
+        let second = first.clone();
+        assert_eq!(first, second);
+        assert_ne!(first.Buffer, second.Buffer);
+        drop(first);
+        assert_eq!(second.to_string_lossy(), "notepad.exe");
+        assert_eq!(alloc::format!("{second:?}"), "\"notepad.exe\"");
+    }
+
+    fn units(text: &str) -> Vec<u16> {
+        encode_utf16(text)
+    }
+
+    fn unit(symbol: char) -> u16 {
+        u16::try_from(u32::from(symbol)).unwrap()
+    }
+
+    #[test]
+    fn upcase_maps_every_unit_to_one_unit() {
+        for unit in 0..=u16::MAX {
+            let upper = upcase(unit);
+            if (0xd800..0xe000).contains(&unit) {
+                assert_eq!(upper, unit);
+            }
+            if unit < 0x80 {
+                assert_eq!(upper, u16::from((unit as u8).to_ascii_uppercase()));
+            }
+        }
+    }
+
+    #[test]
+    fn upcase_pins_the_non_ascii_mapping() {
+        for (lower, upper) in [
+            ('\u{e9}', '\u{c9}'),
+            ('\u{ff}', '\u{178}'),
+            ('\u{3c3}', '\u{3a3}'),
+            ('\u{3c2}', '\u{3a3}'),
+            ('\u{436}', '\u{416}'),
+            ('\u{1c6}', '\u{1c4}'),
+            ('\u{250}', '\u{2c6f}'),
+            ('\u{2d00}', '\u{10a0}'),
+            ('\u{a791}', '\u{a790}'),
+            ('\u{ff41}', '\u{ff21}'),
+        ] {
+            assert_eq!(upcase(unit(lower)), unit(upper), "{lower}");
+        }
+        //already uppercase, no case at all, or an uppercase form of more than one character
+        for unchanged in ['\u{df}', '\u{149}', '\u{1f0}', '\u{c9}', '\u{130}', '1', '\\'] {
+            assert_eq!(upcase(unit(unchanged)), unit(unchanged), "{unchanged}");
+        }
+    }
+
+    #[test]
+    fn upcase_leaves_alone_what_the_kernel_table_does_not_map() {
+        //Unicode maps these to another letter's uppercase, or to the uppercase of a titlecase digraph
+        let compatibility = ['\u{b5}', '\u{131}', '\u{17f}', '\u{1c5}', '\u{1c8}', '\u{1cb}', '\u{1f2}', '\u{345}'];
+        let greek = ['\u{3d0}', '\u{3d1}', '\u{3d5}', '\u{3d6}', '\u{3f0}', '\u{3f1}', '\u{3f5}', '\u{1e9b}', '\u{1fbe}'];
+        //case pairs of Unicode versions newer than the table
+        let newer = ['\u{266}', '\u{10d0}', '\u{13f8}', '\u{2d27}', '\u{a793}', '\u{ab70}'];
+        for unchanged in compatibility.into_iter().chain(greek).chain(newer) {
+            assert_ne!(upcase_char(unchanged), unchanged.to_uppercase().next().unwrap(), "{unchanged}");
+            assert_eq!(upcase(unit(unchanged)), unit(unchanged), "{unchanged}");
+        }
+    }
+
+    #[test]
+    fn strings_fold_as_their_units_do() {
+        for (first, second) in [("\u{e9}diteur.EXE", "\u{c9}DITEUR.exe"), ("\u{131}", "I"), ("\u{3c2}", "\u{3a3}"), ("\u{df}", "SS"), ("a\u{1f600}", "A\u{1f600}")] {
+            let (first_units, second_units) = (units(first), units(second));
+            let expected = UnicodeStr::new(&first_units).eq_ignore_case(UnicodeStr::new(&second_units));
+            assert_eq!(str_eq_ignore_case(first, second), expected, "{first} {second}");
+        }
+        assert!(str_eq_ignore_case("\u{e9}t\u{e9}", "\u{c9}T\u{c9}"));
+        assert!(!str_eq_ignore_case("\u{df}", "SS"));
+        assert_eq!(upcase_char('\u{1f600}'), '\u{1f600}');
+    }
+
+    #[test]
+    fn comparisons_ignoring_case() {
+        let path = units("\\Device\\HarddiskVolume3\\Program Files\\\u{e9}diteur.EXE");
+        let path = UnicodeStr::new(&path);
+        let upper = units("\\DEVICE\\HARDDISKVOLUME3\\PROGRAM FILES\\\u{c9}DITEUR.EXE");
+        assert!(path.eq_ignore_case(UnicodeStr::new(&upper)));
+        assert_ne!(path, UnicodeStr::new(&upper));
+        assert!(!path.eq_ignore_case(UnicodeStr::new(&upper[1..])));
+        let prefix = units("\\device\\harddiskvolume3\\");
+        assert!(path.starts_with(UnicodeStr::new(&prefix), true));
+        assert!(!path.starts_with(UnicodeStr::new(&prefix), false));
+        let suffix = units(".exe");
+        assert!(path.ends_with(UnicodeStr::new(&suffix), true));
+        assert!(!path.ends_with(UnicodeStr::new(&suffix), false));
+        assert!(!UnicodeStr::new(&suffix).ends_with(path, true));
+        let needle = units("program files");
+        assert_eq!(path.find(UnicodeStr::new(&needle), true), Some(24));
+        assert_eq!(path.find(UnicodeStr::new(&needle), false), None);
+        assert_eq!(path.find(UnicodeStr::default(), false), Some(0));
+        assert_eq!(UnicodeStr::new(&needle).find(path, true), None);
+        //`ß` does not fold to `SS`, so the lengths differ
+        assert!(!UnicodeStr::new(&units("stra\u{df}e")).eq_ignore_case(UnicodeStr::new(&units("STRASSE"))));
+    }
+
+    #[test]
+    fn components_skip_empty_parts() {
+        let path = units("\\Device\\\\x\\y.exe\\");
+        let path = UnicodeStr::new(&path);
+        let names: Vec<String> = path.components().map(|component| component.to_string_lossy()).collect();
+        assert_eq!(names, ["Device", "x", "y.exe"]);
+        let reversed: Vec<String> = path.components().rev().map(|component| component.to_string_lossy()).collect();
+        assert_eq!(reversed, ["y.exe", "x", "Device"]);
+        let mut both = path.components();
+        assert_eq!(both.next().unwrap().to_string_lossy(), "Device");
+        assert_eq!(both.next_back().unwrap().to_string_lossy(), "y.exe");
+        assert_eq!(both.next().unwrap().to_string_lossy(), "x");
+        assert!(both.next_back().is_none());
+        assert_eq!(path.file_name().unwrap().to_string_lossy(), "y.exe");
+        assert!(UnicodeStr::new(&units("\\\\")).file_name().is_none());
+        assert_eq!(UnicodeStr::new(&units("notepad.exe")).file_name().unwrap().to_string_lossy(), "notepad.exe");
+    }
+
+    #[test]
+    fn windows_unicode_round_trip() {
+        use crate::WindowsUnicode;
+        let text = String::from("C:\\Program Files\\\u{e9}\u{1f600}.exe");
+        assert_eq!(String::from_unicode(&text.to_unicode()), text);
+        let broken = [0x61u16, 0xdc00];
+        let raw = UNICODE_STRING { Length: 4, MaximumLength: 4, Buffer: broken.as_ptr().cast_mut() };
+        assert_eq!(String::from_unicode(&raw), "a\u{fffd}");
+    }
+}
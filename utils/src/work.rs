@@ -0,0 +1,192 @@
+//! Deferred work on the system worker threads.
+//!
+//! [`WorkItem`] runs any `FnOnce` closure at `PASSIVE_LEVEL`, handing it the
+//! [`Passive`](crate::irql::Passive) token, and frees its
+//! `IO_WORKITEM` by itself. Items queued with a [`WorkTracker`] are counted
+//! until their closure has returned, so an unloading driver can wait for
+//! them before it releases what they use.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::irql::Passive;
+#[cfg(target_os = "windows")]
+use {
+    alloc::boxed::Box,
+    alloc::sync::Arc,
+    crate::irql::{AtMost, Dispatch},
+    crate::kernel::WorkRoutine,
+    crate::status::NtError,
+    core::ptr::NonNull,
+    wdk_sys::_WORK_QUEUE_TYPE::{self, CriticalWorkQueue, DelayedWorkQueue, HyperCriticalWorkQueue},
+    wdk_sys::ntddk::{IoAllocateWorkItem, IoFreeWorkItem, IoQueueWorkItem, KeDelayExecutionThread},
+    wdk_sys::_MODE::KernelMode,
+    wdk_sys::{DEVICE_OBJECT, FALSE, IO_WORKITEM, LARGE_INTEGER, PVOID, STATUS_INSUFFICIENT_RESOURCES},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum WorkQueue {
+    ///for time-critical items; the threads run at a real-time priority
+    Critical,
+    ///the default queue, for anything that can wait
+    #[default]
+    Delayed,
+    ///reserved for the system, never block on this queue
+    HyperCritical,
+}
+
+#[cfg(target_os = "windows")]
+impl WorkQueue {
+    pub const fn to_raw(self) -> _WORK_QUEUE_TYPE::Type {
+        match self {
+            Self::Critical => CriticalWorkQueue,
+            Self::Delayed => DelayedWorkQueue,
+            Self::HyperCritical => HyperCriticalWorkQueue,
+        }
+    }
+}
+
+///counts the work items that are queued but have not finished yet
+#[derive(Debug, Default)]
+pub struct WorkTracker {
+    outstanding: AtomicUsize,
+}
+
+impl WorkTracker {
+    pub const fn new() -> Self {
+        Self { outstanding: AtomicUsize::new(0) }
+    }
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Acquire)
+    }
+    pub fn is_drained(&self) -> bool {
+        self.outstanding() == 0
+    }
+    ///counts one more item; every `begin` must be matched by a `complete`
+    pub fn begin(&self) {
+        self.outstanding.fetch_add(1, Ordering::AcqRel);
+    }
+    pub fn complete(&self) {
+        let previous = self.outstanding.fetch_sub(1, Ordering::AcqRel);
+        debug_assert!(previous > 0, "more work items completed than queued");
+    }
+    /// Blocks until every tracked item has finished.
+    ///
+    /// Must never be called from one of the tracked items, which would wait
+    /// for itself.
+    pub fn wait_drained(&self, irql: &Passive) {
+        while !self.is_drained() {
+            pause(irql);
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn pause(_irql: &Passive) {
+    //relative time in 100ns units
+    let mut interval = LARGE_INTEGER { QuadPart: -10 * 1000 * 10 };
+    let _ = unsafe { KeDelayExecutionThread(KernelMode as _, FALSE as _, &mut interval) };
+}
+
+#[cfg(not(target_os = "windows"))]
+fn pause(_irql: &Passive) {
+    core::hint::spin_loop();
+}
+
+#[cfg(target_os = "windows")]
+pub struct WorkItem {
+    handle: NonNull<IO_WORKITEM>,
+    routine: Option<WorkRoutine>,
+    tracker: Option<Arc<WorkTracker>>,
+}
+
+#[cfg(target_os = "windows")]
+unsafe impl Send for WorkItem {}
+
+#[cfg(target_os = "windows")]
+impl WorkItem {
+    ///allocates the item; the device keeps the driver loaded until the routine has returned
+    pub fn new<I, F>(_irql: &I, device: *mut DEVICE_OBJECT, routine: F) -> Result<Box<Self>, NtError>
+    where
+        I: AtMost<Dispatch>,
+        F: FnOnce(&mut Passive) + Send + 'static,
+    {
+        let handle = NonNull::new(unsafe { IoAllocateWorkItem(device) })
+            .ok_or(NtError::new(STATUS_INSUFFICIENT_RESOURCES))?;
+        Ok(Box::new(Self { handle, routine: Some(Box::new(routine)), tracker: None }))
+    }
+    #[must_use]
+    pub fn tracked_by(mut self: Box<Self>, tracker: &Arc<WorkTracker>) -> Box<Self> {
+        self.tracker = Some(Arc::clone(tracker));
+        self
+    }
+    ///hands the item to the system; it frees itself after the routine has run
+    pub fn queue<I: AtMost<Dispatch>>(self: Box<Self>, _irql: &I, queue: WorkQueue) {
+        if let Some(tracker) = &self.tracker {
+            tracker.begin();
+        }
+        let handle = self.handle.as_ptr();
+        unsafe {
+            IoQueueWorkItem(handle, Some(Self::dispatch), queue.to_raw(), Box::into_raw(self).cast());
+        }
+    }
+    unsafe extern "C" fn dispatch(_device: *mut DEVICE_OBJECT, context: PVOID) {
+        let mut item = Box::from_raw(context.cast::<Self>());
+        if let Some(routine) = item.routine.take() {
+            //work items run at PASSIVE_LEVEL
+            routine(&mut Passive::new_unchecked());
+        }
+        let tracker = item.tracker.take();
+        drop(item);
+        if let Some(tracker) = tracker {
+            tracker.complete();
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for WorkItem {
+    fn drop(&mut self) {
+        unsafe { IoFreeWorkItem(self.handle.as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[test]
+    fn items_are_counted_until_they_complete() {
+        let tracker = WorkTracker::new();
+        assert!(tracker.is_drained());
+        tracker.begin();
+        tracker.begin();
+        assert_eq!(tracker.outstanding(), 2);
+        assert!(!tracker.is_drained());
+        tracker.complete();
+        assert_eq!(tracker.outstanding(), 1);
+        assert!(!tracker.is_drained());
+        tracker.complete();
+        assert_eq!(tracker.outstanding(), 0);
+        assert!(tracker.is_drained());
+    }
+
+    #[test]
+    fn waiting_returns_once_the_last_item_completes() {
+        let tracker = WorkTracker::new();
+        let finished = AtomicBool::new(false);
+        tracker.begin();
+        tracker.begin();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                tracker.complete();
+                thread::yield_now();
+                finished.store(true, Ordering::Release);
+                tracker.complete();
+            });
+            tracker.wait_drained(&Passive::current().unwrap());
+            assert!(finished.load(Ordering::Acquire));
+        });
+        assert!(tracker.is_drained());
+    }
+}